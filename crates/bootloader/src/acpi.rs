@@ -138,7 +138,8 @@ impl LapicIter {
 }
 
 impl Iterator for LapicIter {
-    type Item = LocalApic;
+    /// Both entry types are returned as x2apic entries to keep the full id
+    type Item = LocalX2Apic;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -181,7 +182,7 @@ impl Iterator for LapicIter {
                     // a valid APIC
                     if (lapic.flags & APIC_ENABLED) != 0 || (lapic.flags & APIC_ONLINE_CAPABLE) != 0
                     {
-                        return Some(lapic);
+                        return Some(lapic.into());
                     }
                 }
                 // x2apic entry
@@ -191,14 +192,15 @@ impl Iterator for LapicIter {
                     }
 
                     // Read the struct
-                    let lapic: LocalApic = unsafe { read_phys(self.current) };
+                    let x2apic: LocalX2Apic = unsafe { read_phys(self.current) };
                     // Go to the next ICS entry
                     self.current += len as u32;
                     // If the processor is enabled, or can be enabled, log it as
                     // a valid APIC
-                    if (lapic.flags & APIC_ENABLED) != 0 || (lapic.flags & APIC_ONLINE_CAPABLE) != 0
+                    if (x2apic.flags & APIC_ENABLED) != 0
+                        || (x2apic.flags & APIC_ONLINE_CAPABLE) != 0
                     {
-                        return Some(lapic);
+                        return Some(x2apic);
                     }
                 }
                 _ => {
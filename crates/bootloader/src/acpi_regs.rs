@@ -77,6 +77,36 @@ impl fmt::Debug for LocalApic {
     }
 }
 
+#[derive(Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct LocalX2Apic {
+    pub typ: u8,
+    pub length: u8,
+    pub res0: u16,
+    pub x2apic_id: u32,
+    pub flags: u32,
+    pub processor_uid: u32,
+}
+
+impl From<LocalApic> for LocalX2Apic {
+    fn from(lapic: LocalApic) -> Self {
+        Self {
+            typ: lapic.typ,
+            length: lapic.length,
+            res0: 0,
+            x2apic_id: lapic.id as u32,
+            flags: lapic.flags,
+            processor_uid: lapic.processor_uid as u32,
+        }
+    }
+}
+
+impl fmt::Debug for LocalX2Apic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { write!(f, "LApic id: {}", read_unaligned(addr_of!(self.x2apic_id))) }
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct IntOverride {
@@ -66,7 +66,7 @@ impl BootInfo {
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
 pub struct Cores {
-    cores: [Core; 256],
+    cores: [Core; crate::MAX_CORES],
     pub num_booted_cores: u16,
     pub num_cores: u32,
 }
@@ -74,15 +74,15 @@ pub struct Cores {
 impl Cores {
     pub const fn empty() -> Self {
         Self {
-            cores: [Core::empty(); 256],
+            cores: [Core::empty(); crate::MAX_CORES],
             num_cores: 0,
             num_booted_cores: 0,
         }
     }
 
-    pub fn get_by_apic_id(&self, id: u8) -> Option<(&Core, usize)> {
+    pub fn get_by_apic_id(&self, id: u32) -> Option<(&Core, usize)> {
         for (i, core) in self.cores.iter().take(self.num_cores as usize).enumerate() {
-            if core.apic_id == id.into() {
+            if core.get_apic_id() == Some(id) {
                 return Some((core, i));
             }
         }
@@ -115,7 +115,8 @@ impl fmt::Debug for Cores {
 #[derive(Copy, Clone, PartialEq, Eq)]
 #[repr(C, packed)]
 pub struct Core {
-    apic_id: u16,
+    /// Full x2apic id, `u32::MAX` if unset
+    apic_id: u32,
     /// Start address of stack for physical core
     stack_start_addr: u32,
     /// End address of stack for physical core
@@ -151,7 +152,7 @@ impl TSS {
 impl Core {
     pub const fn empty() -> Self {
         Self {
-            apic_id: u16::MAX,
+            apic_id: u32::MAX,
             stack_start_addr: 0,
             stack_end_addr: 0,
             tss: TSS {
@@ -161,15 +162,15 @@ impl Core {
         }
     }
 
-    pub fn set_apic_id(&mut self, addr: u8) {
-        self.apic_id = addr as u16;
+    pub fn set_apic_id(&mut self, id: u32) {
+        self.apic_id = id;
     }
 
-    pub fn get_apic_id(&self) -> Option<u8> {
-        if self.apic_id == u16::MAX {
+    pub fn get_apic_id(&self) -> Option<u32> {
+        if self.apic_id == u32::MAX {
             None
         } else {
-            Some(self.apic_id as u8)
+            Some(self.apic_id)
         }
     }
 
@@ -395,7 +395,14 @@ unsafe extern "C" fn bootloader_main(magic: u32, mboot2_info_ptr: u32) {
         let lapic_iter = acpi::LapicIter::new().expect("Couldn't find acpi table");
 
         for (i, lapic) in lapic_iter.enumerate() {
+            if i >= bootloader::MAX_CORES {
+                panic!(
+                    "CPU has more then {} cores. Recompile with different MAX_CORES constant",
+                    bootloader::MAX_CORES
+                );
+            }
             BOOT_INFO.cores.num_cores += 1;
+            let apic_id = lapic.x2apic_id;
 
             let addr = iter
                 .next()
@@ -403,13 +410,13 @@ unsafe extern "C" fn bootloader_main(magic: u32, mboot2_info_ptr: u32) {
             let stack_start = addr + stack_size + guard_page;
             BOOT_INFO.cores[i as usize].set_stack_start(stack_start.try_into().unwrap());
             BOOT_INFO.cores[i as usize].stack_end_addr = (addr + guard_page).try_into().unwrap();
-            BOOT_INFO.cores[i as usize].set_apic_id(lapic.id);
+            BOOT_INFO.cores[i as usize].set_apic_id(apic_id);
             log::debug!(
                 "Core {} stack space from: {:#x} to {:#x} with apic id: {}",
                 i,
                 addr + guard_page,
                 stack_start,
-                lapic.id
+                apic_id
             );
 
             // Set 2Mb guard page to readable with NX bit set
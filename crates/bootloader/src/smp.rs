@@ -1,5 +1,5 @@
 use crate::bootinfo;
-use core::arch::x86::__cpuid;
+use core::arch::x86::{__cpuid, __cpuid_count};
 use core::ptr::*;
 use x86::addr::PhysAddr;
 use x86::registers::control::{Cr0, Cr0Flags};
@@ -15,10 +15,19 @@ extern "C" {
     ) -> !;
 }
 
-pub fn apic_id() -> u8 {
+/// Full 32 bit apic id, the 8 bit id of leaf 1 is truncated on x2apic systems
+pub fn apic_id() -> u32 {
     unsafe {
+        // The extended topology leaf reports the x2apic id in edx,
+        // it is valid if it reports logical processors in ebx
+        if __cpuid(0).eax >= 0xb {
+            let res = __cpuid_count(0xb, 0);
+            if res.ebx & 0xffff != 0 {
+                return res.edx;
+            }
+        }
         let res = __cpuid(0x0000_0001);
-        (res.ebx >> 24) as u8
+        res.ebx >> 24
     }
 }
 
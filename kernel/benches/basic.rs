@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+#![feature(bench_black_box)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::hint::black_box;
+use core::panic::PanicInfo;
+use perf_kernel::bench::{BenchConfig, Benchmark};
+use perf_kernel::{klog, println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    klog::init();
+
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== basic benchmarks =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+static EMPTY: Benchmark = Benchmark {
+    name: "empty",
+    config: BenchConfig::new(),
+    routine: || black_box(()),
+};
+
+#[test_case]
+static RDTSC: Benchmark = Benchmark {
+    name: "rdtsc",
+    config: BenchConfig::new(),
+    routine: || {
+        black_box(perf_kernel::time::rdtsc());
+    },
+};
+
+#[test_case]
+static CPUID: Benchmark = Benchmark {
+    name: "cpuid",
+    config: BenchConfig::new(),
+    routine: || {
+        black_box(unsafe { core::arch::x86_64::__cpuid(0) });
+    },
+};
+
+#[test_case]
+static BOX_ALLOC: Benchmark = Benchmark {
+    name: "box alloc",
+    config: BenchConfig::new(),
+    routine: || {
+        black_box(Box::new(black_box(0u64)));
+    },
+};
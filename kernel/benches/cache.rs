@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use perf_kernel::{klog, println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    klog::init();
+
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== cache and tlb characterization =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn cache_and_tlb() {
+    perf_kernel::bench::cache::run_suite();
+}
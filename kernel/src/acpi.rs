@@ -19,12 +19,16 @@ pub unsafe fn init() -> &'static Acpi {
 }
 
 pub struct Acpi {
-    pub apics: Option<Vec<LocalApic>>,
+    /// Local apics and x2apics of the enabled cores, both with their full id
+    pub apics: Option<Vec<LocalX2Apic>>,
     pub ioapics: Option<Vec<IoApic>>,
     pub int_overrides: Option<Vec<IntOverride>>,
     pub nmis: Option<Vec<NonMaskableInts>>,
     pub apic_domains: Option<BTreeMap<u32, u32>>,
     pub memory_domains: Option<BTreeMap<u32, RangeSet>>,
+    pub hpet: Option<HpetTable>,
+    pub fadt: Option<Fadt>,
+    pub mcfg: Option<Vec<McfgEntry>>,
     pub mask_pics: bool,
 }
 
@@ -37,6 +41,9 @@ impl fmt::Debug for Acpi {
         writeln!(f, "non maskable ints: {:?}", self.nmis).unwrap();
         writeln!(f, "apic domains: {:?}", self.apic_domains).unwrap();
         writeln!(f, "memory domains: {:?}", self.memory_domains).unwrap();
+        writeln!(f, "hpet: {:?}", self.hpet).unwrap();
+        writeln!(f, "fadt: {:?}", self.fadt).unwrap();
+        writeln!(f, "mcfg: {:?}", self.mcfg).unwrap();
         writeln!(f, "mask pics: {:?}", self.mask_pics)
     }
 }
@@ -51,6 +58,9 @@ impl Acpi {
             apic_domains: None,
             nmis: None,
             memory_domains: None,
+            hpet: None,
+            fadt: None,
+            mcfg: None,
         }
     }
 
@@ -195,6 +205,30 @@ impl Acpi {
                 let (ad, md) = self.parse_srat(PhysAddr::new(table_ptr as u64));
                 self.apic_domains = Some(ad);
                 self.memory_domains = Some(md);
+
+            // Parse HPET
+            } else if &signature == b"HPET" {
+                if self.hpet.is_some() {
+                    panic!("Multiple HPET entries");
+                }
+                self.hpet = Some(self.parse_hpet(PhysAddr::new(table_ptr as u64)));
+
+            // Parse FADT
+            } else if &signature == b"FACP" {
+                if self.fadt.is_some() {
+                    panic!("Multiple FADT entries");
+                }
+                self.fadt = Some(self.parse_fadt(PhysAddr::new(table_ptr as u64)));
+
+            // Parse MCFG
+            } else if &signature == b"MCFG" {
+                if self.mcfg.is_some() {
+                    panic!("Multiple MCFG entries");
+                }
+                let entries = self.parse_mcfg(PhysAddr::new(table_ptr as u64));
+                if !entries.is_empty() {
+                    self.mcfg = Some(entries);
+                }
             }
         } // enf for rsdt_entries
 
@@ -207,7 +241,7 @@ impl Acpi {
         &self,
         ptr: PhysAddr,
     ) -> (
-        Vec<LocalApic>,
+        Vec<LocalX2Apic>,
         Vec<IoApic>,
         Vec<IntOverride>,
         Vec<NonMaskableInts>,
@@ -270,7 +304,7 @@ impl Acpi {
                     // a valid APIC
                     if (lapic.flags & APIC_ENABLED) != 0 || (lapic.flags & APIC_ONLINE_CAPABLE) != 0
                     {
-                        lapics.push(lapic);
+                        lapics.push(lapic.into());
                     }
                 }
                 // I/O APIC
@@ -298,8 +332,10 @@ impl Acpi {
 
                     let int_override: IntOverride = read_phys(ics);
 
-                    // Filter out identity mappings
-                    if int_override.source as u32 != int_override.mapped_to {
+                    // Filter out identity mappings which keep the ISA defaults
+                    if int_override.source as u32 != int_override.mapped_to
+                        || int_override.flags != 0
+                    {
                         int_overrides.push(int_override);
                     }
                 }
@@ -310,13 +346,11 @@ impl Acpi {
                     }
 
                     // Read the struct
-                    let lapic: LocalApic = read_phys(ics);
+                    let x2apic: LocalX2Apic = read_phys(ics);
+                    let flags = x2apic.flags;
 
-                    // If the processor is enabled, or can be enabled, log it as
-                    // a valid APIC
-                    if (lapic.flags & APIC_ENABLED) != 0 || (lapic.flags & APIC_ONLINE_CAPABLE) != 0
-                    {
-                        lapics.push(lapic);
+                    if (flags & APIC_ENABLED) != 0 || (flags & APIC_ONLINE_CAPABLE) != 0 {
+                        lapics.push(x2apic);
                     }
                 }
                 _ => {
@@ -330,6 +364,35 @@ impl Acpi {
         (lapics, ioapcis, int_overrides, nmis, mask_pics)
     } // end function
 
+    unsafe fn parse_hpet(&self, ptr: PhysAddr) -> HpetTable {
+        let (_header, payload, size) = self.parse_header(ptr);
+        if size < size_of::<HpetTable>() {
+            panic!("Invalid table size for HPET");
+        }
+        read_phys(payload)
+    }
+
+    unsafe fn parse_fadt(&self, ptr: PhysAddr) -> Fadt {
+        let (_header, payload, size) = self.parse_header(ptr);
+        if size < size_of::<Fadt>() {
+            panic!("Invalid table size for FADT");
+        }
+        read_phys(payload)
+    }
+
+    unsafe fn parse_mcfg(&self, ptr: PhysAddr) -> Vec<McfgEntry> {
+        let (_header, payload, size) = self.parse_header(ptr);
+
+        // Skip the 8 reserved bytes to get to the allocations
+        let size = size.checked_sub(8).expect("Invalid table size for MCFG");
+        if size % size_of::<McfgEntry>() != 0 {
+            panic!("Invalid table size for MCFG");
+        }
+        (0..size / size_of::<McfgEntry>())
+            .map(|entry| read_phys(payload + 8_u64 + entry * size_of::<McfgEntry>()))
+            .collect()
+    }
+
     unsafe fn parse_srat(&self, ptr: PhysAddr) -> (BTreeMap<u32, u32>, BTreeMap<u32, RangeSet>) {
         // Parse the SRAT header
         let (_header, payload, size) = self.parse_header(ptr);
@@ -77,6 +77,36 @@ impl fmt::Debug for LocalApic {
     }
 }
 
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct LocalX2Apic {
+    pub typ: u8,
+    pub length: u8,
+    pub res0: u16,
+    pub x2apic_id: u32,
+    pub flags: u32,
+    pub processor_uid: u32,
+}
+
+impl From<LocalApic> for LocalX2Apic {
+    fn from(lapic: LocalApic) -> Self {
+        Self {
+            typ: lapic.typ,
+            length: lapic.length,
+            res0: 0,
+            x2apic_id: lapic.id as u32,
+            flags: lapic.flags,
+            processor_uid: lapic.processor_uid as u32,
+        }
+    }
+}
+
+impl fmt::Debug for LocalX2Apic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe { write!(f, "LApic id: {}", read_unaligned(addr_of!(self.x2apic_id))) }
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct IntOverride {
@@ -121,3 +151,147 @@ impl fmt::Debug for NonMaskableInts {
         }
     }
 }
+
+/// ACPI generic address structure
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// 0 system memory, 1 system I/O
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl fmt::Debug for GenericAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe {
+            write!(
+                f,
+                "GenericAddress space: {} address: {:#x}",
+                self.address_space,
+                read_unaligned(addr_of!(self.address))
+            )
+        }
+    }
+}
+
+/// HPET description table following the ACPI header
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct HpetTable {
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// Minimum clock ticks for periodic mode without lost interrupts
+    pub min_tick: u16,
+    pub page_protection: u8,
+}
+
+impl fmt::Debug for HpetTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe {
+            write!(
+                f,
+                "Hpet number: {} address: {:#x} min tick: {}",
+                self.hpet_number,
+                read_unaligned(addr_of!(self.base_address.address)),
+                read_unaligned(addr_of!(self.min_tick))
+            )
+        }
+    }
+}
+
+/// Fixed part of the FADT following the ACPI header, as defined by ACPI 1.0
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct Fadt {
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    pub res0: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_int: u16,
+    pub smi_cmd: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_req: u8,
+    pub pstate_cnt: u8,
+    pub pm1a_evt_blk: u32,
+    pub pm1b_evt_blk: u32,
+    pub pm1a_cnt_blk: u32,
+    pub pm1b_cnt_blk: u32,
+    pub pm2_cnt_blk: u32,
+    /// I/O port of the power management timer, 0 if there is none
+    pub pm_tmr_blk: u32,
+    pub gpe0_blk: u32,
+    pub gpe1_blk: u32,
+    pub pm1_evt_len: u8,
+    pub pm1_cnt_len: u8,
+    pub pm2_cnt_len: u8,
+    pub pm_tmr_len: u8,
+    pub gpe0_blk_len: u8,
+    pub gpe1_blk_len: u8,
+    pub gpe1_base: u8,
+    pub cst_cnt: u8,
+    pub p_lvl2_lat: u16,
+    pub p_lvl3_lat: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alrm: u8,
+    pub mon_alrm: u8,
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    pub res1: u8,
+    pub flags: u32,
+}
+
+impl Fadt {
+    /// The power management timer counts with 32 instead of 24 bits
+    pub const TMR_VAL_EXT: u32 = 1 << 8;
+}
+
+impl fmt::Debug for Fadt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe {
+            write!(
+                f,
+                "Fadt sci int: {} pm timer port: {:#x} flags: {:#x}",
+                read_unaligned(addr_of!(self.sci_int)),
+                read_unaligned(addr_of!(self.pm_tmr_blk)),
+                read_unaligned(addr_of!(self.flags))
+            )
+        }
+    }
+}
+
+/// Configuration space base address allocation of the MCFG, follows the
+/// ACPI header and 8 reserved bytes
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct McfgEntry {
+    /// Physical address of the ECAM region, bus 0 is at this address even
+    /// if `start_bus` is not 0
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+    pub res0: u32,
+}
+
+impl fmt::Debug for McfgEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        unsafe {
+            write!(
+                f,
+                "Mcfg segment: {} buses: {}-{} address: {:#x}",
+                read_unaligned(addr_of!(self.segment)),
+                self.start_bus,
+                self.end_bus,
+                read_unaligned(addr_of!(self.base_address))
+            )
+        }
+    }
+}
@@ -12,10 +12,12 @@ pub const HEAP_SIZE: usize = 1000 * 1024; // 2Mib
 
 pub mod fixed_size_block;
 
+use crate::lock::{Mutex, MutexGuard};
+
 use fixed_size_block::FixedSizeBlockAllocator;
 #[global_allocator]
 pub static ALLOCATOR: Locked<FixedSizeBlockAllocator> =
-    Locked::new(FixedSizeBlockAllocator::new(HEAP_START));
+    Locked::new("allocator", FixedSizeBlockAllocator::new(HEAP_START));
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
@@ -51,17 +53,17 @@ pub fn init_heap(
 }
 
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: Mutex<A>,
 }
 
 impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
+    pub const fn new(name: &'static str, inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: Mutex::new(name, inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> MutexGuard<A> {
         self.inner.lock()
     }
 }
@@ -1,4 +1,5 @@
 use super::HEAP_SIZE;
+use crate::trace::{self, TraceEvent};
 use alloc::alloc::Layout;
 use core::convert::TryFrom;
 use core::ptr;
@@ -147,10 +148,13 @@ use alloc::alloc::GlobalAlloc;
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut alloc = self.lock();
-        alloc.alloc(&layout)
+        let ptr = alloc.alloc(&layout);
+        trace::event(TraceEvent::Alloc, layout.size() as u64, ptr as u64);
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        trace::event(TraceEvent::Dealloc, layout.size() as u64, ptr as u64);
         let mut alloc = self.lock();
         alloc.dealloc(ptr, &layout);
     }
@@ -158,6 +162,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
         let mut alloc = self.lock();
         let ptr = alloc.alloc(&layout);
+        trace::event(TraceEvent::Alloc, layout.size() as u64, ptr as u64);
         core::intrinsics::write_bytes::<u8>(
             ptr,
             0,
@@ -184,9 +189,11 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             new_size,
         );
 
+        trace::event(TraceEvent::Dealloc, layout.size() as u64, ptr as u64);
+
         use core::cmp::Ordering;
         // Make buffer smaller
-        match old_size.cmp(&new_size) {
+        let new_ptr = match old_size.cmp(&new_size) {
             Ordering::Equal => {
                 log::warn!("Called realloc with same size as previous buffer");
                 ptr
@@ -209,6 +216,9 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 }
                 new_ptr
             }
-        }
+        };
+
+        trace::event(TraceEvent::Alloc, new_size as u64, new_ptr as u64);
+        new_ptr
     }
 }
@@ -5,6 +5,7 @@ use crate::apic_regs::*;
 use crate::interrupts::InterruptIndex;
 use crate::interrupts::PICS;
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use x86_64::registers::model_specific::Msr;
 use x86_64::structures::paging::page_table::PageTableFlags;
 use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size2MiB, Size4KiB};
@@ -13,8 +14,20 @@ use x86_64::PhysAddr;
 // Other constants
 const APIC_BASE: u64 = 0x0_0000_FEE0_0000;
 
-pub unsafe fn mp_init(apic_id: u8, trampoline: u32) {
+/// Apic timer ticks per second, measured once on boot
+static TIMER_TICKS_PER_SEC: AtomicU32 = AtomicU32::new(0);
+
+/// Length of the apic timer calibration
+const TIMER_CALIBRATION_US: u64 = 100 * 1000;
+
+const IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+/// Local apics are in x2apic mode and accessed through MSRs
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+pub unsafe fn mp_init(apic_id: u32, trampoline: u32) {
     log::info!("Booting core {}", apic_id);
+    crate::smp::set_launched(apic_id);
     // Create INIT IPI
     let low = InterCmdRegLow::new()
             .with_vec(0) // INIT needs vec to be zero
@@ -22,10 +35,8 @@ pub unsafe fn mp_init(apic_id: u8, trampoline: u32) {
             .with_msg_type(0b101) // INIT type
             .with_level(0) // 0 for INIT
             ;
-    let high = InterCmdRegHigh::new().with_dest(apic_id);
-
     // Sent INIT IPI
-    send_ipi(&low, &high);
+    send_ipi(&low, apic_id);
 
     // Convert func pointer to u64
     let trampoline = trampoline as u64;
@@ -59,29 +70,63 @@ pub unsafe fn mp_init(apic_id: u8, trampoline: u32) {
             ;
 
     // Sent Startup IPI (SIPI)
-    send_ipi(&low, &high);
+    send_ipi(&low, apic_id);
 
     // Sent Startup IPI (SIPI)
-    send_ipi(&low, &high);
+    send_ipi(&low, apic_id);
 }
 
 #[inline]
 fn ipi_pending() -> bool {
+    // There is no delivery status in x2apic mode
+    if is_x2apic() {
+        return false;
+    }
     unsafe {
         let r = InterCmdRegLow::from_bytes(read_apic(Register::InterCmdRegLow).to_le_bytes());
         r.delivery_status() == 1
     }
 }
 
-unsafe fn send_ipi(low: &InterCmdRegLow, high: &InterCmdRegHigh) {
+#[inline]
+unsafe fn write_icr(low: &InterCmdRegLow, dest: u32) {
+    crate::trace::event(
+        crate::trace::TraceEvent::IpiSend,
+        dest as u64,
+        low.vec() as u64,
+    );
+    let low = u32::from_le_bytes(low.into_bytes());
+
+    // A single write with the 32 bit destination in the upper half
+    if is_x2apic() {
+        Msr::new(X2APIC_ICR).write(((dest as u64) << 32) | low as u64);
+        return;
+    }
+
+    let high = InterCmdRegHigh::new().with_dest(dest as u8);
     write_apic(
         Register::InterCmdRegHigh,
         u32::from_le_bytes(high.into_bytes()),
     );
-    write_apic(
-        Register::InterCmdRegLow,
-        u32::from_le_bytes(low.into_bytes()),
-    );
+    write_apic(Register::InterCmdRegLow, low);
+}
+
+/// Send a fixed interrupt with `vector` to the core `apic_id` and wait
+/// until the local apic has delivered it
+pub unsafe fn send_fixed_ipi(apic_id: u32, vector: u8) {
+    let low = InterCmdRegLow::new()
+        .with_vec(vector)
+        .with_msg_type(0b000) // Fixed
+        .with_level(1);
+
+    write_icr(&low, apic_id);
+    while ipi_pending() {
+        core::hint::spin_loop();
+    }
+}
+
+unsafe fn send_ipi(low: &InterCmdRegLow, dest: u32) {
+    write_icr(low, dest);
     // Sleep 200 microseconds as by spec
     crate::time::sleep(200);
 
@@ -122,6 +167,32 @@ unsafe fn init_chained_pics(acpi: &Acpi) {
     }
 }
 
+/// Deliver the keyboard and serial interrupts to the BSP. The PICs are only
+/// used on machines without an I/O apic.
+unsafe fn init_legacy_irqs(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    acpi: &Acpi,
+) {
+    if !crate::ioapic::init(mapper, frame_allocator, acpi) {
+        log::warn!("No I/O apic found, falling back to the legacy PICs");
+        init_chained_pics(acpi);
+        return;
+    }
+
+    let bsp = apic_id();
+    let legacy = [
+        InterruptIndex::Keyboard,
+        InterruptIndex::COM2,
+        InterruptIndex::COM1,
+    ];
+    for index in legacy.iter() {
+        if let Err(err) = crate::ioapic::route_isa_irq(index.as_isa_irq(), index.as_u8(), bsp) {
+            log::warn!("Failed to route {:?}: {:?}", index, err);
+        }
+    }
+}
+
 pub unsafe fn init(
     mapper: &mut OffsetPageTable,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
@@ -172,14 +243,23 @@ pub unsafe fn init(
     let payload = u64::from_le_bytes(base_reg.into_bytes());
     apic_base_reg.write(payload);
 
+    // Switch to x2apic mode if available, it can only be left by a reset
+    if has_x2apic() {
+        base_reg.set_x2apic_enable(1);
+        apic_base_reg.write(u64::from_le_bytes(base_reg.into_bytes()));
+        X2APIC.store(true, Ordering::SeqCst);
+    } else if is_x2apic() {
+        panic!("Core {} has no x2apic but the other cores do", apic_id());
+    }
+
     let id = apic_id();
 
     // Only execute if bootstrap core
     if base_reg.bootstrap_core() == 1 {
         log::info!("BSP is apic id: {}", id);
 
-        // Initialize or mask chained pics
-        init_chained_pics(acpi);
+        // Route legacy interrupts through the I/O apic if there is one
+        init_legacy_irqs(mapper, frame_allocator, acpi);
     }
 
     // Map spurious interrupts to index
@@ -211,47 +291,151 @@ pub unsafe fn init(
     init_timer();
 }
 
-unsafe fn init_timer() {
-    // Divide by two
-    let div = DivideConfReg::new().with_div(0).with_div2(0);
-    let div = u32::from_le_bytes(div.into_bytes());
-    write_apic(Register::DivideConfReg, div);
+/// Modes of the local apic timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TimerMode {
+    OneShot = 0b00,
+    Periodic = 0b01,
+    /// Fires once the TSC reaches the value written to `IA32_TSC_DEADLINE`
+    TscDeadline = 0b10,
+}
+
+/// Whether the local apic timer supports the TSC-deadline mode
+pub fn has_tsc_deadline() -> bool {
+    use core::arch::x86_64::__cpuid;
+    let res = unsafe { __cpuid(0x0000_0001) };
+    res.ecx & (1 << 24) != 0
+}
 
-    // clear mask & periodic timer
+/// Apic timer ticks per second, shared by all cores
+pub fn timer_ticks_per_sec() -> u32 {
+    TIMER_TICKS_PER_SEC.load(Ordering::Relaxed)
+}
+
+unsafe fn set_timer_lvt(mode: TimerMode, mask: bool) {
     let timer = TimerLvtReg::new()
         .with_vec(InterruptIndex::Timer.as_u8())
         .with_delivery_status(0)
-        .with_mask(0)
-        .with_timer_mode(1); // Periodic timer inters
+        .with_mask(mask as u8)
+        .with_timer_mode(mode as u8);
     let timer = u32::from_le_bytes(timer.into_bytes());
     write_apic(Register::ApicTimer, timer);
+}
+
+unsafe fn init_timer() {
+    // Divide by two
+    let div = DivideConfReg::new().with_div(0).with_div2(0);
+    let div = u32::from_le_bytes(div.into_bytes());
+    write_apic(Register::DivideConfReg, div);
+
+    // The timers of all cores run at the same rate, so only the first core
+    // measures the elapsed ticks against the calibrated TSC
+    if TIMER_TICKS_PER_SEC.load(Ordering::Relaxed) == 0 {
+        set_timer_lvt(TimerMode::OneShot, true);
+        write_apic(Register::TimerInitialCount, u32::MAX);
+
+        crate::time::sleep(TIMER_CALIBRATION_US);
 
-    //TODO: Do this only once, not for every core
-    // Calculate apic tics per second by measuring elapsed ticks
-    // through the PIT timer
-    write_apic(Register::TimerInitialCount, u32::MAX);
+        let ticks_elapsed = u32::MAX - read_apic(Register::TimerCurrentCount);
+        let ticks_per_sec = ticks_elapsed as u64 * 1_000_000 / TIMER_CALIBRATION_US;
+        TIMER_TICKS_PER_SEC.store(ticks_per_sec as u32, Ordering::Relaxed);
+        log::info!("Apic timer ticks per second: {}", ticks_per_sec);
+    }
+
+    // Periodic timer interrupts once per second
+    set_timer_frequency(1);
+}
+
+/// Change the rate of the periodic timer of the current core
+/// and switch it to periodic mode.
+pub unsafe fn set_timer_frequency(hz: u32) {
+    let ticks = TIMER_TICKS_PER_SEC.load(Ordering::Relaxed) / hz.max(1);
+    set_timer_lvt(TimerMode::Periodic, false);
+    write_apic(Register::TimerInitialCount, ticks.max(1));
+}
+
+/// Fire the timer of the current core once after `microseconds`
+pub unsafe fn set_timer_oneshot(microseconds: u64) {
+    let ticks = TIMER_TICKS_PER_SEC.load(Ordering::Relaxed) as u64 * microseconds / 1_000_000;
+    set_timer_lvt(TimerMode::OneShot, false);
+    // The timer fires early if the delay does not fit, the caller re-arms it
+    write_apic(
+        Register::TimerInitialCount,
+        ticks.clamp(1, u32::MAX as u64) as u32,
+    );
+}
+
+/// Fire the timer of the current core once the TSC reaches `deadline`.
+/// Falls back to one-shot mode without TSC-deadline support.
+pub unsafe fn set_timer_deadline(deadline: u64) {
+    if has_tsc_deadline() {
+        set_timer_lvt(TimerMode::TscDeadline, false);
+        // The write to the LVT has to be ordered before the MSR write
+        core::sync::atomic::fence(Ordering::SeqCst);
+        Msr::new(IA32_TSC_DEADLINE).write(deadline.max(1));
+    } else {
+        let now = crate::time::rdtsc();
+        let cycles = deadline.saturating_sub(now);
+        set_timer_oneshot(cycles * 1000 / crate::time::tsc_khz().max(1));
+    }
+}
 
-    // sleep 1s
-    crate::time::sleep(1000*1000);
+/// Stop the timer of the current core
+pub unsafe fn stop_timer() {
+    set_timer_lvt(TimerMode::OneShot, true);
+    write_apic(Register::TimerInitialCount, 0);
+    if has_tsc_deadline() {
+        Msr::new(IA32_TSC_DEADLINE).write(0);
+    }
+}
 
-    let ticks_elapsed  = u32::MAX - read_apic(Register::TimerCurrentCount);
+/// Program the local vector table entry for performance counter overflows
+pub unsafe fn set_perf_counter_lvt(lvt: PerfCounterLvtReg) {
+    write_apic(
+        Register::PerfCounterLvt,
+        u32::from_le_bytes(lvt.into_bytes()),
+    );
+}
 
-    write_apic(Register::TimerInitialCount, ticks_elapsed);
+/// Program the AMD extended LVT entry `offset`. Used by the IBS and other
+/// AMD specific interrupt sources which report their LVT offset through MSRs.
+/// Returns false if the local apic does not implement the entry.
+pub unsafe fn set_ext_lvt(offset: u8, lvt: ExtLvtReg) -> bool {
+    let feature = ExtApicFeature::from_bytes(read_apic(Register::ExtApicFeature).to_le_bytes());
+    match Register::ext_lvt(offset) {
+        Some(register) if offset < feature.ext_lvt_count() => {
+            write_apic(register, u32::from_le_bytes(lvt.into_bytes()));
+            true
+        }
+        _ => false,
+    }
 }
 
-fn apic_id_from_mem() -> u8 {
+fn apic_id_from_mem() -> u32 {
     let id_reg = unsafe { read_apic(Register::ApicId) };
+    // The x2apic id uses the whole register
+    if is_x2apic() {
+        return id_reg;
+    }
     let res = ApicId::from_bytes(id_reg.to_le_bytes());
-    res.aid()
+    res.aid() as u32
 }
 
 unsafe fn read_apic(register: Register) -> u32 {
+    if is_x2apic() {
+        return Msr::new(register.x2apic_msr()).read() as u32;
+    }
     let offset = register as u64;
     let ptr = (APIC_BASE + offset) as *mut u32;
     read_volatile(ptr)
 }
 
 unsafe fn write_apic(register: Register, value: u32) {
+    if is_x2apic() {
+        Msr::new(register.x2apic_msr()).write(value as u64);
+        return;
+    }
     let offset = register as u64;
     let ptr = (APIC_BASE + offset) as *mut u32;
     write_volatile(ptr, value);
@@ -269,9 +453,36 @@ pub fn is_bsp() -> bool {
     }
 }
 
-pub fn apic_id() -> u8 {
-    use core::arch::x86_64::__cpuid;
+/// Full 32 bit apic id of the current core. Executes cpuid, use
+/// `smp::core_index` to index the per-core tables.
+pub fn apic_id() -> u32 {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+
+    // The extended topology leaves report the x2apic id in edx,
+    // a leaf is valid if it reports logical processors in ebx
+    for &leaf in [0x1f, 0xb].iter() {
+        if max_leaf >= leaf {
+            let res = unsafe { __cpuid_count(leaf, 0) };
+            if res.ebx & 0xffff != 0 {
+                return res.edx;
+            }
+        }
+    }
+
     let res = unsafe { __cpuid(0x0000_0001) };
+    res.ebx >> 24
+}
 
-    (res.ebx >> 24) as u8
+/// Whether the cpu supports the x2apic mode
+pub fn has_x2apic() -> bool {
+    use core::arch::x86_64::__cpuid;
+    let feature = unsafe { __cpuid(0x0000_0001) };
+    feature.ecx & (1 << 21) != 0
+}
+
+/// Whether the local apics are accessed through MSRs
+#[inline]
+pub fn is_x2apic() -> bool {
+    X2APIC.load(Ordering::Relaxed)
 }
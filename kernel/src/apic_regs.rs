@@ -17,6 +17,36 @@ pub enum Register {
     LogicalDestReg = 0xD0,
     InterCmdRegLow = 0x300,
     InterCmdRegHigh = 0x310,
+    PerfCounterLvt = 0x340,
+    ExtApicFeature = 0x400,
+    ExtLvt0 = 0x500,
+    ExtLvt1 = 0x510,
+    ExtLvt2 = 0x520,
+    ExtLvt3 = 0x530,
+}
+
+/// Base of the MSRs mapping the registers in x2apic mode
+pub const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Interrupt command register in x2apic mode, one 64 bit MSR
+pub const X2APIC_ICR: u32 = 0x830;
+
+impl Register {
+    /// MSR of the register in x2apic mode
+    pub fn x2apic_msr(self) -> u32 {
+        X2APIC_MSR_BASE + (self as u32 >> 4)
+    }
+
+    /// AMD extended local vector table entry at `offset`
+    pub fn ext_lvt(offset: u8) -> Option<Register> {
+        match offset {
+            0 => Some(Register::ExtLvt0),
+            1 => Some(Register::ExtLvt1),
+            2 => Some(Register::ExtLvt2),
+            3 => Some(Register::ExtLvt3),
+            _ => None,
+        }
+    }
 }
 
 #[bitfield]
@@ -85,7 +115,9 @@ pub struct ApicVersion {
 pub struct ApicBaseReg {
     pub res0: B8,
     pub bootstrap_core: B1,
-    pub res1: B2,
+    pub res1: B1,
+    /// Registers are accessed through MSRs instead of MMIO
+    pub x2apic_enable: B1,
     pub apic_enable: B1,
     pub apic_base_addr: B40,
     pub res2: B12,
@@ -108,8 +140,9 @@ pub struct TimerLvtReg {
     pub delivery_status: B1,
     pub res1: B3,
     pub mask: B1,
-    pub timer_mode: B1,
-    pub res2: B14,
+    /// 0b00 one-shot, 0b01 periodic, 0b10 TSC-deadline
+    pub timer_mode: B2,
+    pub res2: B13,
 }
 
 #[bitfield]
@@ -120,3 +153,43 @@ pub struct SpuriousInterReg {
     pub fcc: B1,
     pub res0: B22,
 }
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtApicFeature {
+    pub int_enable_reg: B1,
+    pub specific_eoi: B1,
+    pub ext_apic_id: B1,
+    pub res0: B13,
+    /// Number of extended LVT entries
+    pub ext_lvt_count: B8,
+    pub res1: B8,
+}
+
+/// Local vector table entry for performance counter overflows
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct PerfCounterLvtReg {
+    pub vec: B8,
+    /// 0b000 fixed, 0b010 SMI, 0b100 NMI
+    pub msg_type: B3,
+    pub res0: B1,
+    pub delivery_status: B1,
+    pub res1: B3,
+    pub mask: B1,
+    pub res2: B15,
+}
+
+/// AMD extended interrupt local vector table entry
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtLvtReg {
+    pub vec: B8,
+    /// 0b000 fixed, 0b010 SMI, 0b100 NMI, 0b111 ExtINT
+    pub msg_type: B3,
+    pub res0: B1,
+    pub delivery_status: B1,
+    pub res1: B3,
+    pub mask: B1,
+    pub res2: B15,
+}
@@ -0,0 +1,131 @@
+//! Frame pointer based stack unwinding.
+//! Frames are only followed inside of the stacks the bootloader allocated
+//! for the current core, so a corrupted chain can not fault the unwinder.
+
+use bootloader::bootinfo::{BootInfo, Core};
+
+/// Number of frames printed by `print`
+pub const MAX_FRAMES: usize = 32;
+
+/// How often a chain may cross from an interrupt stack to another stack
+const MAX_STACK_SWITCHES: usize = 4;
+
+/// Stack layout of each core, indexed by core index
+static mut CORES: [Option<&'static Core>; bootloader::MAX_CORES] = [None; bootloader::MAX_CORES];
+
+/// Remember the stack bounds of the current core
+pub unsafe fn init(boot_info: &'static BootInfo) {
+    let index = crate::smp::core_index();
+    CORES[index] = boot_info.cores.get(index);
+}
+
+/// Returns the (lowest, highest) address of the stack of the current core
+/// `addr` lies on. Covers the kernel stack and all interrupt stacks.
+pub fn stack_containing(addr: u64) -> Option<(u64, u64)> {
+    let core = unsafe { CORES[crate::smp::core_index()]? };
+
+    let main = core
+        .get_stack_start()
+        .map(|start| (core.stack_end_addr as u64, start as u64));
+    let tss = (0..bootloader::TSS_STACKS_PER_CPU).filter_map(|i| {
+        core.tss
+            .get_stack_start(i)
+            .map(|start| (core.tss.stack_end_addr[i] as u64, start as u64))
+    });
+
+    main.into_iter()
+        .chain(tss)
+        .find(|&(bottom, top)| bottom <= addr && addr < top)
+}
+
+/// Follow the frame pointer chain starting at `rbp` and write the return
+/// addresses into `frames`. Returns the number of frames written.
+/// Needs the kernel to be compiled with frame pointers.
+pub fn walk_frames(mut rbp: u64, frames: &mut [u64]) -> usize {
+    let mut range = match stack_containing(rbp) {
+        Some(range) => range,
+        None => return 0,
+    };
+
+    let mut depth = 0;
+    let mut switches = 0;
+    while depth < frames.len() && rbp % 8 == 0 && rbp + 16 <= range.1 {
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        frames[depth] = ret;
+        depth += 1;
+
+        // Stacks grow down, callers always have a higher frame address
+        if next > rbp && next < range.1 {
+            rbp = next;
+            continue;
+        }
+
+        // Handlers on an interrupt stack link to the interrupted stack
+        match stack_containing(next) {
+            Some(next_range) if next_range != range && switches < MAX_STACK_SWITCHES => {
+                range = next_range;
+                rbp = next;
+                switches += 1;
+            }
+            _ => break,
+        }
+    }
+    depth
+}
+
+/// Frame pointer of the calling function
+#[inline(always)]
+pub fn current_rbp() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    rbp
+}
+
+/// Frame pointer of the code interrupted by the calling interrupt handler.
+/// Has to be inlined into the handler, the first slot of its frame holds
+/// the frame pointer of the interrupted code.
+#[inline(always)]
+pub fn interrupted_rbp() -> u64 {
+    let rbp = current_rbp();
+    match stack_containing(rbp) {
+        Some(_) if rbp % 8 == 0 => unsafe { *(rbp as *const u64) },
+        _ => 0,
+    }
+}
+
+fn print_frame(index: usize, addr: u64) {
+    match crate::symbols::lookup(addr) {
+        Some(symbol) => log::error!("  #{:<2} {:#x} {}", index, addr, symbol),
+        None => log::error!("  #{:<2} {:#x} <unknown>", index, addr),
+    }
+}
+
+/// Print a symbolized backtrace starting at the instruction `rip`
+/// of the frame `rbp`
+pub fn print_from(rip: u64, rbp: u64) {
+    let mut frames = [0; MAX_FRAMES];
+    let depth = walk_frames(rbp, &mut frames);
+
+    log::error!("Backtrace:");
+    print_frame(0, rip);
+    for (i, &addr) in frames[..depth].iter().enumerate() {
+        // Return addresses point behind the call
+        print_frame(i + 1, addr - 1);
+    }
+}
+
+/// Print a symbolized backtrace of the caller
+#[inline(never)]
+pub fn print() {
+    let rbp = current_rbp();
+    let mut frames = [0; MAX_FRAMES];
+    let depth = walk_frames(rbp, &mut frames);
+
+    log::error!("Backtrace:");
+    for (i, &addr) in frames[..depth].iter().enumerate() {
+        print_frame(i, addr - 1);
+    }
+}
@@ -1,8 +1,15 @@
-use crate::println;
-use crate::time::{elapsed, rdtsc};
+use crate::pmc::{CounterSet, PmcError, PmcEvent};
+use crate::time::{elapsed, rdtsc, tsc_khz};
+use crate::{print, println};
+use alloc::vec::Vec;
 use core::arch::x86_64::__cpuid;
+use core::hint::black_box;
 use raw_cpuid::CpuId;
 
+pub mod cache;
+pub mod numa;
+pub mod pingpong;
+
 #[repr(u32)]
 pub enum CpuidIndex {
     TscInvariant = 0x8000_0007,
@@ -18,19 +25,255 @@ impl CpuidIndex {
     }
 }
 
+/// One shot measurement of a code section.
+/// Use `run` or a `Benchmark` for statistically sound results.
 pub struct Bench {
     start: u64,
+    counters: Option<CounterSet>,
 }
 
 impl Bench {
     pub fn start() -> Self {
-        Bench { start: rdtsc() }
+        Bench {
+            start: rdtsc(),
+            counters: None,
+        }
+    }
+
+    /// Start a benchmark that additionally counts the given hardware events
+    pub fn start_with_counters(events: &[PmcEvent]) -> Result<Self, PmcError> {
+        let mut counters = CounterSet::new(events)?;
+        counters.reset();
+        Ok(Bench {
+            start: rdtsc(),
+            counters: Some(counters),
+        })
     }
 
     pub fn end(&mut self) {
+        // Read the counters first so printing does not show up in them
+        let values = self.counters.as_ref().map(|c| c.read());
         let diff = elapsed(self.start);
 
         println!("\nSeconds needed: {}", diff);
+        for (event, value) in values.iter().flatten().flatten() {
+            println!("{:?}: {}", event, value);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BenchConfig {
+    /// Runs before measuring to warm up caches and branch predictors
+    pub warmup: u32,
+    /// Number of samples the statistics are computed from
+    pub samples: u32,
+    /// Minimum duration of a sample in TSC cycles. The routine is run
+    /// multiple times per sample until this duration is reached.
+    pub min_sample_cycles: u64,
+    /// Upper bound for the iterations per sample
+    pub max_iters: u64,
+}
+
+impl BenchConfig {
+    pub const fn new() -> Self {
+        BenchConfig {
+            warmup: 10,
+            samples: 100,
+            min_sample_cycles: 100_000,
+            max_iters: 1 << 24,
+        }
+    }
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Statistics of a benchmark. Times are in nanoseconds per iteration.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub samples: usize,
+    pub iters_per_sample: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64,
+    pub p5: f64,
+    pub p25: f64,
+    pub p75: f64,
+    pub p95: f64,
+    pub p99: f64,
+    /// Samples outside of 1.5 times the interquartile range
+    pub low_outliers: usize,
+    pub high_outliers: usize,
+    /// Cost of reading the TSC in nanoseconds, already subtracted
+    pub timer_overhead: f64,
+}
+
+impl Stats {
+    /// Compute the statistics of per iteration times. Sorts `samples`.
+    pub fn from_samples(samples: &mut [f64]) -> Self {
+        if samples.is_empty() {
+            return Stats::default();
+        }
+        samples.sort_unstable_by(|a, b| a.total_cmp(b));
+
+        let n = samples.len() as f64;
+        let mean = samples.iter().sum::<f64>() / n;
+        let variance = if samples.len() > 1 {
+            samples.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / (n - 1.0)
+        } else {
+            0.0
+        };
+
+        let p25 = percentile(samples, 25.0);
+        let p75 = percentile(samples, 75.0);
+        let iqr = p75 - p25;
+        let low_fence = p25 - 1.5 * iqr;
+        let high_fence = p75 + 1.5 * iqr;
+
+        Stats {
+            samples: samples.len(),
+            iters_per_sample: 1,
+            min: samples[0],
+            max: samples[samples.len() - 1],
+            mean,
+            median: percentile(samples, 50.0),
+            stddev: sqrt(variance),
+            p5: percentile(samples, 5.0),
+            p25,
+            p75,
+            p95: percentile(samples, 95.0),
+            p99: percentile(samples, 99.0),
+            low_outliers: samples.iter().filter(|&&x| x < low_fence).count(),
+            high_outliers: samples.iter().filter(|&&x| x > high_fence).count(),
+            timer_overhead: 0.0,
+        }
+    }
+
+    pub fn print(&self, name: &str) {
+        println!(
+            "{}: median {:.2} ns, mean {:.2} ns +- {:.2}, min {:.2} ns, max {:.2} ns",
+            name, self.median, self.mean, self.stddev, self.min, self.max
+        );
+        println!(
+            "    p5 {:.2} p25 {:.2} p75 {:.2} p95 {:.2} p99 {:.2}",
+            self.p5, self.p25, self.p75, self.p95, self.p99
+        );
+        println!(
+            "    {} samples x {} iters, timer overhead {:.2} ns",
+            self.samples, self.iters_per_sample, self.timer_overhead
+        );
+        if self.low_outliers + self.high_outliers > 0 {
+            println!(
+                "    outliers: {} low, {} high",
+                self.low_outliers, self.high_outliers
+            );
+        }
+    }
+}
+
+/// Linear interpolation between the closest ranks of sorted `samples`
+pub fn percentile(samples: &[f64], pct: f64) -> f64 {
+    match samples.len() {
+        0 => 0.0,
+        1 => samples[0],
+        len => {
+            let rank = pct / 100.0 * (len - 1) as f64;
+            let lower = rank as usize;
+            let upper = (lower + 1).min(len - 1);
+            let weight = rank - lower as f64;
+            samples[lower] * (1.0 - weight) + samples[upper] * weight
+        }
+    }
+}
+
+fn sqrt(x: f64) -> f64 {
+    let res: f64;
+    unsafe {
+        asm!("sqrtsd {}, {}", lateout(xmm_reg) res, in(xmm_reg) x, options(pure, nomem, nostack));
+    }
+    res
+}
+
+fn cycles_to_ns(cycles: f64) -> f64 {
+    cycles * 1_000_000.0 / tsc_khz() as f64
+}
+
+/// Smallest number of cycles between two TSC reads
+pub fn timer_overhead() -> u64 {
+    (0..1000)
+        .map(|_| {
+            let start = rdtsc();
+            rdtsc() - start
+        })
+        .min()
+        .unwrap()
+}
+
+/// Cycles needed for `iters` runs of `routine`, minus the timer overhead
+#[inline(never)]
+fn measure<F: FnMut()>(routine: &mut F, iters: u64, overhead: u64) -> u64 {
+    let start = rdtsc();
+    for _ in 0..iters {
+        routine();
+    }
+    (rdtsc() - start).saturating_sub(overhead)
+}
+
+/// Benchmark `routine` on the current core and return the statistics
+/// of its runtime. Use `black_box` inside of the routine to keep the
+/// compiler from optimizing the work away.
+pub fn run<F: FnMut()>(config: &BenchConfig, mut routine: F) -> Stats {
+    let overhead = timer_overhead();
+
+    for _ in 0..config.warmup {
+        routine();
+    }
+
+    // Double the iterations until a sample is long enough
+    let mut iters = 1;
+    while iters < config.max_iters
+        && measure(&mut routine, iters, overhead) < config.min_sample_cycles
+    {
+        iters *= 2;
+    }
+
+    let mut samples: Vec<f64> = (0..config.samples)
+        .map(|_| cycles_to_ns(measure(&mut routine, iters, overhead) as f64 / iters as f64))
+        .collect();
+
+    let mut stats = Stats::from_samples(&mut samples);
+    stats.iters_per_sample = iters;
+    stats.timer_overhead = cycles_to_ns(overhead as f64);
+    stats
+}
+
+/// A benchmark which can be registered with `#[test_case]`
+/// and is run by the custom test runner:
+///
+/// #[test_case]
+/// static EMPTY: Benchmark = Benchmark {
+///     name: "empty",
+///     config: BenchConfig::new(),
+///     routine: || black_box(()),
+/// };
+pub struct Benchmark {
+    pub name: &'static str,
+    pub config: BenchConfig,
+    pub routine: fn(),
+}
+
+impl crate::Testable for Benchmark {
+    fn run(&self) {
+        print!("{}...\t", self.name);
+        let stats = run(&self.config, || black_box(self.routine)());
+        println!("[ok]");
+        stats.print(self.name);
     }
 }
 
@@ -60,11 +303,3 @@ pub fn check_support() {
         panic!("Rdtscp instruction is not supported");
     }
 }
-
-// TODO: When threading is implemented add a counter where execution time is spent most of the time
-// TODO: use ibs execution sampling
-// Use the core performance counters using rdpmc to measure:
-// L2 cache misses
-// Make debug information perf compatible!
-// https://perf.wiki.kernel.org/index.php/Main_Page
-// https://github.com/torvalds/linux/tree/master/tools/perf
@@ -0,0 +1,501 @@
+//! Cache and TLB characterization.
+//! Cache latencies are measured by chasing pointers through a random cyclic
+//! permutation of cache lines, so neither the prefetchers nor out of order
+//! execution can hide the load-to-use latency. TLB latencies are measured by
+//! touching one line per page in many virtual pages which all map to the same
+//! physical memory, so every access hits the L1 cache and only the address
+//! translation gets slower once the TLB reach is exceeded.
+//! The detected capacities are compared against the sizes reported by cpuid.
+
+use super::{run, BenchConfig, CpuidIndex};
+use crate::memory::IDENTITY_MAP_END;
+use crate::println;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use raw_cpuid::CpuId;
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB,
+    Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+const LINE_SIZE: u64 = 64;
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+/// Largest working set of the cache benchmark if cpuid reports no L3
+const MIN_MAX_WORKING_SET: u64 = 64 * MIB;
+
+/// Loads per timed iteration
+const LOADS: usize = 1024;
+
+/// Latency increase between two working sets which is counted as a
+/// capacity boundary
+const STEP_THRESHOLD: f64 = 1.25;
+
+/// Unused virtual address ranges for the TLB benchmark, one top level
+/// entry per page size so the mappings never conflict
+const TLB_4K_START: u64 = 0x5000_0000_0000;
+const TLB_2M_START: u64 = 0x5100_0000_0000;
+const TLB_1G_START: u64 = 0x5200_0000_0000;
+
+/// Page counts up to which each TLB benchmark goes
+const TLB_4K_MAX_PAGES: u64 = 8192;
+const TLB_2M_MAX_PAGES: u64 = 4096;
+const TLB_1G_MAX_PAGES: u64 = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheError {
+    /// No physically contiguous memory for the working set is left
+    OutOfMemory,
+    /// The mapping of the TLB benchmark could not be created
+    MapFailed,
+    /// 1 GiB pages are not supported by the cpu
+    NoHugePages,
+}
+
+/// Measured latency of one working set
+#[derive(Debug, Clone, Copy)]
+pub struct Point {
+    /// Bytes for the cache benchmark, pages for the TLB benchmark
+    pub size: u64,
+    /// Median latency of a load in nanoseconds
+    pub latency: f64,
+}
+
+/// Cache or TLB level as reported by cpuid
+#[derive(Debug, Clone, Copy)]
+pub struct Level {
+    pub name: &'static str,
+    /// Bytes for caches, entries for TLBs
+    pub size: u64,
+}
+
+fn rng_next(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// Working set sizes from `min` to `max` in steps of 1.5x and 2x
+fn sizes(min: u64, max: u64) -> Vec<u64> {
+    let mut sizes = Vec::new();
+    let mut size = min;
+    while size <= max {
+        sizes.push(size);
+        if size * 3 / 2 <= max {
+            sizes.push(size * 3 / 2);
+        }
+        size *= 2;
+    }
+    sizes
+}
+
+/// Link the first `lines` cache lines of `buf` into a random cycle.
+/// The second word of every line is used as scratch space for the permutation.
+pub(crate) unsafe fn build_chain(buf: u64, lines: u64, seed: u64) {
+    let line = |i: u64| (buf + i * LINE_SIZE) as *mut u64;
+    for i in 0..lines {
+        *line(i).add(1) = i;
+    }
+
+    // Sattolo's algorithm yields a single cycle through all lines
+    let mut state = seed | 1;
+    for i in (1..lines).rev() {
+        let j = rng_next(&mut state) % i;
+        core::ptr::swap(line(i).add(1), line(j).add(1));
+    }
+
+    for k in 0..lines {
+        let from = *line(k).add(1);
+        let to = *line((k + 1) % lines).add(1);
+        *line(from) = line(to) as u64;
+    }
+}
+
+#[inline(never)]
+pub(crate) fn chase(mut p: *const u64, loads: usize) -> *const u64 {
+    for _ in 0..loads {
+        p = unsafe { core::ptr::read_volatile(p) } as *const u64;
+    }
+    p
+}
+
+/// Load-to-use latency of a working set of `size` bytes at `buf`
+fn chase_latency(config: &BenchConfig, buf: u64, size: u64) -> f64 {
+    let lines = (size / LINE_SIZE).max(2);
+    unsafe { build_chain(buf, lines, crate::time::rdtsc()) };
+
+    // Pull the working set into the caches
+    let mut p = chase(buf as *const u64, lines as usize);
+    let stats = run(config, || p = chase(core::hint::black_box(p), LOADS));
+    stats.median / LOADS as f64
+}
+
+/// Measure the load latency over growing working sets
+pub fn cache_latencies(max: u64) -> Result<Vec<Point>, CacheError> {
+    let buf = crate::memory::frame_allocator()
+        .lock()
+        .allocate_contiguous(max, Size2MiB::SIZE)
+        .ok_or(CacheError::OutOfMemory)?
+        .as_u64();
+    if buf + max > IDENTITY_MAP_END {
+        return Err(CacheError::OutOfMemory);
+    }
+
+    let config = BenchConfig {
+        warmup: 2,
+        samples: 15,
+        ..BenchConfig::new()
+    };
+    Ok(sizes(4 * KIB, max)
+        .into_iter()
+        .map(|size| Point {
+            size,
+            latency: chase_latency(&config, buf, size),
+        })
+        .collect())
+}
+
+/// Visit `pages` pages in a pseudo random order, the next page depends
+/// on the value loaded from the current one
+#[inline(never)]
+fn tlb_walk(base: u64, page_size: u64, pages: u64, mut index: u64, loads: usize) -> u64 {
+    let mask = pages - 1;
+    for _ in 0..loads {
+        // Different lines per page to spread the accesses over the cache sets
+        let addr = base + index * page_size + (index % 64) * LINE_SIZE;
+        let mut val = unsafe { core::ptr::read_volatile(addr as *const u64) };
+        unsafe { asm!("and {0}, 0", inout(reg) val, options(pure, nomem, nostack)) };
+        // Full period LCG modulo a power of two
+        index = (index.wrapping_mul(5).wrapping_add(1).wrapping_add(val)) & mask;
+    }
+    index
+}
+
+/// Map `pages` pages of size `S` at `start` onto the frame at physical
+/// address zero, read only
+unsafe fn map_aliases<S: PageSize + core::fmt::Debug>(
+    start: u64,
+    pages: u64,
+) -> Result<(), CacheError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let mut mapper = crate::memory::page_table().lock();
+    let mut frame_allocator = crate::memory::frame_allocator().lock();
+    let frame = PhysFrame::<S>::containing_address(PhysAddr::new(0));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+
+    for i in 0..pages {
+        let page = Page::<S>::containing_address(VirtAddr::new(start + i * S::SIZE));
+        mapper
+            .map_to(page, frame, flags, &mut *frame_allocator)
+            .map_err(|_| CacheError::MapFailed)?
+            .flush();
+    }
+    Ok(())
+}
+
+unsafe fn unmap_aliases<S: PageSize + core::fmt::Debug>(start: u64, pages: u64)
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let mut mapper = crate::memory::page_table().lock();
+    for i in 0..pages {
+        let page = Page::<S>::containing_address(VirtAddr::new(start + i * S::SIZE));
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+}
+
+/// Measure the access latency over a growing number of pages of size `S`
+pub fn tlb_latencies<S: PageSize + core::fmt::Debug>(
+    start: u64,
+    max_pages: u64,
+) -> Result<Vec<Point>, CacheError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    if let Err(err) = unsafe { map_aliases::<S>(start, max_pages) } {
+        unsafe { unmap_aliases::<S>(start, max_pages) };
+        return Err(err);
+    }
+
+    let config = BenchConfig {
+        warmup: 2,
+        samples: 15,
+        ..BenchConfig::new()
+    };
+    let mut points = Vec::new();
+    let mut pages = 4;
+    while pages <= max_pages {
+        let mut index = tlb_walk(start, S::SIZE, pages, 0, 2 * pages as usize);
+        let stats = run(&config, || {
+            index = tlb_walk(start, S::SIZE, pages, core::hint::black_box(index), LOADS)
+        });
+        points.push(Point {
+            size: pages,
+            latency: stats.median / LOADS as f64,
+        });
+        pages *= 2;
+    }
+
+    unsafe { unmap_aliases::<S>(start, max_pages) };
+    Ok(points)
+}
+
+/// Sizes after which the latency increases noticeably
+pub fn capacity_steps(points: &[Point]) -> Vec<u64> {
+    let mut steps = Vec::new();
+    let mut base = match points.first() {
+        Some(point) => point.latency,
+        None => return steps,
+    };
+
+    for pair in points.windows(2) {
+        if pair[1].latency > base * STEP_THRESHOLD {
+            steps.push(pair[0].size);
+            base = pair[1].latency;
+        }
+    }
+    steps
+}
+
+/// Data and unified caches reported by cpuid, sizes in bytes
+pub fn cpuid_caches() -> Vec<Level> {
+    let cpuid = CpuId::new();
+    let mut levels = Vec::new();
+
+    // Intel describes its caches in leaf 4
+    if let Some(params) = cpuid.get_cache_parameters() {
+        use raw_cpuid::CacheType;
+        for cache in params {
+            let name = match (cache.level(), cache.cache_type()) {
+                (1, CacheType::Data) => "L1d",
+                (2, CacheType::Unified) => "L2",
+                (3, CacheType::Unified) => "L3",
+                _ => continue,
+            };
+            let size = cache.associativity()
+                * cache.physical_line_partitions()
+                * cache.coherency_line_size()
+                * cache.sets();
+            levels.push(Level {
+                name,
+                size: size as u64,
+            });
+        }
+        if !levels.is_empty() {
+            return levels;
+        }
+    }
+
+    if let Some(l1) = cpuid.get_l1_cache_and_tlb_info() {
+        levels.push(Level {
+            name: "L1d",
+            size: l1.dcache_size() as u64 * KIB,
+        });
+    }
+    if let Some(l2l3) = cpuid.get_l2_l3_cache_and_tlb_info() {
+        if l2l3.l2cache_size() != 0 {
+            levels.push(Level {
+                name: "L2",
+                size: l2l3.l2cache_size() as u64 * KIB,
+            });
+        }
+        if l2l3.l3cache_size() != 0 {
+            levels.push(Level {
+                name: "L3",
+                size: l2l3.l3cache_size() as u64 * 512 * KIB,
+            });
+        }
+    }
+    levels
+}
+
+/// Data TLBs for pages of `page_size` reported by cpuid, sizes in entries.
+/// Only AMD reports its TLBs in a parseable way.
+pub fn cpuid_tlbs(page_size: u64) -> Vec<Level> {
+    let cpuid = CpuId::new();
+    let mut levels = Vec::new();
+
+    let (l1, l2) = match page_size {
+        Size4KiB::SIZE => (
+            cpuid
+                .get_l1_cache_and_tlb_info()
+                .map(|i| i.dtlb_4k_size() as u64),
+            cpuid
+                .get_l2_l3_cache_and_tlb_info()
+                .map(|i| i.dtlb_4k_size() as u64),
+        ),
+        Size2MiB::SIZE => (
+            cpuid
+                .get_l1_cache_and_tlb_info()
+                .map(|i| i.dtlb_2m_4m_size() as u64),
+            cpuid
+                .get_l2_l3_cache_and_tlb_info()
+                .map(|i| i.dtlb_2m_4m_size() as u64),
+        ),
+        _ => {
+            let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+            if max_leaf < CpuidIndex::TLBInfo1GbPages as u32 {
+                (None, None)
+            } else {
+                let res = unsafe { __cpuid(CpuidIndex::TLBInfo1GbPages as u32) };
+                (
+                    Some(((res.eax >> 16) & 0xfff) as u64),
+                    Some(((res.ebx >> 16) & 0xfff) as u64),
+                )
+            }
+        }
+    };
+
+    if let Some(entries) = l1.filter(|&e| e != 0) {
+        levels.push(Level {
+            name: "L1 dTLB",
+            size: entries,
+        });
+    }
+    if let Some(entries) = l2.filter(|&e| e != 0) {
+        levels.push(Level {
+            name: "L2 dTLB",
+            size: entries,
+        });
+    }
+    levels
+}
+
+/// Step closest to `expected` within a factor of two
+fn matching_step(steps: &[u64], expected: u64) -> Option<u64> {
+    steps
+        .iter()
+        .copied()
+        .filter(|&s| s * 2 >= expected && s <= expected * 2)
+        .min_by_key(|&s| {
+            if s > expected {
+                s - expected
+            } else {
+                expected - s
+            }
+        })
+}
+
+/// Latency of the largest working set which fits into half of `size`
+fn latency_within(points: &[Point], size: u64) -> Option<f64> {
+    points
+        .iter()
+        .rev()
+        .find(|p| p.size <= size / 2)
+        .map(|p| p.latency)
+}
+
+fn print_curve(points: &[Point], unit: &str) {
+    for point in points {
+        println!(
+            "    {:>10} {:<5} {:>8.2} ns",
+            point.size, unit, point.latency
+        );
+    }
+}
+
+/// Prints a measured size or "-"
+struct Measured(Option<u64>);
+
+impl fmt::Display for Measured {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(size) => size.fmt(f),
+            None => "-".fmt(f),
+        }
+    }
+}
+
+fn print_levels(levels: &[Level], points: &[Point], unit: &str) {
+    let steps = capacity_steps(points);
+    println!(
+        "{:<10} {:>12} {:>12} {:>12}  check",
+        "level", "cpuid", "measured", "latency"
+    );
+    for level in levels {
+        let latency = latency_within(points, level.size);
+        let measured = matching_step(&steps, level.size);
+        let check = if measured.is_some() { "ok" } else { "MISMATCH" };
+        println!(
+            "{:<10} {:>8} {:<3} {:>8} {:<3} {:>9.2} ns  {}",
+            level.name,
+            level.size,
+            unit,
+            Measured(measured),
+            unit,
+            latency.unwrap_or(0.0),
+            check
+        );
+    }
+    if let Some(last) = points.last() {
+        println!(
+            "{:<10} {:>12} {:>12} {:>9.2} ns",
+            "beyond", "-", "-", last.latency
+        );
+    }
+}
+
+fn print_tlb<S: PageSize + core::fmt::Debug>(name: &str, start: u64, max_pages: u64)
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    println!("\n{} pages:", name);
+    match tlb_latencies::<S>(start, max_pages) {
+        Ok(points) => {
+            print_curve(&points, "pages");
+            print_levels(&cpuid_tlbs(S::SIZE), &points, "");
+        }
+        Err(err) => println!("    failed: {:?}", err),
+    }
+}
+
+/// Run the cache and TLB benchmarks and print the results next
+/// to the values reported by cpuid
+pub fn run_suite() {
+    let caches = cpuid_caches();
+    let largest = caches.iter().map(|l| l.size).max().unwrap_or(0);
+    let max = (4 * largest).max(MIN_MAX_WORKING_SET).next_power_of_two();
+
+    println!("\nCache load-to-use latency:");
+    match cache_latencies(max) {
+        Ok(points) => {
+            let points: Vec<Point> = points
+                .into_iter()
+                .map(|p| Point {
+                    size: p.size / KIB,
+                    latency: p.latency,
+                })
+                .collect();
+            let caches: Vec<Level> = caches
+                .iter()
+                .map(|l| Level {
+                    name: l.name,
+                    size: l.size / KIB,
+                })
+                .collect();
+            print_curve(&points, "KiB");
+            print_levels(&caches, &points, "KiB");
+        }
+        Err(err) => println!("    failed: {:?}", err),
+    }
+
+    print_tlb::<Size4KiB>("4 KiB", TLB_4K_START, TLB_4K_MAX_PAGES);
+    print_tlb::<Size2MiB>("2 MiB", TLB_2M_START, TLB_2M_MAX_PAGES);
+
+    let huge_pages = CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .map_or(false, |f| f.has_1gib_pages());
+    if huge_pages {
+        print_tlb::<Size1GiB>("1 GiB", TLB_1G_START, TLB_1G_MAX_PAGES);
+    } else {
+        println!("\n1 GiB pages: failed: {:?}", CacheError::NoHugePages);
+    }
+}
@@ -0,0 +1,256 @@
+//! NUMA bandwidth and latency matrix.
+//! For every proximity domain of the SRAT one core is picked and a buffer is
+//! allocated in the memory of every domain. Each core then measures read,
+//! write and copy bandwidth and the idle load latency to every buffer.
+//! Without a SRAT all cores and all memory form domain 0.
+
+use super::cache::{build_chain, chase};
+use super::{run, BenchConfig};
+use crate::println;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::hint::black_box;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Size of the buffer allocated in every memory domain
+pub const BUFFER_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Physical memory is mapped at this offset for the benchmark,
+/// so buffers above the identity mapping can be reached
+const NUMA_WINDOW: u64 = 0x5300_0000_0000;
+
+/// Loads per timed iteration of the latency benchmark
+const LOADS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NumaError {
+    /// No memory for the buffer is left in the domain
+    OutOfMemory(u32),
+    /// The buffer could not be mapped
+    MapFailed,
+    /// The core of the domain did not come online
+    CoreOffline(u32),
+}
+
+/// Results of one core accessing one buffer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NumaResult {
+    /// Bandwidths in GB/s. Copies count the bytes read and written.
+    pub read: f64,
+    pub write: f64,
+    pub copy: f64,
+    /// Idle load-to-use latency in nanoseconds
+    pub latency: f64,
+}
+
+/// Apic ids per proximity domain
+pub fn core_domains() -> BTreeMap<u32, Vec<u32>> {
+    let acpi = unsafe { crate::acpi::init() };
+    let mut domains: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+
+    for lapic in acpi.apics.iter().flatten() {
+        let id = lapic.x2apic_id;
+        let domain = acpi
+            .apic_domains
+            .as_ref()
+            .and_then(|d| d.get(&id).copied())
+            .unwrap_or(0);
+        domains.entry(domain).or_default().push(id);
+    }
+    domains
+}
+
+/// Physical address ranges per proximity domain, end exclusive
+pub fn memory_domains() -> BTreeMap<u32, Vec<core::ops::Range<u64>>> {
+    let acpi = unsafe { crate::acpi::init() };
+    match acpi.memory_domains.as_ref() {
+        Some(domains) => domains
+            .iter()
+            .map(|(&domain, ranges)| {
+                let ranges = ranges.entries().iter().map(|r| r.start..r.end + 1);
+                (domain, ranges.collect())
+            })
+            .collect(),
+        None => {
+            let mut domains = BTreeMap::new();
+            domains.insert(0, alloc::vec![0..u64::MAX]);
+            domains
+        }
+    }
+}
+
+/// Allocate a buffer in the memory of a domain and map it into the window
+fn allocate_buffer(domain: u32, ranges: &[core::ops::Range<u64>]) -> Result<u64, NumaError> {
+    let phys = {
+        let mut frame_allocator = crate::memory::frame_allocator().lock();
+        ranges
+            .iter()
+            .find_map(|range| {
+                frame_allocator.allocate_contiguous_in(BUFFER_SIZE, Size2MiB::SIZE, range.clone())
+            })
+            .ok_or(NumaError::OutOfMemory(domain))?
+            .as_u64()
+    };
+
+    let mut mapper = crate::memory::page_table().lock();
+    let mut frame_allocator = crate::memory::frame_allocator().lock();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for offset in (0..BUFFER_SIZE).step_by(Size2MiB::SIZE as usize) {
+        let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(phys + offset));
+        let page = Page::<Size2MiB>::containing_address(VirtAddr::new(NUMA_WINDOW + phys + offset));
+        match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(_)) => {}
+            Err(_) => return Err(NumaError::MapFailed),
+        }
+    }
+    Ok(NUMA_WINDOW + phys)
+}
+
+#[inline(never)]
+fn read_words(buf: &[u64]) -> u64 {
+    // Independent sums so the additions do not limit the load throughput
+    let mut sum = [0u64; 4];
+    for chunk in buf.chunks_exact(4) {
+        for (s, &word) in sum.iter_mut().zip(chunk) {
+            *s = s.wrapping_add(word);
+        }
+    }
+    sum.iter().fold(0, |a, &b| a.wrapping_add(b))
+}
+
+#[inline(never)]
+fn write_words(buf: &mut [u64], val: u64) {
+    for word in buf.iter_mut() {
+        unsafe { core::ptr::write_volatile(word, val) };
+    }
+}
+
+#[inline(never)]
+fn copy_words(src: &[u64], dst: &mut [u64]) {
+    for (d, s) in dst.iter_mut().zip(src.iter()) {
+        unsafe { core::ptr::write_volatile(d, *s) };
+    }
+}
+
+/// Median throughput of `routine` processing `bytes` in GB/s
+fn bandwidth<F: FnMut()>(bytes: u64, routine: F) -> f64 {
+    let config = BenchConfig {
+        warmup: 1,
+        samples: 5,
+        min_sample_cycles: 0,
+        max_iters: 1,
+    };
+    let stats = run(&config, routine);
+    bytes as f64 / stats.median
+}
+
+/// Measure the buffer at `buf` from the current core
+pub fn measure(buf: u64) -> NumaResult {
+    let words = (BUFFER_SIZE / 8) as usize;
+    let data = unsafe { core::slice::from_raw_parts_mut(buf as *mut u64, words) };
+
+    let write = bandwidth(BUFFER_SIZE, || write_words(black_box(&mut *data), 1));
+    let read = bandwidth(BUFFER_SIZE, || {
+        black_box(read_words(black_box(&*data)));
+    });
+    let (src, dst) = data.split_at_mut(words / 2);
+    let copy = bandwidth(BUFFER_SIZE, || {
+        copy_words(black_box(&*src), black_box(&mut *dst))
+    });
+
+    let lines = BUFFER_SIZE / 64;
+    unsafe { build_chain(buf, lines, crate::time::rdtsc()) };
+    let mut p = chase(buf as *const u64, lines as usize);
+    let config = BenchConfig {
+        warmup: 2,
+        samples: 15,
+        ..BenchConfig::new()
+    };
+    let stats = run(&config, || p = chase(black_box(p), LOADS));
+
+    NumaResult {
+        read,
+        write,
+        copy,
+        latency: stats.median / LOADS as f64,
+    }
+}
+
+/// Result matrix indexed by core domain and memory domain
+pub type NumaMatrix = BTreeMap<(u32, u32), NumaResult>;
+
+/// Measure every core domain against every memory domain
+pub fn measure_all() -> Result<NumaMatrix, NumaError> {
+    let cores = core_domains();
+
+    // Allocate in ascending address order, the frame allocator can't go back
+    let mut memory: Vec<(u32, Vec<core::ops::Range<u64>>)> = memory_domains().into_iter().collect();
+    memory.sort_by_key(|(_, ranges)| ranges.iter().map(|r| r.start).min());
+    let mut buffers = Vec::new();
+    for (domain, ranges) in memory.iter() {
+        buffers.push((*domain, allocate_buffer(*domain, ranges)?));
+    }
+    buffers.sort_by_key(|&(domain, _)| domain);
+
+    let mut matrix = BTreeMap::new();
+    for (&core_domain, apic_ids) in cores.iter() {
+        // Prefer the bsp in its own domain
+        let bsp = crate::apic::apic_id();
+        let apic_id = if apic_ids.contains(&bsp) {
+            bsp
+        } else {
+            apic_ids[0]
+        };
+        if !crate::smp::wait_online(apic_id, crate::smp::ONLINE_TIMEOUT_US) {
+            return Err(NumaError::CoreOffline(apic_id));
+        }
+
+        for &(memory_domain, buf) in buffers.iter() {
+            let result = crate::smp::run_on(apic_id, || measure(buf))
+                .map_err(|_| NumaError::CoreOffline(apic_id))?;
+            matrix.insert((core_domain, memory_domain), result);
+        }
+    }
+    Ok(matrix)
+}
+
+fn print_matrix<F: Fn(&NumaResult) -> f64>(title: &str, matrix: &NumaMatrix, value: F) {
+    let mut cores: Vec<u32> = matrix.keys().map(|&(c, _)| c).collect();
+    let mut memory: Vec<u32> = matrix.keys().map(|&(_, m)| m).collect();
+    cores.dedup();
+    memory.sort_unstable();
+    memory.dedup();
+
+    println!("\n{}", title);
+    crate::print!("{:>12}", "core \\ mem");
+    for m in memory.iter() {
+        crate::print!(" {:>9}", m);
+    }
+    println!();
+    for c in cores.iter() {
+        crate::print!("{:>12}", c);
+        for m in memory.iter() {
+            match matrix.get(&(*c, *m)) {
+                Some(result) => crate::print!(" {:>9.2}", value(result)),
+                None => crate::print!(" {:>9}", "-"),
+            }
+        }
+        println!();
+    }
+}
+
+/// Run the NUMA benchmarks and print the matrices
+pub fn run_suite() {
+    match measure_all() {
+        Ok(matrix) => {
+            print_matrix("Read bandwidth (GB/s):", &matrix, |r| r.read);
+            print_matrix("Write bandwidth (GB/s):", &matrix, |r| r.write);
+            print_matrix("Copy bandwidth (GB/s):", &matrix, |r| r.copy);
+            print_matrix("Idle latency (ns):", &matrix, |r| r.latency);
+        }
+        Err(err) => println!("NUMA benchmark failed: {:?}", err),
+    }
+}
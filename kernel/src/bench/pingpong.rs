@@ -0,0 +1,203 @@
+//! Core-to-core round trip latency matrix.
+//! For every ordered pair of online cores the first core bounces a cache
+//! line off the second one with atomic stores. Separately it sends an IPI
+//! which the second core answers with an IPI. The median round trip of
+//! both is reported as an N×N matrix, rows are the initiating core.
+
+use super::Stats;
+use crate::interrupts::InterruptIndex;
+use crate::println;
+use crate::time::{rdtsc, tsc_khz};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+/// Timed round trips per pair
+const ROUNDS: usize = 1000;
+
+/// Round trips before the timed ones, covers the partner core waking up
+const WARMUP_ROUNDS: usize = 100;
+
+const NO_PEER: u32 = u32::MAX;
+
+/// The bounced cache line, nothing else shares it
+#[repr(align(64))]
+struct Line(AtomicU64);
+
+static LINE: Line = Line(AtomicU64::new(0));
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_PEER_ATOMIC: AtomicU32 = AtomicU32::new(NO_PEER);
+
+/// Apic id to answer ping-pong IPIs to, indexed by core index
+static PEERS: [AtomicU32; bootloader::MAX_CORES] = [NO_PEER_ATOMIC; bootloader::MAX_CORES];
+
+/// Answers received by the initiating core
+static RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the initiating core is done with the IPI round trips
+static IPI_DONE: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingPongError {
+    /// The core did not come online
+    CoreOffline(u32),
+}
+
+/// Called by the interrupt handler of `InterruptIndex::PingPong`
+pub fn handle_ipi() {
+    let peer = PEERS[crate::smp::core_index()].load(Ordering::Acquire);
+    if peer == NO_PEER {
+        RECEIVED.fetch_add(1, Ordering::Release);
+    } else {
+        unsafe { crate::apic::send_fixed_ipi(peer, InterruptIndex::PingPong.as_u8()) };
+    }
+}
+
+fn to_ns(cycles: u64) -> f64 {
+    cycles as f64 * 1_000_000.0 / tsc_khz() as f64
+}
+
+/// Median of the round trip samples in nanoseconds
+fn median(samples: &mut [f64]) -> f64 {
+    Stats::from_samples(&mut samples[WARMUP_ROUNDS..]).median
+}
+
+fn cache_ping(samples: &mut Vec<f64>) {
+    for round in 0..(WARMUP_ROUNDS + ROUNDS) as u64 {
+        let start = rdtsc();
+        LINE.0.store(2 * round + 1, Ordering::Release);
+        while LINE.0.load(Ordering::Acquire) != 2 * round + 2 {
+            core::hint::spin_loop();
+        }
+        samples.push(to_ns(rdtsc() - start));
+    }
+}
+
+fn cache_pong() {
+    for round in 0..(WARMUP_ROUNDS + ROUNDS) as u64 {
+        while LINE.0.load(Ordering::Acquire) != 2 * round + 1 {
+            core::hint::spin_loop();
+        }
+        LINE.0.store(2 * round + 2, Ordering::Release);
+    }
+}
+
+fn ipi_ping(peer: u32, samples: &mut Vec<f64>) {
+    for round in 0..(WARMUP_ROUNDS + ROUNDS) as u64 {
+        let start = rdtsc();
+        unsafe { crate::apic::send_fixed_ipi(peer, InterruptIndex::PingPong.as_u8()) };
+        while RECEIVED.load(Ordering::Acquire) != round + 1 {
+            core::hint::spin_loop();
+        }
+        samples.push(to_ns(rdtsc() - start));
+    }
+    IPI_DONE.store(true, Ordering::Release);
+}
+
+fn ipi_pong() {
+    // Answers are sent from the interrupt handler
+    while !IPI_DONE.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Buffer for the samples of one pair. Only the ping core touches them,
+/// the lock just makes them Sync. A lock::Mutex would claim one of the
+/// MAX_LOCKS stats slots per pair.
+fn samples() -> spin::Mutex<Vec<f64>> {
+    spin::Mutex::new(Vec::with_capacity(WARMUP_ROUNDS + ROUNDS))
+}
+
+/// Median cache line round trip from `ping` to `pong` in nanoseconds
+pub fn cache_round_trip(ping: u32, pong: u32) -> Result<f64, PingPongError> {
+    let samples = samples();
+    LINE.0.store(0, Ordering::SeqCst);
+
+    crate::smp::run_on_all(&[ping, pong], |index| match index {
+        0 => cache_ping(&mut samples.lock()),
+        _ => cache_pong(),
+    })
+    .map_err(|crate::smp::SmpError::NotOnline(id)| PingPongError::CoreOffline(id))?;
+
+    Ok(median(&mut samples.into_inner()))
+}
+
+/// Median IPI round trip from `ping` to `pong` in nanoseconds
+pub fn ipi_round_trip(ping: u32, pong: u32) -> Result<f64, PingPongError> {
+    let samples = samples();
+    RECEIVED.store(0, Ordering::SeqCst);
+    IPI_DONE.store(false, Ordering::SeqCst);
+    let peer = &PEERS[crate::smp::index_of(pong).ok_or(PingPongError::CoreOffline(pong))?];
+    peer.store(ping, Ordering::SeqCst);
+
+    let result = crate::smp::run_on_all(&[ping, pong], |index| match index {
+        0 => ipi_ping(pong, &mut samples.lock()),
+        _ => ipi_pong(),
+    });
+    peer.store(NO_PEER, Ordering::SeqCst);
+    result.map_err(|crate::smp::SmpError::NotOnline(id)| PingPongError::CoreOffline(id))?;
+
+    Ok(median(&mut samples.into_inner()))
+}
+
+/// Round trip latencies in nanoseconds indexed by (ping, pong) apic id
+pub type LatencyMatrix = BTreeMap<(u32, u32), f64>;
+
+/// Wait for all cores listed in the ACPI tables and return the online ones
+pub fn cores() -> Vec<u32> {
+    let acpi = unsafe { crate::acpi::init() };
+    for lapic in acpi.apics.iter().flatten() {
+        let id = lapic.x2apic_id;
+        if !crate::smp::wait_online(id, crate::smp::ONLINE_TIMEOUT_US) {
+            log::warn!("Core {} did not come online, skipping it", id);
+        }
+    }
+    crate::smp::online_cores().collect()
+}
+
+/// Measure `round_trip` for every ordered pair of `cores`
+pub fn measure_all<F>(cores: &[u32], round_trip: F) -> Result<LatencyMatrix, PingPongError>
+where
+    F: Fn(u32, u32) -> Result<f64, PingPongError>,
+{
+    let mut matrix = BTreeMap::new();
+    for &ping in cores.iter() {
+        for &pong in cores.iter().filter(|&&pong| pong != ping) {
+            matrix.insert((ping, pong), round_trip(ping, pong)?);
+        }
+    }
+    Ok(matrix)
+}
+
+fn print_matrix(title: &str, cores: &[u32], matrix: &LatencyMatrix) {
+    println!("\n{}", title);
+    crate::print!("{:>12}", "from \\ to");
+    for pong in cores.iter() {
+        crate::print!(" {:>9}", pong);
+    }
+    println!();
+    for ping in cores.iter() {
+        crate::print!("{:>12}", ping);
+        for pong in cores.iter() {
+            match matrix.get(&(*ping, *pong)) {
+                Some(latency) => crate::print!(" {:>9.1}", latency),
+                None => crate::print!(" {:>9}", "-"),
+            }
+        }
+        println!();
+    }
+}
+
+/// Run the ping-pong benchmarks and print the matrices
+pub fn run_suite() {
+    let cores = cores();
+    match measure_all(&cores, cache_round_trip) {
+        Ok(matrix) => print_matrix("Cache line round trip (ns):", &cores, &matrix),
+        Err(err) => println!("Cache line ping-pong failed: {:?}", err),
+    }
+    match measure_all(&cores, ipi_round_trip) {
+        Ok(matrix) => print_matrix("IPI round trip (ns):", &cores, &matrix),
+        Err(err) => println!("IPI ping-pong failed: {:?}", err),
+    }
+}
@@ -0,0 +1,326 @@
+//! Free running counters the kernel can tell time with.
+//! The PIT, the ACPI power management timer and the hpet are registered
+//! as reference clocks with a fixed frequency. The TSC is calibrated
+//! against the best rated reference, cross-checked against all others
+//! and then registered itself. Higher ratings are preferred.
+
+use crate::time::rdtsc;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+
+/// Length of the TSC calibration in milliseconds
+const CALIBRATION_MS: u64 = 100;
+
+/// Length of the cross-check against the other references in milliseconds
+const CROSS_CHECK_MS: u64 = 50;
+
+/// Largest tolerated disagreement between two clocks in parts per million
+const MAX_DEVIATION_PPM: u64 = 5000;
+
+const PIT_HZ: u64 = 1_193_182;
+const PM_TIMER_HZ: u64 = 3_579_545;
+
+static mut SOURCES: Option<Vec<Box<dyn Clocksource>>> = None;
+
+pub trait Clocksource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Ticks per second
+    fn frequency(&self) -> u64;
+
+    /// Higher is better. Sources with a rating of 0 are never selected.
+    fn rating(&self) -> u32;
+
+    fn read(&self) -> u64;
+
+    /// Valid bits of the counter, it wraps after `mask`
+    fn mask(&self) -> u64;
+}
+
+/// Channel 2 of the PIT as a free running 16 bit counter
+pub struct Pit;
+
+impl Pit {
+    unsafe fn start() -> Self {
+        let mut gate: Port<u8> = Port::new(0x61);
+        let mut command: Port<u8> = Port::new(0x43);
+        let mut data: Port<u8> = Port::new(0x42);
+
+        // Enable the gate of channel 2 but keep the speaker off
+        let value = gate.read();
+        gate.write((value & !0x02) | 0x01);
+
+        // Channel 2, lobyte/hibyte access, rate generator, reload of 65536
+        command.write(0xb4);
+        data.write(0);
+        data.write(0);
+        Pit
+    }
+}
+
+impl Clocksource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn frequency(&self) -> u64 {
+        PIT_HZ
+    }
+
+    fn rating(&self) -> u32 {
+        100
+    }
+
+    fn read(&self) -> u64 {
+        let mut command: Port<u8> = Port::new(0x43);
+        let mut data: Port<u8> = Port::new(0x42);
+        unsafe {
+            // Latch the count of channel 2
+            command.write(0x80);
+            let low = data.read() as u64;
+            let high = data.read() as u64;
+            // The counter counts down
+            0xffff - ((high << 8) | low)
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        0xffff
+    }
+}
+
+/// The ACPI power management timer
+pub struct PmTimer {
+    port: u16,
+    mask: u64,
+}
+
+impl PmTimer {
+    /// The power management timer described by the FADT
+    pub fn from_acpi(acpi: &crate::acpi::Acpi) -> Option<Self> {
+        use crate::acpi_regs::Fadt;
+        use core::ptr::{addr_of, read_unaligned};
+
+        let fadt = acpi.fadt.as_ref()?;
+        let (port, flags) = unsafe {
+            (
+                read_unaligned(addr_of!(fadt.pm_tmr_blk)),
+                read_unaligned(addr_of!(fadt.flags)),
+            )
+        };
+        if port == 0 || port > u16::MAX as u32 {
+            return None;
+        }
+        let mask = if flags & Fadt::TMR_VAL_EXT != 0 {
+            0xffff_ffff
+        } else {
+            0xff_ffff
+        };
+        Some(PmTimer {
+            port: port as u16,
+            mask,
+        })
+    }
+}
+
+impl Clocksource for PmTimer {
+    fn name(&self) -> &'static str {
+        "acpi_pm"
+    }
+
+    fn frequency(&self) -> u64 {
+        PM_TIMER_HZ
+    }
+
+    fn rating(&self) -> u32 {
+        200
+    }
+
+    fn read(&self) -> u64 {
+        let mut port: Port<u32> = Port::new(self.port);
+        unsafe { port.read() as u64 & self.mask }
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask
+    }
+}
+
+/// Main counter of the hpet
+pub struct HpetSource(&'static crate::hpet::Hpet);
+
+impl Clocksource for HpetSource {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn frequency(&self) -> u64 {
+        self.0.frequency()
+    }
+
+    fn rating(&self) -> u32 {
+        250
+    }
+
+    fn read(&self) -> u64 {
+        self.0.counter() & self.mask()
+    }
+
+    fn mask(&self) -> u64 {
+        if self.0.is_64bit() {
+            u64::MAX
+        } else {
+            0xffff_ffff
+        }
+    }
+}
+
+pub struct Tsc {
+    hz: u64,
+    rating: u32,
+}
+
+impl Clocksource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn frequency(&self) -> u64 {
+        self.hz
+    }
+
+    fn rating(&self) -> u32 {
+        self.rating
+    }
+
+    fn read(&self) -> u64 {
+        rdtsc()
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+}
+
+fn sources() -> &'static Vec<Box<dyn Clocksource>> {
+    unsafe { SOURCES.as_ref().expect("clocksources not initialized") }
+}
+
+/// All registered clocks
+pub fn all() -> impl Iterator<Item = &'static dyn Clocksource> {
+    sources().iter().map(|source| source.as_ref())
+}
+
+/// The best rated clock
+pub fn best() -> &'static dyn Clocksource {
+    all()
+        .filter(|source| source.rating() != 0)
+        .max_by_key(|source| source.rating())
+        .expect("no clocksource")
+}
+
+/// The best rated clock other than the TSC
+pub fn best_reference() -> &'static dyn Clocksource {
+    all()
+        .filter(|source| source.name() != "tsc" && source.rating() != 0)
+        .max_by_key(|source| source.rating())
+        .expect("no reference clocksource")
+}
+
+/// TSC frequency in kHz measured against `reference` over `milliseconds`
+pub fn measure_tsc_khz(reference: &dyn Clocksource, milliseconds: u64) -> u64 {
+    let ticks = reference.frequency() * milliseconds / 1000;
+    let mask = reference.mask();
+
+    // Start on a fresh tick
+    let first = reference.read();
+    let mut last = reference.read();
+    while last == first {
+        last = reference.read();
+    }
+    let tsc_start = rdtsc();
+
+    // Sum up the deltas, some references wrap within the window
+    let mut elapsed = 0;
+    while elapsed < ticks {
+        let now = reference.read();
+        elapsed += now.wrapping_sub(last) & mask;
+        last = now;
+    }
+    let tsc_end = rdtsc();
+
+    ((tsc_end - tsc_start) as u128 * reference.frequency() as u128 / elapsed as u128 / 1000) as u64
+}
+
+/// Register all reference clocks, calibrate the TSC against the best of them
+/// and register it as well. Returns the TSC frequency in kHz.
+pub unsafe fn init() -> u64 {
+    if let Some(tsc) = SOURCES
+        .as_ref()
+        .and_then(|sources| sources.iter().find(|source| source.name() == "tsc"))
+    {
+        return tsc.frequency() / 1000;
+    }
+
+    let acpi = crate::acpi::init();
+    let mut sources: Vec<Box<dyn Clocksource>> = alloc::vec![Box::new(Pit::start())];
+    if let Some(pm_timer) = PmTimer::from_acpi(acpi) {
+        sources.push(Box::new(pm_timer));
+    }
+    if let Some(hpet) = crate::hpet::get() {
+        sources.push(Box::new(HpetSource(hpet)));
+    }
+    SOURCES = Some(sources);
+
+    let reference = best_reference();
+    let khz = measure_tsc_khz(reference, CALIBRATION_MS);
+    log::info!(
+        "Calibrated TSC against {}: {}.{:03} MHz",
+        reference.name(),
+        khz / 1000,
+        khz % 1000
+    );
+
+    if khz == 0 {
+        log::warn!(
+            "TSC calibration against {} measured 0 kHz, skipping the cross-check",
+            reference.name()
+        );
+    } else {
+        for other in all().filter(|source| source.name() != reference.name()) {
+            let other_khz = measure_tsc_khz(other, CROSS_CHECK_MS);
+            let deviation = (other_khz as i64 - khz as i64).abs() as u64 * 1_000_000 / khz;
+            if deviation > MAX_DEVIATION_PPM {
+                log::warn!(
+                    "Clocksource {} disagrees with {}: TSC at {} kHz instead of {} kHz",
+                    other.name(),
+                    reference.name(),
+                    other_khz,
+                    khz
+                );
+            } else {
+                log::debug!(
+                    "Clocksource {} agrees with {} within {} ppm",
+                    other.name(),
+                    reference.name(),
+                    deviation
+                );
+            }
+        }
+    }
+
+    // A TSC which changes its rate is worse than any reference
+    let rating = if crate::time::tsc_sync::has_invariant_tsc() {
+        300
+    } else {
+        50
+    };
+    SOURCES.as_mut().unwrap().push(Box::new(Tsc {
+        hz: khz * 1000,
+        rating,
+    }));
+    log::info!("Selected clocksource: {}", best().name());
+
+    khz
+}
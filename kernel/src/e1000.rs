@@ -0,0 +1,410 @@
+//! Intel 8254x and 82574 (e1000/e1000e) network driver.
+//! Both directions use a ring of legacy descriptors, each with a DMA
+//! buffer of its own. The hardware owns the receive descriptors between
+//! head and tail and sets the done bit when it filled one. Transmit
+//! descriptors are free once their done bit is set. Interrupts stay
+//! masked, the network stack polls like it does for virtio-net.
+
+use crate::e1000_regs::*;
+use crate::lock::Mutex;
+use crate::memory::BootInfoFrameAllocator;
+use crate::net::{MacAddr, NetDevice, NetError, MAX_FRAME};
+use crate::pci::{
+    bar, Device, DriverError, Match, PciFunction, COMMAND_BUS_MASTER, COMMAND_MEMORY_SPACE,
+};
+use alloc::sync::Arc;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::structures::paging::OffsetPageTable;
+
+const VENDOR_ID: u16 = 0x8086;
+
+/// 82574L, the `-device e1000e` of QEMU
+const DEVICE_ID_82574: u16 = 0x10d3;
+
+pub const MATCHES: &[Match] = &[
+    // 82540EM, the `-device e1000` of QEMU
+    Match::Id {
+        vendor_id: VENDOR_ID,
+        device_id: 0x100e,
+    },
+    // 82545EM
+    Match::Id {
+        vendor_id: VENDOR_ID,
+        device_id: 0x100f,
+    },
+    Match::Id {
+        vendor_id: VENDOR_ID,
+        device_id: DEVICE_ID_82574,
+    },
+];
+
+/// Descriptors per ring, the ring size has to be a multiple of 128 bytes
+const RING_SIZE: u16 = 128;
+
+/// Size of the buffer of a descriptor, matches `bsize` 0b00
+const BUFFER_SIZE: u64 = 2048;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+
+/// Time the device gets to reset and to read the EEPROM
+const RESET_TIMEOUT_US: u64 = 100_000;
+
+struct Ring {
+    descriptors: u64,
+    /// DMA buffer of every descriptor, `BUFFER_SIZE` each
+    buffers: u64,
+    /// Next descriptor to receive from or to transmit with
+    next: u16,
+}
+
+impl Ring {
+    fn new(frame_allocator: &mut BootInfoFrameAllocator) -> Result<Ring, DriverError> {
+        let descriptors = frame_allocator
+            .allocate_dma(RING_SIZE as u64 * DESCRIPTOR_SIZE)
+            .ok_or(DriverError::OutOfMemory)?;
+        let buffers = frame_allocator
+            .allocate_dma(RING_SIZE as u64 * BUFFER_SIZE)
+            .ok_or(DriverError::OutOfMemory)?;
+        Ok(Ring {
+            descriptors,
+            buffers,
+            next: 0,
+        })
+    }
+
+    fn buffer(&self, index: u16) -> u64 {
+        self.buffers + index as u64 * BUFFER_SIZE
+    }
+
+    fn descriptor<T>(&self, index: u16) -> *mut T {
+        (self.descriptors + index as u64 * DESCRIPTOR_SIZE) as *mut T
+    }
+}
+
+struct Inner {
+    base: u64,
+    rx: Ring,
+    tx: Ring,
+}
+
+unsafe fn read(base: u64, register: Register) -> u32 {
+    read_volatile((base + register as u64) as *const u32)
+}
+
+unsafe fn write(base: u64, register: Register, value: u32) {
+    write_volatile((base + register as u64) as *mut u32, value);
+}
+
+pub struct E1000 {
+    function: &'static PciFunction,
+    /// Base of the registers
+    base: spin::Once<u64>,
+    mac: spin::Once<MacAddr>,
+    inner: Mutex<Option<Inner>>,
+}
+
+pub fn probe(function: &'static PciFunction) -> Option<Arc<dyn Device>> {
+    Some(Arc::new(E1000 {
+        function,
+        base: spin::Once::new(),
+        mac: spin::Once::new(),
+        inner: Mutex::new("e1000", None),
+    }))
+}
+
+/// Mask all interrupts and reset the device
+unsafe fn reset(base: u64) -> Result<(), DriverError> {
+    write(base, Register::InterruptMaskClear, u32::MAX);
+    write(
+        base,
+        Register::Control,
+        read(base, Register::Control) | CTRL_RST,
+    );
+
+    let deadline = crate::time::future(RESET_TIMEOUT_US);
+    while read(base, Register::Control) & CTRL_RST != 0 {
+        if crate::time::rdtsc() > deadline {
+            return Err(DriverError::Device("e1000 reset timed out"));
+        }
+        core::hint::spin_loop();
+    }
+    // The reset unmasks nothing, but clear the interrupts it raised
+    write(base, Register::InterruptMaskClear, u32::MAX);
+    read(base, Register::InterruptCause);
+    Ok(())
+}
+
+/// Read a word of the EEPROM, the register layout of the 82574 differs
+unsafe fn read_eeprom(base: u64, address: u8, is_82574: bool) -> Option<u16> {
+    let (done, shift) = if is_82574 {
+        (EERD_DONE_82574, EERD_ADDR_SHIFT_82574)
+    } else {
+        (EERD_DONE, EERD_ADDR_SHIFT)
+    };
+    write(
+        base,
+        Register::EepromRead,
+        EERD_START | (address as u32) << shift,
+    );
+    let deadline = crate::time::future(RESET_TIMEOUT_US);
+    loop {
+        let value = read(base, Register::EepromRead);
+        if value & done != 0 {
+            return Some((value >> 16) as u16);
+        }
+        if crate::time::rdtsc() > deadline {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// The MAC address the firmware loaded into the first receive address,
+/// or the one in the EEPROM
+unsafe fn read_mac(base: u64, is_82574: bool) -> Option<MacAddr> {
+    let high = read(base, Register::ReceiveAddressHigh);
+    let mut mac = MacAddr::default();
+    if high & RAH_AV != 0 {
+        let low = read(base, Register::ReceiveAddressLow);
+        mac.0[..4].copy_from_slice(&low.to_le_bytes());
+        mac.0[4..].copy_from_slice(&high.to_le_bytes()[..2]);
+        return Some(mac);
+    }
+
+    for word in 0..3 {
+        let value = read_eeprom(base, word, is_82574)?;
+        mac.0[2 * word as usize..2 * word as usize + 2].copy_from_slice(&value.to_le_bytes());
+    }
+    // Program it so the receive filter accepts it
+    let low = u32::from_le_bytes([mac.0[0], mac.0[1], mac.0[2], mac.0[3]]);
+    let high = u16::from_le_bytes([mac.0[4], mac.0[5]]) as u32 | RAH_AV;
+    write(base, Register::ReceiveAddressLow, low);
+    write(base, Register::ReceiveAddressHigh, high);
+    Some(mac)
+}
+
+impl Inner {
+    unsafe fn write(&self, register: Register, value: u32) {
+        write(self.base, register, value);
+    }
+
+    unsafe fn setup_rx(&self) {
+        for index in 0..RING_SIZE {
+            let descriptor = RxDescriptor {
+                addr: self.rx.buffer(index),
+                ..Default::default()
+            };
+            write_volatile(self.rx.descriptor(index), descriptor);
+        }
+        self.write(Register::RxDescBaseLow, self.rx.descriptors as u32);
+        self.write(Register::RxDescBaseHigh, (self.rx.descriptors >> 32) as u32);
+        self.write(
+            Register::RxDescLength,
+            (RING_SIZE as u64 * DESCRIPTOR_SIZE) as u32,
+        );
+        self.write(Register::RxDescHead, 0);
+        // The descriptor at the tail is the one the hardware may not fill
+        self.write(Register::RxDescTail, RING_SIZE as u32 - 1);
+
+        let control = RxControlReg::new()
+            .with_en(1)
+            .with_bam(1)
+            .with_bsize(0b00)
+            .with_secrc(1);
+        self.write(
+            Register::RxControl,
+            u32::from_le_bytes(control.into_bytes()),
+        );
+    }
+
+    unsafe fn setup_tx(&self) {
+        // All descriptors start out done, so they are free
+        for index in 0..RING_SIZE {
+            let descriptor = TxDescriptor {
+                addr: self.tx.buffer(index),
+                status: DESC_STATUS_DD,
+                ..Default::default()
+            };
+            write_volatile(self.tx.descriptor(index), descriptor);
+        }
+        self.write(Register::TxDescBaseLow, self.tx.descriptors as u32);
+        self.write(Register::TxDescBaseHigh, (self.tx.descriptors >> 32) as u32);
+        self.write(
+            Register::TxDescLength,
+            (RING_SIZE as u64 * DESCRIPTOR_SIZE) as u32,
+        );
+        self.write(Register::TxDescHead, 0);
+        self.write(Register::TxDescTail, 0);
+        self.write(Register::TxIpg, TIPG_COPPER);
+
+        // Collision threshold and distance for full duplex
+        let control = TxControlReg::new()
+            .with_en(1)
+            .with_psp(1)
+            .with_ct(0x0f)
+            .with_cold(0x40);
+        self.write(
+            Register::TxControl,
+            u32::from_le_bytes(control.into_bytes()),
+        );
+    }
+}
+
+impl E1000 {
+    fn is_82574(&self) -> bool {
+        self.function.header.device_id == DEVICE_ID_82574
+    }
+}
+
+impl NetDevice for E1000 {
+    fn name(&self) -> &'static str {
+        if self.is_82574() {
+            "e1000e"
+        } else {
+            "e1000"
+        }
+    }
+
+    fn mac(&self) -> MacAddr {
+        self.mac.get().copied().unwrap_or_default()
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME {
+            return Err(NetError::TooLarge);
+        }
+        let mut inner = self.inner.lock();
+        let inner = inner.as_mut().ok_or(NetError::NotInitialized)?;
+
+        let index = inner.tx.next;
+        let descriptor = inner.tx.descriptor::<TxDescriptor>(index);
+        unsafe {
+            if read_volatile(descriptor).status & DESC_STATUS_DD == 0 {
+                return Err(NetError::QueueFull);
+            }
+            core::ptr::copy_nonoverlapping(
+                frame.as_ptr(),
+                inner.tx.buffer(index) as *mut u8,
+                frame.len(),
+            );
+            write_volatile(
+                descriptor,
+                TxDescriptor {
+                    addr: inner.tx.buffer(index),
+                    length: frame.len() as u16,
+                    cmd: TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS,
+                    ..Default::default()
+                },
+            );
+            inner.tx.next = (index + 1) % RING_SIZE;
+            inner.write(Register::TxDescTail, inner.tx.next as u32);
+        }
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let inner = inner.as_mut()?;
+
+        loop {
+            let index = inner.rx.next;
+            let descriptor = inner.rx.descriptor::<RxDescriptor>(index);
+            let received = unsafe { read_volatile(descriptor) };
+            if received.status & DESC_STATUS_DD == 0 {
+                return None;
+            }
+
+            // Frames never span buffers without long packets, but drop them if they do
+            let complete = received.status & DESC_STATUS_EOP != 0 && received.errors == 0;
+            let len = (received.length as usize).min(buf.len());
+            if complete {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        inner.rx.buffer(index) as *const u8,
+                        buf.as_mut_ptr(),
+                        len,
+                    );
+                }
+            }
+
+            // Hand the descriptor back to the hardware
+            unsafe {
+                write_volatile(
+                    descriptor,
+                    RxDescriptor {
+                        addr: inner.rx.buffer(index),
+                        ..Default::default()
+                    },
+                );
+                inner.write(Register::RxDescTail, index as u32);
+            }
+            inner.rx.next = (index + 1) % RING_SIZE;
+
+            if complete {
+                return Some(len);
+            }
+        }
+    }
+}
+
+impl Device for E1000 {
+    fn name(&self) -> &'static str {
+        NetDevice::name(self)
+    }
+
+    fn function(&self) -> &'static PciFunction {
+        self.function
+    }
+
+    unsafe fn init(
+        &self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<(), DriverError> {
+        let bar = self.function.bars[0].ok_or(DriverError::MissingBar(0))?;
+        let base = bar::map(mapper, frame_allocator, &bar).ok_or(DriverError::MissingBar(0))?;
+        self.function
+            .enable(COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+        self.base.call_once(|| base);
+
+        reset(base)?;
+        let control = read(base, Register::Control);
+        write(base, Register::Control, control | CTRL_SLU | CTRL_ASDE);
+
+        let mac = read_mac(base, self.is_82574())
+            .ok_or(DriverError::Device("e1000 without mac address"))?;
+        // Only accept unicast frames to us and broadcasts
+        for entry in 0..128 {
+            write_volatile(
+                (base + Register::MulticastTable as u64 + 4 * entry) as *mut u32,
+                0,
+            );
+        }
+
+        let inner = Inner {
+            base,
+            rx: Ring::new(frame_allocator)?,
+            tx: Ring::new(frame_allocator)?,
+        };
+        inner.setup_rx();
+        inner.setup_tx();
+
+        if read(base, Register::Status) & STATUS_LU == 0 {
+            log::warn!(
+                "{} {}: link is down",
+                NetDevice::name(self),
+                self.function.address
+            );
+        }
+
+        self.mac.call_once(|| mac);
+        *self.inner.lock() = Some(inner);
+        Ok(())
+    }
+
+    unsafe fn purge(&self) {
+        if let Some(&base) = self.base.get() {
+            reset(base).ok();
+        }
+    }
+}
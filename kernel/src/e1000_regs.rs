@@ -0,0 +1,143 @@
+use modular_bitfield::prelude::*;
+
+/// e1000 registers (offsets into BAR0)
+#[derive(Clone, Copy)]
+#[repr(usize)]
+pub enum Register {
+    Control = 0x0000,
+    Status = 0x0008,
+    EepromRead = 0x0014,
+    InterruptCause = 0x00c0,
+    InterruptMaskSet = 0x00d0,
+    InterruptMaskClear = 0x00d8,
+    RxControl = 0x0100,
+    TxControl = 0x0400,
+    TxIpg = 0x0410,
+    RxDescBaseLow = 0x2800,
+    RxDescBaseHigh = 0x2804,
+    RxDescLength = 0x2808,
+    RxDescHead = 0x2810,
+    RxDescTail = 0x2818,
+    TxDescBaseLow = 0x3800,
+    TxDescBaseHigh = 0x3804,
+    TxDescLength = 0x3808,
+    TxDescHead = 0x3810,
+    TxDescTail = 0x3818,
+    /// 128 entries of the multicast table array
+    MulticastTable = 0x5200,
+    ReceiveAddressLow = 0x5400,
+    ReceiveAddressHigh = 0x5404,
+}
+
+/// Bits of the device control register
+pub const CTRL_ASDE: u32 = 1 << 5;
+pub const CTRL_SLU: u32 = 1 << 6;
+pub const CTRL_RST: u32 = 1 << 26;
+
+/// Link is up in the device status register
+pub const STATUS_LU: u32 = 1 << 1;
+
+/// The receive address in `ReceiveAddressHigh` is valid
+pub const RAH_AV: u32 = 1 << 31;
+
+/// Bits of the EEPROM read register of the 8254x
+pub const EERD_START: u32 = 1 << 0;
+pub const EERD_DONE: u32 = 1 << 4;
+pub const EERD_ADDR_SHIFT: u32 = 8;
+
+/// The 82574 moved the done bit and the address of the EEPROM read register
+pub const EERD_DONE_82574: u32 = 1 << 1;
+pub const EERD_ADDR_SHIFT_82574: u32 = 2;
+
+/// Inter packet gap recommended for copper links
+pub const TIPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct RxControlReg {
+    pub res0: B1,
+    pub en: B1,
+    /// Store bad packets
+    pub sbp: B1,
+    /// Unicast promiscuous
+    pub upe: B1,
+    /// Multicast promiscuous
+    pub mpe: B1,
+    /// Long packets
+    pub lpe: B1,
+    pub lbm: B2,
+    /// Free descriptors left when the minimum threshold interrupt fires
+    pub rdmts: B2,
+    pub res1: B2,
+    pub mo: B2,
+    pub res2: B1,
+    /// Accept broadcast
+    pub bam: B1,
+    /// 0b00 2048, 0b01 1024, 0b10 512, 0b11 256 bytes
+    pub bsize: B2,
+    pub vfe: B1,
+    pub cfien: B1,
+    pub cfi: B1,
+    pub res3: B1,
+    pub dpf: B1,
+    pub pmcf: B1,
+    pub res4: B1,
+    /// Multiplies `bsize` by 16
+    pub bsex: B1,
+    /// Strip the ethernet CRC
+    pub secrc: B1,
+    pub res5: B5,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct TxControlReg {
+    pub res0: B1,
+    pub en: B1,
+    pub res1: B1,
+    /// Pad short packets
+    pub psp: B1,
+    /// Collision threshold
+    pub ct: B8,
+    /// Collision distance
+    pub cold: B10,
+    pub swxoff: B1,
+    pub res2: B1,
+    pub rtlc: B1,
+    pub nrtu: B1,
+    pub res3: B6,
+}
+
+/// Status bits of the descriptors
+pub const DESC_STATUS_DD: u8 = 1 << 0;
+pub const DESC_STATUS_EOP: u8 = 1 << 1;
+
+/// Command bits of transmit descriptors
+pub const TX_CMD_EOP: u8 = 1 << 0;
+pub const TX_CMD_IFCS: u8 = 1 << 1;
+pub const TX_CMD_RS: u8 = 1 << 3;
+
+/// Legacy receive descriptor
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct RxDescriptor {
+    pub addr: u64,
+    pub length: u16,
+    pub checksum: u16,
+    pub status: u8,
+    pub errors: u8,
+    pub special: u16,
+}
+
+/// Legacy transmit descriptor
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct TxDescriptor {
+    pub addr: u64,
+    pub length: u16,
+    pub cso: u8,
+    pub cmd: u8,
+    pub status: u8,
+    pub css: u8,
+    pub special: u16,
+}
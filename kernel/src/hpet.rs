@@ -0,0 +1,227 @@
+//! High precision event timer.
+//! The ACPI HPET table points to a block of memory mapped registers with
+//! a free running main counter and a number of comparators which can
+//! interrupt through the I/O apic once the counter reaches their value.
+
+use crate::acpi::Acpi;
+use crate::hpet_regs::*;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::structures::paging::page_table::PageTableFlags;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::PhysAddr;
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+static mut HPET: Option<Hpet> = None;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// The comparator does not exist
+    NoSuchTimer(u8),
+    /// The comparator only supports one shot mode
+    NoPeriodicMode(u8),
+    /// The comparator can not interrupt on this I/O apic input
+    IrqNotRoutable(u8, u8),
+}
+
+#[derive(Debug)]
+pub struct Hpet {
+    base: u64,
+    /// Main counter tick period in femtoseconds
+    period: u64,
+    timers: u8,
+    counter_64bit: bool,
+    /// Smallest period which does not lose interrupts, in ticks
+    min_tick: u16,
+}
+
+/// Map the registers of the hpet described by the ACPI tables and start
+/// its main counter. Returns None if the machine has no hpet.
+pub unsafe fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    acpi: &Acpi,
+) -> Option<&'static Hpet> {
+    if HPET.is_some() {
+        return HPET.as_ref();
+    }
+
+    let table = acpi.hpet?;
+    let base = table.base_address.address;
+    if table.base_address.address_space != 0 {
+        log::warn!("Hpet registers are not memory mapped");
+        return None;
+    }
+
+    let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(base));
+    crate::memory::id_map(
+        mapper,
+        frame_allocator,
+        frame,
+        Some(
+            PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::NO_EXECUTE
+                | PageTableFlags::HUGE_PAGE,
+        ),
+    )
+    .unwrap();
+
+    let caps = CapabilitiesReg::from_bytes(read_volatile(base as *const u64).to_le_bytes());
+    // The spec limits the period to 100 ns
+    let period = caps.counter_clk_period() as u64;
+    if period == 0 || period > 100 * FEMTOS_PER_NANO {
+        log::warn!("Hpet reports invalid period of {} fs", period);
+        return None;
+    }
+
+    let hpet = Hpet {
+        base,
+        period,
+        timers: caps.num_tim_cap() + 1,
+        counter_64bit: caps.count_size_cap() == 1,
+        min_tick: table.min_tick,
+    };
+
+    // Disable all comparators before starting the counter
+    for timer in 0..hpet.timers {
+        hpet.stop_timer(timer);
+    }
+    let config = ConfigReg::new().with_enable_cnf(1).with_leg_rt_cnf(0);
+    hpet.write(
+        Register::Config as usize,
+        u64::from_le_bytes(config.into_bytes()),
+    );
+
+    log::info!(
+        "Hpet at {:#x}: {} Hz, {} timers, {} bit counter",
+        base,
+        hpet.frequency(),
+        hpet.timers,
+        if hpet.counter_64bit { 64 } else { 32 }
+    );
+
+    HPET = Some(hpet);
+    HPET.as_ref()
+}
+
+/// The hpet if `init` found one
+pub fn get() -> Option<&'static Hpet> {
+    unsafe { HPET.as_ref() }
+}
+
+impl Hpet {
+    unsafe fn read(&self, offset: usize) -> u64 {
+        read_volatile((self.base + offset as u64) as *const u64)
+    }
+
+    unsafe fn write(&self, offset: usize, value: u64) {
+        write_volatile((self.base + offset as u64) as *mut u64, value);
+    }
+
+    /// Value of the main counter. Wraps after 32 bits on hpets
+    /// without a 64 bit counter.
+    #[inline]
+    pub fn counter(&self) -> u64 {
+        unsafe { self.read(Register::MainCounter as usize) }
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.counter_64bit
+    }
+
+    /// Tick period of the main counter in femtoseconds
+    pub fn period_fs(&self) -> u64 {
+        self.period
+    }
+
+    /// Ticks of the main counter per second
+    pub fn frequency(&self) -> u64 {
+        FEMTOS_PER_SEC / self.period
+    }
+
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period as u128 / FEMTOS_PER_NANO as u128) as u64
+    }
+
+    pub fn ns_to_ticks(&self, nanoseconds: u64) -> u64 {
+        (nanoseconds as u128 * FEMTOS_PER_NANO as u128 / self.period as u128) as u64
+    }
+
+    /// Busy sleep for a given number of microseconds
+    pub fn sleep(&self, microseconds: u64) {
+        let start = self.counter();
+        let ticks = self.ns_to_ticks(microseconds * 1000);
+        while self.counter().wrapping_sub(start) < ticks {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Number of comparators
+    pub fn num_timers(&self) -> u8 {
+        self.timers
+    }
+
+    /// Smallest period in ticks for periodic timers
+    pub fn min_tick(&self) -> u16 {
+        self.min_tick
+    }
+
+    pub fn timer_config(&self, timer: u8) -> Result<TimerConfigReg, HpetError> {
+        if timer >= self.timers {
+            return Err(HpetError::NoSuchTimer(timer));
+        }
+        let config = unsafe { self.read(timer_config(timer)) };
+        Ok(TimerConfigReg::from_bytes(config.to_le_bytes()))
+    }
+
+    /// Let comparator `timer` raise `irq` on the I/O apic after `ticks`,
+    /// and then every `ticks` if `periodic`. The I/O apic entry of
+    /// `irq` has to be programmed by the caller.
+    pub unsafe fn start_timer(
+        &self,
+        timer: u8,
+        irq: u8,
+        ticks: u64,
+        periodic: bool,
+    ) -> Result<(), HpetError> {
+        let config = self.timer_config(timer)?;
+        if periodic && config.per_int_cap() == 0 {
+            return Err(HpetError::NoPeriodicMode(timer));
+        }
+        if irq >= 32 || config.int_route_cap() & (1 << irq) == 0 {
+            return Err(HpetError::IrqNotRoutable(timer, irq));
+        }
+
+        let config = config
+            .with_int_type_cnf(0) // edge-triggered
+            .with_int_route_cnf(irq)
+            .with_fsb_en_cnf(0)
+            .with_mode32_cnf(0)
+            .with_type_cnf(periodic as u8)
+            .with_val_set_cnf(periodic as u8)
+            .with_int_enb_cnf(1);
+        self.write(timer_config(timer), u64::from_le_bytes(config.into_bytes()));
+
+        self.write(timer_comparator(timer), self.counter() + ticks);
+        if periodic {
+            // The second write after val_set_cnf sets the period
+            self.write(timer_comparator(timer), ticks);
+        }
+        Ok(())
+    }
+
+    /// Disable the interrupts of comparator `timer`
+    pub unsafe fn stop_timer(&self, timer: u8) {
+        if let Ok(config) = self.timer_config(timer) {
+            let config = config.with_int_enb_cnf(0).with_type_cnf(0);
+            self.write(timer_config(timer), u64::from_le_bytes(config.into_bytes()));
+        }
+    }
+
+    /// Acknowledge a level triggered interrupt of comparator `timer`
+    pub unsafe fn clear_interrupt(&self, timer: u8) {
+        self.write(Register::InterruptStatus as usize, 1 << timer);
+    }
+}
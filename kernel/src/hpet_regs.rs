@@ -0,0 +1,71 @@
+use modular_bitfield::prelude::*;
+
+/// HPET registers (offsets into MMIO space)
+#[derive(Clone, Copy)]
+#[repr(usize)]
+pub enum Register {
+    Capabilities = 0x0,
+    Config = 0x10,
+    InterruptStatus = 0x20,
+    MainCounter = 0xF0,
+}
+
+/// Offset of the configuration register of comparator `timer`
+pub const fn timer_config(timer: u8) -> usize {
+    0x100 + 0x20 * timer as usize
+}
+
+/// Offset of the comparator value register of comparator `timer`
+pub const fn timer_comparator(timer: u8) -> usize {
+    0x108 + 0x20 * timer as usize
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct CapabilitiesReg {
+    pub rev_id: B8,
+    /// Index of the last comparator
+    pub num_tim_cap: B5,
+    /// Main counter is 64 bits wide
+    pub count_size_cap: B1,
+    pub res0: B1,
+    pub leg_rt_cap: B1,
+    pub vendor_id: B16,
+    /// Main counter tick period in femtoseconds
+    pub counter_clk_period: B32,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigReg {
+    /// Main counter runs and timers may interrupt
+    pub enable_cnf: B1,
+    /// Legacy replacement routing of timer 0 and 1 to irq 0 and 8
+    pub leg_rt_cnf: B1,
+    pub res0: B62,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct TimerConfigReg {
+    pub res0: B1,
+    /// 0 edge, 1 level triggered
+    pub int_type_cnf: B1,
+    pub int_enb_cnf: B1,
+    /// 1 periodic mode
+    pub type_cnf: B1,
+    pub per_int_cap: B1,
+    /// Comparator is 64 bits wide
+    pub size_cap: B1,
+    /// Allows to set the accumulator of periodic timers
+    pub val_set_cnf: B1,
+    pub res1: B1,
+    pub mode32_cnf: B1,
+    /// I/O apic input the timer interrupts
+    pub int_route_cnf: B5,
+    pub fsb_en_cnf: B1,
+    pub fsb_int_del_cap: B1,
+    pub res2: B16,
+    /// Bitmap of the allowed I/O apic inputs
+    pub int_route_cap: B32,
+}
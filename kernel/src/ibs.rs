@@ -0,0 +1,472 @@
+use crate::apic_regs::ExtLvtReg;
+use crate::ibs_regs::*;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+
+/// Number of samples a core buffer can hold if not configured otherwise
+pub const DEFAULT_CAPACITY: usize = 2048;
+
+/// Cache line size used to group data addresses in the report
+const CACHE_LINE: u64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IbsError {
+    /// Cpuid does not report IBS or the requested sampling mode
+    Unsupported,
+    /// The firmware did not assign an extended LVT entry to IBS
+    NoLvtOffset,
+    /// The local apic does not implement the LVT entry from IBS_CTL
+    InvalidLvtOffset(u8),
+    /// Sampling period out of the range the hardware can count
+    InvalidPeriod(u32),
+    /// Sampling is already running on the current core
+    AlreadyRunning,
+    /// Sampling has not been started on the current core
+    NotRunning,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IbsConfig {
+    /// Fetches between two fetch samples, none disables fetch sampling
+    pub fetch_period: Option<u32>,
+    /// Cycles or dispatched ops between two op samples, none disables op sampling
+    pub op_period: Option<u32>,
+    /// Count dispatched ops instead of cycles for the op period
+    pub count_ops: bool,
+    /// Number of samples preallocated per core. Samples taken once the buffer
+    /// is full are dropped.
+    pub capacity: usize,
+}
+
+impl Default for IbsConfig {
+    fn default() -> Self {
+        IbsConfig {
+            fetch_period: None,
+            op_period: Some(0x1_0000),
+            count_ops: false,
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IbsKind {
+    Fetch,
+    Op,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IbsSample {
+    pub kind: IbsKind,
+    pub tsc: u64,
+    /// Instruction the sample has been taken on
+    pub rip: u64,
+    /// Linear address of the accessed data, only set for op samples of loads and stores
+    pub lin_addr: Option<u64>,
+    /// Physical address of the fetched instruction or the accessed data
+    pub phys_addr: Option<u64>,
+    /// Fetch latency or data cache miss latency in cycles
+    pub latency: u16,
+    /// Missed the L1 instruction or data cache
+    pub l1_miss: bool,
+    pub l2_miss: bool,
+    /// Missed the L1 or L2 TLB
+    pub tlb_miss: bool,
+    pub load: bool,
+    pub store: bool,
+    /// Northbridge data source of a load which missed the L2
+    pub data_src: u8,
+}
+
+struct CoreBuffer {
+    samples: Vec<IbsSample>,
+    dropped: u64,
+    config: IbsConfig,
+}
+
+static mut FEATURES: Option<IbsFeatures> = None;
+
+const NO_BUFFER: Option<CoreBuffer> = None;
+
+/// Sample buffers per core, indexed by core index.
+/// Allocated in `start` so that the NMI handler never allocates.
+static mut BUFFERS: [Option<CoreBuffer>; bootloader::MAX_CORES] =
+    [NO_BUFFER; bootloader::MAX_CORES];
+
+/// Check for IBS support through cpuid
+pub unsafe fn init() {
+    if FEATURES.is_none() {
+        FEATURES = detect();
+
+        match FEATURES {
+            Some(features) => log::info!(
+                "IBS: fetch sampling {} op sampling {} op counting {}",
+                features.fetch_sam() == 1,
+                features.op_sam() == 1,
+                features.op_cnt() == 1
+            ),
+            None => log::info!("IBS is not supported"),
+        }
+    }
+}
+
+pub fn features() -> Option<IbsFeatures> {
+    unsafe { FEATURES }
+}
+
+fn detect() -> Option<IbsFeatures> {
+    let cpuid = CpuId::new();
+    let ext = cpuid.get_extended_processor_and_feature_identifiers()?;
+    let max_leaf = raw_cpuid::cpuid!(0x8000_0000).eax;
+    if !ext.has_ibs() || max_leaf < IBS_CPUID_LEAF {
+        return None;
+    }
+
+    let res = raw_cpuid::cpuid!(IBS_CPUID_LEAF);
+    let features = IbsFeatures::from_bytes(res.eax.to_le_bytes());
+    if features.ibsffv() == 0 {
+        return None;
+    }
+    Some(features)
+}
+
+#[inline]
+unsafe fn read_msr(msr: IbsMsr) -> u64 {
+    Msr::new(msr as u32).read()
+}
+
+#[inline]
+unsafe fn write_msr(msr: IbsMsr, val: u64) {
+    Msr::new(msr as u32).write(val)
+}
+
+fn fetch_ctl(config: &IbsConfig) -> Option<IbsFetchCtl> {
+    config.fetch_period.map(|period| {
+        IbsFetchCtl::new()
+            .with_max_cnt((period >> 4) as u16)
+            .with_rand_en(1)
+            .with_en(1)
+    })
+}
+
+fn op_ctl(config: &IbsConfig) -> Option<IbsOpCtl> {
+    config.op_period.map(|period| {
+        IbsOpCtl::new()
+            .with_max_cnt((period >> 4) as u16)
+            .with_max_cnt_ext(((period >> 20) & 0x7f) as u8)
+            .with_cnt_ctl(config.count_ops as u8)
+            .with_en(1)
+    })
+}
+
+fn check_config(features: &IbsFeatures, config: &IbsConfig) -> Result<(), IbsError> {
+    if let Some(period) = config.fetch_period {
+        if features.fetch_sam() == 0 {
+            return Err(IbsError::Unsupported);
+        }
+        if !(0x10..=0xf_fff0).contains(&period) {
+            return Err(IbsError::InvalidPeriod(period));
+        }
+    }
+
+    if let Some(period) = config.op_period {
+        if features.op_sam() == 0 || (config.count_ops && features.op_cnt() == 0) {
+            return Err(IbsError::Unsupported);
+        }
+        let max = if features.op_cnt_ext() == 1 {
+            0x7ff_fff0
+        } else {
+            0xf_fff0
+        };
+        // Periods below 0x90 are not supported by the hardware
+        if !(0x90..=max).contains(&period) {
+            return Err(IbsError::InvalidPeriod(period));
+        }
+    }
+    Ok(())
+}
+
+/// Start sampling on the current core. The samples are delivered as NMI
+/// through the extended LVT entry the firmware assigned to IBS.
+pub fn start(config: &IbsConfig) -> Result<(), IbsError> {
+    let features = features().ok_or(IbsError::Unsupported)?;
+    check_config(&features, config)?;
+
+    let core = crate::smp::core_index();
+    unsafe {
+        if BUFFERS[core].is_some() {
+            return Err(IbsError::AlreadyRunning);
+        }
+
+        let ibs_ctl = IbsCtl::from_bytes(read_msr(IbsMsr::Ctl).to_le_bytes());
+        if ibs_ctl.lvt_offset_val() == 0 {
+            return Err(IbsError::NoLvtOffset);
+        }
+
+        let lvt = ExtLvtReg::new().with_msg_type(0b100); // NMI
+        if !crate::apic::set_ext_lvt(ibs_ctl.lvt_offset(), lvt) {
+            return Err(IbsError::InvalidLvtOffset(ibs_ctl.lvt_offset()));
+        }
+
+        // Install the buffer before the first sample can arrive
+        BUFFERS[core] = Some(CoreBuffer {
+            samples: Vec::with_capacity(config.capacity),
+            dropped: 0,
+            config: *config,
+        });
+
+        if let Some(ctl) = fetch_ctl(config) {
+            write_msr(IbsMsr::FetchCtl, u64::from_le_bytes(ctl.into_bytes()));
+        }
+        if let Some(ctl) = op_ctl(config) {
+            write_msr(IbsMsr::OpCtl, u64::from_le_bytes(ctl.into_bytes()));
+        }
+    }
+    Ok(())
+}
+
+/// Stop sampling on the current core and return the collected samples
+pub fn stop() -> Result<IbsSamples, IbsError> {
+    let core = crate::smp::core_index();
+    unsafe {
+        if BUFFERS[core].is_none() {
+            return Err(IbsError::NotRunning);
+        }
+
+        // Clears the enable and valid bits. The write is serializing so
+        // a pending sample NMI is taken before the buffer is removed.
+        write_msr(IbsMsr::FetchCtl, 0);
+        write_msr(IbsMsr::OpCtl, 0);
+
+        let ibs_ctl = IbsCtl::from_bytes(read_msr(IbsMsr::Ctl).to_le_bytes());
+        let lvt = ExtLvtReg::new().with_msg_type(0b100).with_mask(1);
+        crate::apic::set_ext_lvt(ibs_ctl.lvt_offset(), lvt);
+
+        let buffer = BUFFERS[core].take().unwrap();
+        Ok(IbsSamples {
+            samples: buffer.samples,
+            dropped: buffer.dropped,
+        })
+    }
+}
+
+/// Called by the NMI handler. Records pending samples of the current core
+/// and re-arms the sampling. Returns false if the NMI was not caused by IBS.
+pub fn handle_nmi() -> bool {
+    let features = match features() {
+        Some(features) => features,
+        None => return false,
+    };
+
+    let mut handled = false;
+    unsafe {
+        let buffer = &mut BUFFERS[crate::smp::core_index()];
+        let tsc = crate::time::rdtsc();
+
+        if features.fetch_sam() == 1 {
+            let ctl = IbsFetchCtl::from_bytes(read_msr(IbsMsr::FetchCtl).to_le_bytes());
+            if ctl.val() == 1 {
+                handled = true;
+                match buffer {
+                    Some(buffer) => {
+                        let sample = read_fetch_sample(&ctl, tsc);
+                        record(buffer, sample);
+                        // Re-arm also clears the valid bit and the current count
+                        let ctl = fetch_ctl(&buffer.config).unwrap_or_else(IbsFetchCtl::new);
+                        write_msr(IbsMsr::FetchCtl, u64::from_le_bytes(ctl.into_bytes()));
+                    }
+                    None => write_msr(IbsMsr::FetchCtl, 0),
+                }
+            }
+        }
+
+        if features.op_sam() == 1 {
+            let ctl = IbsOpCtl::from_bytes(read_msr(IbsMsr::OpCtl).to_le_bytes());
+            if ctl.val() == 1 {
+                handled = true;
+                match buffer {
+                    Some(buffer) => {
+                        if let Some(sample) = read_op_sample(tsc) {
+                            record(buffer, sample);
+                        }
+                        let ctl = op_ctl(&buffer.config).unwrap_or_else(IbsOpCtl::new);
+                        write_msr(IbsMsr::OpCtl, u64::from_le_bytes(ctl.into_bytes()));
+                    }
+                    None => write_msr(IbsMsr::OpCtl, 0),
+                }
+            }
+        }
+    }
+    handled
+}
+
+#[inline]
+fn record(buffer: &mut CoreBuffer, sample: IbsSample) {
+    // Never grow the buffer inside of the NMI handler
+    if buffer.samples.len() < buffer.samples.capacity() {
+        buffer.samples.push(sample);
+    } else {
+        buffer.dropped += 1;
+    }
+}
+
+unsafe fn read_fetch_sample(ctl: &IbsFetchCtl, tsc: u64) -> IbsSample {
+    let phys_addr = if ctl.phy_addr_valid() == 1 {
+        Some(read_msr(IbsMsr::FetchPhysAddr))
+    } else {
+        None
+    };
+
+    IbsSample {
+        kind: IbsKind::Fetch,
+        tsc,
+        rip: read_msr(IbsMsr::FetchLinAddr),
+        lin_addr: None,
+        phys_addr,
+        latency: ctl.lat(),
+        l1_miss: ctl.ic_miss() == 1,
+        l2_miss: ctl.l2_miss() == 1,
+        tlb_miss: ctl.l1_tlb_miss() == 1 || ctl.l2_tlb_miss() == 1,
+        load: false,
+        store: false,
+        data_src: 0,
+    }
+}
+
+unsafe fn read_op_sample(tsc: u64) -> Option<IbsSample> {
+    let data = IbsOpData::from_bytes(read_msr(IbsMsr::OpData).to_le_bytes());
+    if data.rip_invalid() == 1 {
+        return None;
+    }
+
+    let data3 = IbsOpData3::from_bytes(read_msr(IbsMsr::OpData3).to_le_bytes());
+    let mem_op = data3.ld_op() == 1 || data3.st_op() == 1;
+
+    let lin_addr = if mem_op && data3.dc_lin_addr_valid() == 1 {
+        Some(read_msr(IbsMsr::DcLinAddr))
+    } else {
+        None
+    };
+    let phys_addr = if mem_op && data3.dc_phy_addr_valid() == 1 {
+        Some(read_msr(IbsMsr::DcPhysAddr))
+    } else {
+        None
+    };
+    let data_src = if data3.ld_op() == 1 && data3.l2_miss() == 1 {
+        IbsOpData2::from_bytes(read_msr(IbsMsr::OpData2).to_le_bytes()).data_src()
+    } else {
+        0
+    };
+
+    Some(IbsSample {
+        kind: IbsKind::Op,
+        tsc,
+        rip: read_msr(IbsMsr::OpRip),
+        lin_addr,
+        phys_addr,
+        latency: data3.dc_miss_lat(),
+        l1_miss: data3.dc_miss() == 1,
+        l2_miss: data3.l2_miss() == 1,
+        tlb_miss: data3.dc_l1_tlb_miss() == 1 || data3.dc_l2_tlb_miss() == 1,
+        load: data3.ld_op() == 1,
+        store: data3.st_op() == 1,
+        data_src,
+    })
+}
+
+/// Aggregated samples of a single instruction or cache line
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Hotspot {
+    pub addr: u64,
+    pub samples: usize,
+    pub l1_misses: usize,
+    pub l2_misses: usize,
+    pub tlb_misses: usize,
+    pub total_latency: u64,
+}
+
+impl Hotspot {
+    fn add(&mut self, sample: &IbsSample) {
+        self.samples += 1;
+        self.l1_misses += sample.l1_miss as usize;
+        self.l2_misses += sample.l2_miss as usize;
+        self.tlb_misses += sample.tlb_miss as usize;
+        self.total_latency += sample.latency as u64;
+    }
+}
+
+/// Samples collected on one core between `start` and `stop`
+pub struct IbsSamples {
+    pub samples: Vec<IbsSample>,
+    /// Samples lost because the buffer was full
+    pub dropped: u64,
+}
+
+impl IbsSamples {
+    fn top<F>(&self, n: usize, key: F) -> Vec<Hotspot>
+    where
+        F: Fn(&IbsSample) -> Option<u64>,
+    {
+        let mut spots: BTreeMap<u64, Hotspot> = BTreeMap::new();
+        for sample in self.samples.iter() {
+            if let Some(addr) = key(sample) {
+                spots
+                    .entry(addr)
+                    .or_insert(Hotspot {
+                        addr,
+                        ..Default::default()
+                    })
+                    .add(sample);
+            }
+        }
+
+        let mut spots: Vec<Hotspot> = spots.into_values().collect();
+        spots.sort_unstable_by(|a, b| {
+            (b.l1_misses, b.total_latency).cmp(&(a.l1_misses, a.total_latency))
+        });
+        spots.truncate(n);
+        spots
+    }
+
+    /// Instructions with the most cache misses
+    pub fn top_instructions(&self, n: usize) -> Vec<Hotspot> {
+        self.top(n, |s| Some(s.rip))
+    }
+
+    /// Cache lines with the most data cache misses
+    pub fn top_data(&self, n: usize) -> Vec<Hotspot> {
+        self.top(n, |s| s.lin_addr.map(|addr| addr & !(CACHE_LINE - 1)))
+    }
+
+    pub fn print_report(&self, n: usize) {
+        log::info!(
+            "IBS: {} samples, {} dropped",
+            self.samples.len(),
+            self.dropped
+        );
+
+        log::info!("Top instructions by cache misses:");
+        for spot in self.top_instructions(n) {
+            print_hotspot(&spot);
+        }
+
+        log::info!("Top data cache lines by cache misses:");
+        for spot in self.top_data(n) {
+            print_hotspot(&spot);
+        }
+    }
+}
+
+fn print_hotspot(spot: &Hotspot) {
+    log::info!(
+        "{:#018x}: samples {} l1 miss {} l2 miss {} tlb miss {} avg latency {}",
+        spot.addr,
+        spot.samples,
+        spot.l1_misses,
+        spot.l2_misses,
+        spot.tlb_misses,
+        spot.total_latency / spot.samples as u64
+    );
+}
@@ -0,0 +1,150 @@
+use modular_bitfield::prelude::*;
+
+/// Cpuid leaf with the IBS feature flags
+pub const IBS_CPUID_LEAF: u32 = 0x8000_001B;
+
+/// IBS MSRs (AMD Family 17h PPR, "IBS Registers")
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum IbsMsr {
+    FetchCtl = 0xC001_1030,
+    FetchLinAddr = 0xC001_1031,
+    FetchPhysAddr = 0xC001_1032,
+    OpCtl = 0xC001_1033,
+    OpRip = 0xC001_1034,
+    OpData = 0xC001_1035,
+    OpData2 = 0xC001_1036,
+    OpData3 = 0xC001_1037,
+    DcLinAddr = 0xC001_1038,
+    DcPhysAddr = 0xC001_1039,
+    Ctl = 0xC001_103A,
+}
+
+/// Cpuid Fn8000_001B_EAX
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsFeatures {
+    /// IBS feature flags valid
+    pub ibsffv: B1,
+    pub fetch_sam: B1,
+    pub op_sam: B1,
+    pub rd_wr_op_cnt: B1,
+    /// Op counting mode (dispatched ops instead of cycles) supported
+    pub op_cnt: B1,
+    pub brn_trgt: B1,
+    /// IbsOpMaxCnt has 7 extra bits
+    pub op_cnt_ext: B1,
+    pub rip_invalid_chk: B1,
+    pub op_brn_fuse: B1,
+    pub fetch_ctl_extd: B1,
+    pub res0: B22,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsFetchCtl {
+    /// Fetch sample period divided by 16
+    pub max_cnt: B16,
+    pub cnt: B16,
+    /// Cycles from the fetch request to the data delivery
+    pub lat: B16,
+    pub en: B1,
+    /// A sample has been taken
+    pub val: B1,
+    pub comp: B1,
+    pub ic_miss: B1,
+    pub phy_addr_valid: B1,
+    pub l1_tlb_pg_sz: B2,
+    pub l1_tlb_miss: B1,
+    pub l2_tlb_miss: B1,
+    /// Randomize the lower 4 bits of the period
+    pub rand_en: B1,
+    pub l2_miss: B1,
+    pub res0: B5,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsOpCtl {
+    /// Bits [19:4] of the op sample period
+    pub max_cnt: B16,
+    pub res0: B1,
+    pub en: B1,
+    /// A sample has been taken
+    pub val: B1,
+    /// Count dispatched ops instead of cycles
+    pub cnt_ctl: B1,
+    /// Bits [26:20] of the op sample period
+    pub max_cnt_ext: B7,
+    pub res1: B5,
+    pub cur_cnt: B27,
+    pub res2: B5,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsOpData {
+    /// Cycles from the completion of the op to its retirement
+    pub comp_to_ret_ctr: B16,
+    /// Cycles from the tagging of the op to its retirement
+    pub tag_to_ret_ctr: B16,
+    pub res0: B2,
+    pub op_return: B1,
+    pub op_brn_taken: B1,
+    pub op_brn_misp: B1,
+    pub op_brn_ret: B1,
+    pub rip_invalid: B1,
+    pub op_brn_fuse: B1,
+    pub op_microcode: B1,
+    pub res1: B23,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsOpData2 {
+    /// Northbridge data source of a load that missed the L2
+    pub data_src: B3,
+    pub res0: B1,
+    pub rmt_node: B1,
+    pub cache_hit_st: B1,
+    pub res1: B58,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsOpData3 {
+    pub ld_op: B1,
+    pub st_op: B1,
+    pub dc_l1_tlb_miss: B1,
+    pub dc_l2_tlb_miss: B1,
+    pub dc_l1_tlb_hit_2m: B1,
+    pub dc_l1_tlb_hit_1g: B1,
+    pub dc_l2_tlb_hit_2m: B1,
+    pub dc_miss: B1,
+    pub dc_mis_acc: B1,
+    pub res0: B4,
+    pub dc_wc_mem_acc: B1,
+    pub dc_uc_mem_acc: B1,
+    pub dc_locked_op: B1,
+    pub dc_miss_no_mab_alloc: B1,
+    pub dc_lin_addr_valid: B1,
+    pub dc_phy_addr_valid: B1,
+    pub dc_l2_tlb_hit_1g: B1,
+    pub l2_miss: B1,
+    pub sw_pf: B1,
+    pub op_mem_width: B4,
+    pub op_dc_miss_open_mem_reqs: B6,
+    /// Cycles from the data cache miss to the data delivery
+    pub dc_miss_lat: B16,
+    pub tlb_refill_lat: B16,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IbsCtl {
+    /// Extended LVT entry the IBS interrupt is delivered through
+    pub lvt_offset: B4,
+    pub res0: B4,
+    pub lvt_offset_val: B1,
+    pub res1: B55,
+}
@@ -1,7 +1,10 @@
 use crate::apic;
+use crate::lock::Mutex;
 use crate::print;
+use crate::trace::{self, TraceEvent};
 use crate::tss;
 
+use core::sync::atomic::{AtomicUsize, Ordering};
 use pic8259_simple::ChainedPics;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
@@ -38,6 +41,8 @@ pub enum InterruptIndex {
     IRQ16,
     SlavePicSpurious,
     Timer = 0xe0,
+    Wakeup = 0xf0,
+    PingPong = 0xf1,
     Spurious = 0xff,
 }
 
@@ -49,6 +54,11 @@ impl InterruptIndex {
     pub fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+    /// ISA irq which the PICs deliver on this vector
+    pub fn as_isa_irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+
     //IMPORTAT: Fix to be migrated
     pub fn as_pic_enable_mask(self) -> u8 {
         let mut diff = self.as_usize() - InterruptIndex::LegacyTimer.as_usize();
@@ -59,11 +69,82 @@ impl InterruptIndex {
         mask as u8
     }
 }
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: Mutex<ChainedPics> = Mutex::new("pics", unsafe {
+    ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
+});
 
 static mut IDT: Option<InterruptDescriptorTable> = None;
 
+/// Vectors handed out by `allocate_vector`, between the legacy vectors and
+/// the apic timer. Starts above `SlavePicSpurious`, the vectors below have
+/// dedicated handlers which never dispatch.
+pub const DYNAMIC_VECTORS: core::ops::Range<usize> = 0x40..0xe0;
+
+/// Handler of an allocated vector, gets the vector and the argument it was
+/// allocated with. Runs in interrupt context.
+pub type Handler = fn(vector: u8, arg: usize);
+
+/// Marks a vector which is allocated but has no handler yet
+const RESERVED: usize = 1;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Handlers of the allocated vectors as function pointers, 0 if free
+static HANDLERS: [AtomicUsize; 256] = [NO_HANDLER; 256];
+static HANDLER_ARGS: [AtomicUsize; 256] = [NO_HANDLER; 256];
+
+/// Allocate a free vector which calls `handler` with `arg` on every core it
+/// is delivered to. The interrupt is acknowledged at the local apic after
+/// the handler returns. Returns None if all vectors are in use.
+pub fn allocate_vector(handler: Handler, arg: usize) -> Option<u8> {
+    let vector = DYNAMIC_VECTORS.clone().find(|&vector| {
+        HANDLERS[vector]
+            .compare_exchange(0, RESERVED, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })?;
+    HANDLER_ARGS[vector].store(arg, Ordering::Relaxed);
+    HANDLERS[vector].store(handler as usize, Ordering::Release);
+    Some(vector as u8)
+}
+
+/// Return a vector of `allocate_vector`. The device must not raise it anymore.
+pub fn free_vector(vector: u8) {
+    let vector = vector as usize;
+    assert!(
+        DYNAMIC_VECTORS.contains(&vector),
+        "Vector {} is not dynamic",
+        vector
+    );
+    HANDLERS[vector].store(0, Ordering::Release);
+}
+
+/// Run the handler of an allocated vector, returns false if there is none
+fn dispatch(vector: usize) -> bool {
+    let handler = HANDLERS[vector].load(Ordering::Acquire);
+    if handler <= RESERVED {
+        return false;
+    }
+    let handler: Handler = unsafe { core::mem::transmute(handler) };
+
+    trace::event(TraceEvent::InterruptEnter, vector as u64, 0);
+    handler(vector as u8, HANDLER_ARGS[vector].load(Ordering::Relaxed));
+    unsafe {
+        apic::end_of_interrupt();
+    }
+    trace::event(TraceEvent::InterruptExit, vector as u64, 0);
+    true
+}
+
+/// Acknowledge an ISA interrupt to whichever controller delivered it
+unsafe fn end_of_legacy_interrupt(index: InterruptIndex) {
+    if crate::ioapic::is_active() {
+        apic::end_of_interrupt();
+    } else {
+        PICS.lock().notify_end_of_interrupt(index.as_u8());
+    }
+}
+
 pub unsafe fn init() {
     if IDT.is_none() {
         let stacks = tss::TSS_STACK_ITER.as_mut().unwrap();
@@ -120,6 +201,8 @@ pub unsafe fn init() {
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
         idt[InterruptIndex::COM2.as_usize()].set_handler_fn(serial_handler);
         idt[InterruptIndex::COM1.as_usize()].set_handler_fn(serial_handler);
+        idt[InterruptIndex::Wakeup.as_usize()].set_handler_fn(wakeup_handler);
+        idt[InterruptIndex::PingPong.as_usize()].set_handler_fn(pingpong_handler);
         idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_handler);
         idt[InterruptIndex::SlavePicSpurious.as_usize()].set_handler_fn(spurious_handler);
         idt[InterruptIndex::MasterPicSpurious.as_usize()].set_handler_fn(spurious_handler);
@@ -139,10 +222,15 @@ extern "x86-interrupt" fn page_fault_handler(
     use x86_64::registers::control::Cr2;
 
     let addr = Cr2::read();
+    trace::event(TraceEvent::PageFault, addr.as_u64(), error_code.bits());
     log::error!("EXCEPTION: PAGE FAULT");
     log::error!("Accessed Address: {:?}", addr);
     log::error!("Error Code: {:?}", error_code);
     log::error!("{:#?}", stack_frame);
+    crate::backtrace::print_from(
+        stack_frame.instruction_pointer.as_u64(),
+        crate::backtrace::interrupted_rbp(),
+    );
     // unsafe {
     //     use x86_64::addr::VirtAddr;
     //     use crate::memory::*;
@@ -156,6 +244,11 @@ extern "x86-interrupt" fn page_fault_handler(
 }
 
 pub extern "x86-interrupt" fn default_handler<const N: usize>(stack_frame: InterruptStackFrame) {
+    // Vectors of `allocate_vector` have no handler of their own
+    if dispatch(N) {
+        return;
+    }
+
     log::error!("EXECPTION: Default Interrupt Handler");
     log::error!("This interrupt has not been initialized: {}", N);
 
@@ -169,6 +262,10 @@ extern "x86-interrupt" fn general_prot_handler(stack_frame: InterruptStackFrame,
     log::error!("EXCEPTION: General Protection Exception");
     log::error!("Error Code: {:?}", error_code);
     log::error!("{:#?}", stack_frame);
+    crate::backtrace::print_from(
+        stack_frame.instruction_pointer.as_u64(),
+        crate::backtrace::interrupted_rbp(),
+    );
     let rsp: u64;
     unsafe { asm!("mov {}, rsp", out(reg) rsp) };
     log::info!("rsp: {:#x}", rsp);
@@ -187,6 +284,8 @@ extern "x86-interrupt" fn alignment_handler(stack_frame: InterruptStackFrame, er
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use x86_64::instructions::port::Port;
+    let vector = InterruptIndex::Keyboard.as_u8() as u64;
+    trace::event(TraceEvent::InterruptEnter, vector, 0);
 
     // Lock keyboard parser
     let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
@@ -207,9 +306,9 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 
     // Renable interrupts again
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        end_of_legacy_interrupt(InterruptIndex::Keyboard);
     }
+    trace::event(TraceEvent::InterruptExit, vector, 0);
 }
 
 // Breakpoint handler
@@ -231,6 +330,9 @@ extern "x86-interrupt" fn invalid_op_handler(stack_frame: InterruptStackFrame) {
 
 // Serial handler
 extern "x86-interrupt" fn serial_handler(_stack_frame: InterruptStackFrame) {
+    let vector = InterruptIndex::COM2.as_u8() as u64;
+    trace::event(TraceEvent::InterruptEnter, vector, 0);
+
     // Disable interrupts because we lock the SERIAL_WRITER here
     x86_64::instructions::interrupts::without_interrupts(|| unsafe {
         let data = [crate::serial::SERIAL_WRITER.as_ref().unwrap().lock().read(); 1];
@@ -239,22 +341,57 @@ extern "x86-interrupt" fn serial_handler(_stack_frame: InterruptStackFrame) {
 
     // Renable interrupts again
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::COM2.as_u8());
+        end_of_legacy_interrupt(InterruptIndex::COM2);
     }
+    trace::event(TraceEvent::InterruptExit, vector, 0);
 }
 
 // timer interrupt handler
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let rbp = crate::backtrace::interrupted_rbp();
+    let vector = InterruptIndex::Timer.as_u8() as u64;
+    trace::event(TraceEvent::InterruptEnter, vector, 0);
+
+    if !crate::profiler::sample_timer(&stack_frame, rbp) && !crate::timer::is_tickless() {
+        print!(".");
+    }
+    crate::timer::interrupt();
 
     // Renable interrupts again
     unsafe {
         apic::end_of_interrupt();
     }
+    trace::event(TraceEvent::InterruptExit, vector, 0);
+}
+
+// Wakes up idle cores to process their mailbox, see `smp::run_on`
+extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame) {
+    let vector = InterruptIndex::Wakeup.as_u8() as u64;
+    trace::event(TraceEvent::InterruptEnter, vector, 0);
+    trace::event(TraceEvent::IpiReceive, vector, 0);
+
+    unsafe {
+        apic::end_of_interrupt();
+    }
+    trace::event(TraceEvent::InterruptExit, vector, 0);
+}
+
+extern "x86-interrupt" fn pingpong_handler(_stack_frame: InterruptStackFrame) {
+    let vector = InterruptIndex::PingPong.as_u8() as u64;
+    trace::event(TraceEvent::InterruptEnter, vector, 0);
+    trace::event(TraceEvent::IpiReceive, vector, 0);
+
+    crate::bench::pingpong::handle_ipi();
+
+    unsafe {
+        apic::end_of_interrupt();
+    }
+    trace::event(TraceEvent::InterruptExit, vector, 0);
 }
 
 extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
+    let vector = InterruptIndex::Spurious.as_u8() as u64;
+    trace::event(TraceEvent::InterruptEnter, vector, 0);
     log::info!("SPURIOUS HANDLER");
 
     // Check if this is a pic8259_simple spurious interrupt or a legitimate interrupt
@@ -266,6 +403,7 @@ extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
             panic!("Not a spurious interrupt, that's bad :o");
         }
     }
+    trace::event(TraceEvent::InterruptExit, vector, 0);
 }
 
 /*
@@ -284,6 +422,16 @@ extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame)
 }
 
 extern "x86-interrupt" fn non_maskable_handler(stack_frame: InterruptStackFrame) {
+    let rbp = crate::backtrace::interrupted_rbp();
+    trace::event(TraceEvent::InterruptEnter, 2, 0);
+
+    // Instruction based sampling and the profiler deliver their samples as NMI
+    let ibs = crate::ibs::handle_nmi();
+    if crate::profiler::handle_nmi(&stack_frame, rbp) || ibs {
+        trace::event(TraceEvent::InterruptExit, 2, 0);
+        return;
+    }
+
     log::info!("non maskable interrupt exception");
     panic!("{:?}", stack_frame);
 }
@@ -0,0 +1,256 @@
+//! I/O apic driver.
+//! Maps every I/O apic listed in the MADT and routes global system
+//! interrupts (GSIs) to vectors on chosen cores. ISA irqs are translated
+//! to GSIs with the interrupt source overrides of the MADT, which also
+//! define their polarity and trigger mode.
+
+use crate::acpi::Acpi;
+use crate::ioapic_regs::*;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::ptr::{addr_of, read_unaligned, read_volatile, write_volatile};
+use x86_64::structures::paging::page_table::PageTableFlags;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::PhysAddr;
+
+static mut IOAPICS: Option<Vec<IoApic>> = None;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicError {
+    /// `init` did not find an I/O apic
+    NotInitialized,
+    /// No I/O apic handles the GSI
+    NoSuchGsi(u32),
+    /// Physical destinations are 8 bit without interrupt remapping
+    DestinationTooLarge(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+#[derive(Debug)]
+pub struct IoApic {
+    id: u8,
+    base: u64,
+    /// First GSI handled by this I/O apic
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, index: u32) -> u32 {
+        write_volatile((self.base + Register::IoRegSel as u64) as *mut u32, index);
+        read_volatile((self.base + Register::IoWin as u64) as *const u32)
+    }
+
+    unsafe fn write(&self, index: u32, value: u32) {
+        write_volatile((self.base + Register::IoRegSel as u64) as *mut u32, index);
+        write_volatile((self.base + Register::IoWin as u64) as *mut u32, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    unsafe fn read_entry(&self, gsi: u32) -> RedirectionEntry {
+        let index = Index::RedirectionTable as u32 + 2 * (gsi - self.gsi_base);
+        let value = self.read(index) as u64 | (self.read(index + 1) as u64) << 32;
+        RedirectionEntry::from_bytes(value.to_le_bytes())
+    }
+
+    unsafe fn write_entry(&self, gsi: u32, entry: RedirectionEntry) {
+        let index = Index::RedirectionTable as u32 + 2 * (gsi - self.gsi_base);
+        let value = u64::from_le_bytes(entry.into_bytes());
+        // Write the half with the mask bit last
+        self.write(index + 1, (value >> 32) as u32);
+        self.write(index, value as u32);
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// GSIs handled by this I/O apic
+    pub fn gsis(&self) -> core::ops::Range<u32> {
+        self.gsi_base..self.gsi_base + self.entries
+    }
+}
+
+/// Map all I/O apics of the MADT, mask all of their entries and mask the
+/// legacy PICs. Returns false if there is no I/O apic.
+pub unsafe fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    acpi: &Acpi,
+) -> bool {
+    if IOAPICS.is_some() {
+        return true;
+    }
+    let entries = match acpi.ioapics.as_ref() {
+        Some(entries) => entries,
+        None => return false,
+    };
+
+    let mut ioapics = Vec::new();
+    for entry in entries.iter() {
+        let base = read_unaligned(addr_of!(entry.address)) as u64;
+        let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(base));
+        crate::memory::id_map(
+            mapper,
+            frame_allocator,
+            frame,
+            Some(
+                PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_CACHE
+                    | PageTableFlags::NO_EXECUTE
+                    | PageTableFlags::HUGE_PAGE,
+            ),
+        )
+        .unwrap();
+
+        let mut ioapic = IoApic {
+            id: entry.id,
+            base,
+            gsi_base: read_unaligned(addr_of!(entry.interrupt_base)),
+            entries: 0,
+        };
+        let version = IoApicVersion::from_bytes(ioapic.read(Index::Version as u32).to_le_bytes());
+        ioapic.entries = version.max_redirection_entry() as u32 + 1;
+
+        for gsi in ioapic.gsis() {
+            let entry = ioapic.read_entry(gsi).with_mask(1);
+            ioapic.write_entry(gsi, entry);
+        }
+        log::info!(
+            "I/O apic {} at {:#x} handles GSI {:?}",
+            ioapic.id,
+            base,
+            ioapic.gsis()
+        );
+        ioapics.push(ioapic);
+    }
+    IOAPICS = Some(ioapics);
+
+    mask_pics(acpi);
+    true
+}
+
+/// Stop the legacy PICs from delivering interrupts
+unsafe fn mask_pics(acpi: &Acpi) {
+    use x86_64::instructions::port::Port;
+
+    // Remap the PICs first so spurious interrupts don't hit exception vectors
+    let mut pics = crate::interrupts::PICS.lock();
+    pics.initialize();
+    pics.mask_all();
+
+    // Systems in PIC mode have to be switched over through the IMCR
+    if acpi.mask_pics {
+        let mut imcr_low: Port<u8> = Port::new(0x22);
+        let mut imcr_high: Port<u8> = Port::new(0x23);
+        imcr_low.write(0x70); // Select imcr register
+        imcr_high.write(0x01); // go through apic
+    }
+}
+
+/// Whether interrupts are routed through the I/O apics instead of the PICs
+pub fn is_active() -> bool {
+    unsafe { IOAPICS.is_some() }
+}
+
+fn ioapic_for(gsi: u32) -> Result<&'static IoApic, IoApicError> {
+    let ioapics = unsafe { IOAPICS.as_ref() }.ok_or(IoApicError::NotInitialized)?;
+    ioapics
+        .iter()
+        .find(|ioapic| ioapic.handles(gsi))
+        .ok_or(IoApicError::NoSuchGsi(gsi))
+}
+
+/// GSI, polarity and trigger mode of the ISA irq `irq`
+pub fn isa_to_gsi(irq: u8) -> (u32, Polarity, Trigger) {
+    let acpi = unsafe { crate::acpi::init() };
+    let int_override = acpi
+        .int_overrides
+        .iter()
+        .flatten()
+        .find(|int_override| int_override.source == irq);
+
+    // ISA interrupts are active high and edge triggered unless overridden
+    let int_override = match int_override {
+        Some(int_override) => int_override,
+        None => return (irq as u32, Polarity::ActiveHigh, Trigger::Edge),
+    };
+    let (gsi, flags) = unsafe {
+        (
+            read_unaligned(addr_of!(int_override.mapped_to)),
+            read_unaligned(addr_of!(int_override.flags)),
+        )
+    };
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => Trigger::Level,
+        _ => Trigger::Edge,
+    };
+    (gsi, polarity, trigger)
+}
+
+/// Deliver `gsi` as `vector` to the core `apic_id` and unmask it
+pub unsafe fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    apic_id: u32,
+    polarity: Polarity,
+    trigger: Trigger,
+) -> Result<(), IoApicError> {
+    let dest = u8::try_from(apic_id).map_err(|_| IoApicError::DestinationTooLarge(apic_id))?;
+    let ioapic = ioapic_for(gsi)?;
+    let entry = RedirectionEntry::new()
+        .with_vector(vector)
+        .with_delivery_mode(0b000) // Fixed
+        .with_dest_mode(0) // Physical
+        .with_polarity((polarity == Polarity::ActiveLow) as u8)
+        .with_trigger_mode((trigger == Trigger::Level) as u8)
+        .with_mask(0)
+        .with_dest(dest);
+    ioapic.write_entry(gsi, entry);
+    Ok(())
+}
+
+/// Deliver the ISA irq `irq` as `vector` to the core `apic_id`,
+/// honoring the interrupt source overrides
+pub unsafe fn route_isa_irq(irq: u8, vector: u8, apic_id: u32) -> Result<u32, IoApicError> {
+    let (gsi, polarity, trigger) = isa_to_gsi(irq);
+    route_gsi(gsi, vector, apic_id, polarity, trigger)?;
+    log::debug!(
+        "Routed ISA irq {} (GSI {}) to vector {:#x} on core {}",
+        irq,
+        gsi,
+        vector,
+        apic_id
+    );
+    Ok(gsi)
+}
+
+pub unsafe fn mask_gsi(gsi: u32) -> Result<(), IoApicError> {
+    let ioapic = ioapic_for(gsi)?;
+    ioapic.write_entry(gsi, ioapic.read_entry(gsi).with_mask(1));
+    Ok(())
+}
+
+pub unsafe fn unmask_gsi(gsi: u32) -> Result<(), IoApicError> {
+    let ioapic = ioapic_for(gsi)?;
+    ioapic.write_entry(gsi, ioapic.read_entry(gsi).with_mask(0));
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use modular_bitfield::prelude::*;
+
+/// I/O apic MMIO registers, the others are accessed indirectly through them
+#[derive(Clone, Copy)]
+#[repr(usize)]
+pub enum Register {
+    /// Selects the indirect register
+    IoRegSel = 0x00,
+    /// Data of the selected register
+    IoWin = 0x10,
+}
+
+/// Indirect I/O apic registers
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum Index {
+    Id = 0x00,
+    Version = 0x01,
+    Arbitration = 0x02,
+    /// Two registers per redirection entry
+    RedirectionTable = 0x10,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicVersion {
+    pub version: B8,
+    pub res0: B8,
+    /// Index of the last redirection entry
+    pub max_redirection_entry: B8,
+    pub res1: B8,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct RedirectionEntry {
+    pub vector: B8,
+    /// 0b000 fixed, 0b001 lowest priority, 0b010 SMI, 0b100 NMI, 0b111 ExtINT
+    pub delivery_mode: B3,
+    /// 0 physical, 1 logical
+    pub dest_mode: B1,
+    pub delivery_status: B1,
+    /// 0 active high, 1 active low
+    pub polarity: B1,
+    pub remote_irr: B1,
+    /// 0 edge, 1 level triggered
+    pub trigger_mode: B1,
+    pub mask: B1,
+    pub res0: B39,
+    pub dest: B8,
+}
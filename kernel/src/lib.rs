@@ -11,6 +11,7 @@
 #![feature(asm)]
 #![feature(test)]
 #![feature(maybe_uninit_uninit_array)]
+#![feature(total_cmp)]
 #![no_std]
 #![allow(clippy::missing_safety_doc)]
 
@@ -19,19 +20,42 @@ pub mod acpi_regs;
 pub mod allocator;
 pub mod apic;
 pub mod apic_regs;
+pub mod backtrace;
 pub mod bench;
+pub mod clocksource;
 pub mod corestate;
 pub mod default_interrupt;
+pub mod e1000;
+pub mod e1000_regs;
+pub mod hpet;
+pub mod hpet_regs;
+pub mod ibs;
+pub mod ibs_regs;
 pub mod interrupts;
+pub mod ioapic;
+pub mod ioapic_regs;
 pub mod klog;
+pub mod lock;
 pub mod memory;
+pub mod net;
+pub mod nvme;
+pub mod nvme_regs;
+pub mod p9;
 pub mod pci;
+pub mod perf_data;
+pub mod pmc;
+pub mod pmc_regs;
 pub mod print;
+pub mod profiler;
 pub mod serial;
 pub mod smp;
+pub mod symbols;
 pub mod time;
+pub mod timer;
+pub mod trace;
 pub mod tss;
 pub mod vga;
+pub mod virtio;
 
 use core::ptr::*;
 extern crate alloc;
@@ -59,8 +83,15 @@ pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
     panic!("Failed to exit Qemu");
 }
 
-// All kernel inits summed up
+// All kernel inits summed up.
+// Only the bsp returns. The application processors park in
+// smp::idle_loop and run what the bsp posts with smp::run_on, so
+// kernel_main and test_main only ever run on the bsp.
 pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
+    // Init online status of cores and cache the index of this core,
+    // locks record it from here on
+    smp::init(boot_info);
+
     klog::init();
 
     // Make sure that other cores have the same register state like bsp
@@ -70,8 +101,8 @@ pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
     //     //smp::check_corestate();
     // }
 
-    // Init online status of cores
-    smp::init();
+    // Locate the kernel symbol table for backtraces
+    symbols::init();
 
     log::debug!("bootinfo: {:#x?}", boot_info.memory_map);
 
@@ -79,6 +110,9 @@ pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
     // Also set code and tss segment selector registers
     tss::init(boot_info);
 
+    // Remember the stack bounds of this core for backtraces
+    backtrace::init(boot_info);
+
     // Load idt into the current cpu with lidt
     interrupts::init();
 
@@ -87,12 +121,15 @@ pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
     let (mapper, frame_allocator) = memory::init(boot_info);
 
     if apic::is_bsp() {
-        // Measure speed of rtsc once
-        time::calibrate();
-
         // Check support of hardware features needed for benchmarking
         bench::check_support();
 
+        // Discover the hardware performance counters
+        pmc::init();
+
+        // Check for instruction based sampling
+        ibs::init();
+
         // Initialize the heap allocator
         // by mapping the heap pages
         allocator::init_heap(
@@ -100,6 +137,16 @@ pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
             frame_allocator.lock().deref_mut(),
         )
         .expect("heap init failed");
+
+        // Map the hpet and start its main counter
+        hpet::init(
+            mapper.lock().deref_mut(),
+            frame_allocator.lock().deref_mut(),
+            acpi::init(),
+        );
+
+        // Measure speed of rtsc once
+        time::calibrate();
     }
 
     log::debug!("Init apic controller");
@@ -115,11 +162,12 @@ pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
         boot_info,
     );
 
+    // Allocate the software timers of this core
+    timer::init();
+
     {
-        let (core, core_index) = boot_info
-        .cores
-        .get_by_apic_id(crate::apic::apic_id())
-        .unwrap();
+        let core_index = smp::core_index();
+        let core = &boot_info.cores[core_index];
 
         log::info!(
             "Enabling interrupts for core index {} apic_id {}", core_index, core.get_apic_id().unwrap()
@@ -131,20 +179,44 @@ pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
 
     if apic::is_bsp() {
         for lapic in acpi.apics.as_ref().unwrap().iter().skip(1) {
-            apic::mp_init(lapic.id, boot_info.smp_trampoline);
+            apic::mp_init(lapic.x2apic_id, boot_info.smp_trampoline);
             //time::sleep(100);
         }
     }
 
     // Search for pci devices
-    //pci::init();
-    // Init pci devices
-    //TODO: uncomment
-    // x86_64::instructions::interrupts::without_interrupts(|| unsafe {
-    //     for device in pci::DEVICES.lock().iter() {
-    //         device.init(&mut mapper, &mut frame_allocator);
-    //     }
-    // });
+    if apic::is_bsp() {
+        pci::init(
+            mapper.lock().deref_mut(),
+            frame_allocator.lock().deref_mut(),
+            acpi,
+        );
+
+        // Init pci devices
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            pci::init_devices(
+                mapper.lock().deref_mut(),
+                frame_allocator.lock().deref_mut(),
+            );
+        });
+
+        // Attach the network drivers to the network stack
+        net::init();
+    }
+
+    // Check in with the kernel
+    smp::set_core_ready();
+
+    // Application processors wait for work from the bsp and never
+    // return to their entry point
+    if !apic::is_bsp() {
+        smp::idle_loop();
+    }
+
+    // Check that the TSCs of all cores agree
+    if let Err(err) = time::tsc_sync::init() {
+        log::warn!("TSC synchronization check failed: {:?}", err);
+    }
 
     //exit_qemu(QemuExitCode::Success);
 }
@@ -191,6 +263,8 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     println!("[failed]\n");
     println!("Error: {}\n", info);
+    backtrace::print();
+    trace::dump_on_panic();
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -0,0 +1,253 @@
+//! Spin lock which records contention statistics per named lock.
+//! Every lock gets a statistics slot on its first acquisition. The slots
+//! live in a global table, so locks may be moved before they are used.
+//! Once all slots are taken, further locks share the last one.
+
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// Number of locks which get their own statistics
+pub const MAX_LOCKS: usize = 64;
+
+const UNREGISTERED: usize = usize::MAX;
+const NO_HOLDER: u32 = u32::MAX;
+
+/// Slot shared by all locks registered after the table is full
+const OVERFLOW_SLOT: usize = MAX_LOCKS - 1;
+
+pub struct LockStats {
+    acquisitions: AtomicU64,
+    /// Acquisitions which found the lock taken
+    contended: AtomicU64,
+    spin_cycles: AtomicU64,
+    max_spin_cycles: AtomicU64,
+    /// Index of the core holding the lock
+    holder: AtomicU32,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_STATS: LockStats = LockStats {
+    acquisitions: AtomicU64::new(0),
+    contended: AtomicU64::new(0),
+    spin_cycles: AtomicU64::new(0),
+    max_spin_cycles: AtomicU64::new(0),
+    holder: AtomicU32::new(NO_HOLDER),
+};
+
+static STATS: [LockStats; MAX_LOCKS] = [EMPTY_STATS; MAX_LOCKS];
+static mut NAMES: [&str; MAX_LOCKS] = [""; MAX_LOCKS];
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+fn register(name: &'static str) -> usize {
+    let slot = NEXT_SLOT.fetch_add(1, Ordering::SeqCst);
+    if slot >= OVERFLOW_SLOT {
+        unsafe { NAMES[OVERFLOW_SLOT] = "<other>" };
+        return OVERFLOW_SLOT;
+    }
+    unsafe { NAMES[slot] = name };
+    slot
+}
+
+/// Snapshot of the statistics of one lock
+#[derive(Debug, Clone, Copy)]
+pub struct LockReport {
+    pub name: &'static str,
+    pub acquisitions: u64,
+    pub contended: u64,
+    pub spin_cycles: u64,
+    pub max_spin_cycles: u64,
+    /// Index of the core holding the lock at the time of the snapshot
+    pub holder: Option<u8>,
+}
+
+impl LockReport {
+    fn from_slot(slot: usize) -> Self {
+        let stats = &STATS[slot];
+        LockReport {
+            name: unsafe { NAMES[slot] },
+            acquisitions: stats.acquisitions.load(Ordering::Relaxed),
+            contended: stats.contended.load(Ordering::Relaxed),
+            spin_cycles: stats.spin_cycles.load(Ordering::Relaxed),
+            max_spin_cycles: stats.max_spin_cycles.load(Ordering::Relaxed),
+            holder: match stats.holder.load(Ordering::Relaxed) {
+                NO_HOLDER => None,
+                core => Some(core as u8),
+            },
+        }
+    }
+
+    /// Average cycles spent waiting per contended acquisition
+    pub fn avg_spin_cycles(&self) -> u64 {
+        self.spin_cycles.checked_div(self.contended).unwrap_or(0)
+    }
+}
+
+/// Statistics of all locks acquired at least once, in the order they
+/// were first acquired
+pub fn report() -> impl Iterator<Item = LockReport> {
+    let used = NEXT_SLOT.load(Ordering::SeqCst).min(MAX_LOCKS);
+    (0..used)
+        .map(LockReport::from_slot)
+        .filter(|r| r.acquisitions != 0)
+}
+
+/// Prints the holder of a lock or "-"
+struct Holder(Option<u8>);
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(core) => core.fmt(f),
+            None => "-".fmt(f),
+        }
+    }
+}
+
+/// Log the statistics of all locks
+pub fn print_report() {
+    log::info!(
+        "{:<20} {:>10} {:>10} {:>14} {:>10} {:>10} {:>6}",
+        "lock",
+        "acquired",
+        "contended",
+        "spin cycles",
+        "avg spin",
+        "max spin",
+        "holder"
+    );
+    for r in report() {
+        log::info!(
+            "{:<20} {:>10} {:>10} {:>14} {:>10} {:>10} {:>6}",
+            r.name,
+            r.acquisitions,
+            r.contended,
+            r.spin_cycles,
+            r.avg_spin_cycles(),
+            r.max_spin_cycles,
+            Holder(r.holder)
+        );
+    }
+}
+
+/// Zero the counters of all locks, e.g. before a benchmark
+pub fn reset() {
+    for stats in STATS.iter() {
+        stats.acquisitions.store(0, Ordering::Relaxed);
+        stats.contended.store(0, Ordering::Relaxed);
+        stats.spin_cycles.store(0, Ordering::Relaxed);
+        stats.max_spin_cycles.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Drop-in replacement for `spin::Mutex` which records its contention
+pub struct Mutex<T> {
+    name: &'static str,
+    slot: AtomicUsize,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(name: &'static str, value: T) -> Self {
+        Mutex {
+            name,
+            slot: AtomicUsize::new(UNREGISTERED),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn stats(&self) -> &'static LockStats {
+        let mut slot = self.slot.load(Ordering::Acquire);
+        if slot == UNREGISTERED {
+            let new = register(self.name);
+            slot = match self.slot.compare_exchange(
+                UNREGISTERED,
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new,
+                // Another core registered the lock first
+                Err(existing) => existing,
+            };
+        }
+        &STATS[slot]
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        let stats = self.stats();
+
+        let guard = match self.inner.try_lock() {
+            Some(guard) => guard,
+            None => {
+                let start = crate::time::rdtsc();
+                let guard = self.inner.lock();
+                let spun = crate::time::rdtsc() - start;
+
+                stats.contended.fetch_add(1, Ordering::Relaxed);
+                stats.spin_cycles.fetch_add(spun, Ordering::Relaxed);
+                stats.max_spin_cycles.fetch_max(spun, Ordering::Relaxed);
+                guard
+            }
+        };
+        stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+        stats
+            .holder
+            .store(crate::smp::core_index() as u32, Ordering::Relaxed);
+
+        MutexGuard { guard, stats }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let guard = self.inner.try_lock()?;
+        let stats = self.stats();
+        stats.acquisitions.fetch_add(1, Ordering::Relaxed);
+        stats
+            .holder
+            .store(crate::smp::core_index() as u32, Ordering::Relaxed);
+
+        Some(MutexGuard { guard, stats })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Index of the core holding the lock
+    pub fn holder(&self) -> Option<u8> {
+        match self.stats().holder.load(Ordering::Relaxed) {
+            NO_HOLDER => None,
+            core => Some(core as u8),
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    stats: &'static LockStats,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // The inner guard is dropped afterwards and releases the lock
+        self.stats.holder.store(NO_HOLDER, Ordering::Relaxed);
+    }
+}
@@ -38,7 +38,8 @@ extern crate alloc;
 entry_point!(kernel_main);
 fn kernel_main(_boot_info: &'static bootinfo::BootInfo) -> ! {
     unsafe {
-        // Initialize routine for kernel
+        // Initialize routine for kernel. Application processors stay in
+        // there, only the bsp continues.
         perf_kernel::init(_boot_info);
     };
 
@@ -100,6 +101,8 @@ fn kernel_main(_boot_info: &'static bootinfo::BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     perf_kernel::println!("{}", info);
+    perf_kernel::backtrace::print();
+    perf_kernel::trace::dump_on_panic();
 
     #[cfg(debug)]
     perf_kernel::exit_qemu(svm_kernel::QemuExitCode::Failed);
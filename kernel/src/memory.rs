@@ -1,3 +1,4 @@
+use crate::lock::Mutex;
 use x86_64::registers::control::Cr3;
 // use x86_64::structures::paging::mapper::MapToError;
 use core::ptr::addr_of;
@@ -34,8 +35,11 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
-static mut PAGE_TABLE: Option<spin::Mutex<OffsetPageTable>> = None;
-static mut FRAME_ALLOCATOR: Option<spin::Mutex<BootInfoFrameAllocator>> = None;
+/// End of the identity mapping set up by the bootloader
+pub const IDENTITY_MAP_END: u64 = 0x1_0000_0000;
+
+static mut PAGE_TABLE: Option<Mutex<OffsetPageTable>> = None;
+static mut FRAME_ALLOCATOR: Option<Mutex<BootInfoFrameAllocator>> = None;
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -46,22 +50,23 @@ static mut FRAME_ALLOCATOR: Option<spin::Mutex<BootInfoFrameAllocator>> = None;
 pub unsafe fn init(
     boot_info: &'static bootloader::bootinfo::BootInfo,
 ) -> (
-    &'static spin::Mutex<OffsetPageTable>,
-    &'static spin::Mutex<BootInfoFrameAllocator>,
+    &'static Mutex<OffsetPageTable>,
+    &'static Mutex<BootInfoFrameAllocator>,
 ) {
     if PAGE_TABLE.is_none() {
         let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
         let level_4_table = active_level_4_table(physical_memory_offset);
-        PAGE_TABLE = Some(spin::Mutex::new(OffsetPageTable::new(
-            level_4_table,
-            physical_memory_offset,
-        )));
+        PAGE_TABLE = Some(Mutex::new(
+            "page_table",
+            OffsetPageTable::new(level_4_table, physical_memory_offset),
+        ));
     }
 
     if FRAME_ALLOCATOR.is_none() {
-        FRAME_ALLOCATOR = Some(spin::Mutex::new(BootInfoFrameAllocator::new(
-            &boot_info.memory_map,
-        )));
+        FRAME_ALLOCATOR = Some(Mutex::new(
+            "frame_allocator",
+            BootInfoFrameAllocator::new(&boot_info.memory_map),
+        ));
     }
 
     (
@@ -70,6 +75,16 @@ pub unsafe fn init(
     )
 }
 
+/// Page table of the kernel, `init` has to be called first
+pub fn page_table() -> &'static Mutex<OffsetPageTable<'static>> {
+    unsafe { PAGE_TABLE.as_ref().expect("memory not initialized") }
+}
+
+/// Frame allocator of the kernel, `init` has to be called first
+pub fn frame_allocator() -> &'static Mutex<BootInfoFrameAllocator> {
+    unsafe { FRAME_ALLOCATOR.as_ref().expect("memory not initialized") }
+}
+
 // Identity maps the phys address + type size and volatile reads the type from
 // memory. Does not unmap the page
 pub unsafe fn read_phys<T: Copy>(addr: PhysAddr) -> T {
@@ -227,6 +242,61 @@ impl BootInfoFrameAllocator {
     }
 }
 
+impl BootInfoFrameAllocator {
+    /// Allocate `size` bytes of physically contiguous memory starting at an
+    /// `align` aligned address. Frames skipped to satisfy the alignment are lost.
+    pub fn allocate_contiguous(&mut self, size: u64, align: u64) -> Option<PhysAddr> {
+        self.allocate_contiguous_in(size, align, 0..u64::MAX)
+    }
+
+    /// Like `allocate_contiguous` but only returns memory inside of `range`,
+    /// e.g. a NUMA domain. All free frames below the allocation are lost,
+    /// so allocate from multiple ranges in ascending order.
+    pub fn allocate_contiguous_in(
+        &mut self,
+        size: u64,
+        align: u64,
+        range: core::ops::Range<u64>,
+    ) -> Option<PhysAddr> {
+        let count = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+        let mut run: Option<(u64, u64)> = None;
+
+        for (index, frame) in self.usable_frames::<Size4KiB>().enumerate().skip(self.next) {
+            let addr = frame.start_address().as_u64();
+            if addr < range.start {
+                continue;
+            }
+            if addr + Size4KiB::SIZE > range.end {
+                break;
+            }
+            run = match run {
+                Some((start, len)) if start + len * Size4KiB::SIZE == addr => Some((start, len + 1)),
+                _ if addr % align == 0 => Some((addr, 1)),
+                _ => None,
+            };
+
+            if let Some((start, len)) = run {
+                if len == count {
+                    log::info!("Allocated contiguous frames {:#x}-{:#x}", start, start + size);
+                    self.next = index + 1;
+                    return Some(PhysAddr::new(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Allocate zeroed, page aligned memory for devices. It lies in the
+    /// identity mapping, so the address is valid for the cpu and for DMA.
+    pub fn allocate_dma(&mut self, size: u64) -> Option<u64> {
+        let addr = self
+            .allocate_contiguous_in(size, Size4KiB::SIZE, 0..IDENTITY_MAP_END)?
+            .as_u64();
+        unsafe { core::ptr::write_bytes(addr as *mut u8, 0, size as usize) };
+        Some(addr)
+    }
+}
+
 //TODO: If rust allows it in the future save the iterator in struct
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
@@ -0,0 +1,406 @@
+//! Minimal IPv4 network stack for streaming data to the host.
+//! Network drivers register as a `NetDevice` and get an `Interface` with a
+//! static IPv4 configuration, an ARP cache and UDP sockets. Everything is
+//! polled, received frames are only processed while a socket sends,
+//! receives or calls `Interface::poll`.
+
+pub mod arp;
+pub mod ipv4;
+pub mod udp;
+
+use crate::lock::Mutex;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Largest ethernet frame without the frame check sequence
+pub const MAX_FRAME: usize = 1514;
+
+pub const ETHERNET_HEADER: usize = 14;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Datagrams queued per socket before new ones are dropped
+const SOCKET_QUEUE: usize = 64;
+
+/// Time to wait for an ARP reply, per attempt
+const ARP_TIMEOUT_US: u64 = 200_000;
+const ARP_ATTEMPTS: usize = 3;
+
+/// Time a stream waits for the driver to free a transmit buffer
+const TRANSMIT_TIMEOUT_US: u64 = 100_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let m = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+impl fmt::Debug for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([0xff; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Ipv4Addr([a, b, c, d])
+    }
+
+    fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let a = self.0;
+        write!(f, "{}.{}.{}.{}", a[0], a[1], a[2], a[3])
+    }
+}
+
+impl fmt::Debug for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// No network driver registered an interface
+    NoInterface,
+    /// The driver has not been initialized
+    NotInitialized,
+    /// The payload does not fit into one frame
+    TooLarge,
+    /// The transmit ring of the driver is full
+    QueueFull,
+    /// Nobody answered the ARP requests for the address
+    Unreachable(Ipv4Addr),
+    PortInUse(u16),
+}
+
+/// Implemented by network drivers
+pub trait NetDevice: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn mac(&self) -> MacAddr;
+
+    /// Send an ethernet frame without the frame check sequence
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Copy the next received frame into `buf` and return its length
+    fn receive(&self, buf: &mut [u8]) -> Option<usize>;
+}
+
+/// Static IPv4 configuration of an interface
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+}
+
+impl Config {
+    /// The defaults of QEMU user mode networking, the host is the gateway
+    pub const QEMU_USER: Config = Config {
+        ip: Ipv4Addr::new(10, 0, 2, 15),
+        netmask: Ipv4Addr::new(255, 255, 255, 0),
+        gateway: Ipv4Addr::new(10, 0, 2, 2),
+    };
+
+    fn is_local(&self, ip: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        ip.to_u32() & mask == self.ip.to_u32() & mask
+    }
+}
+
+/// A received UDP datagram
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    pub src: Ipv4Addr,
+    pub src_port: u16,
+    pub data: Vec<u8>,
+}
+
+struct State {
+    config: Config,
+    arp_cache: BTreeMap<Ipv4Addr, MacAddr>,
+    sockets: BTreeMap<u16, VecDeque<Datagram>>,
+    /// Identification of the next IPv4 packet
+    ip_id: u16,
+    next_ephemeral: u16,
+}
+
+pub struct Interface {
+    device: Arc<dyn NetDevice>,
+    mac: MacAddr,
+    state: Mutex<State>,
+}
+
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new("net_interfaces", Vec::new());
+
+/// Add an interface for `device` with the QEMU user mode defaults
+pub fn register(device: Arc<dyn NetDevice>) -> Arc<Interface> {
+    let interface = Arc::new(Interface {
+        mac: device.mac(),
+        device,
+        state: Mutex::new(
+            "net_interface",
+            State {
+                config: Config::QEMU_USER,
+                arp_cache: BTreeMap::new(),
+                sockets: BTreeMap::new(),
+                ip_id: 0,
+                next_ephemeral: 49152,
+            },
+        ),
+    });
+    log::info!(
+        "Network interface {} {} with {}",
+        interface.device.name(),
+        interface.mac,
+        Config::QEMU_USER.ip
+    );
+    INTERFACES.lock().push(interface.clone());
+    interface
+}
+
+/// Add an interface for every initialized network driver
+pub fn init() {
+    for device in crate::pci::devices::<crate::virtio::net::VirtioNet>() {
+        register(device);
+    }
+    for device in crate::pci::devices::<crate::e1000::E1000>() {
+        register(device);
+    }
+}
+
+pub fn interfaces() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+/// The first registered interface
+pub fn default_interface() -> Result<Arc<Interface>, NetError> {
+    INTERFACES
+        .lock()
+        .first()
+        .cloned()
+        .ok_or(NetError::NoInterface)
+}
+
+impl Interface {
+    pub fn name(&self) -> &'static str {
+        self.device.name()
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    pub fn config(&self) -> Config {
+        self.state.lock().config
+    }
+
+    /// Change the address, drops the ARP cache
+    pub fn configure(&self, config: Config) {
+        let mut state = self.state.lock();
+        state.config = config;
+        state.arp_cache.clear();
+    }
+
+    /// Process all received frames
+    pub fn poll(&self) {
+        let mut frame = [0u8; MAX_FRAME];
+        while let Some(len) = self.device.receive(&mut frame) {
+            self.process(&frame[..len]);
+        }
+    }
+
+    fn process(&self, frame: &[u8]) {
+        if frame.len() < ETHERNET_HEADER {
+            return;
+        }
+        let dst = &frame[0..6];
+        if dst != self.mac.0 && dst != MacAddr::BROADCAST.0 {
+            return;
+        }
+        let ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        let payload = &frame[ETHERNET_HEADER..];
+        match ethertype {
+            ETHERTYPE_ARP => arp::process(self, payload),
+            ETHERTYPE_IPV4 => ipv4::process(self, payload),
+            _ => {}
+        }
+    }
+
+    /// Send an ethernet frame whose payload is written by `fill`, which
+    /// returns the length of the payload
+    fn send_frame(
+        &self,
+        dst: MacAddr,
+        ethertype: u16,
+        fill: impl FnOnce(&mut [u8]) -> usize,
+    ) -> Result<(), NetError> {
+        let mut frame = [0u8; MAX_FRAME];
+        frame[0..6].copy_from_slice(&dst.0);
+        frame[6..12].copy_from_slice(&self.mac.0);
+        frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+        let len = fill(&mut frame[ETHERNET_HEADER..]);
+        // Pad to the minimum frame size
+        let len = (ETHERNET_HEADER + len).max(60);
+        self.device.transmit(&frame[..len])
+    }
+
+    /// MAC address of the next hop towards `ip`
+    fn resolve(&self, ip: Ipv4Addr) -> Result<MacAddr, NetError> {
+        if ip == Ipv4Addr::BROADCAST {
+            return Ok(MacAddr::BROADCAST);
+        }
+        let config = self.config();
+        let next_hop = if config.is_local(ip) {
+            ip
+        } else {
+            config.gateway
+        };
+
+        for _ in 0..ARP_ATTEMPTS {
+            if let Some(&mac) = self.state.lock().arp_cache.get(&next_hop) {
+                return Ok(mac);
+            }
+            arp::request(self, next_hop)?;
+            let deadline = crate::time::future(ARP_TIMEOUT_US);
+            while crate::time::rdtsc() < deadline {
+                self.poll();
+                if let Some(&mac) = self.state.lock().arp_cache.get(&next_hop) {
+                    return Ok(mac);
+                }
+                core::hint::spin_loop();
+            }
+        }
+        Err(NetError::Unreachable(next_hop))
+    }
+
+    fn bind(&self, port: u16) -> Result<u16, NetError> {
+        let mut state = self.state.lock();
+        let port = if port == 0 {
+            // Pick the next free ephemeral port
+            let mut port = state.next_ephemeral;
+            while state.sockets.contains_key(&port) {
+                port = port.checked_add(1).unwrap_or(49152);
+            }
+            state.next_ephemeral = port.checked_add(1).unwrap_or(49152);
+            port
+        } else {
+            port
+        };
+        if state.sockets.contains_key(&port) {
+            return Err(NetError::PortInUse(port));
+        }
+        state.sockets.insert(port, VecDeque::new());
+        Ok(port)
+    }
+
+    fn unbind(&self, port: u16) {
+        self.state.lock().sockets.remove(&port);
+    }
+
+    /// Queue a datagram for the socket bound to `port`
+    fn deliver(&self, port: u16, datagram: Datagram) {
+        let mut state = self.state.lock();
+        if let Some(queue) = state.sockets.get_mut(&port) {
+            if queue.len() < SOCKET_QUEUE {
+                queue.push_back(datagram);
+            }
+        }
+    }
+
+    fn next_ip_id(&self) -> u16 {
+        let mut state = self.state.lock();
+        state.ip_id = state.ip_id.wrapping_add(1);
+        state.ip_id
+    }
+}
+
+/// A UDP socket bound to a port of an interface
+pub struct UdpSocket {
+    interface: Arc<Interface>,
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Bind `port` of the default interface, 0 picks an ephemeral port
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        Self::bind_on(default_interface()?, port)
+    }
+
+    pub fn bind_on(interface: Arc<Interface>, port: u16) -> Result<UdpSocket, NetError> {
+        let port = interface.bind(port)?;
+        Ok(UdpSocket { interface, port })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Send one datagram of at most `udp::MAX_PAYLOAD` bytes
+    pub fn send_to(&self, data: &[u8], dst: Ipv4Addr, dst_port: u16) -> Result<(), NetError> {
+        udp::send(&self.interface, self.port, dst, dst_port, data)
+    }
+
+    /// Send `data` as a sequence of full datagrams. Waits for the driver
+    /// while its transmit ring is full.
+    pub fn stream_to(&self, data: &[u8], dst: Ipv4Addr, dst_port: u16) -> Result<(), NetError> {
+        for chunk in data.chunks(udp::MAX_PAYLOAD) {
+            let deadline = crate::time::future(TRANSMIT_TIMEOUT_US);
+            loop {
+                match self.send_to(chunk, dst, dst_port) {
+                    // Buffers become free again once the device sent their frames
+                    Err(NetError::QueueFull) if crate::time::rdtsc() <= deadline => {
+                        self.interface.poll();
+                        core::hint::spin_loop();
+                    }
+                    result => break result?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The next received datagram, if any
+    pub fn recv_from(&self) -> Option<Datagram> {
+        self.interface.poll();
+        self.interface
+            .state
+            .lock()
+            .sockets
+            .get_mut(&self.port)?
+            .pop_front()
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.interface.unbind(self.port);
+    }
+}
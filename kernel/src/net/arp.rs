@@ -0,0 +1,76 @@
+//! Address resolution for IPv4 over ethernet.
+
+use super::{Interface, Ipv4Addr, MacAddr, NetError, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+
+pub const PACKET_SIZE: usize = 28;
+
+const HTYPE_ETHERNET: u16 = 1;
+pub const OPER_REQUEST: u16 = 1;
+pub const OPER_REPLY: u16 = 2;
+
+/// Write an ARP packet for IPv4 over ethernet into `buf`, returns its length
+pub fn encode(
+    buf: &mut [u8],
+    oper: u16,
+    sha: MacAddr,
+    spa: Ipv4Addr,
+    tha: MacAddr,
+    tpa: Ipv4Addr,
+) -> usize {
+    buf[0..2].copy_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    buf[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    buf[4] = 6;
+    buf[5] = 4;
+    buf[6..8].copy_from_slice(&oper.to_be_bytes());
+    buf[8..14].copy_from_slice(&sha.0);
+    buf[14..18].copy_from_slice(&spa.0);
+    buf[18..24].copy_from_slice(&tha.0);
+    buf[24..28].copy_from_slice(&tpa.0);
+    PACKET_SIZE
+}
+
+/// Broadcast a request for the MAC address of `ip`
+pub fn request(interface: &Interface, ip: Ipv4Addr) -> Result<(), NetError> {
+    let own = interface.config().ip;
+    interface.send_frame(MacAddr::BROADCAST, ETHERTYPE_ARP, |buf| {
+        encode(
+            buf,
+            OPER_REQUEST,
+            interface.mac,
+            own,
+            MacAddr::default(),
+            ip,
+        )
+    })
+}
+
+/// Learn the sender of every ARP packet and answer requests for our address
+pub fn process(interface: &Interface, packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || u16::from_be_bytes([packet[0], packet[1]]) != HTYPE_ETHERNET
+        || u16::from_be_bytes([packet[2], packet[3]]) != ETHERTYPE_IPV4
+    {
+        return;
+    }
+    let oper = u16::from_be_bytes([packet[6], packet[7]]);
+    let mut sha = MacAddr::default();
+    sha.0.copy_from_slice(&packet[8..14]);
+    let mut spa = Ipv4Addr::default();
+    spa.0.copy_from_slice(&packet[14..18]);
+    let mut tpa = Ipv4Addr::default();
+    tpa.0.copy_from_slice(&packet[24..28]);
+
+    let own = {
+        let mut state = interface.state.lock();
+        if spa != Ipv4Addr::UNSPECIFIED {
+            state.arp_cache.insert(spa, sha);
+        }
+        state.config.ip
+    };
+
+    if oper == OPER_REQUEST && tpa == own {
+        let _ = interface.send_frame(sha, ETHERTYPE_ARP, |buf| {
+            encode(buf, OPER_REPLY, interface.mac, own, sha, spa)
+        });
+    }
+}
@@ -0,0 +1,99 @@
+//! IPv4 without options and fragmentation.
+
+use super::{udp, Interface, Ipv4Addr, NetError, ETHERTYPE_IPV4};
+
+pub const HEADER_SIZE: usize = 20;
+
+/// Largest payload of a packet which fits into one ethernet frame
+pub const MAX_PAYLOAD: usize = 1500 - HEADER_SIZE;
+
+pub const PROTOCOL_UDP: u8 = 17;
+
+const DEFAULT_TTL: u8 = 64;
+
+/// Don't fragment flag
+const FLAG_DF: u16 = 1 << 14;
+
+/// Internet checksum over `data`, starting with the partial sum `initial`
+pub fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Send a packet whose payload of `len` bytes is written by `fill`
+pub fn send(
+    interface: &Interface,
+    dst: Ipv4Addr,
+    protocol: u8,
+    len: usize,
+    fill: impl FnOnce(&mut [u8], Ipv4Addr),
+) -> Result<(), NetError> {
+    if len > MAX_PAYLOAD {
+        return Err(NetError::TooLarge);
+    }
+    let mac = interface.resolve(dst)?;
+    let src = interface.config().ip;
+    let id = interface.next_ip_id();
+
+    interface.send_frame(mac, ETHERTYPE_IPV4, |buf| {
+        let total = (HEADER_SIZE + len) as u16;
+        let header = &mut buf[..HEADER_SIZE];
+        header[0] = 0x45; // Version 4, 5 dwords
+        header[1] = 0;
+        header[2..4].copy_from_slice(&total.to_be_bytes());
+        header[4..6].copy_from_slice(&id.to_be_bytes());
+        header[6..8].copy_from_slice(&FLAG_DF.to_be_bytes());
+        header[8] = DEFAULT_TTL;
+        header[9] = protocol;
+        header[10..12].copy_from_slice(&[0, 0]);
+        header[12..16].copy_from_slice(&src.0);
+        header[16..20].copy_from_slice(&dst.0);
+        let sum = checksum(header, 0);
+        header[10..12].copy_from_slice(&sum.to_be_bytes());
+
+        fill(&mut buf[HEADER_SIZE..HEADER_SIZE + len], src);
+        HEADER_SIZE + len
+    })
+}
+
+/// Hand packets for our address to the protocol
+pub fn process(interface: &Interface, packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    let total = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if header_len < HEADER_SIZE || total < header_len || total > packet.len() {
+        return;
+    }
+    if checksum(&packet[..header_len], 0) != 0 {
+        return;
+    }
+    // Fragments are not reassembled
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if fragment & 0x3fff != 0 {
+        return;
+    }
+
+    let mut src = Ipv4Addr::default();
+    src.0.copy_from_slice(&packet[12..16]);
+    let mut dst = Ipv4Addr::default();
+    dst.0.copy_from_slice(&packet[16..20]);
+    if dst != interface.config().ip && dst != Ipv4Addr::BROADCAST {
+        return;
+    }
+
+    if packet[9] == PROTOCOL_UDP {
+        udp::process(interface, src, dst, &packet[header_len..total]);
+    }
+}
@@ -0,0 +1,87 @@
+//! User datagram protocol.
+
+use super::ipv4::{self, checksum, PROTOCOL_UDP};
+use super::{Datagram, Interface, Ipv4Addr, NetError};
+
+pub const HEADER_SIZE: usize = 8;
+
+/// Largest payload of a datagram which fits into one ethernet frame
+pub const MAX_PAYLOAD: usize = ipv4::MAX_PAYLOAD - HEADER_SIZE;
+
+/// Partial checksum of the pseudo header
+fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, len: usize) -> u32 {
+    let mut sum = 0u32;
+    for pair in src.0.chunks_exact(2).chain(dst.0.chunks_exact(2)) {
+        sum += u16::from_be_bytes([pair[0], pair[1]]) as u32;
+    }
+    sum + PROTOCOL_UDP as u32 + len as u32
+}
+
+/// Write the datagram with header and `data` into `buf`, returns its length
+pub fn encode(
+    buf: &mut [u8],
+    src: Ipv4Addr,
+    src_port: u16,
+    dst: Ipv4Addr,
+    dst_port: u16,
+    data: &[u8],
+) -> usize {
+    let len = HEADER_SIZE + data.len();
+    buf[0..2].copy_from_slice(&src_port.to_be_bytes());
+    buf[2..4].copy_from_slice(&dst_port.to_be_bytes());
+    buf[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+    buf[6..8].copy_from_slice(&[0, 0]);
+    buf[HEADER_SIZE..len].copy_from_slice(data);
+
+    // A computed checksum of 0 is sent as all ones
+    let sum = match checksum(&buf[..len], pseudo_header_sum(src, dst, len)) {
+        0 => 0xffff,
+        sum => sum,
+    };
+    buf[6..8].copy_from_slice(&sum.to_be_bytes());
+    len
+}
+
+pub fn send(
+    interface: &Interface,
+    src_port: u16,
+    dst: Ipv4Addr,
+    dst_port: u16,
+    data: &[u8],
+) -> Result<(), NetError> {
+    if data.len() > MAX_PAYLOAD {
+        return Err(NetError::TooLarge);
+    }
+    let len = HEADER_SIZE + data.len();
+    ipv4::send(interface, dst, PROTOCOL_UDP, len, |buf, src| {
+        encode(buf, src, src_port, dst, dst_port, data);
+    })
+}
+
+pub fn process(interface: &Interface, src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+
+    // A checksum of 0 means the sender did not compute one
+    let sum = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if sum != 0 && checksum(datagram, pseudo_header_sum(src, dst, len)) != 0 {
+        return;
+    }
+
+    let src_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let dst_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    interface.deliver(
+        dst_port,
+        Datagram {
+            src,
+            src_port,
+            data: datagram[HEADER_SIZE..].to_vec(),
+        },
+    );
+}
@@ -0,0 +1,808 @@
+//! NVMe driver.
+//! The controller is set up through the registers in BAR0 with a polled
+//! admin queue. Every core gets its own I/O submission and completion
+//! queue pair if the controller has enough of them, the completion
+//! queue interrupts through an MSI-X entry routed to that core. In polled
+//! mode the entries are masked and completions are busy polled instead.
+//!
+//! Each queue has one command in flight at a time. Data goes through a
+//! bounce buffer in DMA memory described by a static PRP list. A queue
+//! whose command timed out fails all later I/O.
+
+use crate::lock::Mutex;
+use crate::memory::BootInfoFrameAllocator;
+use crate::nvme_regs::*;
+use crate::pci::msi::MsiX;
+use crate::pci::{
+    bar, Device, DriverError, Match, PciFunction, COMMAND_BUS_MASTER, COMMAND_MEMORY_SPACE,
+};
+use crate::smp;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use x86_64::structures::paging::OffsetPageTable;
+
+pub const MATCHES: &[Match] = &[Match::Class {
+    class: 0x01,
+    subclass: 0x08,
+    prog_if: Some(0x02),
+}];
+
+const ADMIN_QUEUE_SIZE: u16 = 32;
+const IO_QUEUE_SIZE: u16 = 64;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Bounce buffer of every I/O queue, the largest transfer of one command
+const BOUNCE_SIZE: u64 = 64 * 1024;
+
+/// Time the controller gets for a command
+const COMMAND_TIMEOUT_US: u64 = 5_000_000;
+
+/// Entry sizes as powers of two
+const SQ_ENTRY_SHIFT: u8 = 6;
+const CQ_ENTRY_SHIFT: u8 = 4;
+
+/// Create I/O queue flags
+const QUEUE_PHYS_CONTIGUOUS: u32 = 1 << 0;
+const CQ_INTERRUPTS_ENABLED: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeError {
+    NotInitialized,
+    /// Buffers have to be a multiple of the block size
+    Unaligned,
+    /// The request goes beyond the namespace
+    OutOfRange,
+    Timeout,
+    /// The controller reported a fatal status
+    Fatal,
+    /// Status code type and status code of a failed command
+    Status(u16),
+    /// The controller has no MSI-X
+    NoInterrupts,
+    /// A command of the I/O queue timed out earlier
+    QueueFailed,
+}
+
+impl From<NvmeError> for DriverError {
+    fn from(err: NvmeError) -> Self {
+        DriverError::Device(match err {
+            NvmeError::Timeout => "controller timed out",
+            NvmeError::Fatal => "controller fatal status",
+            NvmeError::Status(_) => "admin command failed",
+            _ => "controller error",
+        })
+    }
+}
+
+/// Mapped registers of the controller
+struct Controller {
+    base: u64,
+    /// Distance between two doorbells
+    stride: u64,
+    /// Time to become ready
+    timeout_us: u64,
+}
+
+impl Controller {
+    unsafe fn read32(&self, register: Register) -> u32 {
+        read_volatile((self.base + register as u64) as *const u32)
+    }
+
+    unsafe fn write32(&self, register: Register, value: u32) {
+        write_volatile((self.base + register as u64) as *mut u32, value);
+    }
+
+    unsafe fn write64(&self, register: Register, value: u64) {
+        write_volatile((self.base + register as u64) as *mut u64, value);
+    }
+
+    unsafe fn config(&self) -> ControllerConfigReg {
+        ControllerConfigReg::from_bytes(self.read32(Register::ControllerConfig).to_le_bytes())
+    }
+
+    unsafe fn set_config(&self, config: ControllerConfigReg) {
+        self.write32(
+            Register::ControllerConfig,
+            u32::from_le_bytes(config.into_bytes()),
+        );
+    }
+
+    unsafe fn status(&self) -> ControllerStatusReg {
+        ControllerStatusReg::from_bytes(self.read32(Register::ControllerStatus).to_le_bytes())
+    }
+
+    unsafe fn wait_ready(&self, ready: bool) -> Result<(), NvmeError> {
+        let deadline = crate::time::future(self.timeout_us);
+        loop {
+            let status = self.status();
+            if ready && status.cfs() == 1 {
+                return Err(NvmeError::Fatal);
+            }
+            if (status.rdy() == 1) == ready {
+                return Ok(());
+            }
+            if crate::time::rdtsc() > deadline {
+                return Err(NvmeError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Reset the controller, this deletes all queues
+    unsafe fn disable(&self) -> Result<(), NvmeError> {
+        let config = self.config();
+        if config.en() == 1 {
+            self.set_config(config.with_en(0));
+        }
+        self.wait_ready(false)
+    }
+
+    unsafe fn enable(&self) -> Result<(), NvmeError> {
+        let config = ControllerConfigReg::new()
+            .with_css(0)
+            .with_mps(0)
+            .with_ams(0)
+            .with_iosqes(SQ_ENTRY_SHIFT)
+            .with_iocqes(CQ_ENTRY_SHIFT)
+            .with_en(1);
+        self.set_config(config);
+        self.wait_ready(true)
+    }
+}
+
+/// A submission queue with its completion queue
+struct Queue {
+    id: u16,
+    size: u16,
+    sq: u64,
+    cq: u64,
+    sq_tail: u16,
+    cq_head: u16,
+    /// Phase tag of new completions, flips on every wrap
+    phase: bool,
+    sq_doorbell: u64,
+    cq_doorbell: u64,
+    next_cid: u16,
+}
+
+impl Queue {
+    fn new(
+        frame_allocator: &mut BootInfoFrameAllocator,
+        controller: &Controller,
+        id: u16,
+        size: u16,
+    ) -> Result<Queue, DriverError> {
+        let sq = frame_allocator
+            .allocate_dma((size as u64) << SQ_ENTRY_SHIFT)
+            .ok_or(DriverError::OutOfMemory)?;
+        let cq = frame_allocator
+            .allocate_dma((size as u64) << CQ_ENTRY_SHIFT)
+            .ok_or(DriverError::OutOfMemory)?;
+        Ok(Queue {
+            id,
+            size,
+            sq,
+            cq,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            sq_doorbell: controller.base + sq_tail_doorbell(id, controller.stride),
+            cq_doorbell: controller.base + cq_head_doorbell(id, controller.stride),
+            next_cid: 0,
+        })
+    }
+
+    /// Size and id as expected by the create queue commands
+    fn create_cdw10(&self) -> u32 {
+        (self.size as u32 - 1) << 16 | self.id as u32
+    }
+
+    /// Place `command` in the submission queue and ring the doorbell.
+    /// Returns the command id.
+    unsafe fn submit(&mut self, mut command: Command) -> u16 {
+        command.cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+
+        let slot = self.sq + ((self.sq_tail as u64) << SQ_ENTRY_SHIFT);
+        write_volatile(slot as *mut Command, command);
+        self.sq_tail = (self.sq_tail + 1) % self.size;
+        write_volatile(self.sq_doorbell as *mut u32, self.sq_tail as u32);
+        command.cid
+    }
+
+    /// Whether the controller posted a completion
+    fn has_completion(&self) -> bool {
+        let slot = self.cq + ((self.cq_head as u64) << CQ_ENTRY_SHIFT);
+        let completion = unsafe { read_volatile(slot as *const Completion) };
+        completion.phase() == self.phase
+    }
+
+    unsafe fn pop(&mut self) -> Option<Completion> {
+        if !self.has_completion() {
+            return None;
+        }
+        let slot = self.cq + ((self.cq_head as u64) << CQ_ENTRY_SHIFT);
+        let completion = read_volatile(slot as *const Completion);
+
+        self.cq_head += 1;
+        if self.cq_head == self.size {
+            self.cq_head = 0;
+            self.phase = !self.phase;
+        }
+        write_volatile(self.cq_doorbell as *mut u32, self.cq_head as u32);
+        Some(completion)
+    }
+
+    /// Submit `command` and wait for its completion, `idle` runs while
+    /// there is none
+    unsafe fn execute(
+        &mut self,
+        command: Command,
+        mut idle: impl FnMut(&Queue),
+    ) -> Result<Completion, NvmeError> {
+        let cid = self.submit(command);
+        let deadline = crate::time::future(COMMAND_TIMEOUT_US);
+        loop {
+            if let Some(completion) = self.pop() {
+                // Completions of commands which timed out earlier
+                if completion.cid != cid {
+                    continue;
+                }
+                return match completion.status_code() {
+                    0 => Ok(completion),
+                    status => Err(NvmeError::Status(status)),
+                };
+            }
+            if crate::time::rdtsc() > deadline {
+                return Err(NvmeError::Timeout);
+            }
+            idle(self);
+        }
+    }
+}
+
+/// I/O queue pair of one or more cores
+struct IoQueue {
+    /// Index of the core the completion interrupt is delivered to
+    core: usize,
+    /// MSI-X entry of the completion queue
+    entry: u16,
+    vector: AtomicU8,
+    interrupts: AtomicU64,
+    inner: Mutex<IoInner>,
+}
+
+struct IoInner {
+    queue: Queue,
+    /// Bounce buffer of `BOUNCE_SIZE`
+    buffer: u64,
+    /// PRP list of the pages of the bounce buffer after the first
+    prp_list: u64,
+    /// A command timed out and may still be in flight
+    failed: bool,
+}
+
+struct Io {
+    queues: Vec<IoQueue>,
+    /// Index into `queues` per core index
+    by_core: [u8; bootloader::MAX_CORES],
+    msix: Option<MsiX>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Namespace {
+    id: u32,
+    blocks: u64,
+    block_size: u64,
+    /// Largest transfer of one command in bytes
+    max_transfer: u64,
+}
+
+pub struct Nvme {
+    function: &'static PciFunction,
+    controller: spin::Once<Controller>,
+    admin: Mutex<Option<Queue>>,
+    namespace: spin::Once<Namespace>,
+    io: spin::Once<Io>,
+    polled: AtomicBool,
+}
+
+pub fn probe(function: &'static PciFunction) -> Option<Arc<dyn Device>> {
+    Some(Arc::new(Nvme {
+        function,
+        controller: spin::Once::new(),
+        admin: Mutex::new("nvme_admin", None),
+        namespace: spin::Once::new(),
+        io: spin::Once::new(),
+        polled: AtomicBool::new(true),
+    }))
+}
+
+/// Completion interrupt of an I/O queue, `arg` points to the `IoQueue`.
+/// The waiting core only needs to wake up from `hlt`.
+fn completion_interrupt(_vector: u8, arg: usize) {
+    let queue = unsafe { &*(arg as *const IoQueue) };
+    queue.interrupts.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Nvme {
+    fn admin(&self, command: Command) -> Result<Completion, NvmeError> {
+        let mut admin = self.admin.lock();
+        let admin = admin.as_mut().ok_or(NvmeError::NotInitialized)?;
+        unsafe { admin.execute(command, |_| core::hint::spin_loop()) }
+    }
+
+    fn namespace(&self) -> Result<&Namespace, NvmeError> {
+        self.namespace.get().ok_or(NvmeError::NotInitialized)
+    }
+
+    /// I/O queue of the current core
+    fn queue(&self) -> Result<&IoQueue, NvmeError> {
+        let io = self.io.get().ok_or(NvmeError::NotInitialized)?;
+        let index = io.by_core[smp::core_index()];
+        Ok(&io.queues[index as usize])
+    }
+
+    /// Run `f` with the I/O queue of the current core locked
+    fn with_queue<R>(
+        &self,
+        f: impl FnOnce(&IoQueue, &mut IoInner) -> Result<R, NvmeError>,
+    ) -> Result<R, NvmeError> {
+        let io = self.queue()?;
+        let mut inner = io.inner.lock();
+        if inner.failed {
+            return Err(NvmeError::QueueFailed);
+        }
+        let result = f(io, &mut inner);
+        if let Err(NvmeError::Timeout) = result {
+            // The controller may still execute the command and DMA into the
+            // bounce buffer, and its submission queue entry may not be
+            // consumed yet. Neither may be reused, so the queue is retired.
+            log::warn!(
+                "nvme {}: command on I/O queue {} timed out, the queue is not used anymore",
+                self.function.address,
+                inner.queue.id
+            );
+            inner.failed = true;
+        }
+        result
+    }
+
+    /// Number of blocks of the namespace
+    pub fn blocks(&self) -> u64 {
+        self.namespace().map_or(0, |namespace| namespace.blocks)
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.namespace().map_or(0, |namespace| namespace.block_size)
+    }
+
+    /// Number of I/O queue pairs
+    pub fn num_queues(&self) -> usize {
+        self.io.get().map_or(0, |io| io.queues.len())
+    }
+
+    /// Completion interrupts received on all queues
+    pub fn interrupts(&self) -> u64 {
+        self.io.get().map_or(0, |io| {
+            io.queues
+                .iter()
+                .map(|queue| queue.interrupts.load(Ordering::Relaxed))
+                .sum()
+        })
+    }
+
+    pub fn is_polled(&self) -> bool {
+        self.polled.load(Ordering::Relaxed)
+    }
+
+    /// Busy poll for completions instead of waiting for their interrupt.
+    /// The MSI-X entries of the queues are masked while polling.
+    pub fn set_polled(&self, polled: bool) -> Result<(), NvmeError> {
+        let io = self.io.get().ok_or(NvmeError::NotInitialized)?;
+        let msix = match io.msix {
+            Some(msix) => msix,
+            None if polled => return Ok(()),
+            None => return Err(NvmeError::NoInterrupts),
+        };
+        for queue in io.queues.iter() {
+            unsafe { msix.set_masked(queue.entry, polled) }.ok();
+        }
+        self.polled.store(polled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Wait for the completion on `queue`. Halts until the completion
+    /// interrupt if it is delivered to the current core.
+    fn idle(&self, io: &IoQueue, queue: &Queue) {
+        use x86_64::instructions::interrupts;
+
+        if self.is_polled() || io.core != smp::core_index() || !interrupts::are_enabled() {
+            core::hint::spin_loop();
+            return;
+        }
+        // The interrupt may not arrive between the check and the hlt
+        interrupts::disable();
+        if queue.has_completion() {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+
+    /// Check the bounds of a transfer of `len` bytes at `lba`
+    fn check(&self, lba: u64, len: usize) -> Result<&Namespace, NvmeError> {
+        let namespace = self.namespace()?;
+        if len as u64 % namespace.block_size != 0 {
+            return Err(NvmeError::Unaligned);
+        }
+        let blocks = len as u64 / namespace.block_size;
+        if lba
+            .checked_add(blocks)
+            .map_or(true, |end| end > namespace.blocks)
+        {
+            return Err(NvmeError::OutOfRange);
+        }
+        Ok(namespace)
+    }
+
+    /// Transfer `len` bytes between `lba` and the bounce buffer
+    fn transfer(
+        &self,
+        io: &IoQueue,
+        inner: &mut IoInner,
+        namespace: &Namespace,
+        opcode: IoOpcode,
+        lba: u64,
+        len: u64,
+    ) -> Result<(), NvmeError> {
+        let prp2 = if len <= PAGE_SIZE {
+            0
+        } else if len <= 2 * PAGE_SIZE {
+            inner.buffer + PAGE_SIZE
+        } else {
+            inner.prp_list
+        };
+        let command = Command {
+            opcode: opcode as u8,
+            nsid: namespace.id,
+            prp1: inner.buffer,
+            prp2,
+            cdw10: lba as u32,
+            cdw11: (lba >> 32) as u32,
+            cdw12: (len / namespace.block_size - 1) as u32,
+            ..Default::default()
+        };
+        unsafe { inner.queue.execute(command, |queue| self.idle(io, queue)) }.map(|_| ())
+    }
+
+    /// Read `buf.len()` bytes starting at block `lba`
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), NvmeError> {
+        let namespace = self.check(lba, buf.len())?;
+        self.with_queue(|io, inner| {
+            let mut lba = lba;
+            for chunk in buf.chunks_mut(namespace.max_transfer as usize) {
+                self.transfer(
+                    io,
+                    inner,
+                    namespace,
+                    IoOpcode::Read,
+                    lba,
+                    chunk.len() as u64,
+                )?;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        inner.buffer as *const u8,
+                        chunk.as_mut_ptr(),
+                        chunk.len(),
+                    );
+                }
+                lba += chunk.len() as u64 / namespace.block_size;
+            }
+            Ok(())
+        })
+    }
+
+    /// Write `buf` starting at block `lba`
+    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<(), NvmeError> {
+        let namespace = self.check(lba, buf.len())?;
+        self.with_queue(|io, inner| {
+            let mut lba = lba;
+            for chunk in buf.chunks(namespace.max_transfer as usize) {
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        chunk.as_ptr(),
+                        inner.buffer as *mut u8,
+                        chunk.len(),
+                    );
+                }
+                self.transfer(
+                    io,
+                    inner,
+                    namespace,
+                    IoOpcode::Write,
+                    lba,
+                    chunk.len() as u64,
+                )?;
+                lba += chunk.len() as u64 / namespace.block_size;
+            }
+            Ok(())
+        })
+    }
+
+    /// Commit the volatile write cache to the media
+    pub fn flush(&self) -> Result<(), NvmeError> {
+        let namespace = self.namespace()?;
+        let command = Command {
+            opcode: IoOpcode::Flush as u8,
+            nsid: namespace.id,
+            ..Default::default()
+        };
+        self.with_queue(|io, inner| {
+            unsafe { inner.queue.execute(command, |queue| self.idle(io, queue)) }.map(|_| ())
+        })
+    }
+
+    /// Identify the controller and namespace 1 into the page `identify`
+    fn identify(&self, identify: u64) -> Result<Namespace, DriverError> {
+        let data =
+            unsafe { core::slice::from_raw_parts(identify as *const u8, PAGE_SIZE as usize) };
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes([
+                data[offset],
+                data[offset + 1],
+                data[offset + 2],
+                data[offset + 3],
+            ])
+        };
+
+        self.admin(Command {
+            opcode: AdminOpcode::Identify as u8,
+            prp1: identify,
+            cdw10: IDENTIFY_CONTROLLER,
+            ..Default::default()
+        })?;
+        let model = core::str::from_utf8(&data[24..64]).unwrap_or("").trim();
+        // Maximum data transfer size as a power of two of the page size
+        let mdts = data[77];
+        let namespaces = u32_at(516);
+        log::info!(
+            "nvme {}: {} with {} namespaces",
+            self.function.address,
+            model,
+            namespaces
+        );
+        if namespaces == 0 {
+            return Err(DriverError::Device("no namespace"));
+        }
+
+        self.admin(Command {
+            opcode: AdminOpcode::Identify as u8,
+            nsid: 1,
+            prp1: identify,
+            cdw10: IDENTIFY_NAMESPACE,
+            ..Default::default()
+        })?;
+        let blocks = u32_at(0) as u64 | (u32_at(4) as u64) << 32;
+        if blocks == 0 {
+            return Err(DriverError::Device("namespace 1 is inactive"));
+        }
+        let format = (data[26] & 0xf) as usize;
+        let block_shift = (u32_at(128 + 4 * format) >> 16) & 0xff;
+
+        let max_transfer = match mdts {
+            0 => BOUNCE_SIZE,
+            // Anything beyond 2^16 pages exceeds the bounce buffer anyway,
+            // clamp before the shift overflows
+            mdts => BOUNCE_SIZE.min(PAGE_SIZE << mdts.min(16)),
+        };
+        Ok(Namespace {
+            id: 1,
+            blocks,
+            block_size: 1 << block_shift,
+            max_transfer,
+        })
+    }
+
+    /// Create the I/O queue pair `id` which interrupts on the MSI-X entry
+    /// `id` if `interrupts` is set
+    fn create_io_queue(
+        &self,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        controller: &Controller,
+        id: u16,
+        size: u16,
+        interrupts: bool,
+    ) -> Result<IoInner, DriverError> {
+        let queue = Queue::new(frame_allocator, controller, id, size)?;
+        let cq_flags = if interrupts {
+            (id as u32) << 16 | CQ_INTERRUPTS_ENABLED | QUEUE_PHYS_CONTIGUOUS
+        } else {
+            QUEUE_PHYS_CONTIGUOUS
+        };
+        self.admin(Command {
+            opcode: AdminOpcode::CreateIoCq as u8,
+            prp1: queue.cq,
+            cdw10: queue.create_cdw10(),
+            cdw11: cq_flags,
+            ..Default::default()
+        })?;
+        self.admin(Command {
+            opcode: AdminOpcode::CreateIoSq as u8,
+            prp1: queue.sq,
+            cdw10: queue.create_cdw10(),
+            cdw11: (id as u32) << 16 | QUEUE_PHYS_CONTIGUOUS,
+            ..Default::default()
+        })?;
+
+        let buffer = frame_allocator
+            .allocate_dma(BOUNCE_SIZE)
+            .ok_or(DriverError::OutOfMemory)?;
+        let prp_list = frame_allocator
+            .allocate_dma(PAGE_SIZE)
+            .ok_or(DriverError::OutOfMemory)?;
+        for page in 1..BOUNCE_SIZE / PAGE_SIZE {
+            unsafe {
+                write_volatile(
+                    (prp_list as *mut u64).add(page as usize - 1),
+                    buffer + page * PAGE_SIZE,
+                );
+            }
+        }
+        Ok(IoInner {
+            queue,
+            buffer,
+            prp_list,
+            failed: false,
+        })
+    }
+}
+
+impl Device for Nvme {
+    fn name(&self) -> &'static str {
+        "nvme"
+    }
+
+    fn function(&self) -> &'static PciFunction {
+        self.function
+    }
+
+    unsafe fn init(
+        &self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<(), DriverError> {
+        let bar = self.function.bars[0].ok_or(DriverError::MissingBar(0))?;
+        let base = bar::map(mapper, frame_allocator, &bar).ok_or(DriverError::MissingBar(0))?;
+        self.function
+            .enable(COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+
+        let caps = CapabilitiesReg::from_bytes(
+            read_volatile((base + Register::Capabilities as u64) as *const u64).to_le_bytes(),
+        );
+        if caps.css() & 1 == 0 {
+            return Err(DriverError::Device("no NVM command set"));
+        }
+        if caps.mpsmin() != 0 {
+            return Err(DriverError::Device("4 KiB pages are not supported"));
+        }
+        let max_entries = caps.mqes() as u32 + 1;
+        let controller = self.controller.call_once(|| Controller {
+            base,
+            stride: 4 << caps.dstrd(),
+            timeout_us: (caps.to() as u64).max(1) * 500_000,
+        });
+
+        // Admin queue
+        controller.disable()?;
+        let size = (ADMIN_QUEUE_SIZE as u32).min(max_entries) as u16;
+        let admin = Queue::new(frame_allocator, controller, 0, size)?;
+        controller.write32(
+            Register::AdminQueueAttributes,
+            (size as u32 - 1) << 16 | (size as u32 - 1),
+        );
+        controller.write64(Register::AdminSubmissionQueue, admin.sq);
+        controller.write64(Register::AdminCompletionQueue, admin.cq);
+        *self.admin.lock() = Some(admin);
+        controller.enable()?;
+
+        let identify = frame_allocator
+            .allocate_dma(PAGE_SIZE)
+            .ok_or(DriverError::OutOfMemory)?;
+        let namespace = self.identify(identify)?;
+        self.namespace.call_once(|| namespace);
+
+        // One I/O queue pair per core, each with its own MSI-X entry after
+        // the one of the admin queue
+        let cores = smp::num_cores();
+        let msix = MsiX::new(mapper, frame_allocator, self.function)
+            .ok()
+            .filter(|msix| msix.entries() > 1);
+        let mut wanted = cores.max(1) as u32;
+        if let Some(msix) = msix {
+            wanted = wanted.min(msix.entries() as u32 - 1);
+        }
+        let granted = self
+            .admin(Command {
+                opcode: AdminOpcode::SetFeatures as u8,
+                cdw10: FEATURE_NUMBER_OF_QUEUES,
+                cdw11: (wanted - 1) << 16 | (wanted - 1),
+                ..Default::default()
+            })?
+            .result;
+        let count = wanted.min((granted & 0xffff) + 1).min((granted >> 16) + 1);
+
+        let size = (IO_QUEUE_SIZE as u32).min(max_entries) as u16;
+        let mut queues = Vec::new();
+        for index in 0..count as usize {
+            let id = index as u16 + 1;
+            let inner =
+                self.create_io_queue(frame_allocator, controller, id, size, msix.is_some())?;
+            queues.push(IoQueue {
+                core: index,
+                entry: id,
+                vector: AtomicU8::new(0),
+                interrupts: AtomicU64::new(0),
+                inner: Mutex::new("nvme_io", inner),
+            });
+        }
+
+        // Cores without a queue of their own share one
+        let mut by_core = [0; bootloader::MAX_CORES];
+        for (index, queue) in by_core.iter_mut().take(cores).enumerate() {
+            *queue = (index % queues.len()) as u8;
+        }
+        let io = self.io.call_once(|| Io {
+            queues,
+            by_core,
+            msix,
+        });
+
+        // The queues don't move anymore, so the handlers can point to them
+        if let Some(msix) = io.msix {
+            for queue in io.queues.iter() {
+                let vector = msix
+                    .route(
+                        queue.entry,
+                        smp::apic_id_of(queue.core).unwrap(),
+                        completion_interrupt,
+                        queue as *const IoQueue as usize,
+                    )
+                    .map_err(DriverError::Interrupt)?;
+                queue.vector.store(vector, Ordering::Relaxed);
+            }
+            msix.enable();
+        }
+        self.polled.store(io.msix.is_none(), Ordering::Relaxed);
+
+        log::info!(
+            "nvme {}: {} blocks of {} bytes, {} I/O queues, {}",
+            self.function.address,
+            namespace.blocks,
+            namespace.block_size,
+            io.queues.len(),
+            if io.msix.is_some() { "MSI-X" } else { "polled" }
+        );
+        Ok(())
+    }
+
+    unsafe fn purge(&self) {
+        if let Some(io) = self.io.get() {
+            if let Some(msix) = io.msix {
+                msix.disable();
+            }
+            for queue in io.queues.iter() {
+                let vector = queue.vector.swap(0, Ordering::Relaxed);
+                if vector != 0 {
+                    crate::interrupts::free_vector(vector);
+                }
+            }
+        }
+        if let Some(controller) = self.controller.get() {
+            controller.disable().ok();
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use modular_bitfield::prelude::*;
+
+/// NVMe controller registers (offsets into BAR0)
+#[derive(Clone, Copy)]
+#[repr(usize)]
+pub enum Register {
+    Capabilities = 0x00,
+    Version = 0x08,
+    InterruptMaskSet = 0x0c,
+    InterruptMaskClear = 0x10,
+    ControllerConfig = 0x14,
+    ControllerStatus = 0x1c,
+    AdminQueueAttributes = 0x24,
+    AdminSubmissionQueue = 0x28,
+    AdminCompletionQueue = 0x30,
+}
+
+/// Offset of the first doorbell
+pub const DOORBELL_BASE: u64 = 0x1000;
+
+/// Offset of the submission queue tail doorbell of `queue`
+pub const fn sq_tail_doorbell(queue: u16, stride: u64) -> u64 {
+    DOORBELL_BASE + (2 * queue as u64) * stride
+}
+
+/// Offset of the completion queue head doorbell of `queue`
+pub const fn cq_head_doorbell(queue: u16, stride: u64) -> u64 {
+    DOORBELL_BASE + (2 * queue as u64 + 1) * stride
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct CapabilitiesReg {
+    /// Maximum queue entries supported, 0 based
+    pub mqes: B16,
+    /// Queues have to be physically contiguous
+    pub cqr: B1,
+    pub ams: B2,
+    pub res0: B5,
+    /// Worst case time to become ready in 500 ms units
+    pub to: B8,
+    /// Doorbell stride is 2 ^ (2 + dstrd) bytes
+    pub dstrd: B4,
+    pub nssrs: B1,
+    /// Supported command sets, bit 0 is the NVM command set
+    pub css: B8,
+    pub bps: B1,
+    pub res1: B2,
+    /// Minimum memory page size is 2 ^ (12 + mpsmin)
+    pub mpsmin: B4,
+    pub mpsmax: B4,
+    pub res2: B8,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct ControllerConfigReg {
+    pub en: B1,
+    pub res0: B3,
+    /// 0 NVM command set
+    pub css: B3,
+    /// Memory page size is 2 ^ (12 + mps)
+    pub mps: B4,
+    pub ams: B3,
+    /// Shutdown notification, 0b01 normal
+    pub shn: B2,
+    /// Submission queue entry size as a power of two
+    pub iosqes: B4,
+    /// Completion queue entry size as a power of two
+    pub iocqes: B4,
+    pub res1: B8,
+}
+
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct ControllerStatusReg {
+    pub rdy: B1,
+    /// Controller fatal status
+    pub cfs: B1,
+    /// Shutdown status, 0b10 complete
+    pub shst: B2,
+    pub nssro: B1,
+    pub pp: B1,
+    pub res0: B26,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum AdminOpcode {
+    DeleteIoSq = 0x00,
+    CreateIoSq = 0x01,
+    DeleteIoCq = 0x04,
+    CreateIoCq = 0x05,
+    Identify = 0x06,
+    SetFeatures = 0x09,
+}
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+pub enum IoOpcode {
+    Flush = 0x00,
+    Write = 0x01,
+    Read = 0x02,
+}
+
+/// Identify data structure selected in cdw10
+pub const IDENTIFY_NAMESPACE: u32 = 0x00;
+pub const IDENTIFY_CONTROLLER: u32 = 0x01;
+
+/// Feature id of the number of I/O queues
+pub const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// Submission queue entry
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Command {
+    pub opcode: u8,
+    pub flags: u8,
+    pub cid: u16,
+    pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub mptr: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+/// Completion queue entry
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Completion {
+    /// Command specific result
+    pub result: u32,
+    pub res0: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub cid: u16,
+    /// Phase tag in bit 0, status code and type above
+    pub status: u16,
+}
+
+impl Completion {
+    pub fn phase(&self) -> bool {
+        self.status & 1 != 0
+    }
+
+    /// Status code type and status code, 0 on success
+    pub fn status_code(&self) -> u16 {
+        (self.status >> 1) & 0x7ff
+    }
+}
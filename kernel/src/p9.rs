@@ -0,0 +1,513 @@
+//! 9P2000.L client for host directories shared with `-virtfs`.
+//! A `Client` attaches to the export of a virtio-9p device and walks
+//! paths relative to its root. Every open file or directory holds a fid
+//! on the server which is clunked when the `File` is dropped. Requests
+//! are sent one at a time and polled by the transport.
+
+pub mod wire;
+
+use crate::virtio::p9::{VirtioP9, MSIZE};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use wire::{Qid, Reader, Writer};
+
+const VERSION: &str = "9P2000.L";
+
+/// Tag of Tversion
+const NOTAG: u16 = 0xffff;
+
+/// Tag of all other requests, only one is in flight
+const TAG: u16 = 0;
+
+const NOFID: u32 = 0xffff_ffff;
+
+/// Fid of the root of the export
+const ROOT_FID: u32 = 0;
+
+/// Names per Twalk
+const MAX_WALK: usize = 16;
+
+/// Header of Rread and Twrite in front of the data
+const IO_HEADER: u32 = 24;
+
+/// Fields of Tgetattr up to the size
+const GETATTR_BASIC: u64 = 0x7ff;
+
+/// Open flags, the Linux values
+pub const O_RDONLY: u32 = 0o0;
+pub const O_WRONLY: u32 = 0o1;
+pub const O_RDWR: u32 = 0o2;
+pub const O_CREAT: u32 = 0o100;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+pub const O_DIRECTORY: u32 = 0o200000;
+
+/// Errno of the server when a path does not exist
+pub const ENOENT: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum P9Error {
+    /// No virtio-9p device with the requested tag
+    NoDevice,
+    NotInitialized,
+    /// The server did not answer in time
+    Timeout,
+    /// Malformed or unexpected response
+    Protocol,
+    /// The request does not fit into a message
+    TooLarge,
+    /// Rlerror with a Linux errno
+    Errno(u32),
+}
+
+/// Attributes of a file returned by Tgetattr
+#[derive(Debug, Clone, Copy)]
+pub struct Attr {
+    pub qid: Qid,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    pub size: u64,
+}
+
+impl Attr {
+    pub fn is_dir(&self) -> bool {
+        self.qid.is_dir()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub qid: Qid,
+    /// Linux `d_type` of the entry
+    pub kind: u8,
+    pub name: String,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.qid.is_dir()
+    }
+}
+
+pub struct Client {
+    device: Arc<VirtioP9>,
+    msize: u32,
+    next_fid: AtomicU32,
+}
+
+/// Attach to the export of the virtio-9p device with `tag`, or the first
+/// one if `tag` is None
+pub fn mount(tag: Option<&str>) -> Result<Arc<Client>, P9Error> {
+    let device = crate::pci::devices::<VirtioP9>()
+        .into_iter()
+        .find(|device| tag.map_or(true, |tag| device.tag() == tag))
+        .ok_or(P9Error::NoDevice)?;
+    let client = Client::attach(device)?;
+    log::info!(
+        "Mounted 9P export {:?} with msize {}",
+        client.device.tag(),
+        client.msize
+    );
+    Ok(Arc::new(client))
+}
+
+/// Split `path` into its non empty components
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+}
+
+/// Split `path` into the path of its parent and its name
+fn split_parent(path: &str) -> Result<(&str, &str), P9Error> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rfind('/') {
+        Some(index) => (&path[..index], &path[index + 1..]),
+        None => ("", path),
+    };
+    if name.is_empty() {
+        return Err(P9Error::Errno(ENOENT));
+    }
+    Ok((parent, name))
+}
+
+impl Client {
+    fn attach(device: Arc<VirtioP9>) -> Result<Self, P9Error> {
+        let msize = device.transact(
+            |buf| {
+                Writer::new(buf, wire::TVERSION, NOTAG)?
+                    .u32(MSIZE)?
+                    .str(VERSION)?
+                    .finish()
+            },
+            |buf| {
+                let mut reader = Reader::new(buf, wire::TVERSION, NOTAG)?;
+                let msize = reader.u32()?;
+                // Reads and writes need room for data behind their header
+                if msize <= IO_HEADER || reader.str()? != VERSION {
+                    return Err(P9Error::Protocol);
+                }
+                Ok(msize.min(MSIZE))
+            },
+        )?;
+
+        let client = Client {
+            device,
+            msize,
+            next_fid: AtomicU32::new(ROOT_FID + 1),
+        };
+        client.device.transact(
+            |buf| {
+                Writer::new(buf, wire::TATTACH, TAG)?
+                    .u32(ROOT_FID)?
+                    .u32(NOFID)?
+                    .str("root")?
+                    .str("")?
+                    .u32(0)?
+                    .finish()
+            },
+            |buf| Reader::new(buf, wire::TATTACH, TAG)?.qid(),
+        )?;
+        Ok(client)
+    }
+
+    /// Largest payload of a read or write
+    fn iounit(&self) -> u32 {
+        self.msize - IO_HEADER
+    }
+
+    fn allocate_fid(&self) -> u32 {
+        self.next_fid.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Send a request which only has a fid and an empty response
+    fn fid_request(&self, kind: u8, fid: u32) -> Result<(), P9Error> {
+        self.device.transact(
+            |buf| Writer::new(buf, kind, TAG)?.u32(fid)?.finish(),
+            |buf| Reader::new(buf, kind, TAG).map(|_| ()),
+        )
+    }
+
+    fn clunk(&self, fid: u32) -> Result<(), P9Error> {
+        self.fid_request(wire::TCLUNK, fid)
+    }
+
+    /// A new fid for `path`, relative to the root
+    fn walk(&self, path: &str) -> Result<u32, P9Error> {
+        let names: Vec<&str> = components(path).collect();
+        let fid = self.allocate_fid();
+        let mut from = ROOT_FID;
+        let mut chunks = names.chunks(MAX_WALK);
+        // An empty walk clones the root
+        let mut chunk: &[&str] = chunks.next().unwrap_or(&[]);
+        loop {
+            let walked = self.device.transact(
+                |buf| {
+                    let mut writer = Writer::new(buf, wire::TWALK, TAG)?;
+                    writer.u32(from)?.u32(fid)?.u16(chunk.len() as u16)?;
+                    for name in chunk {
+                        writer.str(name)?;
+                    }
+                    writer.finish()
+                },
+                |buf| Ok(Reader::new(buf, wire::TWALK, TAG)?.u16()? as usize),
+            );
+            match walked {
+                // The newfid is only valid if all names were walked
+                Ok(walked) if walked == chunk.len() => {}
+                Ok(_) => {
+                    if from == fid {
+                        self.clunk(fid).ok();
+                    }
+                    return Err(P9Error::Errno(ENOENT));
+                }
+                Err(err) => {
+                    if from == fid {
+                        self.clunk(fid).ok();
+                    }
+                    return Err(err);
+                }
+            }
+            from = fid;
+            chunk = match chunks.next() {
+                Some(chunk) => chunk,
+                None => return Ok(fid),
+            };
+        }
+    }
+
+    fn getattr(&self, fid: u32) -> Result<Attr, P9Error> {
+        self.device.transact(
+            |buf| {
+                Writer::new(buf, wire::TGETATTR, TAG)?
+                    .u32(fid)?
+                    .u64(GETATTR_BASIC)?
+                    .finish()
+            },
+            |buf| {
+                let mut reader = Reader::new(buf, wire::TGETATTR, TAG)?;
+                let _valid = reader.u64()?;
+                let qid = reader.qid()?;
+                let mode = reader.u32()?;
+                let uid = reader.u32()?;
+                let gid = reader.u32()?;
+                let nlink = reader.u64()?;
+                let _rdev = reader.u64()?;
+                let size = reader.u64()?;
+                Ok(Attr {
+                    qid,
+                    mode,
+                    uid,
+                    gid,
+                    nlink,
+                    size,
+                })
+            },
+        )
+    }
+
+    /// Attributes of `path`
+    pub fn stat(&self, path: &str) -> Result<Attr, P9Error> {
+        let fid = self.walk(path)?;
+        let attr = self.getattr(fid);
+        self.clunk(fid)?;
+        attr
+    }
+
+    /// Open the existing file `path` with the `O_*` flags
+    pub fn open(self: &Arc<Self>, path: &str, flags: u32) -> Result<File, P9Error> {
+        let fid = self.walk(path)?;
+        let opened = self.device.transact(
+            |buf| {
+                Writer::new(buf, wire::TLOPEN, TAG)?
+                    .u32(fid)?
+                    .u32(flags & !O_CREAT)?
+                    .finish()
+            },
+            |buf| {
+                let mut reader = Reader::new(buf, wire::TLOPEN, TAG)?;
+                Ok((reader.qid()?, reader.u32()?))
+            },
+        );
+        match opened {
+            Ok((qid, iounit)) => Ok(File::new(self, fid, qid, iounit)),
+            Err(err) => {
+                self.clunk(fid).ok();
+                Err(err)
+            }
+        }
+    }
+
+    /// Create the file `path` with the permission bits `mode` and open it
+    /// with the `O_*` flags
+    pub fn create(self: &Arc<Self>, path: &str, flags: u32, mode: u32) -> Result<File, P9Error> {
+        let (parent, name) = split_parent(path)?;
+        // Tlcreate turns the fid of the directory into the one of the file
+        let fid = self.walk(parent)?;
+        let created = self.device.transact(
+            |buf| {
+                Writer::new(buf, wire::TLCREATE, TAG)?
+                    .u32(fid)?
+                    .str(name)?
+                    .u32(flags | O_CREAT)?
+                    .u32(mode)?
+                    .u32(0)?
+                    .finish()
+            },
+            |buf| {
+                let mut reader = Reader::new(buf, wire::TLCREATE, TAG)?;
+                Ok((reader.qid()?, reader.u32()?))
+            },
+        );
+        match created {
+            Ok((qid, iounit)) => Ok(File::new(self, fid, qid, iounit)),
+            Err(err) => {
+                self.clunk(fid).ok();
+                Err(err)
+            }
+        }
+    }
+
+    /// Create the directory `path` with the permission bits `mode`
+    pub fn mkdir(&self, path: &str, mode: u32) -> Result<(), P9Error> {
+        let (parent, name) = split_parent(path)?;
+        let fid = self.walk(parent)?;
+        let created = self.device.transact(
+            |buf| {
+                Writer::new(buf, wire::TMKDIR, TAG)?
+                    .u32(fid)?
+                    .str(name)?
+                    .u32(mode)?
+                    .u32(0)?
+                    .finish()
+            },
+            |buf| Reader::new(buf, wire::TMKDIR, TAG)?.qid(),
+        );
+        self.clunk(fid)?;
+        created.map(|_| ())
+    }
+
+    /// Entries of the directory `path` without `.` and `..`
+    pub fn read_dir(self: &Arc<Self>, path: &str) -> Result<Vec<DirEntry>, P9Error> {
+        let dir = self.open(path, O_RDONLY | O_DIRECTORY)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let start = offset;
+            let count = self.device.transact(
+                |buf| {
+                    Writer::new(buf, wire::TREADDIR, TAG)?
+                        .u32(dir.fid)?
+                        .u64(start)?
+                        .u32(self.iounit())?
+                        .finish()
+                },
+                |buf| {
+                    let mut reader = Reader::new(buf, wire::TREADDIR, TAG)?;
+                    let count = reader.u32()? as usize;
+                    let mut data = Reader::raw(reader.bytes(count)?);
+                    while data.remaining() > 0 {
+                        let qid = data.qid()?;
+                        offset = data.u64()?;
+                        let kind = data.u8()?;
+                        let name = data.str()?;
+                        if name != "." && name != ".." {
+                            entries.push(DirEntry {
+                                qid,
+                                kind,
+                                name: String::from(name),
+                            });
+                        }
+                    }
+                    Ok(count)
+                },
+            )?;
+            if count == 0 {
+                return Ok(entries);
+            }
+        }
+    }
+
+    /// Contents of the file `path`
+    pub fn read_file(self: &Arc<Self>, path: &str) -> Result<Vec<u8>, P9Error> {
+        let mut file = self.open(path, O_RDONLY)?;
+        let mut data = Vec::new();
+        let mut chunk = alloc::vec![0; self.iounit() as usize];
+        loop {
+            let read = file.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    /// Replace the contents of `path` with `data`, creating it if needed
+    pub fn write_file(self: &Arc<Self>, path: &str, data: &[u8]) -> Result<(), P9Error> {
+        let mut file = match self.open(path, O_WRONLY | O_TRUNC) {
+            Err(P9Error::Errno(ENOENT)) => self.create(path, O_WRONLY | O_TRUNC, 0o644)?,
+            file => file?,
+        };
+        file.write(data).map(|_| ())
+    }
+}
+
+/// An open file or directory. The fid is clunked on drop.
+pub struct File {
+    client: Arc<Client>,
+    fid: u32,
+    qid: Qid,
+    iounit: u32,
+    offset: u64,
+}
+
+impl File {
+    fn new(client: &Arc<Client>, fid: u32, qid: Qid, iounit: u32) -> Self {
+        // An iounit of 0 means the message size limits the transfer
+        let iounit = match iounit {
+            0 => client.iounit(),
+            iounit => iounit.min(client.iounit()),
+        };
+        File {
+            client: client.clone(),
+            fid,
+            qid,
+            iounit,
+            offset: 0,
+        }
+    }
+
+    pub fn qid(&self) -> Qid {
+        self.qid
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn seek(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+
+    pub fn stat(&self) -> Result<Attr, P9Error> {
+        self.client.getattr(self.fid)
+    }
+
+    /// Read at the current offset with a single request. Returns 0 at the
+    /// end of the file.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, P9Error> {
+        let count = buf.len().min(self.iounit as usize) as u32;
+        let read = self.client.device.transact(
+            |request| {
+                Writer::new(request, wire::TREAD, TAG)?
+                    .u32(self.fid)?
+                    .u64(self.offset)?
+                    .u32(count)?
+                    .finish()
+            },
+            |response| {
+                let mut reader = Reader::new(response, wire::TREAD, TAG)?;
+                let read = reader.u32()?.min(count) as usize;
+                buf[..read].copy_from_slice(reader.bytes(read)?);
+                Ok(read)
+            },
+        )?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+
+    /// Write all of `buf` at the current offset
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, P9Error> {
+        let mut written = 0;
+        for chunk in buf.chunks(self.iounit as usize) {
+            let count = self.client.device.transact(
+                |request| {
+                    Writer::new(request, wire::TWRITE, TAG)?
+                        .u32(self.fid)?
+                        .u64(self.offset)?
+                        .u32(chunk.len() as u32)?
+                        .bytes(chunk)?
+                        .finish()
+                },
+                |response| Reader::new(response, wire::TWRITE, TAG)?.u32(),
+            )?;
+            self.offset += count as u64;
+            written += count as usize;
+            if count as usize != chunk.len() {
+                break;
+            }
+        }
+        Ok(written)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        if let Err(err) = self.client.clunk(self.fid) {
+            log::warn!("Failed to clunk fid {}: {:?}", self.fid, err);
+        }
+    }
+}
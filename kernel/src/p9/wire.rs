@@ -0,0 +1,175 @@
+//! Encoding of 9P messages.
+//! Every message starts with its size, type and tag. Integers are little
+//! endian, strings are prefixed with their length as u16.
+
+use super::P9Error;
+
+/// size[4] type[1] tag[2]
+pub const HEADER: usize = 7;
+
+/// type[1] version[4] path[8]
+pub const QID_SIZE: usize = 13;
+
+/// Message types of 9P2000.L and the 9P2000 ones it reuses
+pub const RLERROR: u8 = 7;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TGETATTR: u8 = 24;
+pub const TREADDIR: u8 = 40;
+pub const TMKDIR: u8 = 72;
+pub const TVERSION: u8 = 100;
+pub const TATTACH: u8 = 104;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    pub const DIR: u8 = 0x80;
+
+    pub fn is_dir(&self) -> bool {
+        self.kind & Self::DIR != 0
+    }
+}
+
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    /// Start a message of `kind` with `tag`, the size is filled in by `finish`
+    pub fn new(buf: &'a mut [u8], kind: u8, tag: u16) -> Result<Self, P9Error> {
+        let mut writer = Writer { buf, pos: 4 };
+        writer.u8(kind)?;
+        writer.u16(tag)?;
+        Ok(writer)
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) -> Result<&mut Self, P9Error> {
+        let end = self.pos + data.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(P9Error::TooLarge)?
+            .copy_from_slice(data);
+        self.pos = end;
+        Ok(self)
+    }
+
+    pub fn u8(&mut self, value: u8) -> Result<&mut Self, P9Error> {
+        self.bytes(&[value])
+    }
+
+    pub fn u16(&mut self, value: u16) -> Result<&mut Self, P9Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> Result<&mut Self, P9Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> Result<&mut Self, P9Error> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn str(&mut self, value: &str) -> Result<&mut Self, P9Error> {
+        if value.len() > u16::MAX as usize {
+            return Err(P9Error::TooLarge);
+        }
+        self.u16(value.len() as u16)?;
+        self.bytes(value.as_bytes())
+    }
+
+    /// Write the size and return the length of the message
+    pub fn finish(&mut self) -> Result<usize, P9Error> {
+        self.buf[..4].copy_from_slice(&(self.pos as u32).to_le_bytes());
+        Ok(self.pos)
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Check the header of a response, an Rlerror becomes `P9Error::Errno`
+    pub fn new(buf: &'a [u8], kind: u8, tag: u16) -> Result<Self, P9Error> {
+        let mut reader = Reader { buf, pos: 0 };
+        let size = reader.u32()? as usize;
+        if size < HEADER || size > buf.len() {
+            return Err(P9Error::Protocol);
+        }
+        reader.buf = &buf[..size];
+        let response = reader.u8()?;
+        if reader.u16()? != tag {
+            return Err(P9Error::Protocol);
+        }
+        match response {
+            RLERROR => Err(P9Error::Errno(reader.u32()?)),
+            // Responses are the request type plus one
+            response if response == kind + 1 => Ok(reader),
+            _ => Err(P9Error::Protocol),
+        }
+    }
+
+    /// Read fields of a payload without a message header
+    pub fn raw(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], P9Error> {
+        let data = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(P9Error::Protocol)?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    pub fn u8(&mut self) -> Result<u8, P9Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, P9Error> {
+        let mut value = [0; 2];
+        value.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(value))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, P9Error> {
+        let mut value = [0; 4];
+        value.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(value))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, P9Error> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, P9Error> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.bytes(len)?).map_err(|_| P9Error::Protocol)
+    }
+
+    pub fn qid(&mut self) -> Result<Qid, P9Error> {
+        Ok(Qid {
+            kind: self.u8()?,
+            version: self.u32()?,
+            path: self.u64()?,
+        })
+    }
+}
@@ -1,13 +1,30 @@
+//! PCI and PCIe enumeration.
+//! Walks the bus hierarchy from the root buses through all PCI-to-PCI
+//! bridges and sizes the BARs of every function found, so drivers get
+//! the resources of their device without touching configuration space.
+
+pub mod bar;
+pub mod capability;
+pub mod config;
+pub mod msi;
+
+use crate::acpi::Acpi;
+use crate::lock::Mutex;
+use crate::memory::BootInfoFrameAllocator;
+use alloc::collections::BTreeSet;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use bar::Bar;
 use core::any::Any;
+use core::fmt;
 use core::mem::size_of;
-use spin;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB};
 
 /// Trait which allows for converting to an Any
 pub trait AsAny {
     fn as_any(&self) -> &(dyn Any + 'static);
     fn as_any_mut(&mut self) -> &mut (dyn Any + 'static);
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn Any + Send + Sync + 'static>;
 }
 
 /// Implement AsAny for any T that implements Plugin and 'static
@@ -21,11 +38,30 @@ impl<T: Device + 'static> AsAny for T {
     fn as_any_mut(&mut self) -> &mut (dyn Any + 'static) {
         self
     }
+
+    /// Convert a shared Plugin to a shared Any, for `Arc::downcast`
+    fn as_any_arc(self: Arc<Self>) -> Arc<dyn Any + Send + Sync + 'static> {
+        self
+    }
 }
 
 /// An driver for a device. There are multiple instances of a driver for each
 /// device the driver handled during the probe process.
-pub trait Device: Send + Sync {
+pub trait Device: AsAny + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The function the driver was bound to
+    fn function(&self) -> &'static PciFunction;
+
+    /// Invoked once on the BSP after all drivers are probed. Mapping BARs,
+    /// allocating DMA memory and setting up interrupts happens here. Devices
+    /// which fail are removed from `DEVICES`.
+    unsafe fn init(
+        &self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<(), DriverError>;
+
     /// Invoked on a device when we're doing a soft reboot. This may be called
     /// from an exceptionally hostile environment (eg. inside of a panic inside
     /// of an NMI exception). The goal of this function for a driver is to
@@ -40,29 +76,124 @@ pub trait Device: Send + Sync {
     ///
     /// This will be invoked on the device regardless of locks, thus the
     /// device needs to be able to handle that a previous user of the device
-    /// may have been interrupted mid-use.
+    /// may have been interrupted mid-use. Drivers keep what they need here
+    /// outside of their locks, set once by `init`.
     unsafe fn purge(&self);
 }
-type ProbeFunction = fn(&PciDevice, addr: u32) -> Option<()>;
 
-/// List of all driver probe routines on the system. If they return `Some` then
-/// we successfully found a driver and thus we'll register it in the
-/// `DEVICES` database
-const DRIVERS: &[ProbeFunction] = &[];
+/// Creates a driver instance for a function matched by the driver. Returns
+/// None if the driver can not handle the function after all.
+type ProbeFunction = fn(&'static PciFunction) -> Option<Arc<dyn Device>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverError {
+    /// A BAR the driver needs is missing or of the wrong kind
+    MissingBar(u8),
+    /// A capability the driver needs is missing
+    MissingCapability(&'static str),
+    /// Out of memory for rings or buffers
+    OutOfMemory,
+    /// The device did not respond as expected
+    Device(&'static str),
+    /// Interrupt setup failed
+    Interrupt(msi::MsiError),
+}
+
+/// Functions a driver handles
+#[derive(Debug, Clone, Copy)]
+pub enum Match {
+    Id {
+        vendor_id: u16,
+        device_id: u16,
+    },
+    Class {
+        class: u8,
+        subclass: u8,
+        prog_if: Option<u8>,
+    },
+}
+
+impl Match {
+    fn matches(&self, function: &PciFunction) -> bool {
+        let header = &function.header;
+        match *self {
+            Match::Id {
+                vendor_id,
+                device_id,
+            } => header.vendor_id == vendor_id && header.device_id == device_id,
+            Match::Class {
+                class,
+                subclass,
+                prog_if,
+            } => {
+                header.class == class
+                    && header.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| header.prog_if == prog_if)
+            }
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    pub probe: ProbeFunction,
+}
+
+/// List of all drivers on the system. The first driver whose probe routine
+/// returns `Some` for a function is registered in the `DEVICES` database
+const DRIVERS: &[Driver] = &[
+    Driver {
+        name: "virtio-blk",
+        matches: crate::virtio::blk::MATCHES,
+        probe: crate::virtio::blk::probe,
+    },
+    Driver {
+        name: "virtio-net",
+        matches: crate::virtio::net::MATCHES,
+        probe: crate::virtio::net::probe,
+    },
+    Driver {
+        name: "virtio-9p",
+        matches: crate::virtio::p9::MATCHES,
+        probe: crate::virtio::p9::probe,
+    },
+    Driver {
+        name: "nvme",
+        matches: crate::nvme::MATCHES,
+        probe: crate::nvme::probe,
+    },
+    Driver {
+        name: "e1000",
+        matches: crate::e1000::MATCHES,
+        probe: crate::e1000::probe,
+    },
+];
 
 /// If `true` verbose PCI device enumeration will be displayed
 const DEBUG_PCI_DEVICES: bool = false;
 
-/// I/O port for the PCI configuration space window address
-pub const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+/// Offset of the command register in the configuration space
+pub const COMMAND_OFFSET: u16 = 0x04;
+
+/// Bits of the command register
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
 
-/// I/O port for the PCI configuration space window data
-pub const PCI_CONFIG_DATA: u16 = 0xcfc;
+/// Offset of the header type in the configuration space
+const HEADER_TYPE_OFFSET: u16 = 0x0e;
 
-/// Enable bit for accessing the `0xcf8` I/O port
-const PCI_ADDRESS_ENABLE: u32 = 1 << 31;
+/// Offsets of the bus numbers in the configuration space of a bridge
+const SECONDARY_BUS_OFFSET: u16 = 0x19;
+const SUBORDINATE_BUS_OFFSET: u16 = 0x1a;
 
-pub static DEVICES: spin::Mutex<Vec<Arc<()>>> = spin::Mutex::new(Vec::new());
+/// All functions in the order they were found, bridges come before the
+/// functions behind them
+static mut FUNCTIONS: Option<Vec<PciFunction>> = None;
+
+/// Driver instances of all bound functions
+pub static DEVICES: Mutex<Vec<Arc<dyn Device>>> = Mutex::new("pci_devices", Vec::new());
 
 /// Common PCI header for the PCI configuration space of any device or bridge
 #[derive(Clone, Copy, Debug)]
@@ -82,7 +213,7 @@ pub struct PciHeader {
     pub bist: u8,
 }
 
-/// Configuration space for a PCI device
+/// Configuration space for a PCI device, a type 0 header
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct PciDevice {
@@ -107,110 +238,324 @@ pub struct PciDevice {
     pub max_latency: u8,
 }
 
-pub unsafe fn init() {
-    use x86_64::instructions::port::Port;
-    let mut config_port: Port<u32> = Port::new(PCI_CONFIG_ADDRESS);
-    let mut config_data_port: Port<u32> = Port::new(PCI_CONFIG_DATA);
-
-    // Bitmap of present PCI devices
-    let mut pci_enum = [0u64; 256 * 32 * 8 / 64];
-
-    // For each possible bus ID
-    for bus in 0..256 {
-        // For each possible device ID
-        for device in 0..32 {
-            // For each possible function ID
-            for function in 0..8 {
-                // Compute the address to select this BDF combination
-                #[allow(clippy::identity_op)]
-                let pci_addr = (bus << 8) | (device << 3) | (function << 0);
-
-                // Compute the PCI selection address
-                let addr = PCI_ADDRESS_ENABLE | (pci_addr << 8);
-
-                // Select the address and read the device and vendor ID
-                config_port.write(addr);
-                let did_vid = config_data_port.read();
-
-                if did_vid != 0xffff_ffff {
-                    // Set the device present in the PCI enumeration table
-                    let idx = pci_addr / 64;
-                    let bit = pci_addr % 64;
-                    pci_enum[idx as usize] |= 1 << bit;
-                }
+/// Location of a function in the configuration space
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+impl fmt::Debug for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeaderKind {
+    Device,
+    /// PCI-to-PCI bridge with the range of buses behind it
+    Bridge {
+        secondary_bus: u8,
+        subordinate_bus: u8,
+    },
+    CardBus,
+}
+
+/// A function found during enumeration
+#[derive(Clone, Debug)]
+pub struct PciFunction {
+    pub address: PciAddress,
+    pub header: PciHeader,
+    pub kind: HeaderKind,
+    pub bars: [Option<Bar>; 6],
+    pub subsystem_vendor_id: u16,
+    pub subsystem_device_id: u16,
+    /// Offset of the first capability, 0 if there is none
+    pub capabilities: u8,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    /// Index of the bridge in front of this function in `functions()`,
+    /// None for functions on a root bus
+    pub parent: Option<usize>,
+}
+
+impl PciFunction {
+    /// Bridges from the root bus down to and including this function
+    pub fn path(&self) -> Vec<PciAddress> {
+        let mut path = Vec::new();
+        let mut parent = self.parent;
+        while let Some(index) = parent {
+            let bridge = &functions()[index];
+            path.push(bridge.address);
+            parent = bridge.parent;
+        }
+        path.reverse();
+        path.push(self.address);
+        path
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        matches!(self.kind, HeaderKind::Bridge { .. })
+    }
+
+    /// Set `bits` in the command register, like `COMMAND_BUS_MASTER`
+    pub unsafe fn enable(&self, bits: u16) {
+        let command = config::read16(self.address, COMMAND_OFFSET);
+        config::write16(self.address, COMMAND_OFFSET, command | bits);
+    }
+}
+
+impl fmt::Display for PciFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} [{:04x}:{:04x}] class {:02x}{:02x}{:02x}",
+            self.address,
+            self.header.vendor_id,
+            self.header.device_id,
+            self.header.class,
+            self.header.subclass,
+            self.header.prog_if
+        )
+    }
+}
+
+/// All functions found by `init`
+pub fn functions() -> &'static [PciFunction] {
+    unsafe { FUNCTIONS.as_deref().unwrap_or(&[]) }
+}
+
+/// Functions directly behind the bridge at `index` of `functions()`
+pub fn children(index: usize) -> impl Iterator<Item = &'static PciFunction> {
+    functions()
+        .iter()
+        .filter(move |function| function.parent == Some(index))
+}
+
+/// Functions with the given vendor and device id
+pub fn find(vendor_id: u16, device_id: u16) -> impl Iterator<Item = &'static PciFunction> {
+    functions().iter().filter(move |function| {
+        function.header.vendor_id == vendor_id && function.header.device_id == device_id
+    })
+}
+
+/// Read the first `N` dwords of the configuration space into `T`
+unsafe fn read_config<T: Copy, const N: usize>(address: PciAddress) -> T {
+    let mut dwords = [0u32; N];
+    for (rid, register) in dwords.iter_mut().enumerate() {
+        *register = config::read32(address, (rid * size_of::<u32>()) as u16);
+    }
+    core::ptr::read_unaligned(dwords.as_ptr() as *const T)
+}
+
+unsafe fn is_present(address: PciAddress) -> bool {
+    config::read16(address, 0) != 0xffff
+}
+
+unsafe fn read_function(address: PciAddress, parent: Option<usize>) -> PciFunction {
+    let header: PciHeader = read_config::<_, { size_of::<PciHeader>() / 4 }>(address);
+    let mut function = PciFunction {
+        address,
+        header,
+        kind: HeaderKind::CardBus,
+        bars: [None; 6],
+        subsystem_vendor_id: 0,
+        subsystem_device_id: 0,
+        capabilities: 0,
+        interrupt_line: 0,
+        interrupt_pin: 0,
+        parent,
+    };
+
+    // Capability pointer is only valid if the status register says so
+    let has_capabilities = header.status & (1 << 4) != 0;
+    match header.header_type & 0x7f {
+        0 => {
+            let device: PciDevice = read_config::<_, { size_of::<PciDevice>() / 4 }>(address);
+            function.kind = HeaderKind::Device;
+            function.bars = bar::read_bars(address, 6);
+            function.subsystem_vendor_id = device.subsystem_vendor_id;
+            function.subsystem_device_id = device.subsystem_device_id;
+            function.capabilities = if has_capabilities {
+                device.capabilities & !0b11
+            } else {
+                0
+            };
+            function.interrupt_line = device.interrupt_line;
+            function.interrupt_pin = device.interrupt_pin;
+        }
+        1 => {
+            function.kind = HeaderKind::Bridge {
+                secondary_bus: config::read8(address, SECONDARY_BUS_OFFSET),
+                subordinate_bus: config::read8(address, SUBORDINATE_BUS_OFFSET),
+            };
+            function.bars = bar::read_bars(address, 2);
+            // Same offsets as in a type 0 header
+            if has_capabilities {
+                function.capabilities = config::read8(address, 0x34) & !0b11;
             }
+            function.interrupt_line = config::read8(address, 0x3c);
+            function.interrupt_pin = config::read8(address, 0x3d);
         }
+        _ => {}
     }
+    function
+}
 
-    let pci_devices = pci_enum;
+/// Add all functions on `bus` and recursively the ones behind its bridges
+unsafe fn scan_bus(
+    segment: u16,
+    bus: u8,
+    parent: Option<usize>,
+    functions: &mut Vec<PciFunction>,
+    visited: &mut BTreeSet<(u16, u8)>,
+) {
+    // Misconfigured bridges could point back up the hierarchy
+    if !visited.insert((segment, bus)) {
+        return;
+    }
 
-    for (idx, &pci_map) in pci_devices.iter().enumerate() {
-        // No devices here, go to the next `u64`
-        if pci_map == 0 {
+    for device in 0..32 {
+        let first = PciAddress {
+            segment,
+            bus,
+            device,
+            function: 0,
+        };
+        if !is_present(first) {
             continue;
         }
+        let multifunction = config::read8(first, HEADER_TYPE_OFFSET) & 0x80 != 0;
 
-        // There is at least 1 device in this set
-        for bit in 0..64 {
-            // If the bit is not set, no device here, skip it
-            if (pci_map & (1u64 << bit)) == 0 {
+        for function in 0..if multifunction { 8 } else { 1 } {
+            let address = PciAddress { function, ..first };
+            if !is_present(address) {
                 continue;
             }
 
-            // Compute the PCI address for this bit
-            let pci_addr = (idx * 64) | bit;
-
-            // Compute the PCI selection address
-            let addr = PCI_ADDRESS_ENABLE | (pci_addr << 8) as u32;
+            let index = functions.len();
+            let function = read_function(address, parent);
+            let kind = function.kind;
+            if DEBUG_PCI_DEVICES {
+                log::info!("PCI function {} bars: {:x?}", function, function.bars);
+            }
+            functions.push(function);
 
-            // Read the PCI configuration header
-            let mut header = [0u32; size_of::<PciHeader>() / size_of::<u32>()];
-            for (rid, register) in header.iter_mut().enumerate() {
-                // Set the window to the register we want to read and read the
-                // value
-                config_port.write(addr | (rid * size_of::<u32>()) as u32);
-                *register = config_data_port.read();
+            // Bus 0 behind a bridge means the firmware did not configure it
+            if let HeaderKind::Bridge { secondary_bus, .. } = kind {
+                if secondary_bus > bus {
+                    scan_bus(segment, secondary_bus, Some(index), functions, visited);
+                }
             }
+        }
+    }
+}
+
+/// Enumerate all PCI functions and probe the drivers for them. Uses ECAM if
+/// the ACPI tables have an MCFG.
+pub unsafe fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    acpi: &Acpi,
+) {
+    if FUNCTIONS.is_some() {
+        return;
+    }
+    if !config::init(mapper, frame_allocator, acpi) {
+        log::info!("No MCFG found, accessing PCI configuration space through I/O ports");
+    }
 
-            // Convert the header to our `PciHeader` structure
-            let header: PciHeader = core::ptr::read_unaligned(header.as_ptr() as *const PciHeader);
+    let mut found = Vec::new();
+    let mut visited = BTreeSet::new();
+    for (segment, start_bus, end_bus) in config::segments() {
+        scan_bus(segment, start_bus, None, &mut found, &mut visited);
 
-            // Skip non-device PCI entries (skips things like PCI bridges)
-            if (header.header_type & 0x7f) != 0 {
+        // Machines with multiple host bridges have root buses which are not
+        // behind any bridge
+        for bus in start_bus..=end_bus {
+            if visited.contains(&(segment, bus)) {
                 continue;
             }
-
-            // Read the PCI configuration
-            let mut device = [0u32; size_of::<PciDevice>() / size_of::<u32>()];
-            for (rid, register) in device.iter_mut().enumerate() {
-                // Set the window to the register we want to read and read the
-                // value
-                config_port.write(addr | (rid * size_of::<u32>()) as u32);
-                *register = config_data_port.read();
+            let has_device = (0..32).any(|device| {
+                is_present(PciAddress {
+                    segment,
+                    bus,
+                    device,
+                    function: 0,
+                })
+            });
+            if has_device {
+                scan_bus(segment, bus, None, &mut found, &mut visited);
             }
+        }
+    }
 
-            // Convert the device to our `PciDevice` structure
-            let device: PciDevice = core::ptr::read_unaligned(device.as_ptr() as *const PciDevice);
+    for function in found.iter() {
+        log::info!("PCI {}", function);
+    }
+    FUNCTIONS = Some(found);
 
-            if DEBUG_PCI_DEVICES {
-                log::info!(
-                    "PCI device | {:#06x}:{:#06x} | {:#06x}:{:#06x}\n",
-                    device.header.vendor_id,
-                    device.header.device_id,
-                    device.subsystem_vendor_id,
-                    device.subsystem_device_id
-                );
-            }
+    for function in functions() {
+        if function.kind != HeaderKind::Device {
+            continue;
+        }
 
-            // Attempt to find a driver for this device
-            for probe in DRIVERS {
-                if let Some(_driver) = probe(&device, addr) {
-                    // Found a handler, go to the next function during the PCI
-                    // enumeration
-                    //DEVICES.lock().push(driver);
-                    //TODO: Replace () with a driver
-                }
-            }
+        // Attempt to find a driver for this device
+        let driver = DRIVERS
+            .iter()
+            .filter(|driver| driver.matches.iter().any(|m| m.matches(function)))
+            .find_map(|driver| (driver.probe)(function));
+        if let Some(driver) = driver {
+            log::info!("PCI {} bound to {}", function.address, driver.name());
+            DEVICES.lock().push(driver);
         }
     }
 }
+
+/// Initialize all probed drivers, the ones which fail are dropped
+pub unsafe fn init_devices(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) {
+    // Drivers may look up other devices during init, so DEVICES is not
+    // held while they run. It only lists the initialized ones meanwhile.
+    let probed = core::mem::take(&mut *DEVICES.lock());
+    for device in probed {
+        match device.init(mapper, frame_allocator) {
+            Ok(()) => DEVICES.lock().push(device),
+            Err(err) => log::warn!(
+                "Failed to initialize {} at {}: {:?}",
+                device.name(),
+                device.function().address,
+                err
+            ),
+        }
+    }
+}
+
+/// All initialized driver instances of type `T`
+pub fn devices<T: Device + 'static>() -> Vec<Arc<T>> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter_map(|device| device.clone().as_any_arc().downcast::<T>().ok())
+        .collect()
+}
+
+/// The first initialized driver instance of type `T`
+pub fn device<T: Device + 'static>() -> Option<Arc<T>> {
+    devices::<T>().into_iter().next()
+}
@@ -0,0 +1,150 @@
+//! Base address registers.
+//! The size of a BAR is found by writing all ones to it and reading back
+//! which address bits the device lets software change.
+
+use super::{config, PciAddress};
+use x86_64::structures::paging::page_table::PageTableFlags;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::PhysAddr;
+
+/// Offset of the first BAR in the configuration space
+pub const BAR_OFFSET: u16 = 0x10;
+
+/// I/O space and memory space decoding of the command register
+const COMMAND_DECODE: u16 = 0b11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Occupies the following BAR as well
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+
+    /// Physical address of a memory BAR
+    pub fn memory_address(&self) -> Option<u64> {
+        match *self {
+            Bar::Memory { address, .. } => Some(address),
+            Bar::Io { .. } => None,
+        }
+    }
+
+    /// First port of an I/O BAR
+    pub fn io_port(&self) -> Option<u16> {
+        match *self {
+            Bar::Io { port, .. } => Some(port),
+            Bar::Memory { .. } => None,
+        }
+    }
+}
+
+/// Identity map a memory BAR uncached and return its address. Returns None
+/// for I/O BARs and if the BAR can not be mapped, e.g. because it overlaps
+/// memory already mapped with 4 KiB pages.
+pub unsafe fn map(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    bar: &Bar,
+) -> Option<u64> {
+    let address = bar.memory_address()?;
+    let start = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(address));
+    let end = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(address + bar.size() - 1));
+    for frame in PhysFrame::range_inclusive(start, end) {
+        let result = crate::memory::id_map(
+            mapper,
+            frame_allocator,
+            frame,
+            Some(
+                PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_CACHE
+                    | PageTableFlags::NO_EXECUTE
+                    | PageTableFlags::HUGE_PAGE,
+            ),
+        );
+        if let Err(err) = result {
+            log::warn!("Can not map the BAR at {:#x}: {:?}", address, err);
+            return None;
+        }
+    }
+    Some(address)
+}
+
+/// Write all ones to the BAR at `offset` and return the value read back,
+/// the original value is restored
+unsafe fn probe(address: PciAddress, offset: u16) -> (u32, u32) {
+    let original = config::read32(address, offset);
+    config::write32(address, offset, u32::MAX);
+    let mask = config::read32(address, offset);
+    config::write32(address, offset, original);
+    (original, mask)
+}
+
+/// Size the `count` BARs of a function. Decoding is turned off while
+/// sizing so the device does not respond at the probed addresses.
+pub unsafe fn read_bars(address: PciAddress, count: usize) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+
+    let command = config::read16(address, super::COMMAND_OFFSET);
+    config::write16(address, super::COMMAND_OFFSET, command & !COMMAND_DECODE);
+
+    let mut index = 0;
+    while index < count {
+        let offset = BAR_OFFSET + index as u16 * 4;
+        let (low, low_mask) = probe(address, offset);
+
+        if low & 1 == 1 {
+            // I/O space, only the low 16 bits are decoded on x86
+            let mask = low_mask & !0b11 & 0xffff;
+            if mask != 0 {
+                bars[index] = Some(Bar::Io {
+                    port: (low & !0b11) as u16,
+                    size: (!mask & 0xffff) + 1,
+                });
+            }
+            index += 1;
+            continue;
+        }
+
+        let prefetchable = low & (1 << 3) != 0;
+        let is_64bit = (low >> 1) & 0b11 == 0b10;
+        let mut base = (low & !0xf) as u64;
+        let mut mask = (low_mask & !0xf) as u64;
+        if is_64bit && index + 1 < count {
+            let (high, high_mask) = probe(address, offset + 4);
+            base |= (high as u64) << 32;
+            mask |= (high_mask as u64) << 32;
+        }
+
+        // Unimplemented BARs are hardwired to zero
+        if mask != 0 {
+            if mask >> 32 == 0 {
+                mask |= 0xffff_ffff_0000_0000;
+            }
+            bars[index] = Some(Bar::Memory {
+                address: base,
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+                is_64bit,
+            });
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    config::write16(address, super::COMMAND_OFFSET, command);
+    bars
+}
@@ -0,0 +1,212 @@
+//! Capability lists of the configuration space.
+//! Standard capabilities are linked from the capability pointer of the
+//! header and live in the first 256 bytes. Extended capabilities of PCIe
+//! functions start at offset 0x100 and can only be reached through ECAM.
+
+use super::{config, PciFunction};
+use alloc::vec::Vec;
+
+/// Offset of the first extended capability
+const EXTENDED_OFFSET: u16 = 0x100;
+
+/// Guards against malformed lists which loop
+const MAX_CAPABILITIES: usize = 48;
+const MAX_EXTENDED_CAPABILITIES: usize = (4096 - 256) / 8;
+
+/// Standard capability ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CapabilityId {
+    PowerManagement = 0x01,
+    Msi = 0x05,
+    VendorSpecific = 0x09,
+    PciExpress = 0x10,
+    MsiX = 0x11,
+}
+
+/// Extended capability ids
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ExtendedCapabilityId {
+    AdvancedErrorReporting = 0x0001,
+    DeviceSerialNumber = 0x0003,
+    VendorSpecific = 0x000b,
+    SingleRootIoVirtualization = 0x0010,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset in the configuration space
+    pub offset: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    /// Offset in the configuration space
+    pub offset: u16,
+}
+
+/// Device/port type of the PCIe capability
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcieType {
+    Endpoint,
+    LegacyEndpoint,
+    RootPort,
+    UpstreamSwitchPort,
+    DownstreamSwitchPort,
+    PcieToPciBridge,
+    PciToPcieBridge,
+    RootComplexIntegratedEndpoint,
+    RootComplexEventCollector,
+    Unknown(u8),
+}
+
+/// PCI Express capability
+#[derive(Debug, Clone, Copy)]
+pub struct PciExpress {
+    pub offset: u16,
+    pub version: u8,
+    pub device_type: PcieType,
+    /// Negotiated link speed as generation, 1 for 2.5 GT/s
+    pub link_speed: u8,
+    pub link_width: u8,
+}
+
+/// Power management capability
+#[derive(Debug, Clone, Copy)]
+pub struct PowerManagement {
+    pub offset: u16,
+    pub version: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+impl PowerManagement {
+    /// Offset of the control/status register
+    const CONTROL: u16 = 4;
+
+    pub unsafe fn state(&self, function: &PciFunction) -> PowerState {
+        match config::read16(function.address, self.offset + Self::CONTROL) & 0b11 {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Devices need up to 10 ms to leave D3hot, the caller has to wait
+    pub unsafe fn set_state(&self, function: &PciFunction, state: PowerState) {
+        let control = config::read16(function.address, self.offset + Self::CONTROL);
+        config::write16(
+            function.address,
+            self.offset + Self::CONTROL,
+            (control & !0b11) | state as u16,
+        );
+    }
+}
+
+impl PciFunction {
+    /// All standard capabilities of the function
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        let mut offset = self.capabilities as u16;
+        while offset >= 0x40 && capabilities.len() < MAX_CAPABILITIES {
+            let header = unsafe { config::read16(self.address, offset) };
+            capabilities.push(Capability {
+                id: header as u8,
+                offset,
+            });
+            offset = (header >> 8) & 0xfc;
+        }
+        capabilities
+    }
+
+    /// All extended capabilities of the function, empty without ECAM
+    pub fn extended_capabilities(&self) -> Vec<ExtendedCapability> {
+        let mut capabilities = Vec::new();
+        if !config::has_extended_config() || self.pci_express().is_none() {
+            return capabilities;
+        }
+        let mut offset = EXTENDED_OFFSET;
+        while offset >= EXTENDED_OFFSET && capabilities.len() < MAX_EXTENDED_CAPABILITIES {
+            let header = unsafe { config::read32(self.address, offset) };
+            if header == 0 || header == u32::MAX {
+                break;
+            }
+            capabilities.push(ExtendedCapability {
+                id: header as u16,
+                version: ((header >> 16) & 0xf) as u8,
+                offset,
+            });
+            offset = ((header >> 20) & 0xffc) as u16;
+        }
+        capabilities
+    }
+
+    pub fn find_capability(&self, id: CapabilityId) -> Option<Capability> {
+        self.capabilities()
+            .into_iter()
+            .find(|capability| capability.id == id as u8)
+    }
+
+    pub fn find_extended_capability(&self, id: ExtendedCapabilityId) -> Option<ExtendedCapability> {
+        self.extended_capabilities()
+            .into_iter()
+            .find(|capability| capability.id == id as u16)
+    }
+
+    /// Vendor specific capabilities, their layout is up to the vendor
+    pub fn vendor_capabilities(&self) -> impl Iterator<Item = Capability> {
+        self.capabilities()
+            .into_iter()
+            .filter(|capability| capability.id == CapabilityId::VendorSpecific as u8)
+    }
+
+    pub fn pci_express(&self) -> Option<PciExpress> {
+        let offset = self.find_capability(CapabilityId::PciExpress)?.offset;
+        let (capabilities, link_status) = unsafe {
+            (
+                config::read16(self.address, offset + 2),
+                config::read16(self.address, offset + 0x12),
+            )
+        };
+        let device_type = match (capabilities >> 4) & 0xf {
+            0x0 => PcieType::Endpoint,
+            0x1 => PcieType::LegacyEndpoint,
+            0x4 => PcieType::RootPort,
+            0x5 => PcieType::UpstreamSwitchPort,
+            0x6 => PcieType::DownstreamSwitchPort,
+            0x7 => PcieType::PcieToPciBridge,
+            0x8 => PcieType::PciToPcieBridge,
+            0x9 => PcieType::RootComplexIntegratedEndpoint,
+            0xa => PcieType::RootComplexEventCollector,
+            other => PcieType::Unknown(other as u8),
+        };
+        Some(PciExpress {
+            offset,
+            version: (capabilities & 0xf) as u8,
+            device_type,
+            link_speed: (link_status & 0xf) as u8,
+            link_width: ((link_status >> 4) & 0x3f) as u8,
+        })
+    }
+
+    pub fn power_management(&self) -> Option<PowerManagement> {
+        let offset = self.find_capability(CapabilityId::PowerManagement)?.offset;
+        let capabilities = unsafe { config::read16(self.address, offset + 2) };
+        Some(PowerManagement {
+            offset,
+            version: (capabilities & 0b111) as u8,
+        })
+    }
+}
@@ -0,0 +1,187 @@
+//! Access to the configuration space of PCI functions.
+//! Uses the memory mapped ECAM regions described by the MCFG if there are
+//! any and the legacy 0xcf8/0xcfc I/O ports otherwise. Only ECAM reaches
+//! the extended configuration space above 256 bytes.
+
+use super::PciAddress;
+use crate::acpi::Acpi;
+use crate::lock::Mutex;
+use alloc::vec::Vec;
+use core::ptr::{addr_of, read_unaligned, read_volatile, write_volatile};
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::page_table::PageTableFlags;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::PhysAddr;
+
+/// I/O port for the PCI configuration space window address
+pub const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+
+/// I/O port for the PCI configuration space window data
+pub const PCI_CONFIG_DATA: u16 = 0xcfc;
+
+/// Enable bit for accessing the `0xcf8` I/O port
+const PCI_ADDRESS_ENABLE: u32 = 1 << 31;
+
+/// Size of the configuration space reachable through the I/O ports
+pub const LEGACY_CONFIG_SIZE: u16 = 0x100;
+
+/// Size of the configuration space reachable through ECAM
+pub const EXTENDED_CONFIG_SIZE: u16 = 0x1000;
+
+/// The address and data port have to be written back to back
+static PORT_LOCK: Mutex<()> = Mutex::new("pci_config", ());
+
+static mut ECAM: Option<Vec<EcamRegion>> = None;
+
+#[derive(Debug)]
+struct EcamRegion {
+    /// Address of bus 0, even if the region starts at a later bus
+    base: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+/// Map the ECAM regions of the MCFG. Returns false if configuration space
+/// has to be accessed through the I/O ports.
+pub unsafe fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    acpi: &Acpi,
+) -> bool {
+    if ECAM.is_some() {
+        return true;
+    }
+    let entries = match acpi.mcfg.as_ref() {
+        Some(entries) => entries,
+        None => return false,
+    };
+
+    let mut regions = Vec::new();
+    for entry in entries.iter() {
+        let region = EcamRegion {
+            base: read_unaligned(addr_of!(entry.base_address)),
+            segment: read_unaligned(addr_of!(entry.segment)),
+            start_bus: entry.start_bus,
+            end_bus: entry.end_bus,
+        };
+
+        // Every bus has 1 MiB of configuration space
+        let start = region.base + ((region.start_bus as u64) << 20);
+        let end = region.base + ((region.end_bus as u64 + 1) << 20);
+        let start_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(start));
+        let end_frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(end - 1));
+        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+            crate::memory::id_map(
+                mapper,
+                frame_allocator,
+                frame,
+                Some(
+                    PageTableFlags::WRITABLE
+                        | PageTableFlags::NO_CACHE
+                        | PageTableFlags::NO_EXECUTE
+                        | PageTableFlags::HUGE_PAGE,
+                ),
+            )
+            .unwrap();
+        }
+        log::info!("PCI ECAM {:x?}", region);
+        regions.push(region);
+    }
+    ECAM = Some(regions);
+    true
+}
+
+/// Whether the extended configuration space can be accessed
+pub fn has_extended_config() -> bool {
+    unsafe { ECAM.is_some() }
+}
+
+/// Buses of every segment which have configuration space
+pub fn segments() -> Vec<(u16, u8, u8)> {
+    match unsafe { ECAM.as_ref() } {
+        Some(regions) => regions
+            .iter()
+            .map(|region| (region.segment, region.start_bus, region.end_bus))
+            .collect(),
+        None => alloc::vec![(0, 0, 0xff)],
+    }
+}
+
+fn ecam_address(address: PciAddress, offset: u16) -> Option<u64> {
+    let regions = unsafe { ECAM.as_ref()? };
+    let region = regions.iter().find(|region| {
+        region.segment == address.segment
+            && (region.start_bus..=region.end_bus).contains(&address.bus)
+    })?;
+    Some(
+        region.base
+            + ((address.bus as u64) << 20)
+            + ((address.device as u64) << 15)
+            + ((address.function as u64) << 12)
+            + offset as u64,
+    )
+}
+
+fn port_address(address: PciAddress, offset: u16) -> u32 {
+    PCI_ADDRESS_ENABLE
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xfc)
+}
+
+/// Read the aligned dword at `offset`. Returns all ones for offsets which
+/// can not be reached, like absent functions do.
+pub unsafe fn read32(address: PciAddress, offset: u16) -> u32 {
+    debug_assert!(offset % 4 == 0);
+    if let Some(ecam) = ecam_address(address, offset) {
+        return read_volatile(ecam as *const u32);
+    }
+    if address.segment != 0 || offset >= LEGACY_CONFIG_SIZE {
+        return u32::MAX;
+    }
+
+    let _guard = PORT_LOCK.lock();
+    Port::<u32>::new(PCI_CONFIG_ADDRESS).write(port_address(address, offset));
+    Port::<u32>::new(PCI_CONFIG_DATA).read()
+}
+
+/// Write the aligned dword at `offset`. Writes which can not be reached are
+/// dropped.
+pub unsafe fn write32(address: PciAddress, offset: u16, value: u32) {
+    debug_assert!(offset % 4 == 0);
+    if let Some(ecam) = ecam_address(address, offset) {
+        write_volatile(ecam as *mut u32, value);
+        return;
+    }
+    if address.segment != 0 || offset >= LEGACY_CONFIG_SIZE {
+        return;
+    }
+
+    let _guard = PORT_LOCK.lock();
+    Port::<u32>::new(PCI_CONFIG_ADDRESS).write(port_address(address, offset));
+    Port::<u32>::new(PCI_CONFIG_DATA).write(value);
+}
+
+pub unsafe fn read16(address: PciAddress, offset: u16) -> u16 {
+    (read32(address, offset & !3) >> ((offset & 2) * 8)) as u16
+}
+
+pub unsafe fn read8(address: PciAddress, offset: u16) -> u8 {
+    (read32(address, offset & !3) >> ((offset & 3) * 8)) as u8
+}
+
+/// Read-modify-write of the containing dword
+pub unsafe fn write16(address: PciAddress, offset: u16, value: u16) {
+    let shift = (offset & 2) * 8;
+    let dword = read32(address, offset & !3) & !(0xffff << shift);
+    write32(address, offset & !3, dword | (value as u32) << shift);
+}
+
+/// Read-modify-write of the containing dword
+pub unsafe fn write8(address: PciAddress, offset: u16, value: u8) {
+    let shift = (offset & 3) * 8;
+    let dword = read32(address, offset & !3) & !(0xff << shift);
+    write32(address, offset & !3, dword | (value as u32) << shift);
+}
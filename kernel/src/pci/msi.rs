@@ -0,0 +1,238 @@
+//! Message signaled interrupts.
+//! MSI and MSI-X let a function interrupt by writing a message to the
+//! local apic address range instead of asserting an INTx line. The
+//! message address selects the destination core and the data the vector,
+//! which is allocated with `interrupts::allocate_vector`.
+
+use super::capability::CapabilityId;
+use super::{bar, config, PciAddress, PciFunction};
+use crate::interrupts::{self, Handler};
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB};
+
+/// Messages to this range are delivered to the local apics
+const MESSAGE_ADDRESS_BASE: u64 = 0xfee0_0000;
+
+/// Disables INTx of the function when set in the command register
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+/// Bytes per entry of the MSI-X table
+const MSIX_ENTRY_SIZE: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function has no MSI or MSI-X capability
+    NotSupported,
+    /// All dynamic vectors are allocated
+    NoFreeVector,
+    /// The MSI-X table has no such entry
+    NoSuchEntry(u16),
+    /// The MSI-X table is not behind a memory BAR
+    InvalidTableBar(u8),
+    /// Physical destinations are 8 bit without interrupt remapping
+    DestinationTooLarge(u32),
+}
+
+/// Address of a message for the local apic `apic_id`, physical destination
+pub fn message_address(apic_id: u32) -> Result<u64, MsiError> {
+    if apic_id > 0xff {
+        return Err(MsiError::DestinationTooLarge(apic_id));
+    }
+    Ok(MESSAGE_ADDRESS_BASE | (apic_id as u64) << 12)
+}
+
+/// Data of a message for `vector`, fixed delivery and edge triggered
+pub fn message_data(vector: u8) -> u32 {
+    vector as u32
+}
+
+unsafe fn disable_intx(address: PciAddress) {
+    let command = config::read16(address, super::COMMAND_OFFSET);
+    config::write16(
+        address,
+        super::COMMAND_OFFSET,
+        command | COMMAND_INTX_DISABLE,
+    );
+}
+
+/// MSI capability, only a single message is used
+#[derive(Debug, Clone, Copy)]
+pub struct Msi {
+    address: PciAddress,
+    offset: u16,
+    is_64bit: bool,
+}
+
+impl Msi {
+    const CONTROL: u16 = 2;
+    const ENABLE: u16 = 1 << 0;
+    const MULTIPLE_MESSAGE_ENABLE: u16 = 0b111 << 4;
+    const ADDRESS_64BIT: u16 = 1 << 7;
+
+    pub fn new(function: &PciFunction) -> Option<Msi> {
+        let offset = function.find_capability(CapabilityId::Msi)?.offset;
+        let control = unsafe { config::read16(function.address, offset + Self::CONTROL) };
+        Some(Msi {
+            address: function.address,
+            offset,
+            is_64bit: control & Self::ADDRESS_64BIT != 0,
+        })
+    }
+
+    /// Send `vector` to the local apic `apic_id` and enable MSI
+    pub unsafe fn enable(&self, vector: u8, apic_id: u32) -> Result<(), MsiError> {
+        let address = message_address(apic_id)?;
+        config::write32(self.address, self.offset + 4, address as u32);
+        let data_offset = if self.is_64bit {
+            config::write32(self.address, self.offset + 8, (address >> 32) as u32);
+            0xc
+        } else {
+            0x8
+        };
+        config::write16(
+            self.address,
+            self.offset + data_offset,
+            message_data(vector) as u16,
+        );
+
+        let control = config::read16(self.address, self.offset + Self::CONTROL);
+        let control = (control & !Self::MULTIPLE_MESSAGE_ENABLE) | Self::ENABLE;
+        config::write16(self.address, self.offset + Self::CONTROL, control);
+        disable_intx(self.address);
+        Ok(())
+    }
+
+    pub unsafe fn disable(&self) {
+        let control = config::read16(self.address, self.offset + Self::CONTROL);
+        config::write16(
+            self.address,
+            self.offset + Self::CONTROL,
+            control & !Self::ENABLE,
+        );
+    }
+
+    /// Allocate a vector for `handler` and deliver it to the local apic
+    /// `apic_id`. Returns the vector.
+    pub unsafe fn route(&self, apic_id: u32, handler: Handler, arg: usize) -> Result<u8, MsiError> {
+        // Check the destination before a vector is allocated
+        message_address(apic_id)?;
+        let vector = interrupts::allocate_vector(handler, arg).ok_or(MsiError::NoFreeVector)?;
+        self.enable(vector, apic_id)?;
+        Ok(vector)
+    }
+}
+
+/// MSI-X capability with its table mapped
+#[derive(Debug, Clone, Copy)]
+pub struct MsiX {
+    address: PciAddress,
+    offset: u16,
+    /// Virtual address of the table, identity mapped
+    table: u64,
+    entries: u16,
+}
+
+impl MsiX {
+    const CONTROL: u16 = 2;
+    const TABLE: u16 = 4;
+    const FUNCTION_MASK: u16 = 1 << 14;
+    const ENABLE: u16 = 1 << 15;
+    const ENTRY_MASKED: u32 = 1 << 0;
+
+    /// Map the table of the MSI-X capability. All entries start out masked.
+    pub unsafe fn new(
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        function: &PciFunction,
+    ) -> Result<MsiX, MsiError> {
+        let offset = function
+            .find_capability(CapabilityId::MsiX)
+            .ok_or(MsiError::NotSupported)?
+            .offset;
+        let control = config::read16(function.address, offset + Self::CONTROL);
+        let table = config::read32(function.address, offset + Self::TABLE);
+
+        let bir = (table & 0b111) as u8;
+        let base = function
+            .bars
+            .get(bir as usize)
+            .copied()
+            .flatten()
+            .and_then(|bar| bar::map(mapper, frame_allocator, &bar))
+            .ok_or(MsiError::InvalidTableBar(bir))?;
+
+        let msix = MsiX {
+            address: function.address,
+            offset,
+            table: base + (table & !0b111) as u64,
+            entries: (control & 0x7ff) + 1,
+        };
+        for entry in 0..msix.entries {
+            msix.set_masked(entry, true)?;
+        }
+        Ok(msix)
+    }
+
+    /// Number of entries in the table
+    pub fn entries(&self) -> u16 {
+        self.entries
+    }
+
+    fn entry(&self, entry: u16) -> Result<*mut u32, MsiError> {
+        if entry >= self.entries {
+            return Err(MsiError::NoSuchEntry(entry));
+        }
+        Ok((self.table + entry as u64 * MSIX_ENTRY_SIZE) as *mut u32)
+    }
+
+    /// Send `vector` to the local apic `apic_id` on `entry` and unmask it
+    pub unsafe fn set_entry(&self, entry: u16, vector: u8, apic_id: u32) -> Result<(), MsiError> {
+        let ptr = self.entry(entry)?;
+        let address = message_address(apic_id)?;
+        self.set_masked(entry, true)?;
+        write_volatile(ptr, address as u32);
+        write_volatile(ptr.add(1), (address >> 32) as u32);
+        write_volatile(ptr.add(2), message_data(vector));
+        self.set_masked(entry, false)
+    }
+
+    pub unsafe fn set_masked(&self, entry: u16, masked: bool) -> Result<(), MsiError> {
+        let control = self.entry(entry)?.add(3);
+        let value = read_volatile(control) & !Self::ENTRY_MASKED;
+        write_volatile(control, value | if masked { Self::ENTRY_MASKED } else { 0 });
+        Ok(())
+    }
+
+    /// Enable MSI-X, entries still have to be unmasked with `set_entry`
+    pub unsafe fn enable(&self) {
+        let control = config::read16(self.address, self.offset + Self::CONTROL);
+        let control = (control & !Self::FUNCTION_MASK) | Self::ENABLE;
+        config::write16(self.address, self.offset + Self::CONTROL, control);
+        disable_intx(self.address);
+    }
+
+    pub unsafe fn disable(&self) {
+        let control = config::read16(self.address, self.offset + Self::CONTROL);
+        config::write16(
+            self.address,
+            self.offset + Self::CONTROL,
+            control & !Self::ENABLE,
+        );
+    }
+
+    /// Allocate a vector for `handler` and deliver `entry` to the local apic
+    /// `apic_id`. Returns the vector.
+    pub unsafe fn route(
+        &self,
+        entry: u16,
+        apic_id: u32,
+        handler: Handler,
+        arg: usize,
+    ) -> Result<u8, MsiError> {
+        self.entry(entry)?;
+        message_address(apic_id)?;
+        let vector = interrupts::allocate_vector(handler, arg).ok_or(MsiError::NoFreeVector)?;
+        self.set_entry(entry, vector, apic_id)?;
+        Ok(vector)
+    }
+}
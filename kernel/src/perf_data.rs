@@ -0,0 +1,297 @@
+//! Writer for the perf.data file format of the Linux perf tool.
+//! The file is streamed as hex over serial and can be extracted on the host
+//! with `tools/perf_data.sh`, then opened with
+//! `perf report -i perf.data --vmlinux <kernel elf>`.
+//!
+//! Format description:
+//! https://github.com/torvalds/linux/blob/master/tools/perf/Documentation/perf.data-file-format.txt
+
+use crate::pmc::PmcEvent;
+use crate::profiler::{Profile, ProfileSource};
+use alloc::vec::Vec;
+
+/// Kernel ELF the samples belong to, relative to the cargo target
+/// directory. `tools/perf_data.sh` resolves it for `perf report --vmlinux`.
+#[cfg(debug_assertions)]
+pub const KERNEL_ELF: &str = "x86_64-os/debug/perf_kernel";
+#[cfg(not(debug_assertions))]
+pub const KERNEL_ELF: &str = "x86_64-os/release/perf_kernel";
+
+/// The kernel is executed in place where the bootloader loaded it
+const KERNEL_BASE: u64 = 0x20_0000;
+
+/// Everything up to the end of the identity mapping may contain kernel code
+const KERNEL_LEN: u64 = 0x1_0000_0000 - KERNEL_BASE;
+
+const MAGIC: &[u8; 8] = b"PERFILE2";
+const HEADER_SIZE: u64 = 104;
+
+/// perf_event_attr up to and including `sample_max_stack`
+const ATTR_SIZE: u32 = 112;
+
+/// perf_file_attr: perf_event_attr followed by the section of its ids
+const FILE_ATTR_SIZE: u64 = ATTR_SIZE as u64 + 16;
+
+const DATA_OFFSET: u64 = HEADER_SIZE + FILE_ATTR_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerfDataError {
+    /// Sample timestamps can not be converted before `time::calibrate`
+    Uncalibrated,
+}
+
+// perf_event_attr types
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_TYPE_SOFTWARE: u32 = 1;
+
+// perf_hw_id and perf_sw_ids
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+const PERF_COUNT_HW_STALLED_CYCLES_FRONTEND: u64 = 7;
+const PERF_COUNT_HW_STALLED_CYCLES_BACKEND: u64 = 8;
+const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+
+// perf_event_sample_format
+const PERF_SAMPLE_IP: u64 = 1 << 0;
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+const PERF_SAMPLE_CPU: u64 = 1 << 7;
+const PERF_SAMPLE_PERIOD: u64 = 1 << 8;
+
+const SAMPLE_TYPE: u64 = PERF_SAMPLE_IP
+    | PERF_SAMPLE_TID
+    | PERF_SAMPLE_TIME
+    | PERF_SAMPLE_CALLCHAIN
+    | PERF_SAMPLE_CPU
+    | PERF_SAMPLE_PERIOD;
+
+// perf_event_type
+const PERF_RECORD_MMAP: u32 = 1;
+const PERF_RECORD_COMM: u32 = 3;
+const PERF_RECORD_SAMPLE: u32 = 9;
+
+const PERF_RECORD_MISC_KERNEL: u16 = 1;
+
+/// Marks the following callchain entries as kernel addresses
+const PERF_CONTEXT_KERNEL: u64 = -128i64 as u64;
+
+/// Pid perf uses for the kernel maps
+const HOST_KERNEL_PID: u32 = u32::MAX;
+
+/// Event the samples have been taken on, as encoded in perf_event_attr
+#[derive(Debug, Clone, Copy)]
+pub struct PerfEvent {
+    pub kind: u32,
+    pub config: u64,
+    pub sample_period: u64,
+}
+
+impl PerfEvent {
+    pub fn from_source(source: &ProfileSource) -> Self {
+        match *source {
+            ProfileSource::Timer { hz } => PerfEvent {
+                kind: PERF_TYPE_SOFTWARE,
+                config: PERF_COUNT_SW_CPU_CLOCK,
+                // cpu-clock periods are in nanoseconds
+                sample_period: 1_000_000_000 / hz.max(1) as u64,
+            },
+            ProfileSource::Pmc { event, period } => PerfEvent {
+                kind: PERF_TYPE_HARDWARE,
+                config: match event {
+                    PmcEvent::CpuCycles => PERF_COUNT_HW_CPU_CYCLES,
+                    PmcEvent::RetiredInstructions => PERF_COUNT_HW_INSTRUCTIONS,
+                    PmcEvent::BranchMispredicts => PERF_COUNT_HW_BRANCH_MISSES,
+                    PmcEvent::L2CacheMisses | PmcEvent::LlcMisses => PERF_COUNT_HW_CACHE_MISSES,
+                    PmcEvent::StalledCyclesFrontend => PERF_COUNT_HW_STALLED_CYCLES_FRONTEND,
+                    PmcEvent::StalledCyclesBackend => PERF_COUNT_HW_STALLED_CYCLES_BACKEND,
+                },
+                sample_period: period,
+            },
+        }
+    }
+}
+
+/// Builds a perf.data file in memory
+pub struct PerfDataWriter {
+    buf: Vec<u8>,
+    event: PerfEvent,
+}
+
+impl PerfDataWriter {
+    /// Write the file header, the event description and the records
+    /// describing the kernel image
+    pub fn new(event: PerfEvent) -> Self {
+        let mut writer = PerfDataWriter {
+            buf: Vec::new(),
+            event,
+        };
+        writer.write_header();
+        writer.write_attr();
+        debug_assert_eq!(writer.buf.len() as u64, DATA_OFFSET);
+
+        writer.write_kernel_mmap();
+        writer.write_comm(0, 0, "perf_kernel");
+        writer
+    }
+
+    fn put_u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    fn put_u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// Zero terminated string padded to 8 bytes
+    fn put_str(&mut self, s: &str) {
+        self.buf.extend_from_slice(s.as_bytes());
+        let padded = (s.len() + 8) & !7;
+        self.buf.resize(self.buf.len() + padded - s.len(), 0);
+    }
+
+    fn put_record_header(&mut self, kind: u32, misc: u16, size: usize) {
+        self.put_u32(kind);
+        self.put_u16(misc);
+        self.put_u16(size as u16);
+    }
+
+    fn write_header(&mut self) {
+        self.buf.extend_from_slice(MAGIC);
+        self.put_u64(HEADER_SIZE);
+        self.put_u64(FILE_ATTR_SIZE);
+        // attrs section
+        self.put_u64(HEADER_SIZE);
+        self.put_u64(FILE_ATTR_SIZE);
+        // data section, the size is patched in `finish`
+        self.put_u64(DATA_OFFSET);
+        self.put_u64(0);
+        // event_types section, unused
+        self.put_u64(0);
+        self.put_u64(0);
+        // adds_features bitmap, no feature sections
+        for _ in 0..4 {
+            self.put_u64(0);
+        }
+    }
+
+    fn write_attr(&mut self) {
+        self.put_u32(self.event.kind);
+        self.put_u32(ATTR_SIZE);
+        self.put_u64(self.event.config);
+        self.put_u64(self.event.sample_period);
+        self.put_u64(SAMPLE_TYPE);
+        // read_format
+        self.put_u64(0);
+        // flags bitfield
+        self.put_u64(0);
+        // wakeup_events, bp_type
+        self.put_u32(0);
+        self.put_u32(0);
+        // config1, config2, branch_sample_type, sample_regs_user
+        for _ in 0..4 {
+            self.put_u64(0);
+        }
+        // sample_stack_user, clockid
+        self.put_u32(0);
+        self.put_u32(0);
+        // sample_regs_intr
+        self.put_u64(0);
+        // aux_watermark, sample_max_stack, reserved
+        self.put_u32(0);
+        self.put_u16(0);
+        self.put_u16(0);
+        // ids section, no ids
+        self.put_u64(0);
+        self.put_u64(0);
+    }
+
+    fn write_kernel_mmap(&mut self) {
+        let name = "[kernel.kallsyms]";
+        let size = 8 + 4 + 4 + 8 + 8 + 8 + ((name.len() + 8) & !7);
+        self.put_record_header(PERF_RECORD_MMAP, PERF_RECORD_MISC_KERNEL, size);
+        self.put_u32(HOST_KERNEL_PID);
+        self.put_u32(0);
+        self.put_u64(KERNEL_BASE);
+        self.put_u64(KERNEL_LEN);
+        self.put_u64(KERNEL_BASE);
+        self.put_str(name);
+    }
+
+    fn write_comm(&mut self, pid: u32, tid: u32, comm: &str) {
+        let size = 8 + 4 + 4 + ((comm.len() + 8) & !7);
+        self.put_record_header(PERF_RECORD_COMM, 0, size);
+        self.put_u32(pid);
+        self.put_u32(tid);
+        self.put_str(comm);
+    }
+
+    /// Add a kernel sample. `callchain` starts with the sampled instruction
+    /// followed by the return addresses. The core is used as thread id.
+    pub fn add_sample(&mut self, time_ns: u64, cpu: u32, period: u64, callchain: &[u64]) {
+        let ip = callchain.first().copied().unwrap_or(0);
+        let size = 7 * 8 + 8 * (callchain.len() + 1);
+        self.put_record_header(PERF_RECORD_SAMPLE, PERF_RECORD_MISC_KERNEL, size);
+        self.put_u64(ip);
+        // pid, tid
+        self.put_u32(0);
+        self.put_u32(cpu);
+        self.put_u64(time_ns);
+        // cpu, reserved
+        self.put_u32(cpu);
+        self.put_u32(0);
+        self.put_u64(period);
+        self.put_u64(callchain.len() as u64 + 1);
+        self.put_u64(PERF_CONTEXT_KERNEL);
+        for &addr in callchain {
+            self.put_u64(addr);
+        }
+    }
+
+    /// Patch the size of the data section and return the file
+    pub fn finish(mut self) -> Vec<u8> {
+        let data_size = self.buf.len() as u64 - DATA_OFFSET;
+        self.buf[48..56].copy_from_slice(&data_size.to_le_bytes());
+        self.buf
+    }
+}
+
+/// Convert the samples of a profile into a perf.data file
+pub fn from_profile(profile: &Profile) -> Result<Vec<u8>, PerfDataError> {
+    if !crate::time::is_calibrated() {
+        return Err(PerfDataError::Uncalibrated);
+    }
+    let event = PerfEvent::from_source(&profile.source);
+    let mut writer = PerfDataWriter::new(event);
+    let tsc_khz = crate::time::tsc_khz() as u128;
+
+    for sample in profile.samples.iter() {
+        let time_ns = (sample.tsc as u128 * 1_000_000 / tsc_khz) as u64;
+        writer.add_sample(
+            time_ns,
+            sample.core as u32,
+            event.sample_period,
+            sample.frames(),
+        );
+    }
+    Ok(writer.finish())
+}
+
+/// Print a file as hex over serial, enclosed in markers which are
+/// picked up by `tools/perf_data.sh`
+pub fn stream(data: &[u8]) {
+    crate::serial_println!("# perf.data begin {} {}", data.len(), KERNEL_ELF);
+    for line in data.chunks(32) {
+        for byte in line {
+            crate::serial_print!("{:02x}", byte);
+        }
+        crate::serial_println!();
+    }
+    crate::serial_println!("# perf.data end");
+}
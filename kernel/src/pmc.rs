@@ -0,0 +1,435 @@
+use crate::pmc_regs::*;
+use raw_cpuid::CpuId;
+use x86_64::registers::model_specific::Msr;
+
+/// Maximum number of counters a `CounterSet` can hold
+pub const MAX_COUNTERS: usize = 8;
+
+/// Hardware events that can be counted by the core performance counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PmcEvent {
+    /// Core clock cycles while the core is not halted
+    CpuCycles = 0,
+    RetiredInstructions = 1,
+    /// Retired branch instructions that have been mispredicted
+    BranchMispredicts = 2,
+    /// L1 data and instruction cache misses that also missed the L2
+    L2CacheMisses = 3,
+    /// Last level cache misses
+    LlcMisses = 4,
+    /// Cycles where the frontend could not deliver instructions
+    StalledCyclesFrontend = 5,
+    /// Cycles where dispatch stalled because of backend resources
+    StalledCyclesBackend = 6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmcError {
+    /// No usable performance counters have been reported by cpuid
+    NoPmu,
+    /// The cpu can not count this event
+    UnsupportedEvent(PmcEvent),
+    /// All counters of the current core are in use
+    NoFreeCounter,
+    /// More events requested than a `CounterSet` can hold
+    TooManyEvents,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmuKind {
+    /// AMD PerfCtr0-3 at 0xC001_0004
+    AmdLegacy,
+    /// AMD core performance counter extensions, PerfCtr0-5 at 0xC001_0201
+    AmdCore,
+    /// Intel architectural performance monitoring (cpuid leaf 0xA)
+    IntelArch,
+}
+
+/// Description of the performance monitoring unit of the cpu
+#[derive(Debug, Clone, Copy)]
+pub struct Pmu {
+    pub kind: PmuKind,
+    pub num_counters: u8,
+    pub counter_width: u8,
+    /// Version of the Intel architectural PMU, zero on AMD
+    pub version: u8,
+    /// Bitmap of supported `PmcEvent`s
+    supported: u8,
+}
+
+impl Pmu {
+    pub fn supports(&self, event: PmcEvent) -> bool {
+        self.supported & (1 << event as u8) != 0
+    }
+
+    fn counter_mask(&self) -> u64 {
+        if self.counter_width >= 64 {
+            u64::MAX
+        } else {
+            (1 << self.counter_width) - 1
+        }
+    }
+
+    fn evt_sel_msr(&self, index: u8) -> Msr {
+        let index = index as u32;
+        match self.kind {
+            PmuKind::AmdLegacy => Msr::new(AMD_LEGACY_PERF_EVT_SEL + index),
+            PmuKind::AmdCore => Msr::new(AMD_CORE_PERF_EVT_SEL + 2 * index),
+            PmuKind::IntelArch => Msr::new(INTEL_PERF_EVT_SEL + index),
+        }
+    }
+
+    fn ctr_msr(&self, index: u8) -> Msr {
+        let index = index as u32;
+        match self.kind {
+            PmuKind::AmdLegacy => Msr::new(AMD_LEGACY_PERF_CTR + index),
+            PmuKind::AmdCore => Msr::new(AMD_CORE_PERF_CTR + 2 * index),
+            PmuKind::IntelArch => Msr::new(INTEL_PMC + index),
+        }
+    }
+
+    /// Returns the (event select, unit mask) encoding of an event
+    fn encoding(&self, event: PmcEvent) -> (u16, u8) {
+        match self.kind {
+            // Encodings from the AMD Family 17h PPR
+            PmuKind::AmdLegacy | PmuKind::AmdCore => match event {
+                PmcEvent::CpuCycles => (0x076, 0x00),
+                PmcEvent::RetiredInstructions => (0x0C0, 0x00),
+                PmcEvent::BranchMispredicts => (0x0C3, 0x00),
+                // L2CacheReqStat: IcFillMiss | LsRdBlkC
+                PmcEvent::L2CacheMisses => (0x064, 0x09),
+                PmcEvent::LlcMisses => (0x000, 0x00),
+                PmcEvent::StalledCyclesFrontend => (0x087, 0x02),
+                PmcEvent::StalledCyclesBackend => (0x087, 0x01),
+            },
+            // Architectural events from the Intel SDM Vol. 3B 18.2.1.2
+            PmuKind::IntelArch => match event {
+                PmcEvent::CpuCycles => (0x3C, 0x00),
+                PmcEvent::RetiredInstructions => (0xC0, 0x00),
+                PmcEvent::BranchMispredicts => (0xC5, 0x00),
+                PmcEvent::LlcMisses => (0x2E, 0x41),
+                PmcEvent::L2CacheMisses
+                | PmcEvent::StalledCyclesFrontend
+                | PmcEvent::StalledCyclesBackend => (0x00, 0x00),
+            },
+        }
+    }
+}
+
+static mut PMU: Option<Pmu> = None;
+
+/// Bitmap of allocated counters per core, indexed by core index
+static mut COUNTERS_IN_USE: [u8; bootloader::MAX_CORES] = [0; bootloader::MAX_CORES];
+
+/// Discover the performance counters of the cpu through cpuid
+pub unsafe fn init() {
+    if PMU.is_none() {
+        PMU = detect();
+
+        match PMU {
+            Some(pmu) => log::info!(
+                "PMU: {:?} with {} counters of {} bits",
+                pmu.kind,
+                pmu.num_counters,
+                pmu.counter_width
+            ),
+            None => log::warn!("No performance counters available"),
+        }
+    }
+}
+
+pub fn pmu() -> Option<&'static Pmu> {
+    unsafe { PMU.as_ref() }
+}
+
+fn detect() -> Option<Pmu> {
+    let cpuid = CpuId::new();
+    let vendor = cpuid.get_vendor_info()?;
+    let features = cpuid.get_feature_info()?;
+
+    let mut family = features.family_id() as u16;
+    if family == 0xf {
+        family += features.extended_family_id() as u16;
+    }
+
+    match vendor.as_str() {
+        "AuthenticAMD" => {
+            let ext = cpuid.get_extended_processor_and_feature_identifiers()?;
+
+            let mut supported = (1 << PmcEvent::CpuCycles as u8)
+                | (1 << PmcEvent::RetiredInstructions as u8)
+                | (1 << PmcEvent::BranchMispredicts as u8);
+
+            // The L2 and stall encodings changed with Zen
+            if family >= 0x17 {
+                supported |= (1 << PmcEvent::L2CacheMisses as u8)
+                    | (1 << PmcEvent::StalledCyclesFrontend as u8)
+                    | (1 << PmcEvent::StalledCyclesBackend as u8);
+            }
+
+            if ext.has_perf_cntr_extensions() {
+                Some(Pmu {
+                    kind: PmuKind::AmdCore,
+                    num_counters: 6,
+                    counter_width: 48,
+                    version: 0,
+                    supported,
+                })
+            } else {
+                Some(Pmu {
+                    kind: PmuKind::AmdLegacy,
+                    num_counters: 4,
+                    counter_width: 48,
+                    version: 0,
+                    supported,
+                })
+            }
+        }
+        "GenuineIntel" => {
+            let info = cpuid.get_performance_monitoring_info()?;
+            if info.version_id() == 0 || info.number_of_counters() == 0 {
+                return None;
+            }
+
+            // Events are only available if they are enumerated by the ebx bit vector
+            let len = info.ebx_length();
+            let mut supported = 0;
+            if len > 0 && !info.is_core_cyc_ev_unavailable() {
+                supported |= 1 << PmcEvent::CpuCycles as u8;
+            }
+            if len > 1 && !info.is_inst_ret_ev_unavailable() {
+                supported |= 1 << PmcEvent::RetiredInstructions as u8;
+            }
+            if len > 4 && !info.is_ll_cache_miss_ev_unavailable() {
+                supported |= 1 << PmcEvent::LlcMisses as u8;
+            }
+            if len > 6 && !info.is_branch_midpred_ev_unavailable() {
+                supported |= 1 << PmcEvent::BranchMispredicts as u8;
+            }
+
+            Some(Pmu {
+                kind: PmuKind::IntelArch,
+                num_counters: info.number_of_counters(),
+                counter_width: info.counter_bit_width(),
+                version: info.version_id(),
+                supported,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Read performance counter `index` of the current core
+#[inline]
+pub fn rdpmc(index: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdpmc", in("ecx") index, out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// A hardware counter of the current core programmed to count a single event.
+/// The counter is released again when dropped.
+/// Has to be read and dropped on the core it was created on.
+pub struct PerfCounter {
+    pmu: &'static Pmu,
+    index: u8,
+    /// `smp::core_index` of the owning core
+    core: usize,
+    event: PmcEvent,
+    /// Events between two overflow interrupts, zero if the counter only counts
+    period: u64,
+}
+
+impl PerfCounter {
+    /// Allocate a free counter of the current core and start counting `event`
+    /// in kernel and user mode
+    pub fn new(event: PmcEvent) -> Result<Self, PmcError> {
+        Self::program(event, 0)
+    }
+
+    /// Like `new` but raise an interrupt through the LVT performance counter
+    /// entry every `period` events. Call `rearm` from the interrupt handler.
+    pub fn with_overflow(event: PmcEvent, period: u64) -> Result<Self, PmcError> {
+        Self::program(event, period.max(1))
+    }
+
+    fn program(event: PmcEvent, period: u64) -> Result<Self, PmcError> {
+        let pmu = pmu().ok_or(PmcError::NoPmu)?;
+        if !pmu.supports(event) {
+            return Err(PmcError::UnsupportedEvent(event));
+        }
+
+        let core = crate::smp::core_index();
+        let index = allocate_counter(pmu, core)?;
+
+        let (evt, umask) = pmu.encoding(event);
+        let sel = PerfEvtSel::new()
+            .with_event_lo(evt as u8)
+            .with_event_hi((evt >> 8) as u8)
+            .with_unit_mask(umask)
+            .with_usr(1)
+            .with_os(1)
+            .with_int((period != 0) as u8)
+            .with_enable(1);
+
+        let counter = PerfCounter {
+            pmu,
+            index,
+            core,
+            event,
+            period,
+        };
+
+        unsafe {
+            pmu.ctr_msr(index).write(counter.reload_value());
+            pmu.evt_sel_msr(index)
+                .write(u64::from_le_bytes(sel.into_bytes()));
+
+            // Since version 2 every counter has to be enabled globally as well
+            if pmu.kind == PmuKind::IntelArch && pmu.version >= 2 {
+                let mut global = Msr::new(INTEL_PERF_GLOBAL_CTRL);
+                let val = global.read();
+                global.write(val | (1 << index));
+            }
+        }
+
+        Ok(counter)
+    }
+
+    pub fn event(&self) -> PmcEvent {
+        self.event
+    }
+
+    /// Index of the counter as used by `rdpmc`
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Current raw counter value
+    #[inline]
+    pub fn read(&self) -> u64 {
+        debug_assert_eq!(self.core, crate::smp::core_index());
+        rdpmc(self.index as u32) & self.pmu.counter_mask()
+    }
+
+    /// Difference between the current value and `start` taking wrap around
+    /// of the counter into account
+    #[inline]
+    pub fn delta(&self, start: u64) -> u64 {
+        self.read().wrapping_sub(start) & self.pmu.counter_mask()
+    }
+
+    /// The counter starts at `-period` so that it overflows after `period` events
+    fn reload_value(&self) -> u64 {
+        self.period.wrapping_neg() & self.pmu.counter_mask()
+    }
+
+    /// True if a counter with an overflow period has wrapped around. As
+    /// it starts at a negative value the top bit is cleared on overflow.
+    #[inline]
+    pub fn overflowed(&self) -> bool {
+        self.period != 0 && self.read() >> (self.pmu.counter_width - 1) & 1 == 0
+    }
+
+    /// Restart the overflow period after an overflow interrupt
+    pub fn rearm(&self) {
+        unsafe {
+            self.pmu.ctr_msr(self.index).write(self.reload_value());
+
+            if self.pmu.kind == PmuKind::IntelArch && self.pmu.version >= 2 {
+                Msr::new(INTEL_PERF_GLOBAL_OVF_CTRL).write(1 << self.index);
+            }
+        }
+    }
+}
+
+impl Drop for PerfCounter {
+    fn drop(&mut self) {
+        unsafe {
+            self.pmu.evt_sel_msr(self.index).write(0);
+
+            if self.pmu.kind == PmuKind::IntelArch && self.pmu.version >= 2 {
+                let mut global = Msr::new(INTEL_PERF_GLOBAL_CTRL);
+                let val = global.read();
+                global.write(val & !(1 << self.index));
+            }
+        }
+        release_counter(self.core, self.index);
+    }
+}
+
+fn allocate_counter(pmu: &Pmu, core: usize) -> Result<u8, PmcError> {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let in_use = &mut COUNTERS_IN_USE[core];
+        for index in 0..pmu.num_counters.min(8) {
+            if *in_use & (1 << index) == 0 {
+                *in_use |= 1 << index;
+                return Ok(index);
+            }
+        }
+        Err(PmcError::NoFreeCounter)
+    })
+}
+
+fn release_counter(core: usize, index: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        COUNTERS_IN_USE[core] &= !(1 << index);
+    });
+}
+
+/// A group of counters which are started and read together
+pub struct CounterSet {
+    counters: [Option<PerfCounter>; MAX_COUNTERS],
+    start: [u64; MAX_COUNTERS],
+}
+
+impl CounterSet {
+    /// Program one counter per event. Fails if any of the events is not
+    /// supported or not enough counters are free.
+    pub fn new(events: &[PmcEvent]) -> Result<Self, PmcError> {
+        if events.len() > MAX_COUNTERS {
+            return Err(PmcError::TooManyEvents);
+        }
+
+        let mut set = CounterSet {
+            counters: Default::default(),
+            start: [0; MAX_COUNTERS],
+        };
+
+        for (slot, &event) in set.counters.iter_mut().zip(events) {
+            *slot = Some(PerfCounter::new(event)?);
+        }
+        set.reset();
+        Ok(set)
+    }
+
+    /// Take the current counter values as the new starting point
+    #[inline]
+    pub fn reset(&mut self) {
+        for (start, counter) in self.start.iter_mut().zip(self.counters.iter()) {
+            if let Some(counter) = counter {
+                *start = counter.read();
+            }
+        }
+    }
+
+    /// Returns the number of events counted since the last `reset`
+    #[inline]
+    pub fn read(&self) -> [Option<(PmcEvent, u64)>; MAX_COUNTERS] {
+        let mut values = [None; MAX_COUNTERS];
+        for ((value, counter), &start) in values
+            .iter_mut()
+            .zip(self.counters.iter())
+            .zip(self.start.iter())
+        {
+            if let Some(counter) = counter {
+                *value = Some((counter.event(), counter.delta(start)));
+            }
+        }
+        values
+    }
+}
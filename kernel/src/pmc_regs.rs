@@ -0,0 +1,57 @@
+use modular_bitfield::prelude::*;
+
+/// AMD legacy performance event select MSRs (PerfEvtSel0-3)
+pub const AMD_LEGACY_PERF_EVT_SEL: u32 = 0xC001_0000;
+
+/// AMD legacy performance counter MSRs (PerfCtr0-3)
+pub const AMD_LEGACY_PERF_CTR: u32 = 0xC001_0004;
+
+/// AMD core performance event select MSRs (PerfEvtSel0-5)
+/// Interleaved with the counters: PerfEvtSel[n] = base + 2 * n
+pub const AMD_CORE_PERF_EVT_SEL: u32 = 0xC001_0200;
+
+/// AMD core performance counter MSRs (PerfCtr0-5)
+/// Interleaved with the selectors: PerfCtr[n] = base + 2 * n
+pub const AMD_CORE_PERF_CTR: u32 = 0xC001_0201;
+
+/// Intel architectural event select MSRs (IA32_PERFEVTSELx)
+pub const INTEL_PERF_EVT_SEL: u32 = 0x186;
+
+/// Intel architectural performance counter MSRs (IA32_PMCx)
+pub const INTEL_PMC: u32 = 0xC1;
+
+/// Intel global counter enable MSR (IA32_PERF_GLOBAL_CTRL), PMU version >= 2
+pub const INTEL_PERF_GLOBAL_CTRL: u32 = 0x38F;
+
+/// Intel global overflow status reset MSR (IA32_PERF_GLOBAL_OVF_CTRL), PMU version >= 2
+pub const INTEL_PERF_GLOBAL_OVF_CTRL: u32 = 0x390;
+
+/// Performance event select register.
+/// The layout is shared between AMD PerfEvtSel and Intel IA32_PERFEVTSELx,
+/// bits which only exist on one vendor are reserved on the other.
+#[bitfield]
+#[derive(Debug, Clone, Copy)]
+pub struct PerfEvtSel {
+    pub event_lo: B8,
+    pub unit_mask: B8,
+    /// Count in user mode (CPL > 0)
+    pub usr: B1,
+    /// Count in kernel mode (CPL = 0)
+    pub os: B1,
+    pub edge: B1,
+    /// Intel only: pin control
+    pub pc: B1,
+    /// Raise an interrupt through the LVT performance counter entry on overflow
+    pub int: B1,
+    /// Intel only: count on any thread of the core
+    pub any_thread: B1,
+    pub enable: B1,
+    pub invert: B1,
+    pub cnt_mask: B8,
+    /// AMD only: event select bits [11:8]
+    pub event_hi: B4,
+    pub res0: B4,
+    /// AMD only: count only in host or guest mode
+    pub host_guest_only: B2,
+    pub res1: B22,
+}
@@ -0,0 +1,264 @@
+use crate::apic_regs::PerfCounterLvtReg;
+use crate::pmc::{PerfCounter, PmcError, PmcEvent};
+use crate::symbols::Demangle;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Maximum number of frames recorded per sample, including the interrupted RIP
+pub const MAX_DEPTH: usize = 32;
+
+/// Number of samples preallocated per core if not configured otherwise
+pub const DEFAULT_CAPACITY: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileError {
+    /// The overflow counter could not be programmed
+    Pmc(PmcError),
+    /// The profiler is already running on the current core
+    AlreadyRunning,
+    /// The profiler has not been started on the current core
+    NotRunning,
+}
+
+impl From<PmcError> for ProfileError {
+    fn from(err: PmcError) -> Self {
+        ProfileError::Pmc(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ProfileSource {
+    /// Sample from the local apic timer `hz` times per second.
+    /// Code running with interrupts disabled is never sampled.
+    Timer { hz: u32 },
+    /// Sample through a performance counter overflow NMI every `period` events
+    Pmc { event: PmcEvent, period: u64 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProfileConfig {
+    pub source: ProfileSource,
+    /// Number of samples preallocated per core. Samples taken once the buffer
+    /// is full are dropped.
+    pub capacity: usize,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        ProfileConfig {
+            source: ProfileSource::Timer { hz: 1000 },
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+}
+
+/// Interrupted RIP followed by the return addresses of the frame pointer chain
+#[derive(Clone, Copy)]
+pub struct Sample {
+    pub tsc: u64,
+    /// `smp::core_index` of the sampled core
+    pub core: u8,
+    pub depth: u8,
+    pub frames: [u64; MAX_DEPTH],
+}
+
+impl Sample {
+    /// Frames from the leaf to the root
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.depth as usize]
+    }
+}
+
+struct CoreProfile {
+    samples: Vec<Sample>,
+    dropped: u64,
+    source: ProfileSource,
+    counter: Option<PerfCounter>,
+}
+
+const NO_PROFILE: Option<CoreProfile> = None;
+
+/// Sample buffers per core, indexed by core index.
+/// Allocated in `start` so that the interrupt handlers never allocate.
+static mut PROFILES: [Option<CoreProfile>; bootloader::MAX_CORES] =
+    [NO_PROFILE; bootloader::MAX_CORES];
+
+/// Start profiling the current core
+pub fn start(config: &ProfileConfig) -> Result<(), ProfileError> {
+    let core = crate::smp::core_index();
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        if PROFILES[core].is_some() {
+            return Err(ProfileError::AlreadyRunning);
+        }
+
+        let profile = CoreProfile {
+            samples: Vec::with_capacity(config.capacity),
+            dropped: 0,
+            source: config.source,
+            counter: None,
+        };
+
+        match config.source {
+            ProfileSource::Timer { hz } => {
+                PROFILES[core] = Some(profile);
+                // The wheel keeps running on the faster tick
+                let _ = crate::timer::set_periodic(hz);
+            }
+            ProfileSource::Pmc { event, period } => {
+                let lvt = PerfCounterLvtReg::new().with_msg_type(0b100); // NMI
+                crate::apic::set_perf_counter_lvt(lvt);
+
+                // The counter starts counting right away, so the profile has
+                // to be installed before the first overflow
+                PROFILES[core] = Some(profile);
+                match PerfCounter::with_overflow(event, period) {
+                    Ok(counter) => PROFILES[core].as_mut().unwrap().counter = Some(counter),
+                    Err(err) => {
+                        PROFILES[core] = None;
+                        return Err(err.into());
+                    }
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Stop profiling the current core and return the samples
+pub fn stop() -> Result<Profile, ProfileError> {
+    let core = crate::smp::core_index();
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let running = PROFILES[core].as_mut().ok_or(ProfileError::NotRunning)?;
+
+        match running.counter.take() {
+            Some(counter) => {
+                drop(counter);
+                let lvt = PerfCounterLvtReg::new().with_msg_type(0b100).with_mask(1);
+                crate::apic::set_perf_counter_lvt(lvt);
+            }
+            None => {
+                let _ = crate::timer::set_periodic(crate::timer::DEFAULT_HZ);
+            }
+        }
+
+        let profile = PROFILES[core].take().unwrap();
+        Ok(Profile {
+            samples: profile.samples,
+            dropped: profile.dropped,
+            source: profile.source,
+        })
+    })
+}
+
+#[inline]
+fn record(profile: &mut CoreProfile, core: usize, rip: u64, rbp: u64) {
+    // Never grow the buffer inside of an interrupt handler
+    if profile.samples.len() == profile.samples.capacity() {
+        profile.dropped += 1;
+        return;
+    }
+
+    let mut sample = Sample {
+        tsc: crate::time::rdtsc(),
+        core: core as u8,
+        depth: 1,
+        frames: [0; MAX_DEPTH],
+    };
+    sample.frames[0] = rip;
+    sample.depth += crate::backtrace::walk_frames(rbp, &mut sample.frames[1..]) as u8;
+    profile.samples.push(sample);
+}
+
+/// Called by the timer interrupt handler.
+/// Returns false if the profiler is not running on the current core.
+pub fn sample_timer(stack_frame: &InterruptStackFrame, rbp: u64) -> bool {
+    let core = crate::smp::core_index();
+    match unsafe { PROFILES[core].as_mut() } {
+        Some(profile) if profile.counter.is_none() => {
+            record(profile, core, stack_frame.instruction_pointer.as_u64(), rbp);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Called by the NMI handler. Returns false if the NMI was not caused
+/// by the overflow counter of the current core.
+pub fn handle_nmi(stack_frame: &InterruptStackFrame, rbp: u64) -> bool {
+    let core = crate::smp::core_index();
+    let profile = match unsafe { PROFILES[core].as_mut() } {
+        Some(profile) => profile,
+        None => return false,
+    };
+
+    match profile.counter {
+        Some(ref counter) if counter.overflowed() => {
+            counter.rearm();
+            record(profile, core, stack_frame.instruction_pointer.as_u64(), rbp);
+
+            // Intel masks the LVT entry on delivery
+            let lvt = PerfCounterLvtReg::new().with_msg_type(0b100);
+            unsafe { crate::apic::set_perf_counter_lvt(lvt) };
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Samples collected on one or more cores
+pub struct Profile {
+    pub samples: Vec<Sample>,
+    /// Samples lost because the buffer was full
+    pub dropped: u64,
+    pub source: ProfileSource,
+}
+
+impl Profile {
+    /// Add the samples of another core
+    pub fn merge(&mut self, other: Profile) {
+        self.samples.extend(other.samples);
+        self.dropped += other.dropped;
+    }
+
+    /// Count identical call chains, the frames are ordered from the root to the leaf
+    pub fn folded(&self) -> BTreeMap<Vec<u64>, usize> {
+        let mut stacks = BTreeMap::new();
+        for sample in self.samples.iter() {
+            let stack: Vec<u64> = sample.frames().iter().rev().copied().collect();
+            *stacks.entry(stack).or_insert(0) += 1;
+        }
+        stacks
+    }
+
+    /// Print the folded stacks over serial in the format read by
+    /// flamegraph.pl and inferno: `root;caller;leaf count`.
+    /// Frames are resolved to function names where possible.
+    pub fn dump_folded(&self) {
+        log::info!(
+            "Profile: {} samples, {} dropped",
+            self.samples.len(),
+            self.dropped
+        );
+
+        crate::serial_println!("# folded stacks begin");
+        for (stack, count) in self.folded() {
+            for (i, &addr) in stack.iter().enumerate() {
+                if i != 0 {
+                    crate::serial_print!(";");
+                }
+
+                // All frames except the leaf are return addresses behind the call
+                let addr = if i + 1 == stack.len() { addr } else { addr - 1 };
+                match crate::symbols::lookup(addr) {
+                    Some(symbol) => crate::serial_print!("{}", Demangle(symbol.name)),
+                    None => crate::serial_print!("{:#x}", addr),
+                }
+            }
+            crate::serial_println!(" {}", count);
+        }
+        crate::serial_println!("# folded stacks end");
+    }
+}
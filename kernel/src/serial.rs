@@ -1,14 +1,15 @@
+use crate::lock::Mutex;
 use uart_16550::SerialPort;
 
 // Serial programming resource:
 // https://en.wikibooks.org/wiki/Serial_Programming/8250_UART_Programming
 
-pub static mut SERIAL_WRITER: Option<spin::Mutex<SerialPort>> = None;
+pub static mut SERIAL_WRITER: Option<Mutex<SerialPort>> = None;
 
 pub unsafe fn init() {
     let mut serial_port = SerialPort::new(0x3F8);
     serial_port.init();
-    SERIAL_WRITER = Some(spin::Mutex::new(serial_port));
+    SERIAL_WRITER = Some(Mutex::new("serial", serial_port));
 }
 
 use core::fmt;
@@ -26,3 +27,18 @@ pub fn _print(args: fmt::Arguments) {
             .unwrap();
     });
 }
+
+/// Print to the serial port only. Used for bulk output
+/// like profiles which would flood the vga buffer.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
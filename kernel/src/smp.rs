@@ -1,11 +1,29 @@
 use crate::apic;
+use crate::interrupts::InterruptIndex;
+use core::cell::UnsafeCell;
 use core::mem::MaybeUninit;
-use core::sync::atomic::AtomicU8;
 use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicU8, AtomicUsize};
+use x86_64::registers::model_specific::Msr;
 
+/// States of the cores, indexed by core index
 static mut CORES: Option<[MaybeUninit<AtomicU8>; bootloader::MAX_CORES]> = None;
 static mut NUM_CORES_ONLINE: AtomicU8 = AtomicU8::new(0);
 
+/// Apic ids of all cores in the order of `BootInfo::cores`. The position of
+/// an id is the index of the core into the per-core tables.
+static mut APIC_IDS: [u32; bootloader::MAX_CORES] = [u32::MAX; bootloader::MAX_CORES];
+static NUM_CORES: AtomicUsize = AtomicUsize::new(0);
+
+/// Returned in ecx by rdtscp and by rdpid, holds the index of the core
+const IA32_TSC_AUX: u32 = 0xc000_0103;
+
+/// How `core_index` reads the index of the current core
+const INDEX_CPUID: u8 = 0;
+const INDEX_RDTSCP: u8 = 1;
+const INDEX_RDPID: u8 = 2;
+static INDEX_SOURCE: AtomicU8 = AtomicU8::new(INDEX_CPUID);
+
 /// Different states for APICs to be in
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(u8)]
@@ -41,51 +59,315 @@ impl From<u8> for ApicState {
     }
 }
 
-pub unsafe fn init() {
+pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
     if CORES.is_none() {
         CORES = Some(MaybeUninit::uninit_array());
         for core in CORES.as_mut().unwrap().iter_mut() {
             core.write(AtomicU8::new(ApicState::Offline as u8));
         }
+
+        // The bootloader lists the cores in MADT order, the bsp first
+        for (index, core) in boot_info.cores.iter().enumerate() {
+            APIC_IDS[index] = core.get_apic_id().expect("Core without apic id");
+        }
+        NUM_CORES.store(boot_info.cores.len(), Ordering::SeqCst);
+    }
+
+    let index = index_of(apic::apic_id()).expect("Core is not listed in the boot info");
+
+    // Cache the index of this core, reading it back is much cheaper than
+    // cpuid which traps to the hypervisor in a VM
+    if has_rdtscp() {
+        Msr::new(IA32_TSC_AUX).write(index as u64);
+        let source = if has_rdpid() {
+            INDEX_RDPID
+        } else {
+            INDEX_RDTSCP
+        };
+        INDEX_SOURCE.store(source, Ordering::Relaxed);
+    }
+}
+
+fn has_rdtscp() -> bool {
+    use core::arch::x86_64::__cpuid;
+    let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 27) != 0
+}
+
+fn has_rdpid() -> bool {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    max_leaf >= 0x7 && unsafe { __cpuid_count(0x7, 0) }.ecx & (1 << 22) != 0
+}
+
+/// Number of cores listed by the bootloader
+pub fn num_cores() -> usize {
+    NUM_CORES.load(Ordering::Relaxed)
+}
+
+/// Index of the core with `apic_id` into the per-core tables
+pub fn index_of(apic_id: u32) -> Option<usize> {
+    unsafe { APIC_IDS[..num_cores()].iter().position(|&id| id == apic_id) }
+}
+
+/// Apic id of the core with `index`
+pub fn apic_id_of(index: usize) -> Option<u32> {
+    unsafe { APIC_IDS[..num_cores()].get(index).copied() }
+}
+
+/// Dense index of the current core into the per-core tables, the bsp is 0.
+/// Must not be called on a core before it ran `init`.
+#[inline]
+pub fn core_index() -> usize {
+    match INDEX_SOURCE.load(Ordering::Relaxed) {
+        INDEX_RDPID => {
+            let index: u64;
+            unsafe {
+                asm!("rdpid {}", out(reg) index, options(nomem, nostack, preserves_flags));
+            }
+            index as usize
+        }
+        INDEX_RDTSCP => {
+            let mut index = 0;
+            unsafe { core::arch::x86_64::__rdtscp(&mut index) };
+            index as usize
+        }
+        _ => index_of(apic::apic_id()).unwrap_or(0),
     }
 }
 
 pub fn set_core_ready() {
-    let id = apic::apic_id();
-    set_state(id as usize, ApicState::Online);
+    set_state(core_index(), ApicState::Online);
     unsafe {
         NUM_CORES_ONLINE.fetch_add(1, Ordering::SeqCst);
     }
 }
 
+/// Mark the core `apic_id` as launched before the bsp sends its startup IPIs
+pub fn set_launched(apic_id: u32) {
+    if let Some(index) = index_of(apic_id) {
+        if state(index) == ApicState::Offline {
+            set_state(index, ApicState::Launched);
+        }
+    }
+}
+
 // pub fn are_all_cores_online(acpi: &Acpi) -> bool {
 //      unsafe {
 //         if NUM_CORES_ONLINE.load(Ordering::SeqCst) != ;
 //     }
 // }
 
-fn set_state(id: usize, state: ApicState) {
+fn set_state(index: usize, state: ApicState) {
     unsafe {
         CORES
             .as_mut()
             .unwrap()
-            .get_mut(id)
+            .get_mut(index)
             .unwrap()
             .assume_init_mut()
             .store(state as u8, Ordering::SeqCst);
     }
 }
 
-pub fn get_state(id: usize) -> ApicState {
+fn state(index: usize) -> ApicState {
     unsafe {
         ApicState::from(
             CORES
                 .as_ref()
                 .unwrap()
-                .get(id)
+                .get(index)
                 .unwrap()
                 .assume_init_ref()
                 .load(Ordering::SeqCst),
         )
     }
 }
+
+pub fn get_state(apic_id: u32) -> ApicState {
+    match index_of(apic_id) {
+        Some(index) => state(index),
+        None => ApicState::None,
+    }
+}
+
+pub fn num_cores_online() -> u8 {
+    unsafe { NUM_CORES_ONLINE.load(Ordering::SeqCst) }
+}
+
+/// Apic ids of all cores which are running
+pub fn online_cores() -> impl Iterator<Item = u32> {
+    (0..num_cores())
+        .filter(|&index| state(index) == ApicState::Online)
+        .filter_map(apic_id_of)
+}
+
+/// Apic ids of all cores which were launched or are running
+pub fn launched_cores() -> impl Iterator<Item = u32> {
+    (0..num_cores())
+        .filter(|&index| matches!(state(index), ApicState::Launched | ApicState::Online))
+        .filter_map(apic_id_of)
+}
+
+/// Time the application processors get to boot and check in
+pub const ONLINE_TIMEOUT_US: u64 = 10 * 1000 * 1000;
+
+/// Wait until the core `apic_id` has checked in.
+/// Returns false if it did not within `microseconds`.
+pub fn wait_online(apic_id: u32, microseconds: u64) -> bool {
+    let deadline = crate::time::future(microseconds);
+    while get_state(apic_id) != ApicState::Online {
+        if crate::time::rdtsc() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    /// The target core has not checked in with the kernel
+    NotOnline(u32),
+}
+
+const MAILBOX_EMPTY: u8 = 0;
+const MAILBOX_CLAIMED: u8 = 1;
+const MAILBOX_FULL: u8 = 2;
+const MAILBOX_DONE: u8 = 3;
+
+/// Function call handed to an idle core
+struct Mailbox {
+    state: AtomicU8,
+    call: UnsafeCell<Option<*mut (dyn FnMut() + 'static)>>,
+}
+
+// The call is only accessed by the core which claimed the mailbox
+// and by its owner while the state is `MAILBOX_FULL`
+unsafe impl Sync for Mailbox {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_MAILBOX: Mailbox = Mailbox {
+    state: AtomicU8::new(MAILBOX_EMPTY),
+    call: UnsafeCell::new(None),
+};
+
+/// Mailboxes per core, indexed by core index
+static MAILBOXES: [Mailbox; bootloader::MAX_CORES] = [EMPTY_MAILBOX; bootloader::MAX_CORES];
+
+/// Hand `call` to the idle core `index` and wake it up
+unsafe fn post(index: usize, call: &mut dyn FnMut()) {
+    // The caller waits for the call to finish, so it never outlives the borrow
+    let call: *mut (dyn FnMut() + 'static) = core::mem::transmute(call);
+
+    let mailbox = &MAILBOXES[index];
+    while mailbox
+        .state
+        .compare_exchange(
+            MAILBOX_EMPTY,
+            MAILBOX_CLAIMED,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        )
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+
+    *mailbox.call.get() = Some(call);
+    mailbox.state.store(MAILBOX_FULL, Ordering::Release);
+    apic::send_fixed_ipi(apic_id_of(index).unwrap(), InterruptIndex::Wakeup.as_u8());
+}
+
+/// Wait until the call posted to the core `index` has returned
+fn wait(index: usize) {
+    let mailbox = &MAILBOXES[index];
+    while mailbox.state.load(Ordering::Acquire) != MAILBOX_DONE {
+        core::hint::spin_loop();
+    }
+    unsafe { *mailbox.call.get() = None };
+    mailbox.state.store(MAILBOX_EMPTY, Ordering::Release);
+}
+
+/// Run `f` on the core `apic_id` and wait for its result.
+/// The core has to be idling in `idle_loop`.
+pub fn run_on<F, R>(apic_id: u32, f: F) -> Result<R, SmpError>
+where
+    F: FnOnce() -> R + Send,
+    R: Send,
+{
+    let index = index_of(apic_id).ok_or(SmpError::NotOnline(apic_id))?;
+    if index == core_index() {
+        return Ok(f());
+    }
+    if state(index) != ApicState::Online {
+        return Err(SmpError::NotOnline(apic_id));
+    }
+
+    let mut f = Some(f);
+    let mut result = None;
+    let mut call = || result = Some((f.take().unwrap())());
+    unsafe { post(index, &mut call) };
+    wait(index);
+
+    Ok(result.unwrap())
+}
+
+/// Run `f` concurrently on all cores in `apic_ids`, including the current
+/// core if it is listed. `f` gets the index of the core in `apic_ids`.
+/// Returns once all cores are done.
+pub fn run_on_all<F>(apic_ids: &[u32], f: F) -> Result<(), SmpError>
+where
+    F: Fn(usize) + Sync,
+{
+    let me = core_index();
+    let mut cores = alloc::vec::Vec::with_capacity(apic_ids.len());
+    for &id in apic_ids.iter() {
+        match index_of(id) {
+            Some(index) if index == me || state(index) == ApicState::Online => cores.push(index),
+            _ => return Err(SmpError::NotOnline(id)),
+        }
+    }
+
+    let f = &f;
+    let mut calls: alloc::vec::Vec<_> = cores
+        .iter()
+        .enumerate()
+        .map(|(position, _)| move || f(position))
+        .collect();
+
+    for (&index, call) in cores.iter().zip(calls.iter_mut()) {
+        if index != me {
+            unsafe { post(index, call) };
+        }
+    }
+    if let Some(position) = cores.iter().position(|&index| index == me) {
+        f(position);
+    }
+    for &index in cores.iter().filter(|&&index| index != me) {
+        wait(index);
+    }
+    Ok(())
+}
+
+/// Main loop of the application processors. Sleeps until a call is
+/// posted to the mailbox of the core with `run_on`.
+pub fn idle_loop() -> ! {
+    use x86_64::instructions::interrupts;
+
+    let mailbox = &MAILBOXES[core_index()];
+    loop {
+        interrupts::disable();
+        if mailbox.state.load(Ordering::Acquire) == MAILBOX_FULL {
+            interrupts::enable();
+            unsafe {
+                let call = (*mailbox.call.get()).unwrap();
+                (*call)();
+            }
+            mailbox.state.store(MAILBOX_DONE, Ordering::Release);
+        } else {
+            // Atomically wait for the wakeup interrupt
+            interrupts::enable_and_hlt();
+        }
+    }
+}
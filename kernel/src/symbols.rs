@@ -0,0 +1,233 @@
+//! Symbol lookup in the kernel ELF.
+//! The bootloader places the whole kernel file, including the section
+//! headers and the symbol table, at 2Mb and pads it to its memory layout.
+//! Only the debug information is stripped, so `.symtab` is available in memory.
+
+use core::fmt;
+
+/// Address of the kernel ELF file in memory
+const KERNEL_ELF: u64 = bootloader::TWO_MEG;
+
+/// Sanity limit for offsets into the kernel file
+const MAX_ELF_SIZE: u64 = 256 * bootloader::ONE_MEG;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Elf64Shdr {
+    sh_name: u32,
+    sh_type: u32,
+    sh_flags: u64,
+    sh_addr: u64,
+    sh_offset: u64,
+    sh_size: u64,
+    sh_link: u32,
+    sh_info: u32,
+    sh_addralign: u64,
+    sh_entsize: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Elf64Sym {
+    st_name: u32,
+    st_info: u8,
+    st_other: u8,
+    st_shndx: u16,
+    st_value: u64,
+    st_size: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct SymbolTable {
+    symbols: &'static [Elf64Sym],
+    strings: &'static [u8],
+}
+
+/// A resolved address
+#[derive(Clone, Copy)]
+pub struct Symbol {
+    /// Mangled name of the function
+    pub name: &'static str,
+    /// Offset of the address into the function
+    pub offset: u64,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", Demangle(self.name), self.offset)
+    }
+}
+
+static mut SYMBOLS: Option<SymbolTable> = None;
+
+/// Locate the symbol table of the kernel
+pub unsafe fn init() {
+    if SYMBOLS.is_none() {
+        SYMBOLS = SymbolTable::parse(KERNEL_ELF);
+
+        match SYMBOLS {
+            Some(table) => log::debug!("Found {} kernel symbols", table.symbols.len()),
+            None => log::warn!("Kernel ELF has no symbol table"),
+        }
+    }
+}
+
+impl SymbolTable {
+    unsafe fn parse(base: u64) -> Option<Self> {
+        let ident = core::slice::from_raw_parts(base as *const u8, 4);
+        if ident != ELF_MAGIC {
+            return None;
+        }
+
+        let shoff = *((base + 0x28) as *const u64);
+        let shentsize = *((base + 0x3a) as *const u16) as u64;
+        let shnum = *((base + 0x3c) as *const u16) as usize;
+        if shoff == 0 || shoff > MAX_ELF_SIZE || shentsize != 64 {
+            return None;
+        }
+
+        let sections = core::slice::from_raw_parts((base + shoff) as *const Elf64Shdr, shnum);
+        let symtab = sections.iter().find(|s| s.sh_type == SHT_SYMTAB)?;
+        let strtab = sections.get(symtab.sh_link as usize)?;
+        if symtab.sh_offset > MAX_ELF_SIZE || strtab.sh_offset > MAX_ELF_SIZE {
+            return None;
+        }
+
+        Some(SymbolTable {
+            symbols: core::slice::from_raw_parts(
+                (base + symtab.sh_offset) as *const Elf64Sym,
+                (symtab.sh_size / 24) as usize,
+            ),
+            strings: core::slice::from_raw_parts(
+                (base + strtab.sh_offset) as *const u8,
+                strtab.sh_size as usize,
+            ),
+        })
+    }
+
+    fn name(&self, sym: &Elf64Sym) -> &'static str {
+        let strings: &'static [u8] = self.strings;
+        let start = (sym.st_name as usize).min(strings.len());
+        let len = strings[start..]
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(strings.len() - start);
+        core::str::from_utf8(&strings[start..start + len]).unwrap_or("<invalid>")
+    }
+
+    /// Find the function containing `addr`
+    pub fn lookup(&self, addr: u64) -> Option<Symbol> {
+        let mut best: Option<&Elf64Sym> = None;
+        for sym in self.symbols.iter() {
+            if sym.st_info & 0xf != STT_FUNC || sym.st_value > addr {
+                continue;
+            }
+            if sym.st_size != 0 && addr < sym.st_value + sym.st_size {
+                best = Some(sym);
+                break;
+            }
+            // Symbols without a size only match if nothing better is found
+            if sym.st_size == 0 && best.map_or(true, |b| b.st_value < sym.st_value) {
+                best = Some(sym);
+            }
+        }
+
+        best.map(|sym| Symbol {
+            name: self.name(sym),
+            offset: addr - sym.st_value,
+        })
+    }
+}
+
+/// Resolve `addr` to a function of the kernel
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    unsafe { SYMBOLS.as_ref()?.lookup(addr) }
+}
+
+/// Formats legacy Rust symbol names (`_ZN...E`) without allocating.
+/// Other names are printed unchanged.
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = match self.0.strip_prefix("_ZN") {
+            Some(rest) => rest,
+            None => return f.write_str(self.0),
+        };
+
+        let mut first = true;
+        while let Some(len_end) = rest.find(|c: char| !c.is_ascii_digit()) {
+            let len: usize = match rest[..len_end].parse() {
+                Ok(len) if len_end + len <= rest.len() => len,
+                _ => break,
+            };
+            let ident = &rest[len_end..len_end + len];
+            rest = &rest[len_end + len..];
+
+            // Skip the trailing hash
+            if rest.starts_with('E') && is_hash(ident) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_ident(f, ident)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_hash(ident: &str) -> bool {
+    ident.len() == 17 && ident.starts_with('h') && ident[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn write_ident(f: &mut fmt::Formatter, mut ident: &str) -> fmt::Result {
+    // Identifiers starting with an escape get an underscore prepended
+    if ident.starts_with("_$") {
+        ident = &ident[1..];
+    }
+
+    while !ident.is_empty() {
+        if let Some(rest) = ident.strip_prefix("..") {
+            f.write_str("::")?;
+            ident = rest;
+        } else if ident.starts_with('$') {
+            let end = match ident[1..].find('$') {
+                Some(end) => end + 1,
+                None => return f.write_str(ident),
+            };
+            let escape = &ident[1..end];
+            match escape {
+                "SP" => f.write_str("@")?,
+                "BP" => f.write_str("*")?,
+                "RF" => f.write_str("&")?,
+                "LT" => f.write_str("<")?,
+                "GT" => f.write_str(">")?,
+                "LP" => f.write_str("(")?,
+                "RP" => f.write_str(")")?,
+                "C" => f.write_str(",")?,
+                _ => match escape
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(core::char::from_u32)
+                {
+                    Some(c) => write!(f, "{}", c)?,
+                    None => f.write_str(&ident[..=end])?,
+                },
+            }
+            ident = &ident[end + 1..];
+        } else {
+            let end = ident.find(|c| c == '$' || c == '.').unwrap_or(ident.len());
+            // A single dot is printed as is
+            let end = if end == 0 { 1 } else { end };
+            f.write_str(&ident[..end])?;
+            ident = &ident[end..];
+        }
+    }
+    Ok(())
+}
@@ -1,12 +1,16 @@
 use core::arch::x86_64::_rdtsc;
-use core::sync::atomic::{AtomicU64, Ordering};
-use x86_64::instructions::port::Port;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-/// The TSC tick rate in MHz
+pub mod tsc_sync;
+
+/// The TSC tick rate in kHz as selected by the clocksources
 /// We "default" to a 3 GHz tick rate, which is likely within a ballpark of
 /// actual tick rates if you happen to use the time routines prior to
 /// calibrating the TSC.
-static RDTSC_MHZ: AtomicU64 = AtomicU64::new(3_000);
+static RDTSC_KHZ: AtomicU64 = AtomicU64::new(3_000_000);
+
+/// Set once `RDTSC_KHZ` holds a measured rate
+static CALIBRATED: AtomicBool = AtomicBool::new(false);
 
 /// TSC at the time of boot of the system
 static RDTSC_START: AtomicU64 = AtomicU64::new(0);
@@ -14,13 +18,25 @@ static RDTSC_START: AtomicU64 = AtomicU64::new(0);
 /// Get the TSC rate in MHz
 #[inline]
 pub fn tsc_mhz() -> u64 {
-    RDTSC_MHZ.load(Ordering::Relaxed)
+    tsc_khz() / 1000
+}
+
+/// Get the TSC rate in kHz
+#[inline]
+pub fn tsc_khz() -> u64 {
+    RDTSC_KHZ.load(Ordering::Relaxed)
+}
+
+/// Whether `tsc_khz` is measured rather than the boot time guess
+#[inline]
+pub fn is_calibrated() -> bool {
+    CALIBRATED.load(Ordering::Acquire)
 }
 
 /// Returns the TSC value upon a future time in microseconds
 #[inline]
 pub fn future(microseconds: u64) -> u64 {
-    rdtsc() + (microseconds * tsc_mhz())
+    rdtsc() + (microseconds * tsc_khz() / 1000)
 }
 
 /// Returns system uptime in seconds as a float
@@ -32,7 +48,7 @@ pub fn uptime() -> f64 {
 /// Return number of seconds elapsed since a prior TSC value
 #[inline]
 pub fn elapsed(start_time: u64) -> f64 {
-    (rdtsc() - start_time) as f64 / RDTSC_MHZ.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    (rdtsc() - start_time) as f64 / tsc_khz() as f64 / 1_000.0
 }
 
 /// Busy sleep for a given number of microseconds
@@ -51,8 +67,8 @@ pub fn rdtsc() -> u64 {
     unsafe { _rdtsc() }
 }
 
-/// Using the PIT, determine the frequency of rdtsc. Round this frequency to
-/// the nearest 100MHz and return it.
+/// Determine the frequency of rdtsc against the best reference clock.
+/// The result is compared with the frequency the cpu reports through cpuid.
 pub unsafe fn calibrate() {
     // Store off the current rdtsc value
     let start = rdtsc();
@@ -63,44 +79,55 @@ pub unsafe fn calibrate() {
 
     RDTSC_START.store(start, Ordering::Relaxed);
 
-    // Start a timer
-    let mut c0_data: Port<u8> = Port::new(0x40);
-    let mut command: Port<u8> = Port::new(0x43);
-
-    // Program the PIT to use mode 0 (interrupt after countdown) to
-    // count down from 65535. This causes an interrupt to occur after
-    // about 54.92 milliseconds (65535 / 1193182). We mask interrupts
-    // from the PIT, thus we poll by sending the read back command
-    // to check whether the output pin is set to 1, indicating the
-    // countdown completed.
-    command.write(0x30);
-    c0_data.write(0xff);
-    c0_data.write(0xff);
-
-    loop {
-        // Send the read back command to latch status on channel 0
-        command.write(0xe2);
-
-        // If the output pin is high, then we know the countdown is
-        // done. Break from the loop.
-        if (c0_data.read() & 0x80) != 0 {
-            break;
-        }
-    }
-
-    // Stop the timer
-    let end = rdtsc();
+    let khz = crate::clocksource::init();
+    check_cpuid(khz);
 
-    // Compute the time, in seconds, that the countdown was supposed to
-    // take
-    let elapsed = 65535f64 / 1193182f64;
+    // Stock the TSC rate, keep the guess if the measurement is unusable
+    if khz == 0 {
+        log::warn!("TSC calibration returned 0 kHz, keeping {} kHz", tsc_khz());
+        return;
+    }
+    RDTSC_KHZ.store(khz, Ordering::Relaxed);
+    CALIBRATED.store(true, Ordering::Release);
+}
 
-    // Compute MHz for the rdtsc
-    let computed_rate = ((end - start) as f64) / elapsed / 1000000.0;
+/// TSC frequency in kHz reported by cpuid leaf 0x15 or 0x16
+fn cpuid_khz() -> Option<u64> {
+    use core::arch::x86_64::__cpuid;
 
-    // Round to the nearest 100MHz value
-    let rounded_rate = (((computed_rate / 100.0) + 0.5) as u64) * 100;
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 0x15 {
+        // Ratio of TSC to core crystal clock and the crystal clock in Hz
+        let res = unsafe { __cpuid(0x15) };
+        if res.eax != 0 && res.ebx != 0 && res.ecx != 0 {
+            return Some(res.ecx as u64 * res.ebx as u64 / res.eax as u64 / 1000);
+        }
+    }
+    if max_leaf >= 0x16 {
+        // Processor base frequency in MHz
+        let res = unsafe { __cpuid(0x16) };
+        if res.eax != 0 {
+            return Some(res.eax as u64 * 1000);
+        }
+    }
+    None
+}
 
-    // Stock the TSC rate
-    RDTSC_MHZ.store(rounded_rate, Ordering::Relaxed);
+fn check_cpuid(khz: u64) {
+    let reported = match cpuid_khz() {
+        Some(reported) => reported,
+        None => return,
+    };
+
+    // Base frequencies from leaf 0x16 are nominal, allow for 1%
+    let diff = (reported as i64 - khz as i64).abs() as u64;
+    if diff > reported / 100 {
+        log::warn!(
+            "Calibrated TSC frequency {} kHz differs from {} kHz reported by cpuid",
+            khz,
+            reported
+        );
+    } else {
+        log::debug!("TSC frequency matches cpuid: {} kHz", reported);
+    }
 }
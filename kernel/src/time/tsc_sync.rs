@@ -0,0 +1,219 @@
+//! Cross-core TSC synchronization check.
+//! All online cores leapfrog a shared timestamp under a lock and record
+//! whenever the TSC they read is older than the last one, i.e. time went
+//! backwards between two cores. The offset of every core to the bsp is
+//! estimated from the round trip with the smallest delay. With
+//! `IA32_TSC_ADJUST` the offsets are written back and the check repeated.
+
+use crate::apic;
+use crate::smp;
+use core::arch::x86_64::{__cpuid_count, _mm_lfence};
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_TSC_ADJUST: u32 = 0x3b;
+
+/// Lock acquisitions per core during the warp test
+const WARP_ITERATIONS: usize = 20_000;
+
+/// Request and reply exchanges per core to estimate its offset
+const OFFSET_ROUNDS: u64 = 1000;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// TSC of the core minus TSC of the bsp, indexed by core index
+static OFFSETS: [AtomicI64; bootloader::MAX_CORES] = [ZERO_OFFSET; bootloader::MAX_CORES];
+
+/// Whether timestamps taken on different cores can be compared
+static RELIABLE: AtomicBool = AtomicBool::new(false);
+
+/// Last timestamp of the leapfrog test
+static LAST_TSC: spin::Mutex<u64> = spin::Mutex::new(0);
+static WARPS: AtomicU64 = AtomicU64::new(0);
+static MAX_WARP: AtomicU64 = AtomicU64::new(0);
+
+/// Request and reply of the offset estimation, each on its own cache line
+#[repr(align(64))]
+struct Slot(AtomicU64);
+
+static REQUEST: Slot = Slot(AtomicU64::new(0));
+static REPLY: Slot = Slot(AtomicU64::new(0));
+static REPLY_TSC: Slot = Slot(AtomicU64::new(0));
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WarpResult {
+    /// Number of times time went backwards between two cores
+    pub warps: u64,
+    /// Largest step backwards in cycles
+    pub max_warp: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TscSyncReport {
+    pub before: WarpResult,
+    /// Result of the second warp test if offsets were corrected
+    pub after: Option<WarpResult>,
+    pub reliable: bool,
+}
+
+/// Rdtsc which is not executed before preceding loads
+#[inline]
+fn rdtsc_ordered() -> u64 {
+    unsafe { _mm_lfence() };
+    super::rdtsc()
+}
+
+pub fn has_tsc_adjust() -> bool {
+    let res = unsafe { __cpuid_count(0x7, 0) };
+    res.ebx & (1 << 1) != 0
+}
+
+/// The TSC ticks at the same rate in all power states
+pub fn has_invariant_tsc() -> bool {
+    let res = unsafe { core::arch::x86_64::__cpuid(0x8000_0007) };
+    res.edx & (1 << 8) != 0
+}
+
+/// Whether TSC values read on different cores can be compared.
+/// False until `init` has run.
+pub fn is_reliable() -> bool {
+    RELIABLE.load(Ordering::Relaxed)
+}
+
+/// Estimated TSC offset of the core with index `core` to the bsp in cycles
+pub fn offset(core: usize) -> i64 {
+    OFFSETS[core].load(Ordering::Relaxed)
+}
+
+fn leapfrog() {
+    for _ in 0..WARP_ITERATIONS {
+        let mut last = LAST_TSC.lock();
+        let now = rdtsc_ordered();
+        if now < *last {
+            WARPS.fetch_add(1, Ordering::Relaxed);
+            MAX_WARP.fetch_max(*last - now, Ordering::Relaxed);
+        }
+        *last = now;
+    }
+}
+
+/// Let all `cores` leapfrog concurrently and count the warps
+pub fn warp_test(cores: &[u32]) -> Result<WarpResult, smp::SmpError> {
+    *LAST_TSC.lock() = 0;
+    WARPS.store(0, Ordering::SeqCst);
+    MAX_WARP.store(0, Ordering::SeqCst);
+
+    smp::run_on_all(cores, |_| leapfrog())?;
+
+    Ok(WarpResult {
+        warps: WARPS.load(Ordering::SeqCst),
+        max_warp: MAX_WARP.load(Ordering::SeqCst),
+    })
+}
+
+fn offset_request(best: &AtomicI64) {
+    let mut min_rtt = u64::MAX;
+    for round in 1..=OFFSET_ROUNDS {
+        let t0 = rdtsc_ordered();
+        REQUEST.0.store(round, Ordering::Release);
+        while REPLY.0.load(Ordering::Acquire) != round {
+            core::hint::spin_loop();
+        }
+        let t2 = rdtsc_ordered();
+        let t1 = REPLY_TSC.0.load(Ordering::Relaxed);
+
+        // The reply was taken somewhere between t0 and t2
+        if t2 - t0 < min_rtt {
+            min_rtt = t2 - t0;
+            best.store(t1 as i64 - (t0 + (t2 - t0) / 2) as i64, Ordering::Relaxed);
+        }
+    }
+}
+
+fn offset_reply() {
+    for round in 1..=OFFSET_ROUNDS {
+        while REQUEST.0.load(Ordering::Acquire) != round {
+            core::hint::spin_loop();
+        }
+        REPLY_TSC.0.store(rdtsc_ordered(), Ordering::Relaxed);
+        REPLY.0.store(round, Ordering::Release);
+    }
+}
+
+/// Estimate the TSC of `apic_id` minus the TSC of the current core
+pub fn measure_offset(apic_id: u32) -> Result<i64, smp::SmpError> {
+    REQUEST.0.store(0, Ordering::SeqCst);
+    REPLY.0.store(0, Ordering::SeqCst);
+    let best = AtomicI64::new(0);
+
+    smp::run_on_all(&[apic::apic_id(), apic_id], |index| match index {
+        0 => offset_request(&best),
+        _ => offset_reply(),
+    })?;
+    Ok(best.load(Ordering::SeqCst))
+}
+
+/// Shift the TSC of the current core by `-offset` cycles
+unsafe fn adjust(offset: i64) {
+    let mut msr = Msr::new(IA32_TSC_ADJUST);
+    let value = msr.read() as i64;
+    msr.write(value.wrapping_sub(offset) as u64);
+}
+
+/// Record the offsets of all `cores` to the bsp
+fn measure_offsets(cores: &[u32], bsp: u32) -> Result<(), smp::SmpError> {
+    for &core in cores.iter().filter(|&&core| core != bsp) {
+        let offset = measure_offset(core)?;
+        let index = smp::index_of(core).ok_or(smp::SmpError::NotOnline(core))?;
+        OFFSETS[index].store(offset, Ordering::Relaxed);
+        log::debug!("TSC offset of core {}: {} cycles", core, offset);
+    }
+    Ok(())
+}
+
+/// Run the warp test on all cores and correct their offsets if possible.
+/// Has to be called on the bsp after the application processors started.
+pub unsafe fn init() -> Result<TscSyncReport, smp::SmpError> {
+    // Cores the bsp never launched would only run into the timeout
+    for id in smp::launched_cores() {
+        if !smp::wait_online(id, smp::ONLINE_TIMEOUT_US) {
+            log::warn!("Core {} is not online, skipping TSC check", id);
+        }
+    }
+    let cores: alloc::vec::Vec<u32> = smp::online_cores().collect();
+    let bsp = apic::apic_id();
+
+    let before = warp_test(&cores)?;
+    measure_offsets(&cores, bsp)?;
+
+    let mut after = None;
+    if before.warps != 0 && has_tsc_adjust() {
+        for &core in cores.iter().filter(|&&core| core != bsp) {
+            let offset = offset(smp::index_of(core).unwrap());
+            smp::run_on(core, || adjust(offset))?;
+        }
+        after = Some(warp_test(&cores)?);
+        measure_offsets(&cores, bsp)?;
+    }
+
+    let warps = after.unwrap_or(before).warps;
+    let reliable = warps == 0 && has_invariant_tsc();
+    RELIABLE.store(reliable, Ordering::Relaxed);
+
+    if reliable {
+        log::info!("TSC is synchronized across {} cores", cores.len());
+    } else {
+        log::warn!(
+            "TSC is not synchronized: {} warps, max {} cycles. Cross-core timestamps are unreliable",
+            warps,
+            after.unwrap_or(before).max_warp
+        );
+    }
+
+    Ok(TscSyncReport {
+        before,
+        after,
+        reliable,
+    })
+}
@@ -0,0 +1,356 @@
+//! Software timers on top of the local apic timer.
+//! Every core has a hashed timer wheel of `WHEEL_SLOTS` slots, one per
+//! `TICK_US`. Timers run their callback in interrupt context on the core
+//! which scheduled them and can only be cancelled from that core.
+//!
+//! In periodic mode the wheel advances on every tick of the apic timer and
+//! timers fire with the granularity of the tick. In tickless mode the apic
+//! timer is armed for the earliest timer, in TSC-deadline mode if
+//! available, and a core without timers gets no timer interrupts at all.
+
+use crate::apic;
+use crate::smp;
+use crate::time::{rdtsc, tsc_khz};
+use alloc::boxed::Box;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Resolution of the wheel in microseconds
+pub const TICK_US: u64 = 1000;
+
+/// Number of slots, timers further away than one rotation wait in their
+/// slot for the later round
+const WHEEL_SLOTS: usize = 256;
+
+/// Timers per core
+pub const MAX_TIMERS: usize = 128;
+
+/// Rate of the periodic timer after boot
+pub const DEFAULT_HZ: u32 = 1;
+
+const NIL: u16 = u16::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// All timers of the core are in use
+    Full,
+    /// `init` has not run on this core
+    NotInitialized,
+}
+
+/// Handle to cancel a scheduled timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: u16,
+    generation: u16,
+}
+
+/// Callbacks get the TSC at which they run and their argument
+pub type Callback = fn(now: u64, arg: u64);
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// TSC at which the timer fires
+    deadline: u64,
+    /// Period in TSC cycles, 0 for one-shot timers
+    period: u64,
+    callback: Option<Callback>,
+    arg: u64,
+    /// Next entry in the slot, or the free list
+    next: u16,
+    slot: u16,
+    generation: u16,
+}
+
+const EMPTY_ENTRY: Entry = Entry {
+    deadline: 0,
+    period: 0,
+    callback: None,
+    arg: 0,
+    next: NIL,
+    slot: 0,
+    generation: 0,
+};
+
+struct Wheel {
+    entries: [Entry; MAX_TIMERS],
+    slots: [u16; WHEEL_SLOTS],
+    free: u16,
+    /// Last tick whose slot was processed
+    last_tick: u64,
+    tickless: bool,
+    periodic_hz: u32,
+}
+
+const NO_WHEEL: Option<Box<Wheel>> = None;
+
+/// Timer wheels per core, indexed by core index
+static mut WHEELS: [Option<Box<Wheel>>; bootloader::MAX_CORES] = [NO_WHEEL; bootloader::MAX_CORES];
+
+/// TSC cycles per tick of the wheel
+fn tick_cycles() -> u64 {
+    (tsc_khz() * TICK_US / 1000).max(1)
+}
+
+impl Wheel {
+    fn new(now: u64) -> Self {
+        let mut entries = [EMPTY_ENTRY; MAX_TIMERS];
+        for (index, entry) in entries.iter_mut().enumerate() {
+            entry.next = if index + 1 < MAX_TIMERS {
+                (index + 1) as u16
+            } else {
+                NIL
+            };
+        }
+        Wheel {
+            entries,
+            slots: [NIL; WHEEL_SLOTS],
+            free: 0,
+            last_tick: now / tick_cycles(),
+            tickless: false,
+            periodic_hz: DEFAULT_HZ,
+        }
+    }
+
+    fn slot_of(&self, deadline: u64) -> usize {
+        // Timers which are already due go into the next slot to be processed
+        let tick = (deadline / tick_cycles()).max(self.last_tick + 1);
+        (tick % WHEEL_SLOTS as u64) as usize
+    }
+
+    fn link(&mut self, index: u16) {
+        let slot = self.slot_of(self.entries[index as usize].deadline);
+        self.entries[index as usize].next = self.slots[slot];
+        self.entries[index as usize].slot = slot as u16;
+        self.slots[slot] = index;
+    }
+
+    /// Remove `index` from its slot, returns false if it is not linked
+    fn unlink(&mut self, index: u16) -> bool {
+        let slot = self.entries[index as usize].slot as usize;
+        let next = self.entries[index as usize].next;
+        if self.slots[slot] == index {
+            self.slots[slot] = next;
+            return true;
+        }
+        let mut prev = self.slots[slot];
+        while prev != NIL {
+            if self.entries[prev as usize].next == index {
+                self.entries[prev as usize].next = next;
+                return true;
+            }
+            prev = self.entries[prev as usize].next;
+        }
+        false
+    }
+
+    fn release(&mut self, index: u16) {
+        let entry = &mut self.entries[index as usize];
+        entry.callback = None;
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.next = self.free;
+        self.free = index;
+    }
+
+    fn insert(
+        &mut self,
+        deadline: u64,
+        period: u64,
+        callback: Callback,
+        arg: u64,
+    ) -> Result<TimerId, TimerError> {
+        if self.free == NIL {
+            return Err(TimerError::Full);
+        }
+        let index = self.free;
+        self.free = self.entries[index as usize].next;
+
+        let entry = &mut self.entries[index as usize];
+        entry.deadline = deadline;
+        entry.period = period;
+        entry.callback = Some(callback);
+        entry.arg = arg;
+        let generation = entry.generation;
+        self.link(index);
+
+        Ok(TimerId { index, generation })
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        let entry = &self.entries[id.index as usize];
+        if entry.generation != id.generation || entry.callback.is_none() {
+            return false;
+        }
+        self.unlink(id.index);
+        self.release(id.index);
+        true
+    }
+
+    /// Unlink the first expired timer of `slot`
+    fn pop_expired(&mut self, slot: usize, now: u64) -> Option<u16> {
+        let mut index = self.slots[slot];
+        while index != NIL {
+            if self.entries[index as usize].deadline <= now {
+                self.unlink(index);
+                return Some(index);
+            }
+            index = self.entries[index as usize].next;
+        }
+        None
+    }
+
+    /// Earliest deadline of all timers
+    fn next_deadline(&self) -> Option<u64> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.callback.is_some())
+            .map(|entry| entry.deadline)
+            .min()
+    }
+}
+
+fn wheel() -> Option<&'static mut Wheel> {
+    unsafe { WHEELS[smp::core_index()].as_deref_mut() }
+}
+
+/// Allocate the wheel of the current core. Has to run after the heap and
+/// the apic timer are initialized.
+pub fn init() {
+    without_interrupts(|| unsafe {
+        let slot = &mut WHEELS[smp::core_index()];
+        if slot.is_none() {
+            *slot = Some(Box::new(Wheel::new(rdtsc())));
+        }
+    });
+}
+
+/// Program the apic timer for the earliest timer of a tickless wheel
+fn arm(wheel: &Wheel) {
+    unsafe {
+        match wheel.next_deadline() {
+            Some(deadline) => apic::set_timer_deadline(deadline),
+            None => apic::stop_timer(),
+        }
+    }
+}
+
+/// Run all expired timers of the current core. Called by the timer
+/// interrupt handler.
+pub fn interrupt() {
+    let now = rdtsc();
+    let (tickless, first) = match wheel() {
+        Some(wheel) => (wheel.tickless, wheel.last_tick + 1),
+        None => return,
+    };
+
+    let now_tick = now / tick_cycles();
+    // Timers which are due early are placed in the next slot
+    let last = now_tick.max(first).min(first + WHEEL_SLOTS as u64 - 1);
+
+    for tick in first..=last {
+        let slot = (tick % WHEEL_SLOTS as u64) as usize;
+        loop {
+            // Callbacks may schedule timers, so the wheel is not held across them
+            let wheel = match wheel() {
+                Some(wheel) => wheel,
+                None => return,
+            };
+            let index = match wheel.pop_expired(slot, now) {
+                Some(index) => index,
+                None => break,
+            };
+            let entry = wheel.entries[index as usize];
+            if entry.period != 0 {
+                // Skip missed periods instead of firing them back to back
+                let missed = (now - entry.deadline) / entry.period;
+                wheel.entries[index as usize].deadline += (missed + 1) * entry.period;
+                wheel.link(index);
+            } else {
+                wheel.release(index);
+            }
+            if let Some(callback) = entry.callback {
+                callback(now, entry.arg);
+            }
+        }
+    }
+
+    if let Some(wheel) = wheel() {
+        wheel.last_tick = wheel.last_tick.max(now_tick);
+        if tickless {
+            arm(wheel);
+        }
+    }
+}
+
+/// Whether the current core runs without a periodic tick
+pub fn is_tickless() -> bool {
+    wheel().map_or(false, |wheel| wheel.tickless)
+}
+
+/// Only interrupt the current core when a timer expires
+pub fn set_tickless(tickless: bool) -> Result<(), TimerError> {
+    without_interrupts(|| {
+        let wheel = wheel().ok_or(TimerError::NotInitialized)?;
+        wheel.tickless = tickless;
+        if tickless {
+            arm(wheel);
+        } else {
+            unsafe { apic::set_timer_frequency(wheel.periodic_hz) };
+        }
+        Ok(())
+    })
+}
+
+/// Run the apic timer of the current core periodically with `hz`.
+/// The wheel advances with every tick.
+pub fn set_periodic(hz: u32) -> Result<(), TimerError> {
+    without_interrupts(|| {
+        let wheel = wheel().ok_or(TimerError::NotInitialized)?;
+        wheel.tickless = false;
+        wheel.periodic_hz = hz;
+        unsafe { apic::set_timer_frequency(hz) };
+        Ok(())
+    })
+}
+
+fn schedule(
+    deadline: u64,
+    period: u64,
+    callback: Callback,
+    arg: u64,
+) -> Result<TimerId, TimerError> {
+    without_interrupts(|| {
+        let wheel = wheel().ok_or(TimerError::NotInitialized)?;
+        let rearm = wheel.tickless && wheel.next_deadline().map_or(true, |next| deadline < next);
+        let id = wheel.insert(deadline, period, callback, arg)?;
+        if rearm {
+            arm(wheel);
+        }
+        Ok(id)
+    })
+}
+
+/// Run `callback` on the current core once the TSC reaches `deadline`
+pub fn schedule_at(deadline: u64, callback: Callback, arg: u64) -> Result<TimerId, TimerError> {
+    schedule(deadline, 0, callback, arg)
+}
+
+/// Run `callback` on the current core in `microseconds`
+pub fn schedule_in(microseconds: u64, callback: Callback, arg: u64) -> Result<TimerId, TimerError> {
+    schedule_at(crate::time::future(microseconds), callback, arg)
+}
+
+/// Run `callback` on the current core every `microseconds`
+pub fn schedule_every(
+    microseconds: u64,
+    callback: Callback,
+    arg: u64,
+) -> Result<TimerId, TimerError> {
+    let period = (microseconds * tsc_khz() / 1000).max(1);
+    schedule(rdtsc() + period, period, callback, arg)
+}
+
+/// Cancel a timer of the current core. Returns false if it already
+/// fired or was cancelled.
+pub fn cancel(id: TimerId) -> bool {
+    without_interrupts(|| wheel().map_or(false, |wheel| wheel.cancel(id)))
+}
@@ -0,0 +1,239 @@
+//! Event tracing into per-core ring buffers.
+//! Recording an event is lock-free and never allocates, so trace points can
+//! be placed inside of interrupt handlers and the allocator. Slots are
+//! reserved with an atomic increment, which also keeps nested interrupts on
+//! the same core from overwriting each other. Full rings overwrite their
+//! oldest records.
+//!
+//! The buffers are dumped over serial in the Chrome trace event format,
+//! extract them with `tools/trace.sh` and load them into
+//! chrome://tracing or https://ui.perfetto.dev
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Records per core if not configured otherwise
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TraceEvent {
+    /// arg0: vector
+    InterruptEnter = 1,
+    /// arg0: vector
+    InterruptExit,
+    /// arg0: destination apic id, arg1: vector
+    IpiSend,
+    /// arg0: vector
+    IpiReceive,
+    /// arg0: accessed address, arg1: error code
+    PageFault,
+    /// arg0: size, arg1: address
+    Alloc,
+    /// arg0: size, arg1: address
+    Dealloc,
+    /// Start of a named span, arg0/arg1: pointer and length of a static string
+    Begin,
+    /// End of a named span, arg0/arg1: pointer and length of a static string
+    End,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Record {
+    pub tsc: u64,
+    pub arg0: u64,
+    pub arg1: u64,
+    /// `smp::core_index` of the recording core
+    pub core: u8,
+    /// Zero if the slot has never been written
+    pub event: u8,
+}
+
+const EMPTY: Record = Record {
+    tsc: 0,
+    arg0: 0,
+    arg1: 0,
+    core: 0,
+    event: 0,
+};
+
+struct Ring {
+    records: Vec<Record>,
+    /// Number of slots reserved so far, the next slot is `head & mask`
+    head: AtomicUsize,
+    mask: usize,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+const NO_RING: Option<Ring> = None;
+
+/// Ring buffers per core, indexed by `smp::core_index`
+static mut RINGS: [Option<Ring>; bootloader::MAX_CORES] = [NO_RING; bootloader::MAX_CORES];
+
+/// Allocate the ring of the current core with at least `capacity` records
+/// and enable tracing. Has to be called on every core that should be traced.
+pub fn start(capacity: usize) {
+    let capacity = capacity.max(2).next_power_of_two();
+    let core = crate::smp::core_index();
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        if RINGS[core].is_none() {
+            let mut records = Vec::with_capacity(capacity);
+            records.resize(capacity, EMPTY);
+            RINGS[core] = Some(Ring {
+                records,
+                head: AtomicUsize::new(0),
+                mask: capacity - 1,
+            });
+        }
+    });
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Stop recording on all cores. The buffers are kept until `dump`.
+pub fn stop() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record an event on the current core
+#[inline]
+pub fn event(event: TraceEvent, arg0: u64, arg1: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let core = crate::smp::core_index();
+    let ring = match unsafe { RINGS[core].as_ref() } {
+        Some(ring) => ring,
+        None => return,
+    };
+
+    let index = ring.head.fetch_add(1, Ordering::Relaxed) & ring.mask;
+    let record = Record {
+        tsc: crate::time::rdtsc(),
+        arg0,
+        arg1,
+        core: core as u8,
+        event: event as u8,
+    };
+    unsafe {
+        let slot = ring.records.as_ptr().add(index) as *mut Record;
+        core::ptr::write_volatile(slot, record);
+    }
+}
+
+/// Begin a named span on the current core
+#[inline]
+pub fn begin(name: &'static str) {
+    event(TraceEvent::Begin, name.as_ptr() as u64, name.len() as u64);
+}
+
+/// End a named span on the current core
+#[inline]
+pub fn end(name: &'static str) {
+    event(TraceEvent::End, name.as_ptr() as u64, name.len() as u64);
+}
+
+/// Records of a ring from the oldest to the newest
+fn records(ring: &Ring) -> impl Iterator<Item = &Record> {
+    let head = ring.head.load(Ordering::SeqCst);
+    let len = head.min(ring.records.len());
+    (head - len..head).map(move |i| &ring.records[i & ring.mask])
+}
+
+fn print_record(record: &Record, first: bool) {
+    let event = match record.event {
+        1 => TraceEvent::InterruptEnter,
+        2 => TraceEvent::InterruptExit,
+        3 => TraceEvent::IpiSend,
+        4 => TraceEvent::IpiReceive,
+        5 => TraceEvent::PageFault,
+        6 => TraceEvent::Alloc,
+        7 => TraceEvent::Dealloc,
+        8 => TraceEvent::Begin,
+        9 => TraceEvent::End,
+        _ => return,
+    };
+
+    // Timestamps are in microseconds on the time base of the bsp
+    let tsc = record.tsc as i64 - crate::time::tsc_sync::offset(record.core as usize);
+    let ts = tsc as f64 * 1000.0 / crate::time::tsc_khz() as f64;
+    let sep = if first { "" } else { "," };
+    let (a, b) = (record.arg0, record.arg1);
+
+    match event {
+        TraceEvent::InterruptEnter | TraceEvent::InterruptExit => {
+            let ph = if event == TraceEvent::InterruptEnter {
+                "B"
+            } else {
+                "E"
+            };
+            crate::serial_println!(
+                "{}{{\"name\":\"irq {:#x}\",\"cat\":\"irq\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":0,\"tid\":{}}}",
+                sep, a, ph, ts, record.core
+            );
+        }
+        TraceEvent::Begin | TraceEvent::End => {
+            let name = unsafe {
+                core::str::from_utf8_unchecked(core::slice::from_raw_parts(
+                    a as *const u8,
+                    b as usize,
+                ))
+            };
+            let ph = if event == TraceEvent::Begin { "B" } else { "E" };
+            crate::serial_println!(
+                "{}{{\"name\":\"{}\",\"cat\":\"span\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":0,\"tid\":{}}}",
+                sep, name, ph, ts, record.core
+            );
+        }
+        _ => {
+            let (name, arg0, arg1) = match event {
+                TraceEvent::IpiSend => ("ipi send", "dest", "vector"),
+                TraceEvent::IpiReceive => ("ipi receive", "vector", "unused"),
+                TraceEvent::PageFault => ("page fault", "addr", "error"),
+                TraceEvent::Alloc => ("alloc", "size", "addr"),
+                _ => ("dealloc", "size", "addr"),
+            };
+            crate::serial_println!(
+                "{}{{\"name\":\"{}\",\"ph\":\"i\",\"s\":\"t\",\"ts\":{:.3},\"pid\":0,\"tid\":{},\"args\":{{\"{}\":\"{:#x}\",\"{}\":\"{:#x}\"}}}}",
+                sep, name, ts, record.core, arg0, a, arg1, b
+            );
+        }
+    }
+}
+
+/// Stop tracing and print the records of all cores over serial as one
+/// Chrome trace. Does not allocate, so it can be used from a panic handler.
+pub fn dump() {
+    stop();
+
+    crate::serial_println!("# trace begin");
+    crate::serial_println!("{{\"traceEvents\":[");
+    let mut first = true;
+    for ring in unsafe { RINGS.iter() }.flatten() {
+        for record in records(ring) {
+            if record.event != 0 {
+                print_record(record, first);
+                first = false;
+            }
+        }
+    }
+    crate::serial_println!(
+        "],\"displayTimeUnit\":\"ns\",\"otherData\":{{\"tscReliable\":{}}}}}",
+        crate::time::tsc_sync::is_reliable()
+    );
+    crate::serial_println!("# trace end");
+}
+
+/// Dump the buffers if tracing was running when the kernel panicked
+pub fn dump_on_panic() {
+    if is_enabled() {
+        dump();
+    }
+}
@@ -36,10 +36,8 @@ static mut TSS_ARR: [Option<TaskStateSegment>; bootloader::MAX_CORES] =
     [None; bootloader::MAX_CORES];
 
 pub unsafe fn init(boot_info: &'static bootloader::bootinfo::BootInfo) {
-    let (core, core_index) = boot_info
-        .cores
-        .get_by_apic_id(crate::apic::apic_id())
-        .unwrap();
+    let core_index = crate::smp::core_index();
+    let core = &boot_info.cores[core_index];
 
     TSS_STACK_ITER = Some(StackIter::new(
         bootloader::TSS_STACKS_PER_CPU.try_into().unwrap(),
@@ -1,11 +1,16 @@
-pub static mut VGA_WRITER: Option<spin::Mutex<Writer>> = None;
+use crate::lock::Mutex;
+
+pub static mut VGA_WRITER: Option<Mutex<Writer>> = None;
 
 pub unsafe fn init() {
-    VGA_WRITER = Some(spin::Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Black, Color::Yellow),
-        buffer: &mut *(0xb8000 as *mut Buffer),
-    }))
+    VGA_WRITER = Some(Mutex::new(
+        "vga",
+        Writer {
+            column_position: 0,
+            color_code: ColorCode::new(Color::Black, Color::Yellow),
+            buffer: &mut *(0xb8000 as *mut Buffer),
+        },
+    ))
 }
 
 #[allow(dead_code)]
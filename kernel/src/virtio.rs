@@ -0,0 +1,271 @@
+//! Virtio 1.x over PCI.
+//! Modern virtio devices describe the location of their configuration
+//! structures with vendor specific PCI capabilities. The common
+//! configuration negotiates features and sets up the virtqueues, the
+//! notification area kicks the device after buffers were added and the
+//! device configuration holds the device type specific fields.
+
+pub mod blk;
+pub mod net;
+pub mod p9;
+pub mod queue;
+
+use crate::memory::BootInfoFrameAllocator;
+use crate::pci::{bar, config, DriverError, PciFunction, COMMAND_BUS_MASTER, COMMAND_MEMORY_SPACE};
+use core::ptr::{read_volatile, write_volatile};
+use queue::Virtqueue;
+use x86_64::structures::paging::OffsetPageTable;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// Device ids of modern devices are this plus the virtio device type
+pub const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+
+/// The device complies to virtio 1.0 or later
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// Types of the virtio PCI capabilities
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+/// Offsets in the common configuration
+#[derive(Clone, Copy)]
+#[repr(u64)]
+enum Common {
+    DeviceFeatureSelect = 0x00,
+    DeviceFeature = 0x04,
+    DriverFeatureSelect = 0x08,
+    DriverFeature = 0x0c,
+    NumQueues = 0x12,
+    DeviceStatus = 0x14,
+    QueueSelect = 0x16,
+    QueueSize = 0x18,
+    QueueMsixVector = 0x1a,
+    QueueEnable = 0x1c,
+    QueueNotifyOff = 0x1e,
+    QueueDesc = 0x20,
+    QueueDriver = 0x28,
+    QueueDevice = 0x30,
+}
+
+/// MSI-X vector value which disables the interrupt of a queue
+pub const NO_VECTOR: u16 = 0xffff;
+
+/// Time the device gets to complete a reset
+const RESET_TIMEOUT_US: u64 = 1_000_000;
+
+/// Configuration structures of a virtio PCI function, all identity mapped
+#[derive(Debug)]
+pub struct VirtioPci {
+    function: &'static PciFunction,
+    common: u64,
+    notify: u64,
+    notify_multiplier: u32,
+    isr: u64,
+    device: u64,
+}
+
+impl VirtioPci {
+    /// Find and map the configuration structures of `function`
+    pub unsafe fn new(
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        function: &'static PciFunction,
+    ) -> Result<VirtioPci, DriverError> {
+        let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+        let mut notify_multiplier = 0;
+
+        for capability in function.vendor_capabilities() {
+            let address = function.address;
+            let cfg_type = config::read8(address, capability.offset + 3);
+            let bar_index = config::read8(address, capability.offset + 4);
+            let offset = config::read32(address, capability.offset + 8) as u64;
+
+            // Take the first structure of each type, the later ones are alternatives
+            let slot = match cfg_type {
+                CAP_COMMON_CFG => &mut common,
+                CAP_NOTIFY_CFG => &mut notify,
+                CAP_ISR_CFG => &mut isr,
+                CAP_DEVICE_CFG => &mut device,
+                _ => continue,
+            };
+            if slot.is_some() {
+                continue;
+            }
+            let bar = function
+                .bars
+                .get(bar_index as usize)
+                .copied()
+                .flatten()
+                .ok_or(DriverError::MissingBar(bar_index))?;
+            let base = bar::map(mapper, frame_allocator, &bar)
+                .ok_or(DriverError::MissingBar(bar_index))?;
+            *slot = Some(base + offset);
+
+            if cfg_type == CAP_NOTIFY_CFG {
+                notify_multiplier = config::read32(address, capability.offset + 16);
+            }
+        }
+
+        function.enable(COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+        Ok(VirtioPci {
+            function,
+            common: common.ok_or(DriverError::MissingCapability("virtio common config"))?,
+            notify: notify.ok_or(DriverError::MissingCapability("virtio notify config"))?,
+            notify_multiplier,
+            isr: isr.ok_or(DriverError::MissingCapability("virtio isr config"))?,
+            device: device.unwrap_or(0),
+        })
+    }
+
+    pub fn function(&self) -> &'static PciFunction {
+        self.function
+    }
+
+    unsafe fn read_common<T>(&self, register: Common) -> T {
+        read_volatile((self.common + register as u64) as *const T)
+    }
+
+    unsafe fn write_common<T>(&self, register: Common, value: T) {
+        write_volatile((self.common + register as u64) as *mut T, value);
+    }
+
+    pub unsafe fn status(&self) -> u8 {
+        self.read_common(Common::DeviceStatus)
+    }
+
+    unsafe fn add_status(&self, status: u8) {
+        let current = self.status();
+        self.write_common(Common::DeviceStatus, current | status);
+    }
+
+    /// Reset the device, it stops using all queues
+    pub unsafe fn reset(&self) -> Result<(), DriverError> {
+        self.write_common(Common::DeviceStatus, 0u8);
+        let deadline = crate::time::future(RESET_TIMEOUT_US);
+        while self.status() != 0 {
+            if crate::time::rdtsc() > deadline {
+                return Err(DriverError::Device("virtio reset timed out"));
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    /// Give up on a device after a request of the driver `name` timed out.
+    /// The request stays in flight and the device may still write to its
+    /// buffers, so the device is reset before they are reused. The driver
+    /// must not use its queues afterwards.
+    pub unsafe fn abort(&self, name: &str) {
+        log::warn!(
+            "{} {}: request timed out, resetting the device",
+            name,
+            self.function.address
+        );
+        if let Err(err) = self.reset() {
+            log::warn!("{} {}: {:?}", name, self.function.address, err);
+        }
+    }
+
+    /// Reset the device and negotiate the `wanted` features it offers.
+    /// Returns the accepted features.
+    pub unsafe fn negotiate(&self, wanted: u64) -> Result<u64, DriverError> {
+        self.reset()?;
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let mut offered = 0u64;
+        for select in 0..2u32 {
+            self.write_common(Common::DeviceFeatureSelect, select);
+            offered |= (self.read_common::<u32>(Common::DeviceFeature) as u64) << (32 * select);
+        }
+        if offered & VIRTIO_F_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(DriverError::Device("legacy only virtio device"));
+        }
+
+        let accepted = offered & (wanted | VIRTIO_F_VERSION_1);
+        for select in 0..2u32 {
+            self.write_common(Common::DriverFeatureSelect, select);
+            self.write_common(Common::DriverFeature, (accepted >> (32 * select)) as u32);
+        }
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(DriverError::Device("virtio features rejected"));
+        }
+        Ok(accepted)
+    }
+
+    pub unsafe fn num_queues(&self) -> u16 {
+        self.read_common(Common::NumQueues)
+    }
+
+    /// Allocate and enable queue `index` with at most `size` entries
+    pub unsafe fn setup_queue(
+        &self,
+        frame_allocator: &mut BootInfoFrameAllocator,
+        index: u16,
+        size: u16,
+    ) -> Result<Virtqueue, DriverError> {
+        self.write_common(Common::QueueSelect, index);
+        let max = self.read_common::<u16>(Common::QueueSize);
+        if max == 0 {
+            return Err(DriverError::Device("virtqueue does not exist"));
+        }
+        // Split queues have a power of two size
+        let size = size.min(max);
+        let size = 1 << (15 - size.leading_zeros());
+
+        let notify_off = self.read_common::<u16>(Common::QueueNotifyOff) as u64;
+        let notify = self.notify + notify_off * self.notify_multiplier as u64;
+        let queue =
+            Virtqueue::new(frame_allocator, index, size, notify).ok_or(DriverError::OutOfMemory)?;
+
+        self.write_common(Common::QueueSize, size);
+        self.write_common(Common::QueueMsixVector, NO_VECTOR);
+        self.write_common(Common::QueueDesc, queue.descriptor_area());
+        self.write_common(Common::QueueDriver, queue.driver_area());
+        self.write_common(Common::QueueDevice, queue.device_area());
+        self.write_common(Common::QueueEnable, 1u16);
+        Ok(queue)
+    }
+
+    /// Let queue `index` interrupt through MSI-X table entry `entry`.
+    /// Returns false if the device could not allocate the vector.
+    pub unsafe fn set_queue_msix(&self, index: u16, entry: u16) -> bool {
+        self.write_common(Common::QueueSelect, index);
+        self.write_common(Common::QueueMsixVector, entry);
+        self.read_common::<u16>(Common::QueueMsixVector) == entry
+    }
+
+    /// Finish initialization, the device may use the queues from now on
+    pub unsafe fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Read and acknowledge the interrupt status of INTx interrupts
+    pub unsafe fn isr(&self) -> u8 {
+        read_volatile(self.isr as *const u8)
+    }
+
+    /// Read a field of the device specific configuration
+    pub unsafe fn read_config<T: Copy>(&self, offset: u64) -> T {
+        assert!(self.device != 0, "virtio device has no device config");
+        read_volatile((self.device + offset) as *const T)
+    }
+
+    pub unsafe fn write_config<T: Copy>(&self, offset: u64, value: T) {
+        assert!(self.device != 0, "virtio device has no device config");
+        write_volatile((self.device + offset) as *mut T, value);
+    }
+}
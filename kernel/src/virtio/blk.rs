@@ -0,0 +1,310 @@
+//! Virtio block device.
+//! Requests are a chain of a header with the type and sector, the data
+//! buffers and a status byte written by the device. Data goes through a
+//! bounce buffer in DMA memory and completions are polled.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{VirtioPci, MODERN_DEVICE_ID_BASE, VENDOR_ID};
+use crate::lock::Mutex;
+use crate::memory::BootInfoFrameAllocator;
+use crate::pci::{Device, DriverError, Match, PciFunction};
+use alloc::sync::Arc;
+use x86_64::structures::paging::OffsetPageTable;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Sectors per request, the size of the bounce buffer
+const MAX_SECTORS: usize = 128;
+
+/// Entries of the request queue
+const QUEUE_SIZE: u16 = 64;
+
+/// Time the device gets for a request
+const TIMEOUT_US: u64 = 5_000_000;
+
+/// Feature bits
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+/// Request status
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Offsets in the device configuration
+const CONFIG_CAPACITY: u64 = 0;
+const CONFIG_BLK_SIZE: u64 = 20;
+
+pub const MATCHES: &[Match] = &[
+    Match::Id {
+        vendor_id: VENDOR_ID,
+        device_id: MODERN_DEVICE_ID_BASE + 2,
+    },
+    // Transitional device
+    Match::Id {
+        vendor_id: VENDOR_ID,
+        device_id: 0x1001,
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlkError {
+    NotInitialized,
+    /// Buffers have to be a multiple of `SECTOR_SIZE`
+    Unaligned,
+    /// The request goes beyond the capacity
+    OutOfRange,
+    ReadOnly,
+    Unsupported,
+    /// The device reported an error status
+    Io(u8),
+    Timeout,
+}
+
+/// Header of a request
+#[repr(C)]
+struct RequestHeader {
+    typ: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+struct Inner {
+    queue: Virtqueue,
+    /// Sectors of 512 bytes
+    capacity: u64,
+    block_size: u32,
+    read_only: bool,
+    flush: bool,
+    /// DMA page with the request header and the status byte
+    request: u64,
+    /// DMA bounce buffer of `MAX_SECTORS`
+    bounce: u64,
+}
+
+pub struct VirtioBlk {
+    function: &'static PciFunction,
+    transport: spin::Once<VirtioPci>,
+    inner: Mutex<Option<Inner>>,
+}
+
+pub fn probe(function: &'static PciFunction) -> Option<Arc<dyn Device>> {
+    Some(Arc::new(VirtioBlk {
+        function,
+        transport: spin::Once::new(),
+        inner: Mutex::new("virtio_blk", None),
+    }))
+}
+
+impl Inner {
+    /// Submit a request and poll for its completion
+    fn submit(&mut self, typ: u32, sector: u64, data: Option<Buffer>) -> Result<(), BlkError> {
+        let status = self.request + core::mem::size_of::<RequestHeader>() as u64;
+        unsafe {
+            core::ptr::write_volatile(
+                self.request as *mut RequestHeader,
+                RequestHeader {
+                    typ,
+                    reserved: 0,
+                    sector,
+                },
+            );
+            core::ptr::write_volatile(status as *mut u8, 0xff);
+        }
+
+        let header = Buffer::readable(self.request, core::mem::size_of::<RequestHeader>() as u32);
+        let status_buffer = Buffer::writable(status, 1);
+        let head = match data {
+            Some(data) => self.queue.add(&[header, data, status_buffer]),
+            None => self.queue.add(&[header, status_buffer]),
+        }
+        .expect("virtio-blk queue full");
+        self.queue.notify();
+
+        // Only one request is in flight at a time
+        let deadline = crate::time::future(TIMEOUT_US);
+        loop {
+            if let Some((used, _)) = self.queue.pop_used() {
+                debug_assert_eq!(used, head);
+                break;
+            }
+            if crate::time::rdtsc() > deadline {
+                return Err(BlkError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+
+        match unsafe { core::ptr::read_volatile(status as *const u8) } {
+            VIRTIO_BLK_S_OK => Ok(()),
+            VIRTIO_BLK_S_UNSUPP => Err(BlkError::Unsupported),
+            status => Err(BlkError::Io(status)),
+        }
+    }
+
+    fn check(&self, sector: u64, len: usize) -> Result<(), BlkError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(BlkError::Unaligned);
+        }
+        let end = sector
+            .checked_add((len / SECTOR_SIZE) as u64)
+            .ok_or(BlkError::OutOfRange)?;
+        if end > self.capacity {
+            return Err(BlkError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+impl VirtioBlk {
+    fn with_inner<R>(
+        &self,
+        f: impl FnOnce(&mut Inner) -> Result<R, BlkError>,
+    ) -> Result<R, BlkError> {
+        let mut inner = self.inner.lock();
+        let result = f(inner.as_mut().ok_or(BlkError::NotInitialized)?);
+        if let Err(BlkError::Timeout) = result {
+            if let Some(transport) = self.transport.get() {
+                unsafe { transport.abort("virtio-blk") };
+            }
+            *inner = None;
+        }
+        result
+    }
+
+    /// Size of the disk in sectors of `SECTOR_SIZE`
+    pub fn capacity(&self) -> u64 {
+        self.with_inner(|inner| Ok(inner.capacity)).unwrap_or(0)
+    }
+
+    /// Optimal I/O size reported by the device
+    pub fn block_size(&self) -> u32 {
+        self.with_inner(|inner| Ok(inner.block_size))
+            .unwrap_or(SECTOR_SIZE as u32)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.with_inner(|inner| Ok(inner.read_only)).unwrap_or(true)
+    }
+
+    /// Read `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    pub fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlkError> {
+        self.with_inner(|inner| {
+            inner.check(sector, buf.len())?;
+            for (i, chunk) in buf.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+                let sector = sector + (i * MAX_SECTORS) as u64;
+                let data = Buffer::writable(inner.bounce, chunk.len() as u32);
+                inner.submit(VIRTIO_BLK_T_IN, sector, Some(data))?;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        inner.bounce as *const u8,
+                        chunk.as_mut_ptr(),
+                        chunk.len(),
+                    );
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Write `buf.len() / SECTOR_SIZE` sectors starting at `sector`
+    pub fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlkError> {
+        self.with_inner(|inner| {
+            if inner.read_only {
+                return Err(BlkError::ReadOnly);
+            }
+            inner.check(sector, buf.len())?;
+            for (i, chunk) in buf.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+                let sector = sector + (i * MAX_SECTORS) as u64;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        chunk.as_ptr(),
+                        inner.bounce as *mut u8,
+                        chunk.len(),
+                    );
+                }
+                let data = Buffer::readable(inner.bounce, chunk.len() as u32);
+                inner.submit(VIRTIO_BLK_T_OUT, sector, Some(data))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Make all completed writes persistent
+    pub fn flush(&self) -> Result<(), BlkError> {
+        self.with_inner(|inner| {
+            if !inner.flush {
+                return Ok(());
+            }
+            inner.submit(VIRTIO_BLK_T_FLUSH, 0, None)
+        })
+    }
+}
+
+impl Device for VirtioBlk {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn function(&self) -> &'static PciFunction {
+        self.function
+    }
+
+    unsafe fn init(
+        &self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<(), DriverError> {
+        let transport = VirtioPci::new(mapper, frame_allocator, self.function)?;
+        let features =
+            transport.negotiate(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH)?;
+        let queue = transport.setup_queue(frame_allocator, 0, QUEUE_SIZE)?;
+
+        let capacity = transport.read_config::<u64>(CONFIG_CAPACITY);
+        let block_size = if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+            transport.read_config::<u32>(CONFIG_BLK_SIZE)
+        } else {
+            SECTOR_SIZE as u32
+        };
+        let request = frame_allocator
+            .allocate_dma(4096)
+            .ok_or(DriverError::OutOfMemory)?;
+        let bounce = frame_allocator
+            .allocate_dma((MAX_SECTORS * SECTOR_SIZE) as u64)
+            .ok_or(DriverError::OutOfMemory)?;
+        transport.driver_ok();
+
+        log::info!(
+            "virtio-blk {}: {} sectors ({} MiB){}",
+            self.function.address,
+            capacity,
+            capacity * SECTOR_SIZE as u64 / (1024 * 1024),
+            if features & VIRTIO_BLK_F_RO != 0 {
+                ", read only"
+            } else {
+                ""
+            }
+        );
+        self.transport.call_once(|| transport);
+        *self.inner.lock() = Some(Inner {
+            queue,
+            capacity,
+            block_size,
+            read_only: features & VIRTIO_BLK_F_RO != 0,
+            flush: features & VIRTIO_BLK_F_FLUSH != 0,
+            request,
+            bounce,
+        });
+        Ok(())
+    }
+
+    unsafe fn purge(&self) {
+        if let Some(transport) = self.transport.get() {
+            transport.reset().ok();
+        }
+    }
+}
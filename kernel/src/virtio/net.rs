@@ -0,0 +1,210 @@
+//! Virtio network device.
+//! Queue 0 receives and queue 1 transmits. Every descriptor of both queues
+//! owns a DMA buffer which holds the virtio-net header followed by one
+//! ethernet frame, so the head of a used chain identifies its buffer.
+//! Completions are polled by the network stack.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{VirtioPci, MODERN_DEVICE_ID_BASE, VENDOR_ID};
+use crate::lock::Mutex;
+use crate::memory::BootInfoFrameAllocator;
+use crate::net::{MacAddr, NetDevice, NetError, MAX_FRAME};
+use crate::pci::{Device, DriverError, Match, PciFunction};
+use alloc::sync::Arc;
+use x86_64::structures::paging::OffsetPageTable;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// Entries of each queue
+const QUEUE_SIZE: u16 = 128;
+
+/// Size of the buffer of a descriptor
+const BUFFER_SIZE: u64 = 2048;
+
+/// Size of the virtio-net header of virtio 1.0 devices
+const HEADER_SIZE: usize = 12;
+
+/// Feature bits
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+/// Offsets in the device configuration
+const CONFIG_MAC: u64 = 0;
+const CONFIG_STATUS: u64 = 6;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+pub const MATCHES: &[Match] = &[
+    Match::Id {
+        vendor_id: VENDOR_ID,
+        device_id: MODERN_DEVICE_ID_BASE + 1,
+    },
+    // Transitional device
+    Match::Id {
+        vendor_id: VENDOR_ID,
+        device_id: 0x1000,
+    },
+];
+
+struct Queue {
+    queue: Virtqueue,
+    /// DMA buffer of every descriptor, `BUFFER_SIZE` each
+    buffers: u64,
+}
+
+impl Queue {
+    fn buffer(&self, index: u16) -> u64 {
+        self.buffers + index as u64 * BUFFER_SIZE
+    }
+}
+
+struct Inner {
+    rx: Queue,
+    tx: Queue,
+}
+
+pub struct VirtioNet {
+    function: &'static PciFunction,
+    transport: spin::Once<VirtioPci>,
+    mac: spin::Once<MacAddr>,
+    inner: Mutex<Option<Inner>>,
+}
+
+pub fn probe(function: &'static PciFunction) -> Option<Arc<dyn Device>> {
+    Some(Arc::new(VirtioNet {
+        function,
+        transport: spin::Once::new(),
+        mac: spin::Once::new(),
+        inner: Mutex::new("virtio_net", None),
+    }))
+}
+
+unsafe fn setup_queue(
+    transport: &VirtioPci,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    index: u16,
+) -> Result<Queue, DriverError> {
+    let queue = transport.setup_queue(frame_allocator, index, QUEUE_SIZE)?;
+    let buffers = frame_allocator
+        .allocate_dma(queue.size() as u64 * BUFFER_SIZE)
+        .ok_or(DriverError::OutOfMemory)?;
+    Ok(Queue { queue, buffers })
+}
+
+impl Inner {
+    /// Give a receive buffer back to the device
+    fn post_rx(&mut self, index: u16) {
+        let buffer = Buffer::writable(self.rx.buffer(index), BUFFER_SIZE as u32);
+        let head = self.rx.queue.add(&[buffer]);
+        debug_assert_eq!(head, Some(index));
+    }
+}
+
+impl NetDevice for VirtioNet {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn mac(&self) -> MacAddr {
+        self.mac.get().copied().unwrap_or_default()
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME {
+            return Err(NetError::TooLarge);
+        }
+        let mut inner = self.inner.lock();
+        let inner = inner.as_mut().ok_or(NetError::NotInitialized)?;
+
+        // Reclaim the buffers of sent frames
+        while inner.tx.queue.pop_used().is_some() {}
+
+        // Chains are single descriptors, so the head owns the buffer
+        let index = inner.tx.queue.peek_free().ok_or(NetError::QueueFull)?;
+        let buffer = inner.tx.buffer(index);
+        unsafe {
+            core::ptr::write_bytes(buffer as *mut u8, 0, HEADER_SIZE);
+            core::ptr::copy_nonoverlapping(
+                frame.as_ptr(),
+                (buffer + HEADER_SIZE as u64) as *mut u8,
+                frame.len(),
+            );
+        }
+        let buffer = Buffer::readable(buffer, (HEADER_SIZE + frame.len()) as u32);
+        inner.tx.queue.add(&[buffer]).ok_or(NetError::QueueFull)?;
+        inner.tx.queue.notify();
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut inner = self.inner.lock();
+        let inner = inner.as_mut()?;
+        let (head, len) = inner.rx.queue.pop_used()?;
+
+        let len = (len as usize).saturating_sub(HEADER_SIZE).min(buf.len());
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (inner.rx.buffer(head) + HEADER_SIZE as u64) as *const u8,
+                buf.as_mut_ptr(),
+                len,
+            );
+        }
+        inner.post_rx(head);
+        inner.rx.queue.notify();
+        Some(len)
+    }
+}
+
+impl Device for VirtioNet {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn function(&self) -> &'static PciFunction {
+        self.function
+    }
+
+    unsafe fn init(
+        &self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<(), DriverError> {
+        let transport = VirtioPci::new(mapper, frame_allocator, self.function)?;
+        let features = transport.negotiate(VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS)?;
+        if features & VIRTIO_NET_F_MAC == 0 {
+            return Err(DriverError::Device("virtio-net without mac address"));
+        }
+
+        let rx = setup_queue(&transport, frame_allocator, RX_QUEUE)?;
+        let tx = setup_queue(&transport, frame_allocator, TX_QUEUE)?;
+        let mut inner = Inner { rx, tx };
+        for index in 0..inner.rx.queue.size() {
+            inner.post_rx(index);
+        }
+
+        let mut mac = MacAddr::default();
+        for (i, byte) in mac.0.iter_mut().enumerate() {
+            *byte = transport.read_config::<u8>(CONFIG_MAC + i as u64);
+        }
+        transport.driver_ok();
+        inner.rx.queue.notify();
+
+        if features & VIRTIO_NET_F_STATUS != 0
+            && transport.read_config::<u16>(CONFIG_STATUS) & VIRTIO_NET_S_LINK_UP == 0
+        {
+            log::warn!("virtio-net {}: link is down", self.function.address);
+        }
+
+        self.mac.call_once(|| mac);
+        self.transport.call_once(|| transport);
+        *self.inner.lock() = Some(inner);
+        Ok(())
+    }
+
+    unsafe fn purge(&self) {
+        if let Some(transport) = self.transport.get() {
+            transport.reset().ok();
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! Virtio 9P transport.
+//! Every request is a chain of the T-message, readable by the device, and
+//! a buffer for the R-message. Only one request is in flight, it is
+//! polled until the device returns the chain.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{VirtioPci, MODERN_DEVICE_ID_BASE, VENDOR_ID};
+use crate::lock::Mutex;
+use crate::memory::BootInfoFrameAllocator;
+use crate::p9::P9Error;
+use crate::pci::{Device, DriverError, Match, PciFunction};
+use alloc::string::String;
+use alloc::sync::Arc;
+use x86_64::structures::paging::OffsetPageTable;
+
+/// Largest message in either direction
+pub const MSIZE: u32 = 64 * 1024;
+
+const QUEUE_SIZE: u16 = 16;
+
+/// Time the host gets for a request
+const TIMEOUT_US: u64 = 10_000_000;
+
+/// The device config contains the mount tag
+const VIRTIO_9P_MOUNT_TAG: u64 = 1 << 0;
+
+/// Offsets in the device configuration
+const CONFIG_TAG_LEN: u64 = 0;
+const CONFIG_TAG: u64 = 2;
+
+pub const MATCHES: &[Match] = &[
+    Match::Id {
+        vendor_id: VENDOR_ID,
+        device_id: MODERN_DEVICE_ID_BASE + 9,
+    },
+    // Transitional device
+    Match::Id {
+        vendor_id: VENDOR_ID,
+        device_id: 0x1009,
+    },
+];
+
+struct Inner {
+    queue: Virtqueue,
+    /// DMA buffers of `MSIZE` for the T- and R-message
+    request: u64,
+    response: u64,
+}
+
+pub struct VirtioP9 {
+    function: &'static PciFunction,
+    transport: spin::Once<VirtioPci>,
+    tag: spin::Once<String>,
+    inner: Mutex<Option<Inner>>,
+}
+
+pub fn probe(function: &'static PciFunction) -> Option<Arc<dyn Device>> {
+    Some(Arc::new(VirtioP9 {
+        function,
+        transport: spin::Once::new(),
+        tag: spin::Once::new(),
+        inner: Mutex::new("virtio_9p", None),
+    }))
+}
+
+impl VirtioP9 {
+    /// Mount tag of the export, the `mount_tag` of `-virtfs`
+    pub fn tag(&self) -> &str {
+        self.tag.get().map_or("", |tag| tag.as_str())
+    }
+
+    /// Let `build` write a T-message of at most `MSIZE` bytes and return its
+    /// length, send it and hand the R-message to `parse`
+    pub fn transact<R>(
+        &self,
+        build: impl FnOnce(&mut [u8]) -> Result<usize, P9Error>,
+        parse: impl FnOnce(&[u8]) -> Result<R, P9Error>,
+    ) -> Result<R, P9Error> {
+        let mut guard = self.inner.lock();
+        let inner = guard.as_mut().ok_or(P9Error::NotInitialized)?;
+
+        let request =
+            unsafe { core::slice::from_raw_parts_mut(inner.request as *mut u8, MSIZE as usize) };
+        let len = build(request)?;
+
+        let chain = [
+            Buffer::readable(inner.request, len as u32),
+            Buffer::writable(inner.response, MSIZE),
+        ];
+        let head = inner.queue.add(&chain).ok_or(P9Error::NotInitialized)?;
+        inner.queue.notify();
+
+        let deadline = crate::time::future(TIMEOUT_US);
+        let written = loop {
+            match inner.queue.pop_used() {
+                Some((used, written)) if used == head => break written,
+                // Only one request is in flight, anything else is stale
+                Some(_) => continue,
+                None => {}
+            }
+            if crate::time::rdtsc() > deadline {
+                if let Some(transport) = self.transport.get() {
+                    unsafe { transport.abort("virtio-9p") };
+                }
+                *guard = None;
+                return Err(P9Error::Timeout);
+            }
+            core::hint::spin_loop();
+        };
+
+        let response = unsafe {
+            core::slice::from_raw_parts(inner.response as *const u8, written.min(MSIZE) as usize)
+        };
+        parse(response)
+    }
+}
+
+impl Device for VirtioP9 {
+    fn name(&self) -> &'static str {
+        "virtio-9p"
+    }
+
+    fn function(&self) -> &'static PciFunction {
+        self.function
+    }
+
+    unsafe fn init(
+        &self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> Result<(), DriverError> {
+        let transport = VirtioPci::new(mapper, frame_allocator, self.function)?;
+        let features = transport.negotiate(VIRTIO_9P_MOUNT_TAG)?;
+        let queue = transport.setup_queue(frame_allocator, 0, QUEUE_SIZE)?;
+        let request = frame_allocator
+            .allocate_dma(MSIZE as u64)
+            .ok_or(DriverError::OutOfMemory)?;
+        let response = frame_allocator
+            .allocate_dma(MSIZE as u64)
+            .ok_or(DriverError::OutOfMemory)?;
+
+        let mut tag = String::new();
+        if features & VIRTIO_9P_MOUNT_TAG != 0 {
+            let len = transport.read_config::<u16>(CONFIG_TAG_LEN);
+            for i in 0..len as u64 {
+                tag.push(transport.read_config::<u8>(CONFIG_TAG + i) as char);
+            }
+        }
+        transport.driver_ok();
+
+        log::info!("virtio-9p {}: mount tag {:?}", self.function.address, tag);
+        self.tag.call_once(|| tag);
+        self.transport.call_once(|| transport);
+        *self.inner.lock() = Some(Inner {
+            queue,
+            request,
+            response,
+        });
+        Ok(())
+    }
+
+    unsafe fn purge(&self) {
+        if let Some(transport) = self.transport.get() {
+            transport.reset().ok();
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Split virtqueues.
+//! A queue consists of the descriptor table, the available ring written by
+//! the driver and the used ring written by the device. Buffers are chains
+//! of descriptors, the head of a chain identifies the request.
+
+use crate::memory::BootInfoFrameAllocator;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// The driver does not want interrupts for used buffers
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+
+/// Buffer of a chain
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    /// Physical address
+    pub addr: u64,
+    pub len: u32,
+    /// The device writes to the buffer instead of reading it
+    pub writable: bool,
+}
+
+impl Buffer {
+    pub fn readable(addr: u64, len: u32) -> Self {
+        Buffer {
+            addr,
+            len,
+            writable: false,
+        }
+    }
+
+    pub fn writable(addr: u64, len: u32) -> Self {
+        Buffer {
+            addr,
+            len,
+            writable: true,
+        }
+    }
+}
+
+/// The rings live in identity mapped memory, so all addresses are physical
+/// and virtual at the same time
+#[derive(Debug)]
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    /// Where to write the queue index to notify the device
+    notify: u64,
+    /// Free descriptors are linked through their next field
+    free_head: u16,
+    num_free: u16,
+    /// Next index of the available ring to write
+    avail_idx: u16,
+    /// Next index of the used ring to read
+    last_used: u16,
+}
+
+impl Virtqueue {
+    pub fn new(
+        frame_allocator: &mut BootInfoFrameAllocator,
+        index: u16,
+        size: u16,
+        notify: u64,
+    ) -> Option<Virtqueue> {
+        let desc_size = DESCRIPTOR_SIZE * size as u64;
+        let avail_size = 6 + 2 * size as u64;
+        let used_size = 6 + 8 * size as u64;
+
+        // The used ring has to be 4 byte aligned
+        let used_offset = (desc_size + avail_size + 3) & !3;
+        let desc = frame_allocator.allocate_dma(used_offset + used_size)?;
+
+        let mut queue = Virtqueue {
+            index,
+            size,
+            desc,
+            avail: desc + desc_size,
+            used: desc + used_offset,
+            notify,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            last_used: 0,
+        };
+        for i in 0..size {
+            queue.set_next(i, (i + 1) % size);
+        }
+        queue.set_interrupts(false);
+        Some(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    /// Descriptor which becomes the head of the next chain
+    pub fn peek_free(&self) -> Option<u16> {
+        if self.num_free == 0 {
+            None
+        } else {
+            Some(self.free_head)
+        }
+    }
+
+    pub fn descriptor_area(&self) -> u64 {
+        self.desc
+    }
+
+    pub fn driver_area(&self) -> u64 {
+        self.avail
+    }
+
+    pub fn device_area(&self) -> u64 {
+        self.used
+    }
+
+    fn descriptor(&self, index: u16) -> u64 {
+        self.desc + index as u64 * DESCRIPTOR_SIZE
+    }
+
+    fn next(&self, index: u16) -> u16 {
+        unsafe { read_volatile((self.descriptor(index) + 14) as *const u16) }
+    }
+
+    fn set_next(&mut self, index: u16, next: u16) {
+        unsafe { write_volatile((self.descriptor(index) + 14) as *mut u16, next) };
+    }
+
+    fn flags(&self, index: u16) -> u16 {
+        unsafe { read_volatile((self.descriptor(index) + 12) as *const u16) }
+    }
+
+    /// Ask the device to interrupt when it used buffers. Only a hint.
+    pub fn set_interrupts(&mut self, enabled: bool) {
+        let flags = if enabled { 0 } else { AVAIL_F_NO_INTERRUPT };
+        unsafe { write_volatile(self.avail as *mut u16, flags) };
+    }
+
+    /// Make a chain of `buffers` available to the device. Returns the head
+    /// of the chain, or None if there are not enough free descriptors.
+    /// The device sees the chain after the next `notify`.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free as usize {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let last = i + 1 == buffers.len();
+            let mut flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if !last {
+                flags |= DESC_F_NEXT;
+            }
+            let next = self.next(index);
+            let desc = self.descriptor(index);
+            unsafe {
+                write_volatile(desc as *mut u64, buffer.addr);
+                write_volatile((desc + 8) as *mut u32, buffer.len);
+                write_volatile((desc + 12) as *mut u16, flags);
+            }
+            if !last {
+                index = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.num_free -= buffers.len() as u16;
+
+        let slot = self.avail_idx % self.size;
+        unsafe { write_volatile((self.avail + 4 + 2 * slot as u64) as *mut u16, head) };
+
+        // The ring entry has to be visible before the index
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe { write_volatile((self.avail + 2) as *mut u16, self.avail_idx) };
+        Some(head)
+    }
+
+    /// Tell the device about new buffers
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { write_volatile(self.notify as *mut u16, self.index) };
+    }
+
+    /// Whether the device returned buffers which were not popped yet
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        let used_idx = unsafe { read_volatile((self.used + 2) as *const u16) };
+        used_idx != self.last_used
+    }
+
+    /// Take the next chain the device is done with and free its
+    /// descriptors. Returns the head and the number of bytes written.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        let slot = self.last_used % self.size;
+        let elem = self.used + 4 + 8 * slot as u64;
+        let (head, len) = unsafe {
+            (
+                read_volatile(elem as *const u32) as u16,
+                read_volatile((elem + 4) as *const u32),
+            )
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Return the chain to the free list
+        let mut tail = head;
+        let mut count = 1;
+        while self.flags(tail) & DESC_F_NEXT != 0 {
+            tail = self.next(tail);
+            count += 1;
+        }
+        self.set_next(tail, self.free_head);
+        self.free_head = head;
+        self.num_free += count;
+        Some((head, len))
+    }
+}
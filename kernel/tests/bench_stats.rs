@@ -0,0 +1,58 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use perf_kernel::bench::{percentile, Stats};
+use perf_kernel::{klog, println};
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    klog::init();
+    println!("==== bench_stats ====");
+    test_main();
+
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn stats_of_sequence() {
+    let mut samples = [5.0, 3.0, 1.0, 4.0, 2.0];
+    let stats = Stats::from_samples(&mut samples);
+
+    assert_eq!(stats.samples, 5);
+    assert_eq!(stats.min, 1.0);
+    assert_eq!(stats.max, 5.0);
+    assert_eq!(stats.mean, 3.0);
+    assert_eq!(stats.median, 3.0);
+    assert_eq!(stats.p25, 2.0);
+    assert_eq!(stats.p75, 4.0);
+    // Sample standard deviation of 1..=5 is sqrt(2.5)
+    assert!(stats.stddev > 1.581 && stats.stddev < 1.582);
+    assert_eq!(stats.low_outliers + stats.high_outliers, 0);
+}
+
+#[test_case]
+fn stats_outliers() {
+    let mut samples = [10.0, 11.0, 10.0, 12.0, 11.0, 10.0, 100.0, 11.0];
+    let stats = Stats::from_samples(&mut samples);
+
+    assert_eq!(stats.high_outliers, 1);
+    assert_eq!(stats.low_outliers, 0);
+}
+
+#[test_case]
+fn percentile_interpolates() {
+    let samples = [0.0, 10.0];
+    assert_eq!(percentile(&samples, 50.0), 5.0);
+    assert_eq!(percentile(&samples, 0.0), 0.0);
+    assert_eq!(percentile(&samples, 100.0), 10.0);
+    assert_eq!(percentile(&[], 50.0), 0.0);
+}
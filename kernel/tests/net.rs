@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use perf_kernel::net::{arp, ipv4, udp, Ipv4Addr, MacAddr};
+use perf_kernel::{klog, println};
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    klog::init();
+    println!("==== net ====");
+    test_main();
+
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn checksum_of_rfc1071_example() {
+    let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+    assert_eq!(ipv4::checksum(&data, 0), 0x220d);
+    // An odd byte is padded with zero
+    assert_eq!(ipv4::checksum(&data[..7], 0), 0x2304);
+}
+
+#[test_case]
+fn checksum_of_ipv4_header() {
+    let mut header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    assert_eq!(ipv4::checksum(&header, 0), 0xb861);
+    // A header with its checksum filled in sums up to zero
+    header[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+    assert_eq!(ipv4::checksum(&header, 0), 0);
+}
+
+#[test_case]
+fn udp_encoding() {
+    let mut buf = [0u8; 32];
+    let len = udp::encode(
+        &mut buf,
+        Ipv4Addr::new(10, 0, 2, 15),
+        1234,
+        Ipv4Addr::new(10, 0, 2, 2),
+        5555,
+        b"hello",
+    );
+
+    assert_eq!(len, udp::HEADER_SIZE + 5);
+    assert_eq!(buf[0..2], 1234u16.to_be_bytes());
+    assert_eq!(buf[2..4], 5555u16.to_be_bytes());
+    assert_eq!(buf[4..6], (len as u16).to_be_bytes());
+    assert_eq!(buf[6..8], 0x896cu16.to_be_bytes());
+    assert_eq!(&buf[udp::HEADER_SIZE..len], b"hello");
+}
+
+#[test_case]
+fn arp_request_encoding() {
+    let mut buf = [0u8; 64];
+    let mac = MacAddr([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    let len = arp::encode(
+        &mut buf,
+        arp::OPER_REQUEST,
+        mac,
+        Ipv4Addr::new(10, 0, 2, 15),
+        MacAddr::default(),
+        Ipv4Addr::new(10, 0, 2, 2),
+    );
+
+    assert_eq!(len, arp::PACKET_SIZE);
+    assert_eq!(
+        buf[..len],
+        [
+            0x00, 0x01, // Ethernet
+            0x08, 0x00, // IPv4
+            6, 4, // Address lengths
+            0x00, 0x01, // Request
+            0x52, 0x54, 0x00, 0x12, 0x34, 0x56, // Sender MAC
+            10, 0, 2, 15, // Sender IP
+            0, 0, 0, 0, 0, 0, // Target MAC
+            10, 0, 2, 2, // Target IP
+        ]
+    );
+}
@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(perf_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::BootInfo;
+use bootloader::entry_point;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use perf_kernel::interrupts::{self, InterruptIndex};
+use perf_kernel::{apic, klog, println, time, timer};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    klog::init();
+
+    unsafe {
+        perf_kernel::init(boot_info);
+    }
+    println!("===== timer =====");
+
+    test_main();
+    perf_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    perf_kernel::test_panic_handler(info)
+}
+
+static FIRED: AtomicU64 = AtomicU64::new(0);
+
+fn count(_now: u64, arg: u64) {
+    FIRED.fetch_add(arg, Ordering::SeqCst);
+}
+
+fn count_vector(_vector: u8, arg: usize) {
+    FIRED.fetch_add(arg as u64, Ordering::SeqCst);
+}
+
+/// Wait until `FIRED` reaches `value` or `microseconds` passed
+fn wait_for(value: u64, microseconds: u64) -> bool {
+    let deadline = time::future(microseconds);
+    while FIRED.load(Ordering::SeqCst) < value {
+        if time::rdtsc() > deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+#[test_case]
+fn one_shot_tickless() {
+    FIRED.store(0, Ordering::SeqCst);
+    timer::set_tickless(true).unwrap();
+
+    timer::schedule_in(5 * 1000, count, 1).unwrap();
+    assert!(wait_for(1, 100 * 1000));
+
+    timer::set_tickless(false).unwrap();
+}
+
+#[test_case]
+fn cancel() {
+    FIRED.store(0, Ordering::SeqCst);
+    timer::set_tickless(true).unwrap();
+
+    let id = timer::schedule_in(5 * 1000, count, 1).unwrap();
+    assert!(timer::cancel(id));
+    assert!(!timer::cancel(id));
+    assert!(!wait_for(1, 20 * 1000));
+
+    timer::set_tickless(false).unwrap();
+}
+
+#[test_case]
+fn periodic() {
+    FIRED.store(0, Ordering::SeqCst);
+    timer::set_periodic(1000).unwrap();
+
+    let id = timer::schedule_every(2 * 1000, count, 1).unwrap();
+    assert!(wait_for(5, 100 * 1000));
+    assert!(timer::cancel(id));
+
+    timer::set_periodic(timer::DEFAULT_HZ).unwrap();
+}
+
+#[test_case]
+fn allocate_and_trigger_two_vectors() {
+    FIRED.store(0, Ordering::SeqCst);
+
+    let first = interrupts::allocate_vector(count_vector, 1).unwrap();
+    let second = interrupts::allocate_vector(count_vector, 2).unwrap();
+    assert_ne!(first, second);
+    for &vector in [first, second].iter() {
+        assert!(interrupts::DYNAMIC_VECTORS.contains(&(vector as usize)));
+        assert!(vector > InterruptIndex::SlavePicSpurious.as_u8());
+    }
+
+    unsafe { apic::send_fixed_ipi(apic::apic_id(), first) };
+    assert!(wait_for(1, 100 * 1000));
+    unsafe { apic::send_fixed_ipi(apic::apic_id(), second) };
+    assert!(wait_for(3, 100 * 1000));
+
+    interrupts::free_vector(first);
+    interrupts::free_vector(second);
+}
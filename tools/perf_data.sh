@@ -0,0 +1,16 @@
+#!/usr/bin/env bash
+# Extract a perf.data file streamed over serial by the kernel
+# Usage: cargo run | tee serial.log; tools/perf_data.sh serial.log [perf.data] [kernel elf]
+set -euo pipefail
+
+LOG="${1:?usage: $0 <serial log> [output] [kernel elf]}"
+OUT="${2:-perf.data}"
+TARGET="${CARGO_TARGET_DIR:-$(dirname "$0")/../kernel/target}"
+
+sed -n '/^# perf.data begin/,/^# perf.data end/{/^#/d;p}' "$LOG" | tr -d '\r' | xxd -r -p > "$OUT"
+
+# The kernel names its ELF relative to the target directory
+ELF="${3:-$TARGET/$(grep -m1 '^# perf.data begin' "$LOG" | tr -d '\r' | cut -d' ' -f5)}"
+echo "Written $OUT, open it with:"
+echo "perf report -i $OUT --vmlinux $ELF"
+echo "perf script -i $OUT --vmlinux $ELF"
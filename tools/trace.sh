@@ -0,0 +1,11 @@
+#!/usr/bin/env bash
+# Extract a trace dumped over serial by the kernel
+# Usage: cargo run | tee serial.log; tools/trace.sh serial.log [trace.json]
+set -euo pipefail
+
+LOG="${1:?usage: $0 <serial log> [output]}"
+OUT="${2:-trace.json}"
+
+sed -n '/^# trace begin/,/^# trace end/{/^#/d;p}' "$LOG" | tr -d '\r' > "$OUT"
+
+echo "Written $OUT, open it in chrome://tracing or https://ui.perfetto.dev"